@@ -8,6 +8,18 @@ pub struct MaterializeOptions {
     pub intercept_name: &'static str,
     /// Whether to clean column names using `make_clean_names()`.
     pub clean_names: bool,
+    /// Whether to write smooths in mixed model form as brms does: the
+    /// unpenalized null space goes into X and the penalized part into Z.
+    pub mixed_model_smooths: bool,
+    /// Whether to check that the response lies in the support of the family,
+    /// e.g. non-negative integers for `poisson`. Off for new data, whose
+    /// response may be a placeholder.
+    pub check_response: bool,
+    /// Whether to code a categorical response for its family: ordered codes
+    /// for `cumulative()`, indicator columns for `categorical()` and 0/1 for
+    /// a two-level `bernoulli()` response. The levels are recorded in
+    /// `DesignInfo::response_encoding`.
+    pub encode_response: bool,
 }
 
 impl Default for MaterializeOptions {
@@ -16,6 +28,9 @@ impl Default for MaterializeOptions {
             rhs_intercept: true,
             intercept_name: "intercept",
             clean_names: true,
+            mixed_model_smooths: false,
+            check_response: true,
+            encode_response: true,
         }
     }
 }
@@ -51,12 +66,15 @@ pub struct Formula {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Response {
     Var(String),
-    Multi(Vec<String>), // cbind/mvbind
+    Multi {
+        name: String,
+        responses: Vec<Expr>,
+    }, // cbind(s, n - s), mvbind(y1, y2)
     Surv {
         time: Expr,
         event: Expr,
         time2: Option<Expr>,
-    },
+    }, // Surv(time, event) or Surv(start, stop, event) with `time2` the stop time
     Func {
         name: String,
         args: Vec<Expr>,
@@ -130,6 +148,10 @@ pub enum Expr {
         name: String,
         args: Vec<Expr>,
     },
+    Named {
+        name: String,
+        value: Box<Expr>,
+    }, // df=5 inside a function call
     Identity(Box<Expr>), // I(...)
     Intercept(bool),     // 1 or 0
     Dot,                 // .
@@ -163,6 +185,38 @@ pub enum GroupOp {
 #[derive(Debug, Clone, PartialEq)]
 pub struct GroupExpr(pub Vec<(String, Option<GroupOp>)>); // [(g1,None),(g2,Some(Cross)),...]
 
+impl GroupExpr {
+    /// The grouping factors crossed by `:` alone, e.g. `a:b`.
+    pub fn crossed(factors: Vec<String>) -> Self {
+        GroupExpr(
+            factors
+                .into_iter()
+                .enumerate()
+                .map(|(i, f)| (f, (i > 0).then_some(GroupOp::Cross)))
+                .collect(),
+        )
+    }
+
+    /// Expand nesting and splitting into the crossed factors of separate terms,
+    /// as lme4 does: `a/b` gives `a` and `a:b`, `a + b` gives `a` and `b`.
+    /// `:` binds tighter than `/`, so `a/b:c` gives `a` and `a:b:c`.
+    pub fn expand(&self) -> Vec<Vec<String>> {
+        let mut terms = Vec::new();
+        let mut current: Vec<String> = Vec::new();
+        for (name, op) in &self.0 {
+            match op {
+                None | Some(GroupOp::Cross) => {}
+                Some(GroupOp::Nest) => terms.push(current.clone()),
+                Some(GroupOp::Split) => terms.push(std::mem::take(&mut current)),
+            }
+            current.push(name.clone());
+        }
+        terms.push(current);
+        terms.dedup();
+        terms
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum SmoothKind {
     S,
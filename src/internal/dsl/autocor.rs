@@ -0,0 +1,341 @@
+//! Autocorrelation structures of the residuals, as in brms.
+//!
+//! `ar()`, `ma()`, `arma()`, `cosy()` and `unstr()` correlate the residuals
+//! of the observations of a group over time; `car()`, `sar()` and `fcor()`
+//! correlate them through a matrix `M` given with the data (an adjacency,
+//! spatial weights or covariance matrix). The terms are parsed into
+//! [`Autocor`] with their arguments by name, checked against the signature
+//! of their structure, and evaluated into [`AutocorTerm`] metadata: the time
+//! and group of every row and the order of the rows within their group.
+
+use super::ast::{Autocor, Expr, ModelSpec};
+use super::random::group_levels;
+use crate::Error;
+use polars::prelude::*;
+use std::collections::HashMap;
+
+/// Arguments of each structure in positional order, and which are required.
+const SIGNATURES: &[(&str, &[&str], &[&str])] = &[
+    ("ar", &["time", "gr", "p", "cov"], &[]),
+    ("ma", &["time", "gr", "q", "cov"], &[]),
+    ("arma", &["time", "gr", "p", "q", "cov"], &[]),
+    ("cosy", &["time", "gr"], &[]),
+    ("unstr", &["time", "gr"], &["time", "gr"]),
+    ("car", &["M", "gr", "type"], &["M"]),
+    ("sar", &["M", "type"], &["M"]),
+    ("fcor", &["M"], &["M"]),
+];
+
+/// Structures of the residuals of a group over time; a model has at most one.
+const TIME_SERIES: &[&str] = &["ar", "ma", "arma", "cosy", "unstr"];
+
+const CAR_TYPES: &[&str] = &["escar", "esicar", "icar", "bym2"];
+const SAR_TYPES: &[&str] = &["lag", "error"];
+
+fn signature(structure: &str) -> Option<(&'static [&'static str], &'static [&'static str])> {
+    SIGNATURES
+        .iter()
+        .find(|(name, _, _)| *name == structure)
+        .map(|(_, args, required)| (*args, *required))
+}
+
+/// Whether a function call is an autocorrelation term.
+pub(crate) fn is_autocor_function(name: &str) -> bool {
+    signature(name).is_some()
+}
+
+/// Name the arguments of an inline autocorrelation call as R matches them:
+/// named arguments keep their name and the positional ones take the
+/// remaining names of the signature in order, e.g. `ar(t, g, p=2)` gets
+/// `time=t, gr=g, p=2`. Positional arguments beyond the signature are
+/// named `arg<i>` and rejected by [`check_autocor`].
+pub(crate) fn autocor_from_call(name: &str, args: Vec<Expr>) -> Autocor {
+    let names = signature(name).map(|(names, _)| names).unwrap_or(&[]);
+    let mut named = HashMap::new();
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Named { name, value } => {
+                named.insert(name, *value);
+            }
+            arg => positional.push(arg),
+        }
+    }
+    let free: Vec<&str> = names
+        .iter()
+        .copied()
+        .filter(|n| !named.contains_key(*n))
+        .collect();
+    let mut free = free.into_iter();
+    for (i, arg) in positional.into_iter().enumerate() {
+        let key = match free.next() {
+            Some(name) => name.to_string(),
+            None => format!("arg{}", i),
+        };
+        named.insert(key, arg);
+    }
+    Autocor {
+        name: name.to_string(),
+        args: named,
+    }
+}
+
+/// The arguments of a term in signature order, for printing.
+pub(crate) fn ordered_args(autocor: &Autocor) -> Vec<(&str, &Expr)> {
+    let names = signature(&autocor.name)
+        .map(|(names, _)| names)
+        .unwrap_or(&[]);
+    let mut args: Vec<(&str, &Expr)> = autocor
+        .args
+        .iter()
+        .map(|(name, value)| (name.as_str(), value))
+        .collect();
+    args.sort_by_key(|(name, _)| {
+        (
+            names.iter().position(|n| n == name).unwrap_or(names.len()),
+            name.to_string(),
+        )
+    });
+    args
+}
+
+/// Check the autocorrelation terms of a spec against the signatures of their
+/// structures: known argument names, required arguments present, variables
+/// for `time`, `gr` and `M`, non-negative integer orders, a logical `cov`
+/// and a known `type`. A model has at most one time-series structure and at
+/// most one of `car()`, `sar()` and `fcor()`.
+pub(crate) fn check_autocor(spec: &ModelSpec) -> Result<(), Error> {
+    for autocor in &spec.autocor {
+        check_term(autocor)?;
+    }
+    for (group, what) in [
+        (TIME_SERIES, "time-series"),
+        (&["car", "sar", "fcor"][..], "spatial or fixed"),
+    ] {
+        let terms: Vec<&str> = spec
+            .autocor
+            .iter()
+            .map(|autocor| autocor.name.as_str())
+            .filter(|name| group.contains(name))
+            .collect();
+        if terms.len() > 1 {
+            return Err(Error::Semantic(format!(
+                "A model may have one {} autocorrelation structure, found {}",
+                what,
+                terms.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_term(autocor: &Autocor) -> Result<(), Error> {
+    let name = autocor.name.as_str();
+    let (names, required) = signature(name)
+        .ok_or_else(|| Error::Semantic(format!("Unknown autocorrelation structure '{}'", name)))?;
+    let invalid = |arg: &str, what: &str| {
+        Err(Error::Semantic(format!(
+            "Argument '{}' of {}() must be {}",
+            arg, name, what
+        )))
+    };
+    for (arg, value) in ordered_args(autocor) {
+        match arg {
+            arg if !names.contains(&arg) => {
+                return Err(Error::Semantic(format!(
+                    "{}() has no argument '{}'; its arguments are {}",
+                    name,
+                    arg,
+                    names.join(", ")
+                )))
+            }
+            "time" | "gr" if !matches!(value, Expr::Var(_)) => return invalid(arg, "a variable"),
+            "M" if !matches!(value, Expr::Var(_) | Expr::Str(_)) => {
+                return invalid(arg, "the name of a matrix")
+            }
+            "p" | "q" => match value {
+                Expr::Num(n) if *n >= 0.0 && n.fract() == 0.0 => {}
+                _ => return invalid(arg, "a non-negative integer"),
+            },
+            "cov" if !matches!(value, Expr::Bool(_)) => return invalid(arg, "TRUE or FALSE"),
+            "type" => {
+                let types = if name == "car" { CAR_TYPES } else { SAR_TYPES };
+                match value {
+                    Expr::Str(t) | Expr::Var(t) if types.contains(&t.as_str()) => {}
+                    _ => return invalid(arg, &format!("one of {}", types.join(", "))),
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(missing) = required
+        .iter()
+        .find(|arg| !autocor.args.contains_key(**arg))
+    {
+        return Err(Error::Semantic(format!(
+            "{}() needs the argument '{}'",
+            name, missing
+        )));
+    }
+    Ok(())
+}
+
+/// An autocorrelation term evaluated on the rows of a data frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutocorTerm {
+    /// The structure: `ar`, `ma`, `arma`, `cosy`, `unstr`, `car`, `sar` or
+    /// `fcor`.
+    pub structure: String,
+    /// Autoregressive order; 1 for `ar()` and `arma()` unless given, 0 for
+    /// the other structures.
+    pub p: usize,
+    /// Moving average order; 1 for `ma()` and `arma()` unless given, 0 for
+    /// the other structures.
+    pub q: usize,
+    /// Whether the ARMA structure is modelled through the residual covariance
+    /// matrix (`cov=TRUE`).
+    pub cov: bool,
+    /// The time variable, or `None` if the rows are in time order.
+    pub time: Option<String>,
+    /// The grouping variable, or `None` if all rows form one group.
+    pub gr: Option<String>,
+    /// Name of the matrix `M` of `car()`, `sar()` and `fcor()`, to be looked
+    /// up with the data.
+    pub matrix: Option<String>,
+    /// `type=` of `car()` (by default `escar`) or `sar()` (by default `lag`).
+    pub kind: Option<String>,
+    /// Time of each row: the value of `time`, or the row number.
+    pub times: Vec<f64>,
+    /// Levels of the grouping variable, sorted; a single empty level without
+    /// one.
+    pub groups: Vec<String>,
+    /// Group of each row, as an index into `groups`.
+    pub group: Vec<usize>,
+    /// Rows ordered by group and by time within the group.
+    pub order: Vec<usize>,
+    /// Position of each row in the time order of its group, from 0.
+    pub position: Vec<usize>,
+}
+
+/// Evaluate the autocorrelation terms of a spec on the rows of `df`.
+pub(crate) fn materialize_autocor(
+    df: &DataFrame,
+    spec: &ModelSpec,
+) -> Result<Vec<AutocorTerm>, Error> {
+    spec.autocor
+        .iter()
+        .map(|autocor| materialize_term(df, autocor))
+        .collect()
+}
+
+fn materialize_term(df: &DataFrame, autocor: &Autocor) -> Result<AutocorTerm, Error> {
+    let structure = autocor.name.clone();
+    let var = |arg: &str| match autocor.args.get(arg) {
+        Some(Expr::Var(name) | Expr::Str(name)) => Some(name.clone()),
+        _ => None,
+    };
+    let order = |arg: &str, default: bool| match autocor.args.get(arg) {
+        Some(Expr::Num(n)) => *n as usize,
+        _ => usize::from(default),
+    };
+    let p = order("p", matches!(structure.as_str(), "ar" | "arma"));
+    let q = order("q", matches!(structure.as_str(), "ma" | "arma"));
+    let cov = matches!(autocor.args.get("cov"), Some(Expr::Bool(true)));
+    let kind = var("type").or(match structure.as_str() {
+        "car" => Some("escar".to_string()),
+        "sar" => Some("lag".to_string()),
+        _ => None,
+    });
+    let (time, gr, matrix) = (var("time"), var("gr"), var("M"));
+    let n = df.height();
+
+    let times: Vec<f64> = match &time {
+        Some(name) => {
+            let column = df.column(name).map_err(|_| {
+                Error::Semantic(format!("Column '{}' not found in DataFrame", name))
+            })?;
+            let values = column
+                .as_materialized_series()
+                .strict_cast(&DataType::Float64)
+                .map_err(|_| {
+                    Error::Semantic(format!("Time variable '{}' must be numeric", name))
+                })?;
+            values
+                .f64()
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                .into_iter()
+                .map(|v| {
+                    v.ok_or_else(|| {
+                        Error::Semantic(format!("Time variable '{}' has missing values", name))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        }
+        None => (0..n).map(|row| row as f64).collect(),
+    };
+    let (groups, group) = match &gr {
+        Some(name) => {
+            let (levels, rows) = group_levels(df, name)?;
+            let rows = rows
+                .into_iter()
+                .map(|level| {
+                    level.ok_or_else(|| {
+                        Error::Semantic(format!("Grouping variable '{}' has missing values", name))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            (levels, rows)
+        }
+        None => (vec![String::new()], vec![0; n]),
+    };
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| group[a].cmp(&group[b]).then(times[a].total_cmp(&times[b])));
+    let mut position = vec![0; n];
+    for pair in order.windows(2) {
+        let (prev, row) = (pair[0], pair[1]);
+        if group[prev] != group[row] {
+            continue;
+        }
+        if time.is_some() && times[prev] == times[row] {
+            return Err(Error::Semantic(format!(
+                "Time points of {}() must be unique within each group; {} appears twice{}",
+                structure,
+                times[row],
+                gr.as_ref()
+                    .map(|_| format!(" in group '{}'", groups[group[row]]))
+                    .unwrap_or_default()
+            )));
+        }
+        position[row] = position[prev] + 1;
+    }
+
+    Ok(AutocorTerm {
+        structure,
+        p,
+        q,
+        cov,
+        time,
+        gr,
+        matrix,
+        kind,
+        times,
+        groups,
+        group,
+        order,
+        position,
+    })
+}
+
+/// Variables of the autocorrelation terms whose missing values drop a row:
+/// `time` and `gr`.
+pub(crate) fn autocor_columns(spec: &ModelSpec) -> Vec<String> {
+    spec.autocor
+        .iter()
+        .flat_map(|autocor| ["time", "gr"].map(|arg| autocor.args.get(arg)))
+        .filter_map(|value| match value {
+            Some(Expr::Var(name)) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,4 +1,5 @@
 use super::ast::*;
+use super::autocor::{autocor_from_call, is_autocor_function};
 use std::collections::HashSet;
 
 /// Canonicalize a ModelSpec by expanding syntactic sugar and normalizing expressions.
@@ -207,11 +208,19 @@ pub fn canonicalize_expr(expr: Expr) -> Expr {
         } => canonicalize_group_expr(*inner, spec, kind, id),
         // Canonicalize identity expressions
         Expr::Identity(inner) => Expr::Identity(Box::new(canonicalize_expr(*inner))),
+        // Offsets are arithmetic expressions such as `log(a + b)`, not formula
+        // terms, so they are kept as written
+        Expr::Func { name, args } if name == "offset" => Expr::Func { name, args },
         // Canonicalize function calls
         Expr::Func { name, args } => Expr::Func {
             name,
             args: args.into_iter().map(canonicalize_expr).collect(),
         },
+        // Canonicalize named function arguments
+        Expr::Named { name, value } => Expr::Named {
+            name,
+            value: Box::new(canonicalize_expr(*value)),
+        },
         // Canonicalize smooth expressions
         Expr::Smooth { kind, vars, args } => {
             let canonicalized_args = args
@@ -332,99 +341,106 @@ fn canonicalize_group_expr(
     let canonicalized_inner = canonicalize_expr(inner);
     let canonicalized_spec = canonicalize_group_spec(spec);
 
-    match canonicalized_inner {
-        // If inner is just an intercept (1), keep as is
-        Expr::Intercept(true) => Expr::Group {
-            inner: Box::new(Expr::Intercept(true)),
+    // Nested and split grouping factors become separate terms:
+    // (1|a/b) is (1|a) + (1|a:b) and (1|a+b) is (1|a) + (1|b)
+    if let GroupSpec::Expr(group) = &canonicalized_spec {
+        let expanded = group.expand();
+        if expanded.len() > 1 {
+            let mut terms = Vec::new();
+            for factors in expanded {
+                match canonicalize_group_term(
+                    canonicalized_inner.clone(),
+                    GroupSpec::Expr(GroupExpr::crossed(factors)),
+                    kind.clone(),
+                    id.clone(),
+                ) {
+                    Expr::Sum(split) => terms.extend(split),
+                    term => terms.push(term),
+                }
+            }
+            return Expr::Sum(terms);
+        }
+    }
+
+    canonicalize_group_term(canonicalized_inner, canonicalized_spec, kind, id)
+}
+
+/// Canonicalize a group term with a single (possibly crossed) grouping factor
+fn canonicalize_group_term(
+    canonicalized_inner: Expr,
+    canonicalized_spec: GroupSpec,
+    kind: GroupKind,
+    id: Option<String>,
+) -> Expr {
+    // Correlated terms stay together, with an explicit intercept unless it was removed:
+    // (x|g) and (1 + x|g) both become (1 + x|g)
+    let inner = with_group_intercept(canonicalized_inner);
+    if matches!(kind, GroupKind::Correlated) {
+        return Expr::Group {
+            inner: Box::new(inner),
             spec: canonicalized_spec,
             kind,
             id,
-        },
-        // If inner is a variable (e.g., Days), expand to (1|group) + (0 + var|group)
-        Expr::Var(var_name) => {
-            let random_intercept = Expr::Group {
-                inner: Box::new(Expr::Intercept(true)),
-                spec: canonicalized_spec.clone(),
-                kind: kind.clone(),
-                id: id.clone(),
-            };
-
-            let random_slope = Expr::Group {
-                inner: Box::new(Expr::Sum(vec![
-                    Expr::Intercept(false), // 0 +
-                    Expr::Var(var_name.clone()),
-                ])),
-                spec: canonicalized_spec,
-                kind,
-                id,
-            };
+        };
+    }
 
-            Expr::Sum(vec![random_intercept, random_slope])
-        }
-        // If inner is a sum expression, check if it contains both intercept and variables
+    // Uncorrelated terms are split into one term per inner term, as lme4 does:
+    // (1 + x + f||g) becomes (1||g) + (0 + x||g) + (0 + f||g)
+    let (intercept, slopes) = match inner {
         Expr::Sum(terms) => {
-            let mut has_intercept = false;
-            let mut variables = Vec::new();
-            
-            for term in &terms {
-                match term {
-                    Expr::Intercept(true) => has_intercept = true,
-                    Expr::Var(var_name) => variables.push(var_name.clone()),
-                    _ => {
-                        // For other terms, just keep them as part of the group
-                        return Expr::Group {
-                            inner: Box::new(Expr::Sum(terms)),
-                            spec: canonicalized_spec,
-                            kind,
-                            id,
-                        };
-                    }
-                }
-            }
-            
-            // If we have both intercept and variables, expand to separate groups
-            if has_intercept && !variables.is_empty() {
-                let mut result = Vec::new();
-                
-                // Add random intercept group
-                result.push(Expr::Group {
-                    inner: Box::new(Expr::Intercept(true)),
-                    spec: canonicalized_spec.clone(),
-                    kind: kind.clone(),
-                    id: id.clone(),
-                });
-                
-                // Add random slope groups for each variable
-                for var_name in variables {
-                    result.push(Expr::Group {
-                        inner: Box::new(Expr::Sum(vec![
-                            Expr::Intercept(false), // 0 +
-                            Expr::Var(var_name),
-                        ])),
-                        spec: canonicalized_spec.clone(),
-                        kind: kind.clone(),
-                        id: id.clone(),
-                    });
-                }
-                
-                Expr::Sum(result)
-            } else {
-                // Otherwise, keep as a single group
-                Expr::Group {
-                    inner: Box::new(Expr::Sum(terms)),
-                    spec: canonicalized_spec,
-                    kind,
-                    id,
-                }
+            let mut terms = terms.into_iter();
+            (terms.next(), terms.collect())
+        }
+        intercept => (Some(intercept), Vec::new()),
+    };
+    let mut result: Vec<Expr> = intercept
+        .filter(|term| matches!(term, Expr::Intercept(true)))
+        .into_iter()
+        .chain(
+            slopes
+                .into_iter()
+                .map(|slope| Expr::Sum(vec![Expr::Intercept(false), slope])),
+        )
+        .map(|inner| Expr::Group {
+            inner: Box::new(inner),
+            spec: canonicalized_spec.clone(),
+            kind: kind.clone(),
+            id: id.clone(),
+        })
+        .collect();
+    match result.len() {
+        1 => result.remove(0),
+        _ => Expr::Sum(result),
+    }
+}
+
+/// Write the intercept of a group term's inner expression explicitly, as a
+/// leading `1` or `0`: (x|g) becomes (1 + x|g) and (x - 1|g) becomes (0 + x|g).
+fn with_group_intercept(inner: Expr) -> Expr {
+    let terms = match inner {
+        Expr::Sum(terms) => terms,
+        inner => vec![inner],
+    };
+    let mut intercept = true;
+    let mut rest = Vec::new();
+    for term in terms {
+        match term {
+            Expr::Intercept(true) => {}
+            Expr::Intercept(false) => intercept = false,
+            Expr::Func { name, args }
+                if name == "NEG"
+                    && args.len() == 1
+                    && matches!(args[0], Expr::Intercept(true) | Expr::Num(1.0)) =>
+            {
+                intercept = false
             }
+            term => rest.push(term),
         }
-        // For other expressions, canonicalize and keep as group
-        other => Expr::Group {
-            inner: Box::new(other),
-            spec: canonicalized_spec,
-            kind,
-            id,
-        },
+    }
+    if rest.is_empty() {
+        Expr::Intercept(intercept)
+    } else {
+        Expr::Sum(std::iter::once(Expr::Intercept(intercept)).chain(rest).collect())
     }
 }
 
@@ -441,38 +457,17 @@ fn canonicalize_group_spec(spec: GroupSpec) -> GroupSpec {
 
 /// Canonicalize aterms
 fn canonicalize_aterms(aterms: Vec<Aterm>) -> Vec<Aterm> {
-    // Remove duplicates and canonicalize expressions within aterms
+    // Aterm arguments are arithmetic expressions such as `1/var`, not formula
+    // terms, so they are kept as written; only duplicates are removed
     let mut seen = HashSet::new();
     let mut result = Vec::new();
 
     for aterm in aterms {
-        let canonicalized = match aterm {
-            Aterm::Se(expr) => Aterm::Se(canonicalize_expr(expr)),
-            Aterm::Weights(expr) => Aterm::Weights(canonicalize_expr(expr)),
-            Aterm::Trials(expr) => Aterm::Trials(canonicalize_expr(expr)),
-            Aterm::Cens(expr) => Aterm::Cens(canonicalize_expr(expr)),
-            Aterm::Trunc { lb, ub } => Aterm::Trunc {
-                lb: lb.map(canonicalize_expr),
-                ub: ub.map(canonicalize_expr),
-            },
-            Aterm::Subset(expr) => Aterm::Subset(canonicalize_expr(expr)),
-            Aterm::Rate(expr) => Aterm::Rate(canonicalize_expr(expr)),
-            Aterm::Thres { gr } => Aterm::Thres {
-                gr: gr.map(canonicalize_expr),
-            },
-            Aterm::Dec(expr) => Aterm::Dec(canonicalize_expr(expr)),
-            Aterm::Cat(expr) => Aterm::Cat(canonicalize_expr(expr)),
-            Aterm::Index(expr) => Aterm::Index(canonicalize_expr(expr)),
-            Aterm::VReal(exprs) => Aterm::VReal(exprs.into_iter().map(canonicalize_expr).collect()),
-            Aterm::VInt(exprs) => Aterm::VInt(exprs.into_iter().map(canonicalize_expr).collect()),
-            Aterm::Mi => Aterm::Mi,
-        };
-
         // Simple deduplication based on discriminant
-        let key = std::mem::discriminant(&canonicalized);
+        let key = std::mem::discriminant(&aterm);
         if !seen.contains(&key) {
             seen.insert(key);
-            result.push(canonicalized);
+            result.push(aterm);
         }
     }
 
@@ -491,24 +486,24 @@ fn hoist_autocor_recursive(expr: Expr, hoisted: &mut Vec<Autocor>) -> Expr {
     match expr {
         // Detect autocorrelation function calls and hoist them
         Expr::Func { name, args } if is_autocor_function(&name) => {
-            // Convert to Autocor and add to hoisted list
-            let mut autocor_args = std::collections::HashMap::new();
-            for (i, arg) in args.into_iter().enumerate() {
-                autocor_args.insert(format!("arg{}", i), arg);
-            }
-            hoisted.push(Autocor {
-                name: name.clone(),
-                args: autocor_args,
-            });
+            // Name the arguments and add the term to the hoisted list
+            hoisted.push(autocor_from_call(&name, args));
 
             // Replace with intercept (effectively remove from expression)
             Expr::Intercept(true)
         }
         Expr::Sum(terms) => {
+            // Only the hoisted terms are removed; an explicit intercept such as
+            // the 1 of (1 + x|g) is kept
             let processed: Vec<Expr> = terms
                 .into_iter()
-                .map(|t| hoist_autocor_recursive(t, hoisted))
-                .filter(|t| !matches!(t, Expr::Intercept(true))) // Remove hoisted terms
+                .filter_map(|t| match t {
+                    Expr::Func { name, args } if is_autocor_function(&name) => {
+                        hoisted.push(autocor_from_call(&name, args));
+                        None
+                    }
+                    t => Some(hoist_autocor_recursive(t, hoisted)),
+                })
                 .collect();
             flatten_sum_result(processed)
         }
@@ -533,11 +528,3 @@ fn hoist_autocor_recursive(expr: Expr, hoisted: &mut Vec<Autocor>) -> Expr {
         expr => expr,
     }
 }
-
-/// Check if a function name is an autocorrelation function
-fn is_autocor_function(name: &str) -> bool {
-    matches!(
-        name,
-        "ar" | "ma" | "arma" | "cosy" | "unstr" | "sar" | "car" | "fcor"
-    )
-}
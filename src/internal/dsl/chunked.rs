@@ -0,0 +1,832 @@
+//! Batch-by-batch materialization of data that does not fit in memory.
+//!
+//! Materialization runs in two passes. The first learns everything that ties
+//! the columns of y, X and Z to the data (factor levels, knots, polynomial
+//! coefficients, smoothing constraints and grouping levels) into a
+//! [`DesignInfo`], from a summary of each term that needs state updated one
+//! batch of rows at a time. The second materializes each batch with that
+//! state, so every batch gets the same columns whatever rows it holds.
+//!
+//! Levels are the distinct values seen and polynomial coefficients come from
+//! power sums of x, so they take memory in the number of levels and the
+//! degree only. Spline knots are quantiles and smooth bases depend on every
+//! row, so those terms keep the values of their variables.
+
+use super::ast::*;
+// The lazy prelude has its own Expr; formula expressions are the AST ones
+use super::ast::Expr;
+use super::design::{DesignInfo, TransformState};
+use super::family::{encode_response, response_coding};
+use super::materialize::{
+    collect_sum_terms, is_spline_function, learn_smooth_state, learn_spline_state,
+    materialize_aterms, materialize_offset, materialize_with_info, poly_args, random_blocks,
+    series_to_f64, spline_values, split_call_args, subset_mask, PolyState,
+};
+use super::model_data::collect_vars;
+use super::pretty::pretty_expr;
+use super::suffstats::SufficientStats;
+use crate::Error;
+use polars::prelude::{
+    col, len, lit, DataFrame, DataType, IdxSize, LazyFrame, Schema, UniqueKeepStrategy,
+};
+use std::collections::BTreeSet;
+
+/// What a term learns from the data, accumulated one batch of rows at a time.
+enum Summary {
+    /// The distinct values of a categorical variable.
+    Levels {
+        var: String,
+        levels: BTreeSet<String>,
+    },
+    /// The distinct rows of the variables of a group term, whose inner
+    /// expression is left out, for its grouping levels.
+    Groups { term: Expr, rows: Rows },
+    /// The distinct values of a response coded for its family.
+    Response { family: Family, rows: Rows },
+    /// Power sums of the variable of an orthogonal `poly()`.
+    Poly {
+        key: String,
+        var: String,
+        moments: PolyMoments,
+    },
+    /// The non-null values of the first argument of a spline.
+    Spline { term: Expr, values: Vec<f64> },
+    /// The rows of the variables of a smooth.
+    Smooth { term: Expr, rows: Rows },
+}
+
+impl Summary {
+    fn key(&self) -> String {
+        match self {
+            Summary::Levels { var, .. } => var.clone(),
+            Summary::Response { .. } => "~".to_string(),
+            Summary::Poly { key, .. } => key.clone(),
+            Summary::Groups { term, .. }
+            | Summary::Spline { term, .. }
+            | Summary::Smooth { term, .. } => pretty_expr(term),
+        }
+    }
+
+    /// The columns the summary reads.
+    fn columns(&self) -> Vec<String> {
+        match self {
+            Summary::Levels { var, .. } | Summary::Poly { var, .. } => vec![var.clone()],
+            Summary::Groups { rows, .. }
+            | Summary::Response { rows, .. }
+            | Summary::Smooth { rows, .. } => rows.columns.clone(),
+            Summary::Spline { term, .. } => {
+                let mut columns = Vec::new();
+                collect_vars(term, &mut columns);
+                columns.sort();
+                columns.dedup();
+                columns
+            }
+        }
+    }
+
+    /// Whether only the distinct rows of the columns matter.
+    fn distinct(&self) -> bool {
+        matches!(
+            self,
+            Summary::Levels { .. } | Summary::Groups { .. } | Summary::Response { .. }
+        )
+    }
+
+    fn update(&mut self, batch: &DataFrame) -> Result<(), Error> {
+        match self {
+            Summary::Levels { var, levels } => {
+                let column = batch.column(var).map_err(|_| {
+                    Error::Semantic(format!("Column '{}' not found in DataFrame", var))
+                })?;
+                let values = column.str().map_err(|e| Error::Semantic(e.to_string()))?;
+                levels.extend(values.into_iter().flatten().map(String::from));
+            }
+            Summary::Groups { rows, .. }
+            | Summary::Response { rows, .. }
+            | Summary::Smooth { rows, .. } => rows.update(batch)?,
+            Summary::Poly { var, moments, .. } => {
+                let column = batch.column(var).map_err(|_| {
+                    Error::Semantic(format!("Column '{}' not found in DataFrame", var))
+                })?;
+                let values = series_to_f64(
+                    column.as_materialized_series(),
+                    &format!("Column '{}' for polynomial expansion", var),
+                )?;
+                moments.update(&values.into_iter().flatten().collect::<Vec<_>>());
+            }
+            Summary::Spline { term, values } => {
+                let Expr::Func { name, args } = term else {
+                    unreachable!("spline summaries are of function calls")
+                };
+                let (positional, _) = split_call_args(args);
+                let var_expr = positional.first().ok_or_else(|| {
+                    Error::Semantic(format!("{}() requires a variable as first argument", name))
+                })?;
+                let batch_values =
+                    spline_values(batch, name, var_expr, &mut DesignInfo::default())?;
+                values.extend(batch_values.into_iter().flatten());
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the learned state in `info`.
+    fn finish(self, info: &mut DesignInfo) -> Result<(), Error> {
+        match self {
+            Summary::Levels { var, levels } => {
+                info.levels
+                    .entry(var)
+                    .or_insert_with(|| levels.into_iter().collect());
+            }
+            Summary::Groups { term, rows } => {
+                if let Expr::Group { inner, spec, .. } = &term {
+                    random_blocks(&rows.into_frame()?, inner, spec, info)?;
+                }
+            }
+            Summary::Response { family, rows } => {
+                encode_response(&family, rows.into_frame()?, &mut info.response_encoding)?;
+            }
+            Summary::Poly { key, moments, .. } => {
+                let state = moments.finish()?;
+                info.transforms
+                    .entry(key)
+                    .or_insert(TransformState::Poly(state));
+            }
+            Summary::Spline { term, values } => {
+                let key = pretty_expr(&term);
+                if let (Expr::Func { name, args }, false) =
+                    (&term, info.transforms.contains_key(&key))
+                {
+                    let (positional, named) = split_call_args(args);
+                    let state = learn_spline_state(name, &positional[1..], &named, &values)?;
+                    info.transforms.insert(key, TransformState::Spline(state));
+                }
+            }
+            Summary::Smooth { term, rows } => {
+                let key = pretty_expr(&term);
+                if let (Expr::Smooth { kind, vars, args }, false) =
+                    (&term, info.transforms.contains_key(&key))
+                {
+                    let state = learn_smooth_state(&rows.into_frame()?, kind, vars, args)?;
+                    info.transforms.insert(key, TransformState::Smooth(state));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rows of some columns kept across batches, or only their distinct rows.
+struct Rows {
+    columns: Vec<String>,
+    distinct: bool,
+    df: Option<DataFrame>,
+}
+
+impl Rows {
+    /// Keep the columns `term` reads, of those in `schema`.
+    fn of(term: &Expr, schema: &Schema, distinct: bool) -> Self {
+        let mut columns = Vec::new();
+        collect_vars(term, &mut columns);
+        columns.retain(|name| schema.contains(name));
+        columns.sort();
+        columns.dedup();
+        Self {
+            columns,
+            distinct,
+            df: None,
+        }
+    }
+
+    fn update(&mut self, batch: &DataFrame) -> Result<(), Error> {
+        let rows = batch
+            .select(self.columns.iter().map(String::as_str))
+            .map_err(|e| Error::Semantic(e.to_string()))?;
+        let distinct = |df: &DataFrame| {
+            df.unique_stable(None, UniqueKeepStrategy::Any, None)
+                .map_err(|e| Error::Semantic(e.to_string()))
+        };
+        self.df = Some(match self.df.take() {
+            None if self.distinct => distinct(&rows)?,
+            None => rows,
+            Some(mut df) => {
+                df.vstack_mut(&rows)
+                    .map_err(|e| Error::Semantic(format!("Batches do not match: {}", e)))?;
+                if self.distinct {
+                    distinct(&df)?
+                } else {
+                    df
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn into_frame(self) -> Result<DataFrame, Error> {
+        self.df
+            .ok_or_else(|| Error::Semantic("Cannot learn a design from no batches".into()))
+    }
+}
+
+/// Power sums of the observed values of x, from which the [`PolyState`] of
+/// `poly(x, degree)` is learned without keeping the values.
+///
+/// The sums are of `u = (x - shift) / scale`, with `shift` and `scale` taken
+/// from the first values seen so that the sums stay of moderate size, and the
+/// polynomials are carried as their coefficients in u.
+struct PolyMoments {
+    degree: usize,
+    shift: f64,
+    scale: f64,
+    /// The sums of `u^j` for `j` from 0 to `2 * degree`.
+    sums: Vec<f64>,
+    /// Distinct values of x, up to one more than the degree.
+    unique: Vec<f64>,
+}
+
+impl PolyMoments {
+    fn new(degree: usize) -> Self {
+        Self {
+            degree,
+            shift: 0.0,
+            scale: 1.0,
+            sums: vec![0.0; 2 * degree + 1],
+            unique: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, x: &[f64]) {
+        if x.is_empty() {
+            return;
+        }
+        if self.sums[0] == 0.0 {
+            self.shift = x.iter().sum::<f64>() / x.len() as f64;
+            let spread = x
+                .iter()
+                .fold(0.0, |m: f64, x| m.max((x - self.shift).abs()));
+            self.scale = if spread > 0.0 && spread.is_finite() {
+                spread
+            } else {
+                1.0
+            };
+        }
+        for x in x {
+            let u = (x - self.shift) / self.scale;
+            let mut power = 1.0;
+            for sum in self.sums.iter_mut() {
+                *sum += power;
+                power *= u;
+            }
+        }
+        for x in x {
+            if self.unique.len() > self.degree {
+                break;
+            }
+            if !self.unique.contains(x) {
+                self.unique.push(*x);
+            }
+        }
+    }
+
+    /// The sum over the observations of `p(u) * q(u) * u^shift`.
+    fn inner(&self, p: &[f64], q: &[f64], shift: usize) -> f64 {
+        p.iter()
+            .enumerate()
+            .flat_map(|(j, p)| {
+                q.iter()
+                    .enumerate()
+                    .map(move |(l, q)| p * q * self.sums[j + l + shift])
+            })
+            .sum()
+    }
+
+    /// The coefficients [`PolyState::learn`] finds on the same values.
+    fn finish(&self) -> Result<PolyState, Error> {
+        PolyState::check_degree(self.degree, self.unique.len())?;
+        let mut alpha = Vec::with_capacity(self.degree);
+        let mut norm2 = vec![1.0, self.sums[0]];
+        let mut previous: Vec<f64> = Vec::new();
+        let mut current = vec![1.0];
+        for k in 0..self.degree {
+            let weight = self.inner(&current, &current, 0);
+            let centre = self.inner(&current, &current, 1) / weight;
+            let ratio = norm2[k + 1] / norm2[k];
+            // x - alpha[k] is scale * (u - centre)
+            let mut next = vec![0.0; k + 2];
+            for (j, c) in current.iter().enumerate() {
+                next[j + 1] += self.scale * c;
+                next[j] -= self.scale * centre * c;
+            }
+            for (j, c) in previous.iter().enumerate() {
+                next[j] -= ratio * c;
+            }
+            alpha.push(self.shift + self.scale * centre);
+            norm2.push(self.inner(&next, &next, 0));
+            previous = std::mem::replace(&mut current, next);
+        }
+        Ok(PolyState { alpha, norm2 })
+    }
+}
+
+/// The summaries a spec learns its state from, one per term that needs state,
+/// and the `subset()` that selects the rows they see.
+struct Learner {
+    summaries: Vec<Summary>,
+    subset: Option<Expr>,
+}
+
+impl Learner {
+    fn new(spec: &ModelSpec, opts: &MaterializeOptions, schema: &Schema) -> Result<Self, Error> {
+        let mut learner = Self {
+            summaries: Vec::new(),
+            subset: subset_condition(spec).cloned(),
+        };
+        if let (Some(family), Response::Var(name), true) =
+            (&spec.family, &spec.formula.lhs, opts.encode_response)
+        {
+            if response_coding(family).is_some() {
+                learner.push(Summary::Response {
+                    family: family.clone(),
+                    rows: Rows::of(&Expr::Var(name.clone()), schema, true),
+                });
+            }
+        }
+        learner.add(&spec.formula.rhs, schema)?;
+        Ok(learner)
+    }
+
+    fn push(&mut self, summary: Summary) {
+        let key = summary.key();
+        if !self.summaries.iter().any(|s| s.key() == key) {
+            self.summaries.push(summary);
+        }
+    }
+
+    /// Add the summaries of the terms of `expr` that need state.
+    fn add(&mut self, expr: &Expr, schema: &Schema) -> Result<(), Error> {
+        match expr {
+            Expr::Sum(terms) | Expr::Prod(terms) | Expr::Interaction(terms) => {
+                for term in terms {
+                    self.add(term, schema)?;
+                }
+            }
+            Expr::Func { name, args } if name == "NEG" => {
+                for arg in args {
+                    self.add(arg, schema)?;
+                }
+            }
+            Expr::Var(var) if matches!(schema.get(var), Some(DataType::String)) => {
+                self.push(Summary::Levels {
+                    var: var.clone(),
+                    levels: BTreeSet::new(),
+                })
+            }
+            Expr::Func { name, args } if name == "poly" => {
+                let (var, degree, raw) = poly_args(args)?;
+                if !raw {
+                    self.push(Summary::Poly {
+                        key: pretty_expr(expr),
+                        var: var.to_string(),
+                        moments: PolyMoments::new(degree),
+                    });
+                }
+            }
+            Expr::Func { name, .. } if is_spline_function(name) => self.push(Summary::Spline {
+                term: expr.clone(),
+                values: Vec::new(),
+            }),
+            Expr::Smooth { .. } => self.push(Summary::Smooth {
+                term: expr.clone(),
+                rows: Rows::of(expr, schema, false),
+            }),
+            Expr::Group {
+                inner,
+                spec,
+                kind,
+                id,
+            } => {
+                // The inner terms are materialized as the fixed effects are
+                self.add(inner, schema)?;
+                let term = Expr::Group {
+                    inner: Box::new(Expr::Intercept(true)),
+                    spec: spec.clone(),
+                    kind: kind.clone(),
+                    id: id.clone(),
+                };
+                let rows = Rows::of(&term, schema, true);
+                self.push(Summary::Groups { term, rows });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The columns of the condition of `subset()`.
+    fn subset_columns(&self, schema: &Schema) -> Vec<String> {
+        self.subset
+            .as_ref()
+            .map(|condition| Rows::of(condition, schema, false).columns)
+            .unwrap_or_default()
+    }
+
+    /// The rows of `batch` selected by `subset()`.
+    fn select(&self, batch: DataFrame) -> Result<DataFrame, Error> {
+        select_subset(batch, self.subset.as_ref())
+    }
+
+    fn finish(self, opts: &MaterializeOptions, info: &mut DesignInfo) -> Result<(), Error> {
+        if opts.mixed_model_smooths {
+            info.mixed_model_smooths = true;
+        }
+        for summary in self.summaries {
+            summary.finish(info)?;
+        }
+        Ok(())
+    }
+}
+
+/// The condition of the `subset()` aterm of a spec, if any.
+fn subset_condition(spec: &ModelSpec) -> Option<&Expr> {
+    spec.formula.aterms.iter().find_map(|a| match a {
+        Aterm::Subset(condition) => Some(condition),
+        _ => None,
+    })
+}
+
+/// The rows of `batch` selected by a `subset()` condition.
+fn select_subset(batch: DataFrame, condition: Option<&Expr>) -> Result<DataFrame, Error> {
+    match condition {
+        None => Ok(batch),
+        Some(condition) => {
+            let mask = subset_mask(&batch, condition)?;
+            batch
+                .filter(&mask)
+                .map_err(|e| Error::Semantic(e.to_string()))
+        }
+    }
+}
+
+/// Learn the state of a spec from batches of data, one batch at a time.
+pub(crate) fn learn_from_batches<I>(
+    batches: I,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = DataFrame>,
+{
+    let mut learner: Option<Learner> = None;
+    for batch in batches {
+        let learner = match &mut learner {
+            Some(learner) => learner,
+            None => learner.insert(Learner::new(spec, &opts, batch.schema())?),
+        };
+        let batch = learner.select(batch)?;
+        for summary in &mut learner.summaries {
+            summary.update(&batch)?;
+        }
+    }
+    learner
+        .ok_or_else(|| Error::Semantic("Cannot learn a design from no batches".into()))?
+        .finish(&opts, info)
+}
+
+/// Learn the state of a spec from a lazy frame with one query per term that
+/// needs state, reading only the columns of that term: the distinct values of
+/// categorical variables and grouping factors, the power sums of `poly()`, and
+/// the values of the variables of splines and smooths.
+pub(crate) fn learn_from_lazy(
+    lf: &LazyFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<(), Error> {
+    let schema = lf
+        .clone()
+        .collect_schema()
+        .map_err(|e| Error::Semantic(e.to_string()))?;
+    let mut learner = Learner::new(spec, &opts, &schema)?;
+    let subset_columns = learner.subset_columns(&schema);
+    let summaries = std::mem::take(&mut learner.summaries);
+    for mut summary in summaries {
+        match &mut summary {
+            Summary::Poly { var, moments, .. }
+                if learner.subset.is_none()
+                    && schema.get(var).is_some_and(|d| d.is_primitive_numeric()) =>
+            {
+                *moments = lazy_poly_moments(lf, var, moments.degree)?;
+            }
+            _ => {
+                let mut columns = summary.columns();
+                columns.extend(subset_columns.iter().cloned());
+                columns.sort();
+                columns.dedup();
+                let mut rows = lf.clone().select(
+                    columns
+                        .iter()
+                        .map(|name| col(name.as_str()))
+                        .collect::<Vec<_>>(),
+                );
+                if summary.distinct() {
+                    rows = rows.unique(None, UniqueKeepStrategy::Any);
+                }
+                let rows = rows.collect().map_err(|e| Error::Semantic(e.to_string()))?;
+                summary.update(&learner.select(rows)?)?;
+            }
+        }
+        learner.summaries.push(summary);
+    }
+    learner.finish(&opts, info)
+}
+
+/// The power sums of `poly(var, degree)` over a lazy frame, from aggregate
+/// queries.
+fn lazy_poly_moments(lf: &LazyFrame, var: &str, degree: usize) -> Result<PolyMoments, Error> {
+    let x = col(var).cast(DataType::Float64);
+    let scalars = |exprs: Vec<polars::prelude::Expr>| -> Result<Vec<Option<f64>>, Error> {
+        let df = lf
+            .clone()
+            .select(exprs)
+            .collect()
+            .map_err(|e| Error::Semantic(e.to_string()))?;
+        df.get_columns()
+            .iter()
+            .map(|column| series_to_f64(column.as_materialized_series(), var))
+            .map(|values| values.map(|values| values.into_iter().flatten().next()))
+            .collect()
+    };
+
+    let mut moments = PolyMoments::new(degree);
+    let range = scalars(vec![
+        x.clone().mean().alias("mean"),
+        x.clone().min().alias("min"),
+        x.clone().max().alias("max"),
+    ])?;
+    if let [Some(mean), Some(min), Some(max)] = range[..] {
+        let spread = (max - mean).max(mean - min);
+        moments.shift = mean;
+        if spread > 0.0 && spread.is_finite() {
+            moments.scale = spread;
+        }
+    }
+    let u = (x.clone() - lit(moments.shift)) / lit(moments.scale);
+    let sums = scalars(
+        (0..moments.sums.len())
+            .map(|j| u.clone().pow(j as f64).sum().alias(format!("u{}", j)))
+            .collect(),
+    )?;
+    moments.sums = sums.into_iter().map(|sum| sum.unwrap_or(0.0)).collect();
+
+    let unique = lf
+        .clone()
+        .select([x.drop_nulls().unique().head(Some(degree + 1))])
+        .collect()
+        .map_err(|e| Error::Semantic(e.to_string()))?;
+    moments.unique = series_to_f64(unique.get_columns()[0].as_materialized_series(), var)?
+        .into_iter()
+        .flatten()
+        .collect();
+    Ok(moments)
+}
+
+/// Slices of `batch_size` rows of a lazy frame, each collected on its own.
+///
+/// Each slice runs the query of the frame again. A scan pushes the slice down
+/// and reads only up to the end of the batch, but row-wise sources are still
+/// read from the start, so going through the whole frame reads O(N²/b) rows
+/// for N rows in batches of b; an expensive query (a join, a sort) is run
+/// N/b times. Polars has no public streaming collection yielding batches, so
+/// larger batches are the way to fewer passes.
+struct LazySlices {
+    lf: LazyFrame,
+    offset: usize,
+    height: usize,
+    batch_size: usize,
+}
+
+impl Iterator for LazySlices {
+    type Item = Result<DataFrame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.height {
+            return None;
+        }
+        let batch = self
+            .lf
+            .clone()
+            .slice(self.offset as i64, self.batch_size as IdxSize)
+            .collect()
+            .map_err(|e| Error::Semantic(e.to_string()));
+        self.offset += self.batch_size;
+        Some(batch)
+    }
+}
+
+/// The slices of `batch_size` rows of `df`, which share its memory.
+pub(crate) fn frame_slices(
+    df: &DataFrame,
+    batch_size: usize,
+) -> Result<impl Iterator<Item = DataFrame> + '_, Error> {
+    if batch_size == 0 {
+        return Err(Error::Semantic("Batch size must be positive".into()));
+    }
+    Ok((0..df.height())
+        .step_by(batch_size)
+        .map(move |offset| df.slice(offset as i64, batch_size)))
+}
+
+/// Response and design matrices of a data set, one batch of rows at a time.
+///
+/// Each item is the `(y, X, Z)` of the rows of a batch selected by
+/// `subset()`, materialized with the [`DesignInfo`] learned from all the data
+/// beforehand, so the columns of every batch are those of the first; a batch
+/// with other columns (e.g. with a level of a categorical predictor that was
+/// not learned) is an error.
+pub struct DesignBatches<'a> {
+    spec: ModelSpec,
+    opts: MaterializeOptions,
+    info: DesignInfo,
+    batches: Box<dyn Iterator<Item = Result<DataFrame, Error>> + 'a>,
+    columns: Option<[Vec<String>; 3]>,
+}
+
+impl<'a> DesignBatches<'a> {
+    /// Materialize `batches` with the state in `info`.
+    pub(crate) fn new<I>(
+        batches: I,
+        spec: &ModelSpec,
+        opts: MaterializeOptions,
+        info: DesignInfo,
+    ) -> Self
+    where
+        I: IntoIterator<Item = DataFrame>,
+        I::IntoIter: 'a,
+    {
+        Self::from_results(batches.into_iter().map(Ok), spec, opts, info)
+    }
+
+    /// Materialize the batches of `batch_size` rows of `lf` with the state in
+    /// `info`.
+    pub(crate) fn from_lazy(
+        lf: &LazyFrame,
+        batch_size: usize,
+        spec: &ModelSpec,
+        opts: MaterializeOptions,
+        info: DesignInfo,
+    ) -> Result<Self, Error> {
+        if batch_size == 0 {
+            return Err(Error::Semantic("Batch size must be positive".into()));
+        }
+        let height = lf
+            .clone()
+            .select([len()])
+            .collect()
+            .and_then(|df| df.get_columns()[0].get(0).map(|v| v.extract::<usize>()))
+            .map_err(|e| Error::Semantic(e.to_string()))?
+            .unwrap_or(0);
+        let slices = LazySlices {
+            lf: lf.clone(),
+            offset: 0,
+            height,
+            batch_size,
+        };
+        Ok(Self::from_results(slices, spec, opts, info))
+    }
+
+    /// Materialize the batches of `batch_size` rows of `df` with the state in
+    /// `info`.
+    pub(crate) fn from_frame(
+        df: &'a DataFrame,
+        batch_size: usize,
+        spec: &ModelSpec,
+        opts: MaterializeOptions,
+        info: DesignInfo,
+    ) -> Result<Self, Error> {
+        let slices = frame_slices(df, batch_size)?.map(Ok);
+        Ok(Self::from_results(slices, spec, opts, info))
+    }
+
+    fn from_results<I>(
+        batches: I,
+        spec: &ModelSpec,
+        opts: MaterializeOptions,
+        info: DesignInfo,
+    ) -> Self
+    where
+        I: Iterator<Item = Result<DataFrame, Error>> + 'a,
+    {
+        Self {
+            spec: spec.clone(),
+            opts,
+            info,
+            batches: Box::new(batches),
+            columns: None,
+        }
+    }
+
+    /// The state every batch is materialized with, with the penalties and
+    /// positions of the smooth terms once the first batch is materialized.
+    pub fn info(&self) -> &DesignInfo {
+        &self.info
+    }
+
+    /// Accumulate the cross-products of X and y over all remaining batches,
+    /// with the weights of a `weights()` aterm and less the `offset()` terms,
+    /// on the rows selected by `subset()`. Z is not used, so the group terms
+    /// are not materialized.
+    pub fn sufficient_stats(mut self) -> Result<SufficientStats, Error> {
+        let mut terms = Vec::new();
+        collect_sum_terms(&self.spec.formula.rhs, &mut terms);
+        let fixed = terms
+            .into_iter()
+            .filter(|term| !matches!(term, Expr::Group { .. }))
+            .cloned()
+            .collect();
+        self.spec.formula.rhs = Expr::Sum(fixed);
+        if let Some([_, _, z]) = &mut self.columns {
+            // Batches already yielded had the Z columns
+            z.clear();
+        }
+
+        let mut stats: Option<SufficientStats> = None;
+        while let Some(batch) = self.batches.next() {
+            let df = select_subset(batch?, subset_condition(&self.spec))?;
+            let (y, x, _) = self.materialize(&df)?;
+
+            let weights = if self
+                .spec
+                .formula
+                .aterms
+                .iter()
+                .any(|a| matches!(a, Aterm::Weights(_)))
+            {
+                let aterms = materialize_aterms(&df, &self.spec.formula, &y)?;
+                let weights = aterms
+                    .column("weights")
+                    .map_err(|e| Error::Semantic(e.to_string()))?;
+                Some(series_to_f64(weights.as_materialized_series(), "weights")?)
+            } else {
+                None
+            };
+            let offset = materialize_offset(&df, &self.spec.formula.rhs)?;
+
+            let names = |df: &DataFrame| {
+                df.get_column_names()
+                    .into_iter()
+                    .map(|name| name.to_string())
+                    .collect()
+            };
+            stats
+                .get_or_insert_with(|| SufficientStats::new(names(&x), names(&y)))
+                .update(&y, &x, weights.as_deref(), offset.as_deref())?;
+        }
+        stats.ok_or_else(|| Error::Semantic("Cannot accumulate statistics of no batches".into()))
+    }
+
+    fn materialize(&mut self, df: &DataFrame) -> Result<(DataFrame, DataFrame, DataFrame), Error> {
+        // Each batch starts from the learned state, as new data does
+        let mut info = self.info.clone();
+        let (y, x, z) = materialize_with_info(df, &self.spec, self.opts.clone(), &mut info)?;
+
+        let names = |df: &DataFrame| {
+            df.get_column_names()
+                .into_iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let columns = [names(&y), names(&x), names(&z)];
+        match &self.columns {
+            None => {
+                self.columns = Some(columns);
+                self.info.smooths = info.smooths;
+            }
+            Some(first) => {
+                for ((first, this), matrix) in first.iter().zip(&columns).zip(["y", "X", "Z"]) {
+                    if first != this {
+                        return Err(Error::Semantic(format!(
+                            "Batch has {} columns [{}] instead of [{}]",
+                            matrix,
+                            this.join(", "),
+                            first.join(", ")
+                        )));
+                    }
+                }
+            }
+        }
+        Ok((y, x, z))
+    }
+}
+
+impl Iterator for DesignBatches<'_> {
+    type Item = Result<(DataFrame, DataFrame, DataFrame), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = self.batches.next()?;
+        Some(
+            batch
+                .and_then(|df| select_subset(df, subset_condition(&self.spec)))
+                .and_then(|df| self.materialize(&df)),
+        )
+    }
+}
@@ -0,0 +1,176 @@
+//! Conversion of design matrices to and from linear algebra crates.
+//!
+//! The y, X and Z of materialization are Polars DataFrames with one `f64`
+//! column per design column. [`ToFaer`], [`ToNdarray`] and [`ToNalgebra`] copy
+//! them into dense or sparse matrices of `faer`, `ndarray`/`sprs` and
+//! `nalgebra`/`nalgebra-sparse`, each behind the feature of that name. The
+//! matrices carry no names: [`column_names`] keeps them alongside, and the
+//! `coefficients_from_*` functions label a coefficient vector with them.
+//! Missing values cannot be represented in a matrix, so a column with nulls is
+//! an error.
+
+use super::sparse::CscMatrix;
+use crate::Error;
+use polars::prelude::*;
+
+/// The names of the columns of a design matrix, in the order of the columns
+/// of its conversions.
+pub fn column_names(df: &DataFrame) -> Vec<String> {
+    df.get_column_names()
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// The columns of a DataFrame as `f64` values, without nulls.
+fn dense_columns(df: &DataFrame) -> Result<Vec<Vec<f64>>, Error> {
+    df.get_columns()
+        .iter()
+        .map(|column| {
+            let values = column
+                .as_materialized_series()
+                .strict_cast(&DataType::Float64)
+                .map_err(|_| Error::Semantic(format!("'{}' is not numeric", column.name())))?;
+            let values = values.f64().map_err(|e| Error::Semantic(e.to_string()))?;
+            if values.null_count() > 0 {
+                return Err(Error::Semantic(format!(
+                    "'{}' has {} missing values; drop or impute them first",
+                    column.name(),
+                    values.null_count()
+                )));
+            }
+            Ok(values.into_no_null_iter().collect())
+        })
+        .collect()
+}
+
+/// The nonzero entries of a DataFrame in compressed sparse column form.
+fn sparse_columns(df: &DataFrame) -> Result<CscMatrix, Error> {
+    let mut matrix = CscMatrix::new(df.height());
+    for column in dense_columns(df)? {
+        matrix.push_column(column.into_iter().enumerate().filter(|&(_, v)| v != 0.0));
+    }
+    Ok(matrix)
+}
+
+/// A labelled coefficient vector: a `term` column with the names and an
+/// `estimate` column with the values.
+fn coefficients_frame(columns: &[String], values: Vec<f64>) -> Result<DataFrame, Error> {
+    if columns.len() != values.len() {
+        return Err(Error::Semantic(format!(
+            "{} coefficients for {} columns",
+            values.len(),
+            columns.len()
+        )));
+    }
+    DataFrame::new(vec![
+        Column::new("term".into(), columns),
+        Column::new("estimate".into(), values),
+    ])
+    .map_err(|e| Error::Semantic(e.to_string()))
+}
+
+/// Conversion of a design matrix to `faer` matrices.
+#[cfg(feature = "faer")]
+pub trait ToFaer {
+    /// A dense column-major copy, e.g. of y or X.
+    fn to_faer(&self) -> Result<faer::Mat<f64>, Error>;
+
+    /// A sparse copy of the nonzero entries, e.g. of Z.
+    fn to_faer_sparse(&self) -> Result<faer::sparse::SparseColMat<usize, f64>, Error>;
+}
+
+#[cfg(feature = "faer")]
+impl ToFaer for DataFrame {
+    fn to_faer(&self) -> Result<faer::Mat<f64>, Error> {
+        let columns = dense_columns(self)?;
+        Ok(faer::Mat::from_fn(self.height(), columns.len(), |i, j| {
+            columns[j][i]
+        }))
+    }
+
+    fn to_faer_sparse(&self) -> Result<faer::sparse::SparseColMat<usize, f64>, Error> {
+        Ok(sparse_columns(self)?.to_faer())
+    }
+}
+
+/// Label the coefficients `beta` of the design columns `columns`, e.g. the
+/// column names of X, as a DataFrame with `term` and `estimate` columns.
+#[cfg(feature = "faer")]
+pub fn coefficients_from_faer(
+    columns: &[String],
+    beta: faer::ColRef<'_, f64>,
+) -> Result<DataFrame, Error> {
+    coefficients_frame(columns, beta.iter().copied().collect())
+}
+
+/// Conversion of a design matrix to `ndarray` and `sprs` matrices.
+#[cfg(feature = "ndarray")]
+pub trait ToNdarray {
+    /// A dense column-major (Fortran order) copy, e.g. of y or X.
+    fn to_ndarray(&self) -> Result<ndarray::Array2<f64>, Error>;
+
+    /// A sparse CSC copy of the nonzero entries, e.g. of Z.
+    fn to_sprs(&self) -> Result<sprs::CsMat<f64>, Error>;
+}
+
+#[cfg(feature = "ndarray")]
+impl ToNdarray for DataFrame {
+    fn to_ndarray(&self) -> Result<ndarray::Array2<f64>, Error> {
+        use ndarray::ShapeBuilder;
+        let columns = dense_columns(self)?;
+        let shape = (self.height(), columns.len()).f();
+        ndarray::Array2::from_shape_vec(shape, columns.concat())
+            .map_err(|e| Error::Semantic(e.to_string()))
+    }
+
+    fn to_sprs(&self) -> Result<sprs::CsMat<f64>, Error> {
+        sparse_columns(self)?.to_sprs()
+    }
+}
+
+/// Label the coefficients `beta` of the design columns `columns`, e.g. the
+/// column names of X, as a DataFrame with `term` and `estimate` columns.
+#[cfg(feature = "ndarray")]
+pub fn coefficients_from_ndarray(
+    columns: &[String],
+    beta: ndarray::ArrayView1<'_, f64>,
+) -> Result<DataFrame, Error> {
+    coefficients_frame(columns, beta.to_vec())
+}
+
+/// Conversion of a design matrix to `nalgebra` and `nalgebra-sparse` matrices.
+#[cfg(feature = "nalgebra")]
+pub trait ToNalgebra {
+    /// A dense column-major copy, e.g. of y or X.
+    fn to_nalgebra(&self) -> Result<nalgebra::DMatrix<f64>, Error>;
+
+    /// A sparse CSC copy of the nonzero entries, e.g. of Z.
+    fn to_nalgebra_sparse(&self) -> Result<nalgebra_sparse::CscMatrix<f64>, Error>;
+}
+
+#[cfg(feature = "nalgebra")]
+impl ToNalgebra for DataFrame {
+    fn to_nalgebra(&self) -> Result<nalgebra::DMatrix<f64>, Error> {
+        let columns = dense_columns(self)?;
+        Ok(nalgebra::DMatrix::from_vec(
+            self.height(),
+            columns.len(),
+            columns.concat(),
+        ))
+    }
+
+    fn to_nalgebra_sparse(&self) -> Result<nalgebra_sparse::CscMatrix<f64>, Error> {
+        sparse_columns(self)?.to_nalgebra_sparse()
+    }
+}
+
+/// Label the coefficients `beta` of the design columns `columns`, e.g. the
+/// column names of X, as a DataFrame with `term` and `estimate` columns.
+#[cfg(feature = "nalgebra")]
+pub fn coefficients_from_nalgebra(
+    columns: &[String],
+    beta: nalgebra::DVectorView<'_, f64>,
+) -> Result<DataFrame, Error> {
+    coefficients_frame(columns, beta.iter().copied().collect())
+}
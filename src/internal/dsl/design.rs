@@ -0,0 +1,166 @@
+use super::materialize::PolyState;
+use super::smooths::SmoothState;
+use super::sparse::GroupBy;
+use super::splines::SplineState;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// State learned from the data while materializing a formula.
+///
+/// Stateful transforms such as spline bases record what they learned (knots,
+/// boundaries, constraints) keyed by the canonical text of the term. Passing the
+/// same `DesignInfo` back when materializing new data rebuilds identical columns
+/// instead of re-learning them from the new rows.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_new_data, materialize_with_info};
+///
+/// let train = df!("y" => [1.0, 2.0, 3.0, 4.0, 5.0], "x" => [0.0, 1.0, 2.0, 3.0, 4.0])?;
+/// let spec = canonicalize("y ~ bs(x, df=4)")?;
+/// let (_y, _x, _z, info) = materialize_with_info(&spec, &train)?;
+///
+/// let new = df!("y" => [0.0, 0.0], "x" => [2.5, 5.0])?;
+/// let (_y, x_new, _z) = materialize_new_data(&spec, &new, &info)?;
+/// assert_eq!(x_new.width(), 5); // intercept + 4 spline columns
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DesignInfo {
+    /// Learned transform state keyed by the canonical term label, e.g. `bs(x, df=4)`.
+    pub transforms: BTreeMap<String, TransformState>,
+    /// Sorted levels of each categorical predictor, keyed by variable; new
+    /// data is coded with the levels of the training data.
+    pub levels: BTreeMap<String, Vec<String>>,
+    /// Sorted levels of each grouping factor of the random effects, keyed by
+    /// its label (`g`, `a:b` or `mm(g1, g2)`). New data gets the same Z
+    /// columns; its rows in other levels get no random effects.
+    pub group_levels: BTreeMap<String, Vec<String>>,
+    /// The `by` levels of each `gr(g, by = x)` term and the `by` level of
+    /// every learned level of `g`, keyed as `group_levels`; new data without
+    /// some levels of `g` keeps the same mapping.
+    pub group_by: BTreeMap<String, GroupBy>,
+    /// Penalties and constraints of the smooth terms, in column order.
+    pub smooths: Vec<SmoothInfo>,
+    /// Smooths are written in mixed model form (see
+    /// `MaterializeOptions::mixed_model_smooths`); kept here so new data gets
+    /// the same representation.
+    pub mixed_model_smooths: bool,
+    /// Columns of a `cbind()` or `mvbind()` response, in the column order of
+    /// `y`; empty for a single response.
+    pub responses: Vec<ResponseInfo>,
+    /// Levels of a categorical response coded for its family, e.g. the ordered
+    /// categories of a `cumulative()` response; `None` if `y` is left as is.
+    pub response_encoding: Option<ResponseEncoding>,
+    /// State learned for the formula of each distributional parameter, keyed
+    /// by its name, e.g. `sigma` for `y ~ x + sigma ~ bs(z, df=4)`.
+    pub dpars: BTreeMap<String, DesignInfo>,
+}
+
+/// One column of a multivariate response.
+///
+/// `cbind(incidence, size - incidence)` has two entries, the successes and the
+/// failures of a binomial model; each response of `mvbind(y1, y2)` is modelled
+/// separately.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseInfo {
+    /// Name of the column in `y`: the variable, or the text of an expression
+    /// such as `size - incidence`.
+    pub name: String,
+    /// The binding function, `cbind` or `mvbind`.
+    pub bind: String,
+    /// Number of missing values in the column.
+    pub null_count: usize,
+}
+
+/// How a categorical response was coded for its family.
+///
+/// Levels are sorted, as R sorts the levels of a factor, unless they were
+/// learned before: new data reuses the levels of the training data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseEncoding {
+    /// Name of the response column.
+    pub name: String,
+    /// How the levels are coded in `y`.
+    pub coding: ResponseCoding,
+    /// Levels in code order.
+    pub levels: Vec<String>,
+}
+
+impl ResponseEncoding {
+    /// The level of a code: `1..=K` for ordinal codes, 0 or 1 for a binary
+    /// response and the column position for indicators.
+    pub fn level(&self, code: usize) -> Option<&str> {
+        let index = match self.coding {
+            ResponseCoding::Ordinal => code.checked_sub(1)?,
+            ResponseCoding::Binary | ResponseCoding::Indicators => code,
+        };
+        self.levels.get(index).map(String::as_str)
+    }
+}
+
+/// Coding of a categorical response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCoding {
+    /// One integer column of codes `1..=K` in level order, for `cumulative`,
+    /// `sratio`, `cratio` and `acat`.
+    Ordinal,
+    /// One 0/1 indicator column `<name>_<level>` per level, for `categorical`
+    /// and `multinomial`.
+    Indicators,
+    /// One 0/1 column, 1 for the second level, for `bernoulli`.
+    Binary,
+}
+
+/// Columns and penalties of one smooth term in the fixed effects design matrix.
+///
+/// A smooth with a factor `by=` variable contributes one entry per level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmoothInfo {
+    /// Canonical label of the smooth term, e.g. `s(x, bs="cr", k=5)`.
+    pub label: String,
+    /// Level of the factor `by=` variable this entry belongs to.
+    pub by_level: Option<String>,
+    /// Names of the columns of this smooth in the fixed effects design matrix.
+    pub columns: Vec<String>,
+    /// Positions of `columns` in the fixed effects design matrix.
+    pub column_range: Range<usize>,
+    /// Names of the penalized columns in the random effects design matrix when
+    /// the smooth is written in mixed model form; empty otherwise.
+    pub random_columns: Vec<String>,
+    /// Positions of `random_columns` in the random effects design matrix.
+    pub random_column_range: Range<usize>,
+    /// Penalty matrices (one `Vec` per row) on the coefficients of `columns`,
+    /// or on `random_columns` in mixed model form, where each is an identity
+    /// on the columns belonging to one variance component.
+    pub penalties: Vec<Vec<Vec<f64>>>,
+    /// Dimension of the unpenalized null space of the penalties.
+    pub null_space_dim: usize,
+    /// Sum-to-zero constraint absorbed into the basis: the column means of the
+    /// unconstrained basis, to which the coefficients are made orthogonal.
+    pub constraint: Option<Vec<f64>>,
+}
+
+#[cfg(feature = "faer")]
+impl SmoothInfo {
+    /// The penalty matrices as `faer` matrices.
+    pub fn penalties_faer(&self) -> Vec<faer::Mat<f64>> {
+        self.penalties
+            .iter()
+            .map(|s| faer::Mat::from_fn(s.len(), s.len(), |i, j| s[i][j]))
+            .collect()
+    }
+}
+
+/// Learned state of a single stateful transform.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransformState {
+    /// Knots and options of `bs()`, `ns()`, `cr()` or `cc()`.
+    Spline(SplineState),
+    /// Bases, knots and constraints of `s()`, `te()`, `ti()` or `t2()`.
+    Smooth(SmoothState),
+    /// Recurrence coefficients of the orthogonal polynomials of `poly()`.
+    Poly(PolyState),
+}
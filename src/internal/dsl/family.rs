@@ -0,0 +1,702 @@
+//! Response families, their links and distributional parameters.
+//!
+//! Every family has a mean `mu`, predicted by the main formula through a link
+//! function, and possibly further parameters such as `sigma` or `zi` that may
+//! get formulas of their own (`y ~ x + sigma ~ z`). The builtin families are
+//! listed in a registry of [`FamilyInfo`], used to check a spec and to check
+//! and code the values of its response. The names follow brms.
+
+use super::ast::{Expr, Family, Link, ModelSpec, Response};
+use super::design::{ResponseCoding, ResponseEncoding};
+use crate::Error;
+use polars::prelude::*;
+
+/// A builtin response family: its links, distributional parameters and the
+/// values its response may take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FamilyInfo {
+    /// Name as written in `family=`, e.g. `gaussian` or `zero_inflated_poisson`.
+    pub name: &'static str,
+    /// Valid link functions of the mean, the default first.
+    pub links: &'static [&'static str],
+    /// Distributional parameters, `mu` first.
+    pub dpars: &'static [&'static str],
+    /// Values the response may take.
+    pub support: Support,
+}
+
+impl FamilyInfo {
+    /// The link used when none is given, e.g. `log` for `poisson`.
+    pub fn default_link(&self) -> &'static str {
+        self.links[0]
+    }
+
+    /// Look up a builtin family by name.
+    pub fn lookup(name: &str) -> Option<&'static FamilyInfo> {
+        FAMILIES.iter().find(|family| family.name == name)
+    }
+
+    /// All builtin families.
+    pub fn all() -> &'static [FamilyInfo] {
+        FAMILIES
+    }
+}
+
+/// Values the response of a family may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Any real number.
+    Real,
+    /// Positive numbers.
+    Positive,
+    /// Non-negative numbers, as for hurdle models of positive data.
+    NonNegative,
+    /// Non-negative integers, such as counts or binomial successes.
+    Count,
+    /// 0 or 1, logical values, or a factor with two levels.
+    Binary,
+    /// Proportions strictly between 0 and 1.
+    Unit,
+    /// Proportions in [0, 1), as for zero-inflated beta models.
+    UnitWithZero,
+    /// Proportions in [0, 1].
+    UnitClosed,
+    /// Ordered categories: positive integers or a factor.
+    Ordinal,
+    /// Unordered categories: integers or a factor.
+    Categorical,
+    /// Proportions summing to 1 over the response columns.
+    Simplex,
+    /// Angles in [-pi, pi].
+    Circular,
+}
+
+impl Support {
+    /// What the response must be, for error messages.
+    fn requirement(&self) -> &'static str {
+        match self {
+            Support::Real => "real numbers",
+            Support::Positive => "positive",
+            Support::NonNegative => "non-negative",
+            Support::Count => "non-negative integers",
+            Support::Binary => "0 or 1",
+            Support::Unit => "strictly between 0 and 1",
+            Support::UnitWithZero => "in [0, 1)",
+            Support::UnitClosed => "in [0, 1]",
+            Support::Ordinal => "positive integers or a factor",
+            Support::Categorical => "integers or a factor",
+            Support::Simplex => "proportions summing to 1",
+            Support::Circular => "angles in [-pi, pi]",
+        }
+    }
+
+    /// Whether a single numeric value is within the support.
+    fn contains(&self, v: f64) -> bool {
+        let integer = v.fract() == 0.0;
+        match self {
+            Support::Real => v.is_finite(),
+            Support::Positive => v > 0.0,
+            Support::NonNegative => v >= 0.0,
+            Support::Count => v >= 0.0 && integer,
+            Support::Binary => v == 0.0 || v == 1.0,
+            Support::Unit => v > 0.0 && v < 1.0,
+            Support::UnitWithZero => (0.0..1.0).contains(&v),
+            Support::UnitClosed | Support::Simplex => (0.0..=1.0).contains(&v),
+            Support::Ordinal => v >= 1.0 && integer,
+            Support::Categorical => integer,
+            Support::Circular => (-std::f64::consts::PI..=std::f64::consts::PI).contains(&v),
+        }
+    }
+}
+
+const BINARY_LINKS: &[&str] = &[
+    "logit",
+    "probit",
+    "probit_approx",
+    "cloglog",
+    "cauchit",
+    "identity",
+    "log",
+];
+const ORDINAL_LINKS: &[&str] = &["logit", "probit", "probit_approx", "cloglog", "cauchit"];
+const COUNT_LINKS: &[&str] = &["log", "identity", "sqrt", "softplus"];
+const POSITIVE_LINKS: &[&str] = &["log", "identity", "inverse", "softplus"];
+
+const fn family(
+    name: &'static str,
+    links: &'static [&'static str],
+    dpars: &'static [&'static str],
+    support: Support,
+) -> FamilyInfo {
+    FamilyInfo {
+        name,
+        links,
+        dpars,
+        support,
+    }
+}
+
+/// The builtin families, named and parameterized as in brms.
+const FAMILIES: &[FamilyInfo] = &[
+    family(
+        "gaussian",
+        &["identity", "log", "inverse", "softplus"],
+        &["mu", "sigma"],
+        Support::Real,
+    ),
+    family(
+        "student",
+        &["identity", "log", "inverse", "softplus"],
+        &["mu", "sigma", "nu"],
+        Support::Real,
+    ),
+    family(
+        "skew_normal",
+        &["identity", "log", "inverse"],
+        &["mu", "sigma", "alpha"],
+        Support::Real,
+    ),
+    family("binomial", BINARY_LINKS, &["mu"], Support::Count),
+    family("bernoulli", BINARY_LINKS, &["mu"], Support::Binary),
+    family(
+        "beta_binomial",
+        BINARY_LINKS,
+        &["mu", "phi"],
+        Support::Count,
+    ),
+    family("poisson", COUNT_LINKS, &["mu"], Support::Count),
+    family("negbinomial", COUNT_LINKS, &["mu", "shape"], Support::Count),
+    family(
+        "negbinomial2",
+        COUNT_LINKS,
+        &["mu", "sigma"],
+        Support::Count,
+    ),
+    family("geometric", COUNT_LINKS, &["mu"], Support::Count),
+    family("Gamma", POSITIVE_LINKS, &["mu", "shape"], Support::Positive),
+    family(
+        "weibull",
+        POSITIVE_LINKS,
+        &["mu", "shape"],
+        Support::Positive,
+    ),
+    family("exponential", POSITIVE_LINKS, &["mu"], Support::Positive),
+    family(
+        "lognormal",
+        &["identity", "inverse"],
+        &["mu", "sigma"],
+        Support::Positive,
+    ),
+    family(
+        "shifted_lognormal",
+        &["identity", "inverse"],
+        &["mu", "sigma", "ndt"],
+        Support::Positive,
+    ),
+    family(
+        "exgaussian",
+        &["identity", "log", "inverse"],
+        &["mu", "sigma", "beta"],
+        Support::Real,
+    ),
+    family("frechet", POSITIVE_LINKS, &["mu", "nu"], Support::Positive),
+    family(
+        "gen_extreme_value",
+        &["identity", "log", "inverse"],
+        &["mu", "sigma", "xi"],
+        Support::Real,
+    ),
+    family(
+        "inverse.gaussian",
+        &["1/mu^2", "inverse", "identity", "log"],
+        &["mu", "shape"],
+        Support::Positive,
+    ),
+    family("Beta", BINARY_LINKS, &["mu", "phi"], Support::Unit),
+    family("beta", BINARY_LINKS, &["mu", "phi"], Support::Unit),
+    family(
+        "xbeta",
+        BINARY_LINKS,
+        &["mu", "phi", "kappa"],
+        Support::UnitClosed,
+    ),
+    family(
+        "von_mises",
+        &["tan_half", "identity"],
+        &["mu", "kappa"],
+        Support::Circular,
+    ),
+    family(
+        "asym_laplace",
+        &["identity", "log", "inverse"],
+        &["mu", "sigma", "quantile"],
+        Support::Real,
+    ),
+    family("cox", &["log", "identity"], &["mu"], Support::Positive),
+    family(
+        "wiener",
+        &["identity", "log", "softplus"],
+        &["mu", "bs", "ndt", "bias"],
+        Support::Positive,
+    ),
+    family(
+        "cumulative",
+        ORDINAL_LINKS,
+        &["mu", "disc"],
+        Support::Ordinal,
+    ),
+    family("sratio", ORDINAL_LINKS, &["mu", "disc"], Support::Ordinal),
+    family("cratio", ORDINAL_LINKS, &["mu", "disc"], Support::Ordinal),
+    family("acat", ORDINAL_LINKS, &["mu", "disc"], Support::Ordinal),
+    family("categorical", &["logit"], &["mu"], Support::Categorical),
+    family("multinomial", &["logit"], &["mu"], Support::Count),
+    family("dirichlet", &["logit"], &["mu", "phi"], Support::Simplex),
+    family(
+        "logistic_normal",
+        &["identity"],
+        &["mu", "sigma"],
+        Support::Simplex,
+    ),
+    family("hurdle_poisson", COUNT_LINKS, &["mu", "hu"], Support::Count),
+    family(
+        "hurdle_negbinomial",
+        COUNT_LINKS,
+        &["mu", "shape", "hu"],
+        Support::Count,
+    ),
+    family(
+        "hurdle_gamma",
+        &["log", "identity", "inverse"],
+        &["mu", "shape", "hu"],
+        Support::NonNegative,
+    ),
+    family(
+        "hurdle_lognormal",
+        &["identity", "inverse"],
+        &["mu", "sigma", "hu"],
+        Support::NonNegative,
+    ),
+    family(
+        "hurdle_cumulative",
+        ORDINAL_LINKS,
+        &["mu", "hu", "disc"],
+        Support::Ordinal,
+    ),
+    family(
+        "zero_inflated_poisson",
+        COUNT_LINKS,
+        &["mu", "zi"],
+        Support::Count,
+    ),
+    family(
+        "zero_inflated_negbinomial",
+        COUNT_LINKS,
+        &["mu", "shape", "zi"],
+        Support::Count,
+    ),
+    family(
+        "zero_inflated_binomial",
+        BINARY_LINKS,
+        &["mu", "zi"],
+        Support::Count,
+    ),
+    family(
+        "zero_inflated_beta_binomial",
+        BINARY_LINKS,
+        &["mu", "phi", "zi"],
+        Support::Count,
+    ),
+    family(
+        "zero_inflated_beta",
+        BINARY_LINKS,
+        &["mu", "phi", "zi"],
+        Support::UnitWithZero,
+    ),
+    family(
+        "zero_one_inflated_beta",
+        BINARY_LINKS,
+        &["mu", "phi", "zoi", "coi"],
+        Support::UnitClosed,
+    ),
+    family(
+        "zero_inflated_asym_laplace",
+        &["identity", "log", "inverse"],
+        &["mu", "sigma", "quantile", "zi"],
+        Support::Real,
+    ),
+];
+
+/// Families with one mean per response category, predicted by `mu<category>`
+/// formulas such as `muB ~ x`.
+const CATEGORICAL: &[&str] = &["categorical", "multinomial", "dirichlet", "logistic_normal"];
+
+impl Family {
+    /// The distributional parameters of the family, or `None` for a builtin
+    /// family that is not known.
+    ///
+    /// A mixture numbers the parameters of its components and adds their
+    /// mixing proportions: `mixture(gaussian(), gaussian())` has `mu1`,
+    /// `sigma1`, `mu2`, `sigma2`, `theta1` and `theta2`.
+    pub fn dpars(&self) -> Option<Vec<String>> {
+        match self {
+            Family::Builtin(name, _) => {
+                builtin_dpars(name).map(|dpars| dpars.iter().map(|dpar| dpar.to_string()).collect())
+            }
+            Family::Custom { dpars, .. } => Some(dpars.clone()),
+            Family::Mixture(components) => {
+                let mut dpars = Vec::new();
+                for (i, component) in components.iter().enumerate() {
+                    for dpar in component.dpars()? {
+                        dpars.push(format!("{}{}", dpar, i + 1));
+                    }
+                }
+                dpars.extend((1..=components.len()).map(|i| format!("theta{}", i)));
+                Some(dpars)
+            }
+        }
+    }
+
+    /// The registry entry of a builtin family, or `None` for an unknown,
+    /// custom or mixture family.
+    pub fn info(&self) -> Option<&'static FamilyInfo> {
+        match self {
+            Family::Builtin(name, _) => FamilyInfo::lookup(name),
+            _ => None,
+        }
+    }
+
+    /// The link given as an argument of a builtin family, e.g. `probit` for
+    /// `binomial(link="probit")` or `binomial("probit")`.
+    pub fn link_arg(&self) -> Option<&str> {
+        let Family::Builtin(_, args) = self else {
+            return None;
+        };
+        let link = args
+            .iter()
+            .find_map(|arg| match arg {
+                Expr::Named { name, value } if name == "link" => Some(value.as_ref()),
+                _ => None,
+            })
+            .or_else(|| {
+                args.first()
+                    .filter(|arg| !matches!(arg, Expr::Named { .. }))
+            })?;
+        match link {
+            Expr::Str(name) | Expr::Var(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Name of the family as written, e.g. `gaussian` or `mixture`.
+    pub fn name(&self) -> &str {
+        match self {
+            Family::Builtin(name, _) => name,
+            Family::Custom { name, .. } => name,
+            Family::Mixture(_) => "mixture",
+        }
+    }
+
+    /// Whether `dpar` may have a formula of its own: any parameter but the
+    /// mean `mu` of a single family, which the main formula predicts.
+    fn accepts_dpar(&self, dpar: &str) -> Option<bool> {
+        let dpars = self.dpars()?;
+        if let Family::Builtin(name, _) = self {
+            if CATEGORICAL.contains(&name.as_str()) && dpar.len() > 2 && dpar.starts_with("mu") {
+                return Some(true);
+            }
+        }
+        let main = !matches!(self, Family::Mixture(_)) && dpar == "mu";
+        Some(!main && dpars.iter().any(|d| d == dpar))
+    }
+}
+
+fn builtin_dpars(name: &str) -> Option<&'static [&'static str]> {
+    FamilyInfo::lookup(name).map(|family| family.dpars)
+}
+
+/// Check that the family of a spec is known and its link valid for it, given
+/// as `family=poisson(link="sqrt")` or `family=poisson(), link=sqrt`.
+pub(crate) fn check_family(spec: &ModelSpec) -> Result<(), Error> {
+    let Some(family) = &spec.family else {
+        return Ok(());
+    };
+    let header_link = spec.link.as_ref().map(|Link::Named(name, _)| name.as_str());
+    check_family_link(family, header_link)
+}
+
+fn check_family_link(family: &Family, header_link: Option<&str>) -> Result<(), Error> {
+    match family {
+        Family::Builtin(name, _) => {
+            let info = FamilyInfo::lookup(name).ok_or_else(|| {
+                Error::Semantic(format!(
+                    "Unknown family '{}'; use custom_family() for families that are not built in",
+                    name
+                ))
+            })?;
+            for link in family.link_arg().into_iter().chain(header_link) {
+                if !info.links.contains(&link) {
+                    return Err(Error::Semantic(format!(
+                        "Link '{}' is not valid for family '{}'; use one of {}",
+                        link,
+                        name,
+                        info.links.join(", ")
+                    )));
+                }
+            }
+            Ok(())
+        }
+        Family::Mixture(components) => components
+            .iter()
+            .try_for_each(|component| check_family_link(component, header_link)),
+        Family::Custom { .. } => Ok(()),
+    }
+}
+
+/// Check that the materialized response lies in the support of the family,
+/// e.g. non-negative integer counts for `poisson`. Only the times of a `Surv()`
+/// response are checked, and every component of a mixture.
+pub(crate) fn check_response(family: &Family, lhs: &Response, y: &DataFrame) -> Result<(), Error> {
+    let support = match family {
+        Family::Builtin(..) => match family.info() {
+            Some(info) => info.support,
+            None => return Ok(()),
+        },
+        Family::Mixture(components) => {
+            return components
+                .iter()
+                .try_for_each(|component| check_response(component, lhs, y))
+        }
+        Family::Custom { .. } => return Ok(()),
+    };
+    let columns: Vec<&Column> = y
+        .get_columns()
+        .iter()
+        .filter(|column| !(matches!(lhs, Response::Surv { .. }) && column.name() == "status"))
+        .collect();
+    let fail = |found: String| {
+        Err(Error::Semantic(format!(
+            "Response of family '{}' must be {}, found {}",
+            family.name(),
+            support.requirement(),
+            found
+        )))
+    };
+
+    let mut values = Vec::with_capacity(columns.len());
+    for column in &columns {
+        let series = column.as_materialized_series();
+        if let Ok(strings) = series.str() {
+            let levels = strings
+                .n_unique()
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                - usize::from(strings.null_count() > 0);
+            match support {
+                Support::Ordinal | Support::Categorical => continue,
+                Support::Binary if levels <= 2 => continue,
+                Support::Count if family.name() == "multinomial" && columns.len() == 1 => continue,
+                Support::Binary => return fail(format!("{} levels", levels)),
+                _ => return fail(format!("text in '{}'", column.name())),
+            }
+        }
+        let numeric = series
+            .cast(&DataType::Float64)
+            .map_err(|_| Error::Semantic(format!("Response '{}' is not numeric", column.name())))?;
+        let numeric: Vec<Option<f64>> = numeric
+            .f64()
+            .map_err(|e| Error::Semantic(e.to_string()))?
+            .into_iter()
+            .collect();
+        for (row, v) in numeric.iter().enumerate() {
+            if let Some(v) = v {
+                if !support.contains(*v) {
+                    return fail(format!("{} in row {}", v, row));
+                }
+            }
+        }
+        values.push(numeric);
+    }
+
+    if support == Support::Simplex && values.len() > 1 {
+        for row in 0..y.height() {
+            let total: Option<f64> = values.iter().map(|column| column[row]).sum();
+            if let Some(total) = total {
+                if (total - 1.0).abs() > 1e-8 {
+                    return fail(format!("a sum of {} in row {}", total, row));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How the response of a family is coded, or `None` if it is used as it is.
+pub(crate) fn response_coding(family: &Family) -> Option<ResponseCoding> {
+    let info = family.info()?;
+    match info.support {
+        Support::Ordinal => Some(ResponseCoding::Ordinal),
+        Support::Categorical => Some(ResponseCoding::Indicators),
+        Support::Count if info.name == "multinomial" => Some(ResponseCoding::Indicators),
+        Support::Binary => Some(ResponseCoding::Binary),
+        _ => None,
+    }
+}
+
+/// Code a categorical response for its family: ordered integer codes for the
+/// ordinal families, 0/1 indicator columns for `categorical` and
+/// `multinomial`, and 0/1 for a two-level or logical `bernoulli` response.
+///
+/// Numeric ordinal and binary responses are already codes and kept as they
+/// are, as are multi-column responses and mixtures. Levels found in `state`
+/// are reused, so new data is coded as the training data; otherwise they are
+/// learned from `y` and stored there.
+pub(crate) fn encode_response(
+    family: &Family,
+    y: DataFrame,
+    state: &mut Option<ResponseEncoding>,
+) -> Result<DataFrame, Error> {
+    let Some(coding) = response_coding(family) else {
+        return Ok(y);
+    };
+    let [column] = y.get_columns() else {
+        return Ok(y);
+    };
+    let series = column.as_materialized_series();
+    let name = series.name().to_string();
+
+    // The values as level labels, with the levels in their natural order.
+    let (values, mut levels): (Vec<Option<String>>, Vec<String>) = match series.dtype() {
+        DataType::String => {
+            let values: Vec<Option<String>> = series
+                .str()
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                .into_iter()
+                .map(|v| v.map(str::to_string))
+                .collect();
+            let mut levels: Vec<String> = values.iter().flatten().cloned().collect();
+            levels.sort();
+            levels.dedup();
+            (values, levels)
+        }
+        DataType::Boolean if coding == ResponseCoding::Binary => {
+            let values = series
+                .bool()
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                .into_iter()
+                .map(|v| v.map(|v| v.to_string()))
+                .collect();
+            (values, vec!["false".to_string(), "true".to_string()])
+        }
+        dtype if dtype.is_primitive_numeric() && coding == ResponseCoding::Indicators => {
+            let numbers: Vec<Option<f64>> = series
+                .cast(&DataType::Float64)
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                .f64()
+                .map_err(|e| Error::Semantic(e.to_string()))?
+                .into_iter()
+                .collect();
+            let mut sorted: Vec<f64> = numbers.iter().flatten().copied().collect();
+            sorted.sort_by(f64::total_cmp);
+            sorted.dedup();
+            let values = numbers.iter().map(|v| v.map(|v| v.to_string())).collect();
+            (values, sorted.iter().map(|v| v.to_string()).collect())
+        }
+        _ => return Ok(y),
+    };
+
+    match state {
+        Some(learned) if learned.name == name && learned.coding == coding => {
+            levels = learned.levels.clone();
+        }
+        _ => {
+            if coding == ResponseCoding::Binary && levels.len() > 2 {
+                return Err(Error::Semantic(format!(
+                    "Response '{}' of family '{}' must have at most 2 levels, found {}",
+                    name,
+                    family.name(),
+                    levels.len()
+                )));
+            }
+            *state = Some(ResponseEncoding {
+                name: name.clone(),
+                coding,
+                levels: levels.clone(),
+            });
+        }
+    }
+
+    let codes = values
+        .iter()
+        .map(|value| {
+            value
+                .as_ref()
+                .map(|value| {
+                    levels
+                        .iter()
+                        .position(|level| level == value)
+                        .ok_or_else(|| {
+                            Error::Semantic(format!(
+                                "Response '{}' has level '{}', which is not among the levels {}",
+                                name,
+                                value,
+                                levels.join(", ")
+                            ))
+                        })
+                })
+                .transpose()
+        })
+        .collect::<Result<Vec<Option<usize>>, Error>>()?;
+
+    let columns: Vec<Column> = match coding {
+        ResponseCoding::Ordinal => {
+            let codes: Int32Chunked = codes.iter().map(|c| c.map(|c| c as i32 + 1)).collect();
+            vec![codes.with_name(name.as_str().into()).into_column()]
+        }
+        ResponseCoding::Binary => {
+            let codes: Float64Chunked = codes.iter().map(|c| c.map(|c| c as f64)).collect();
+            vec![codes.with_name(name.as_str().into()).into_column()]
+        }
+        ResponseCoding::Indicators => levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let indicator: Float64Chunked = codes
+                    .iter()
+                    .map(|c| c.map(|c| f64::from(u8::from(c == i))))
+                    .collect();
+                indicator
+                    .with_name(format!("{}_{}", name, level).into())
+                    .into_column()
+            })
+            .collect(),
+    };
+    DataFrame::new(columns).map_err(|e| Error::Semantic(e.to_string()))
+}
+
+/// Check that every distributional parameter formula of a spec belongs to its
+/// family, e.g. `zi ~ x` needs a zero-inflated family. Specs without a family
+/// are not checked.
+pub(crate) fn check_dpars(spec: &ModelSpec) -> Result<(), Error> {
+    let Some(family) = &spec.family else {
+        return Ok(());
+    };
+    for dpar in &spec.dpars {
+        if family.accepts_dpar(&dpar.name) == Some(false) {
+            let others: Vec<String> = family
+                .dpars()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|d| family.accepts_dpar(d) == Some(true))
+                .collect();
+            return Err(Error::Semantic(format!(
+                "Family '{}' has no distributional parameter '{}' to predict; {}",
+                family.name(),
+                dpar.name,
+                match others.as_slice() {
+                    [] => "it has none but mu, which the main formula predicts".to_string(),
+                    others => format!("it has {}", others.join(", ")),
+                }
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,576 @@
+//! Compilation of formulas to Polars expressions.
+//!
+//! Each column of the fixed effects design matrix X is compiled into a Polars
+//! expression over the columns of the data, so that a `LazyFrame` can select
+//! the design matrix and leave projection and predicate pushdown, streaming
+//! and out-of-core execution to the query engine. The columns have the names
+//! and values [`materialize`](super::materialize::materialize) gives them.
+//!
+//! Factor contrasts become `when/then` indicators and orthogonal polynomials
+//! follow their recurrence with native arithmetic; spline bases and math
+//! functions are applied elementwise. The levels, polynomial coefficients and
+//! knots these need are read from a [`DesignInfo`]; [`materialize_lazy`]
+//! learns the missing ones with small queries on the frame first.
+
+use super::ast::*;
+// The lazy prelude has its own Expr; formula expressions are the AST ones
+use super::ast::Expr;
+use super::design::{DesignInfo, TransformState};
+use super::materialize::{
+    final_column_names, has_intercept_removal, is_spline_function, learn_spline_state,
+    math_function, poly_args, split_call_args, PolyState,
+};
+use super::pretty::pretty_expr;
+use crate::Error;
+use polars::prelude::Expr as PlExpr;
+use polars::prelude::{
+    col, len, lit, repeat, when, Column, DataType, Field, Float64Chunked, GetOutput, IntoColumn,
+    IntoSeries, LazyFrame, PolarsResult, Schema, Series, StructChunked,
+};
+use std::collections::btree_map::Entry;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+impl ModelSpec {
+    /// Compile the columns of the fixed effects design matrix X into Polars
+    /// expressions over a frame with the given schema, named as by
+    /// [`materialize`](crate::materialize): the intercept first, unless the
+    /// formula removes it.
+    ///
+    /// Only stateless terms compile without learned state; categorical
+    /// variables, `poly()` and splines need the levels and coefficients of
+    /// [`to_exprs_with_info`](Self::to_exprs_with_info). Random effects and
+    /// offsets are not columns of X and compile to nothing; smooths are not
+    /// supported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use polars::prelude::*;
+    /// use polars_formula::canonicalize;
+    ///
+    /// let df = df!("y" => [1.0, 2.0, 3.0], "x" => [1.0, 4.0, 9.0])?;
+    /// let spec = canonicalize("y ~ x + sqrt(x)")?;
+    /// let exprs = spec.to_exprs(&df.schema())?;
+    /// let x = df.lazy().select(exprs).collect()?;
+    /// assert_eq!(x.get_column_names(), ["intercept", "x", "sqrt"]);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn to_exprs(&self, schema: &Schema) -> Result<Vec<PlExpr>, Error> {
+        self.to_exprs_with_info(schema, &DesignInfo::default())
+    }
+
+    /// Compile the columns of X like [`to_exprs`](Self::to_exprs), with the
+    /// factor levels and transform state learned on training data, e.g. by
+    /// [`materialize_with_info`](crate::materialize_with_info).
+    pub fn to_exprs_with_info(
+        &self,
+        schema: &Schema,
+        info: &DesignInfo,
+    ) -> Result<Vec<PlExpr>, Error> {
+        let mut bases = Bases::inline();
+        design_exprs(
+            &self.formula.rhs,
+            schema,
+            info,
+            &MaterializeOptions::default(),
+            &mut bases,
+        )
+    }
+}
+
+/// Compile a spec against a lazy frame into lazy frames of the response and
+/// of the fixed effects design matrix X.
+///
+/// Factor levels and transform state missing from `info` are learned from
+/// `lf` and added to it, each with a query of the one column it needs; the
+/// returned frames are not collected. The response is selected as is, without
+/// the family checks and coding of the eager materialization.
+pub fn materialize_lazy(
+    lf: &LazyFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<(LazyFrame, LazyFrame), Error> {
+    let schema = lf.clone().collect_schema().map_err(polars_error)?;
+    learn_state(lf, &spec.formula.rhs, &schema, info)?;
+
+    let y = lf
+        .clone()
+        .select(response_exprs(&spec.formula.lhs, &schema)?);
+    let mut bases = Bases::staged();
+    let columns = design_exprs(&spec.formula.rhs, &schema, info, &opts, &mut bases)?;
+    let x = lf.clone().with_columns(bases.exprs).select(columns);
+    Ok((y, x))
+}
+
+fn polars_error(e: polars::prelude::PolarsError) -> Error {
+    Error::Semantic(e.to_string())
+}
+
+/// Spline bases of the design, evaluated as one struct column per term whose
+/// fields are the basis columns.
+///
+/// Staged bases are computed once, as temporary columns added to the frame
+/// before X is selected, and the columns of X read their fields. Inline bases
+/// make every column self-contained, for a plain `select`, at the price of
+/// evaluating the basis once per column.
+struct Bases {
+    staged: bool,
+    exprs: Vec<PlExpr>,
+    names: HashSet<String>,
+}
+
+impl Bases {
+    fn staged() -> Self {
+        Self {
+            staged: true,
+            exprs: Vec::new(),
+            names: HashSet::new(),
+        }
+    }
+
+    fn inline() -> Self {
+        Self {
+            staged: false,
+            ..Self::staged()
+        }
+    }
+
+    /// The struct of a basis to read the columns from: `basis` itself, or a
+    /// temporary column computing it once.
+    fn source(&mut self, label: &str, basis: PlExpr) -> PlExpr {
+        if !self.staged {
+            return basis;
+        }
+        let name = format!("__basis_{}", label);
+        if self.names.insert(name.clone()) {
+            self.exprs.push(basis.alias(name.as_str()));
+        }
+        col(name.as_str())
+    }
+}
+
+/// The columns of X with their final names.
+fn design_exprs(
+    rhs: &Expr,
+    schema: &Schema,
+    info: &DesignInfo,
+    opts: &MaterializeOptions,
+    bases: &mut Bases,
+) -> Result<Vec<PlExpr>, Error> {
+    let mut columns = Vec::new();
+    if opts.rhs_intercept && !has_intercept_removal(rhs) {
+        columns.push((opts.intercept_name.to_string(), ones()));
+    }
+    columns.extend(compile_columns(rhs, schema, info, bases)?);
+
+    let (names, exprs): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+    Ok(final_column_names(names, opts)
+        .into_iter()
+        .zip(exprs)
+        .map(|(name, expr)| expr.alias(name))
+        .collect())
+}
+
+/// A column of ones as long as the frame; a bare literal would be a single
+/// row when no other column is selected.
+fn ones() -> PlExpr {
+    repeat(lit(1.0), len())
+}
+
+/// A column of the frame, checked against its schema.
+fn column(schema: &Schema, name: &str) -> Result<PlExpr, Error> {
+    if schema.get(name).is_none() {
+        return Err(Error::Semantic(format!(
+            "Column '{}' not found in DataFrame",
+            name
+        )));
+    }
+    Ok(col(name))
+}
+
+/// The response columns: the variable, the successes of `y | trials(n)`, or
+/// each response of `cbind()` and `mvbind()` named by its text.
+fn response_exprs(response: &Response, schema: &Schema) -> Result<Vec<PlExpr>, Error> {
+    match response {
+        Response::Var(name) => Ok(vec![column(schema, name)?]),
+        Response::BinomialTrials {
+            successes: Expr::Var(name),
+            ..
+        } => Ok(vec![column(schema, name)?]),
+        Response::Multi { responses, .. } => responses
+            .iter()
+            .map(|response| Ok(numeric(response, schema)?.alias(pretty_expr(response))))
+            .collect(),
+        _ => Err(Error::Semantic(
+            "Only variable, cbind() and mvbind() responses can be compiled to Polars expressions"
+                .into(),
+        )),
+    }
+}
+
+/// Learn the factor levels, polynomial coefficients and spline knots of the
+/// terms of `expr` that `info` does not have yet.
+fn learn_state(
+    lf: &LazyFrame,
+    expr: &Expr,
+    schema: &Schema,
+    info: &mut DesignInfo,
+) -> Result<(), Error> {
+    match expr {
+        Expr::Sum(terms) | Expr::Prod(terms) | Expr::Interaction(terms) => {
+            for term in terms {
+                learn_state(lf, term, schema, info)?;
+            }
+        }
+        Expr::Func { name, args } if name == "NEG" => {
+            for arg in args {
+                learn_state(lf, arg, schema, info)?;
+            }
+        }
+        Expr::Var(name)
+            if matches!(schema.get(name), Some(DataType::String))
+                && !info.levels.contains_key(name) =>
+        {
+            let values = collect(lf, column(schema, name)?.unique())?;
+            let values = values.str().map_err(polars_error)?;
+            let mut levels: Vec<String> = values.into_iter().flatten().map(String::from).collect();
+            levels.sort();
+            levels.dedup();
+            info.levels.insert(name.clone(), levels);
+        }
+        Expr::Func { name, args } if name == "poly" => {
+            let (var, degree, raw) = poly_args(args)?;
+            if let (false, Entry::Vacant(entry)) = (raw, info.transforms.entry(pretty_expr(expr))) {
+                let observed = collect_f64(lf, numeric(&Expr::Var(var.to_string()), schema)?)?;
+                let state = PolyState::learn(&observed, degree)?;
+                entry.insert(TransformState::Poly(state));
+            }
+        }
+        Expr::Func { name, args } if is_spline_function(name) => {
+            if let Entry::Vacant(entry) = info.transforms.entry(pretty_expr(expr)) {
+                let (positional, named) = split_call_args(args);
+                let var_expr = positional.first().ok_or_else(|| {
+                    Error::Semantic(format!("{}() requires a variable as first argument", name))
+                })?;
+                let observed = collect_f64(lf, numeric(var_expr, schema)?)?;
+                let state = learn_spline_state(name, &positional[1..], &named, &observed)?;
+                entry.insert(TransformState::Spline(state));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Collect a single expression over the frame.
+fn collect(lf: &LazyFrame, expr: PlExpr) -> Result<Column, Error> {
+    let df = lf
+        .clone()
+        .select([expr.alias("value")])
+        .collect()
+        .map_err(polars_error)?;
+    df.column("value").cloned().map_err(polars_error)
+}
+
+/// Collect the non-null values of a numeric expression over the frame.
+fn collect_f64(lf: &LazyFrame, expr: PlExpr) -> Result<Vec<f64>, Error> {
+    let values = collect(lf, expr)?;
+    let values = values.f64().map_err(polars_error)?;
+    Ok(values.into_iter().flatten().collect())
+}
+
+/// Compile a term into its columns, mirroring the eager materialization.
+fn compile_columns(
+    expr: &Expr,
+    schema: &Schema,
+    info: &DesignInfo,
+    bases: &mut Bases,
+) -> Result<Vec<(String, PlExpr)>, Error> {
+    match expr {
+        Expr::Sum(terms) | Expr::Prod(terms) => {
+            let mut columns = Vec::new();
+            for term in terms {
+                columns.extend(compile_columns(term, schema, info, bases)?);
+            }
+            Ok(columns)
+        }
+        // Random effects are columns of Z
+        Expr::Group { .. } => Ok(Vec::new()),
+        Expr::Interaction(terms) => {
+            // All combinations of the columns of the terms, the first term
+            // varying slowest
+            let mut columns = vec![(String::new(), None::<PlExpr>)];
+            for term in terms {
+                let term_columns = compile_columns(term, schema, info, bases)?;
+                columns = columns
+                    .iter()
+                    .flat_map(|(name1, expr1)| {
+                        term_columns.iter().map(move |(name2, expr2)| match expr1 {
+                            None => (name2.clone(), Some(expr2.clone())),
+                            Some(expr1) => (
+                                format!("{}_x_{}", name1, name2),
+                                Some(expr1.clone() * expr2.clone()),
+                            ),
+                        })
+                    })
+                    .collect();
+            }
+            Ok(columns
+                .into_iter()
+                .filter_map(|(name, expr)| expr.map(|expr| (name, expr)))
+                .collect())
+        }
+        Expr::Func { name, args } if name == "NEG" => match args.as_slice() {
+            // -1 removes the intercept
+            [Expr::Num(n)] if *n == 1.0 => Ok(Vec::new()),
+            [inner] => compile_columns(inner, schema, info, bases),
+            _ => Err(Error::Semantic(
+                "NEG function with wrong number of arguments".into(),
+            )),
+        },
+        // Offsets have a fixed coefficient of 1 and are not columns of X
+        Expr::Func { name, .. } if name == "offset" => Ok(Vec::new()),
+        Expr::Func { name, args } if name == "poly" => poly_columns(expr, args, schema, info),
+        Expr::Func { name, args } if is_spline_function(name) => {
+            spline_columns(expr, name, args, schema, info, bases)
+        }
+        Expr::Smooth { .. } => Err(Error::Semantic(format!(
+            "Smooth term '{}' cannot be compiled to Polars expressions; use materialize()",
+            pretty_expr(expr)
+        ))),
+        Expr::Var(name) => match schema.get(name) {
+            None => Err(Error::Semantic(format!(
+                "Column '{}' not found in DataFrame",
+                name
+            ))),
+            Some(DataType::String) => contrast_columns(name, info),
+            Some(_) => Ok(vec![(name.clone(), col(name.as_str()))]),
+        },
+        Expr::Func { name, args } if name == "I" => match args.first() {
+            Some(inner) => Ok(vec![(name.clone(), numeric(inner, schema)?)]),
+            None => Err(Error::Semantic(
+                "Identity function with no arguments".into(),
+            )),
+        },
+        Expr::Func { name, .. } if math_function(name).is_some() => {
+            Ok(vec![(name.clone(), numeric(expr, schema)?)])
+        }
+        Expr::Num(n) => Ok(vec![(format!("constant_{}", n), ones() * lit(*n))]),
+        Expr::Intercept(true) => Ok(vec![("intercept".to_string(), ones())]),
+        Expr::Intercept(false) => Ok(vec![("zero".to_string(), ones() * lit(0.0))]),
+        other => Err(Error::Semantic(format!(
+            "Term '{}' cannot be compiled to Polars expressions",
+            pretty_expr(other)
+        ))),
+    }
+}
+
+/// Treatment contrasts of a categorical variable against its first level.
+fn contrast_columns(name: &str, info: &DesignInfo) -> Result<Vec<(String, PlExpr)>, Error> {
+    let levels = info.levels.get(name).ok_or_else(|| {
+        Error::Semantic(format!(
+            "Levels of the categorical variable '{}' are unknown; learn them with materialize_lazy() or materialize_with_info()",
+            name
+        ))
+    })?;
+    Ok(levels
+        .iter()
+        .skip(1)
+        .map(|level| {
+            let indicator = when(col(name).eq(lit(level.as_str())))
+                .then(lit(1.0))
+                .otherwise(lit(0.0));
+            (format!("{}_{}", name, level), indicator)
+        })
+        .collect())
+}
+
+/// Raw powers, or orthogonal polynomials from their stored recurrence.
+fn poly_columns(
+    expr: &Expr,
+    args: &[Expr],
+    schema: &Schema,
+    info: &DesignInfo,
+) -> Result<Vec<(String, PlExpr)>, Error> {
+    let (var, degree, raw) = poly_args(args)?;
+    let x = column(schema, var)?.cast(DataType::Float64);
+    if raw {
+        return Ok((1..=degree)
+            .map(|d| {
+                let name = if d == 1 {
+                    var.to_string()
+                } else {
+                    format!("{}_{}", var, d)
+                };
+                (name, x.clone().pow(d as f64))
+            })
+            .collect());
+    }
+
+    let state = match info.transforms.get(&pretty_expr(expr)) {
+        Some(TransformState::Poly(state)) => state,
+        _ => return Err(missing_state(expr)),
+    };
+    let (mut previous, mut current) = (lit(0.0), lit(1.0));
+    Ok((0..state.degree())
+        .map(|k| {
+            let ratio = state.norm2[k + 1] / state.norm2[k];
+            let next =
+                (x.clone() - lit(state.alpha[k])) * current.clone() - lit(ratio) * previous.clone();
+            previous = std::mem::replace(&mut current, next.clone());
+            (
+                format!("poly_{}_{}", var, k + 1),
+                next / lit(state.norm2[k + 2].sqrt()),
+            )
+        })
+        .collect())
+}
+
+/// Spline basis columns with the stored knots. The basis is evaluated in one
+/// pass over the variable into a struct with a field per column.
+fn spline_columns(
+    expr: &Expr,
+    name: &str,
+    args: &[Expr],
+    schema: &Schema,
+    info: &DesignInfo,
+    bases: &mut Bases,
+) -> Result<Vec<(String, PlExpr)>, Error> {
+    let (positional, _) = split_call_args(args);
+    let var_expr = *positional.first().ok_or_else(|| {
+        Error::Semantic(format!("{}() requires a variable as first argument", name))
+    })?;
+    let state = match info.transforms.get(&pretty_expr(expr)) {
+        Some(TransformState::Spline(state)) => Arc::new(state.clone()),
+        _ => return Err(missing_state(expr)),
+    };
+    let var_label = pretty_expr(var_expr);
+    let names: Vec<String> = (0..state.ncols())
+        .map(|i| format!("{}_{}_{}", name, var_label, i + 1))
+        .collect();
+    let fields = names
+        .iter()
+        .map(|name| Field::new(name.as_str().into(), DataType::Float64))
+        .collect();
+    let field_names = names.clone();
+    let basis = numeric(var_expr, schema)?.map(
+        move |c| basis_struct(c, &field_names, |x| state.basis(x)),
+        GetOutput::from_type(DataType::Struct(fields)),
+    );
+    let source = bases.source(&pretty_expr(expr), basis);
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let column = source.clone().struct_().field_by_name(&name);
+            (name, column)
+        })
+        .collect())
+}
+
+fn missing_state(expr: &Expr) -> Error {
+    Error::Semantic(format!(
+        "No learned state for '{}'; learn it with materialize_lazy() or materialize_with_info()",
+        pretty_expr(expr)
+    ))
+}
+
+/// Evaluate `f` on the non-null values of a column, keeping nulls in place.
+fn map_observed(column: Column, f: impl Fn(&[f64]) -> Vec<f64>) -> PolarsResult<Option<Column>> {
+    let values = column.cast(&DataType::Float64)?;
+    let values = values.f64()?;
+    let observed: Vec<f64> = values.into_iter().flatten().collect();
+    let mut mapped = f(&observed).into_iter();
+    let out: Float64Chunked = values
+        .into_iter()
+        .map(|v| v.and_then(|_| mapped.next()))
+        .collect();
+    Ok(Some(out.with_name(column.name().clone()).into_column()))
+}
+
+/// Evaluate the basis `f` on the non-null values of a column into a struct
+/// with one field per basis column, keeping nulls in place.
+fn basis_struct(
+    column: Column,
+    names: &[String],
+    f: impl Fn(&[f64]) -> Vec<Vec<f64>>,
+) -> PolarsResult<Option<Column>> {
+    let values = column.cast(&DataType::Float64)?;
+    let values = values.f64()?;
+    let observed: Vec<f64> = values.into_iter().flatten().collect();
+    let fields: Vec<Series> = f(&observed)
+        .into_iter()
+        .zip(names)
+        .map(|(basis, name)| {
+            let mut basis = basis.into_iter();
+            let out: Float64Chunked = values
+                .into_iter()
+                .map(|v| v.and_then(|_| basis.next()))
+                .collect();
+            out.with_name(name.as_str().into()).into_series()
+        })
+        .collect();
+    let out = StructChunked::from_series(column.name().clone(), column.len(), fields.iter())?;
+    Ok(Some(out.into_series().into_column()))
+}
+
+/// Compile an arithmetic expression such as `log(n + 1)` to a Float64
+/// expression, as `eval_numeric` evaluates it. Nulls propagate.
+fn numeric(expr: &Expr, schema: &Schema) -> Result<PlExpr, Error> {
+    match expr {
+        Expr::Num(v) => Ok(lit(*v)),
+        Expr::Intercept(b) => Ok(lit(if *b { 1.0 } else { 0.0 })),
+        Expr::Var(name) => Ok(column(schema, name)?.cast(DataType::Float64)),
+        Expr::Sum(terms) => {
+            let mut total = lit(0.0);
+            for term in terms {
+                total = match term {
+                    Expr::Func { name, args } if name == "NEG" && args.len() == 1 => {
+                        total - numeric(&args[0], schema)?
+                    }
+                    term => total + numeric(term, schema)?,
+                };
+            }
+            Ok(total)
+        }
+        Expr::Prod(terms) => {
+            let mut total = lit(1.0);
+            for term in terms {
+                total = total * numeric(term, schema)?;
+            }
+            Ok(total)
+        }
+        Expr::Nest {
+            outer,
+            inner,
+            kind: NestKind::Slash,
+        } => Ok(numeric(outer, schema)? / numeric(inner, schema)?),
+        Expr::Pow { base, exp } => Ok(numeric(base, schema)?.pow(numeric(exp, schema)?)),
+        Expr::Identity(inner) => numeric(inner, schema),
+        Expr::Func { name, args } if args.len() == 1 => {
+            let inner = numeric(&args[0], schema)?;
+            match name.as_str() {
+                "I" => Ok(inner),
+                "NEG" => Ok(inner * lit(-1.0)),
+                name => {
+                    let f = math_function(name).ok_or_else(|| {
+                        Error::Semantic(format!(
+                            "Unsupported function '{}' in expression '{}'",
+                            name,
+                            pretty_expr(expr)
+                        ))
+                    })?;
+                    Ok(inner.map(
+                        move |c| map_observed(c, |x| x.iter().map(|v| f(*v)).collect()),
+                        GetOutput::from_type(DataType::Float64),
+                    ))
+                }
+            }
+        }
+        other => Err(Error::Semantic(format!(
+            "Cannot evaluate '{}' as a numeric expression",
+            pretty_expr(other)
+        ))),
+    }
+}
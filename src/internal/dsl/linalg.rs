@@ -0,0 +1,395 @@
+//! Small dense linear algebra helpers used by the basis constructors.
+//!
+//! Matrices are plain `Vec<Vec<f64>>`. Square matrices (penalties, systems to
+//! solve) are stored by rows; design bases are stored by columns, matching how
+//! they are turned into Polars columns.
+
+/// Householder QR of a tall matrix (given by columns), following LINPACK's
+/// `dqrdc2` so that `qty` reproduces R's `qr.qty()` including signs.
+pub(crate) struct Householder {
+    reflectors: Vec<Vec<f64>>,
+    rows: usize,
+}
+
+impl Householder {
+    pub(crate) fn new(mut columns: Vec<Vec<f64>>) -> Self {
+        let rows = columns.first().map_or(0, |c| c.len());
+        let k = columns.len().min(rows);
+        let mut reflectors = Vec::with_capacity(k);
+        for l in 0..k {
+            let norm = columns[l][l..].iter().map(|v| v * v).sum::<f64>().sqrt();
+            if norm == 0.0 {
+                reflectors.push(Vec::new());
+                continue;
+            }
+            let norm = if columns[l][l] != 0.0 {
+                norm.copysign(columns[l][l])
+            } else {
+                norm
+            };
+            let mut u: Vec<f64> = columns[l][l..].iter().map(|v| v / norm).collect();
+            u[0] += 1.0;
+            for column in columns.iter_mut().skip(l + 1) {
+                let dot: f64 = u.iter().zip(&column[l..]).map(|(a, b)| a * b).sum();
+                let scale = -dot / u[0];
+                for (c, ui) in column[l..].iter_mut().zip(&u) {
+                    *c += scale * ui;
+                }
+            }
+            reflectors.push(u);
+        }
+        Householder { reflectors, rows }
+    }
+
+    fn reflect(l: usize, u: &[f64], y: &mut [f64]) {
+        if u.is_empty() {
+            return;
+        }
+        let dot: f64 = u.iter().zip(&y[l..]).map(|(a, b)| a * b).sum();
+        let scale = -dot / u[0];
+        for (yi, ui) in y[l..].iter_mut().zip(u) {
+            *yi += scale * ui;
+        }
+    }
+
+    /// Overwrite `y` with `Q' y`.
+    pub(crate) fn qty(&self, y: &mut [f64]) {
+        for (l, u) in self.reflectors.iter().enumerate() {
+            Self::reflect(l, u, y);
+        }
+    }
+
+    /// Overwrite `y` with `Q y`.
+    pub(crate) fn qy(&self, y: &mut [f64]) {
+        for (l, u) in self.reflectors.iter().enumerate().rev() {
+            Self::reflect(l, u, y);
+        }
+    }
+
+    /// Columns `from..` of the full orthogonal factor `Q`.
+    pub(crate) fn q_columns(&self, from: usize) -> Vec<Vec<f64>> {
+        (from..self.rows)
+            .map(|j| {
+                let mut e = vec![0.0; self.rows];
+                e[j] = 1.0;
+                self.qy(&mut e);
+                e
+            })
+            .collect()
+    }
+}
+
+/// Null space basis `Z` of the constraint rows `c` (so that `c Z = 0`), as the
+/// trailing columns of `Q` from the QR decomposition of `c'`. This is how mgcv
+/// and Patsy absorb identifiability constraints.
+pub(crate) fn constraint_null_space(constraints: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    Householder::new(constraints.to_vec()).q_columns(constraints.len())
+}
+
+/// Solve `a x = b` for each column of `b` by Gaussian elimination with partial pivoting.
+///
+/// `a` and `b` are stored by rows.
+pub(crate) fn solve_dense(mut a: Vec<Vec<f64>>, mut b: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    let n = a.len();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap_or(col);
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (a_top, a_rest) = a.split_at_mut(col + 1);
+        let (b_top, b_rest) = b.split_at_mut(col + 1);
+        let (a_pivot, b_pivot) = (&a_top[col], &b_top[col]);
+        for (a_row, b_row) in a_rest.iter_mut().zip(b_rest.iter_mut()) {
+            let factor = a_row[col] / a_pivot[col];
+            if factor == 0.0 {
+                continue;
+            }
+            for (v, p) in a_row[col..].iter_mut().zip(&a_pivot[col..]) {
+                *v -= factor * p;
+            }
+            for (v, p) in b_row.iter_mut().zip(b_pivot) {
+                *v -= factor * p;
+            }
+        }
+    }
+    for col in (0..n).rev() {
+        for k in 0..b[col].len() {
+            let mut acc = b[col][k];
+            for j in col + 1..n {
+                acc -= a[col][j] * b[j][k];
+            }
+            b[col][k] = acc / a[col][col];
+        }
+    }
+    b
+}
+
+/// Eigen decomposition of a symmetric matrix.
+///
+/// Returns the eigenvalues in decreasing order and the matching unit
+/// eigenvectors. Uses Householder tridiagonalization followed by the implicit
+/// QL algorithm (EISPACK `tred2`/`tql2`).
+pub(crate) fn symmetric_eigen(a: &[Vec<f64>]) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    if n == 0 {
+        return (Vec::new(), Vec::new());
+    }
+    let mut v: Vec<Vec<f64>> = a.to_vec();
+    let mut d = vec![0.0; n];
+    let mut e = vec![0.0; n];
+    tred2(&mut v, &mut d, &mut e);
+    tql2(&mut v, &mut d, &mut e);
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| d[j].total_cmp(&d[i]));
+    let values = order.iter().map(|&i| d[i]).collect();
+    let vectors = order
+        .iter()
+        .map(|&j| (0..n).map(|i| v[i][j]).collect())
+        .collect();
+    (values, vectors)
+}
+
+fn tred2(v: &mut [Vec<f64>], d: &mut [f64], e: &mut [f64]) {
+    let n = d.len();
+    d.copy_from_slice(&v[n - 1]);
+
+    for i in (1..n).rev() {
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        let mut h = 0.0;
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+                v[j][i] = 0.0;
+            }
+        } else {
+            for dk in d[..i].iter_mut() {
+                *dk /= scale;
+                h += *dk * *dk;
+            }
+            let mut f = d[i - 1];
+            let mut g = h.sqrt();
+            if f > 0.0 {
+                g = -g;
+            }
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            for ej in e[..i].iter_mut() {
+                *ej = 0.0;
+            }
+            for j in 0..i {
+                f = d[j];
+                v[j][i] = f;
+                g = e[j] + v[j][j] * f;
+                for k in j + 1..i {
+                    g += v[k][j] * d[k];
+                    e[k] += v[k][j] * f;
+                }
+                e[j] = g;
+            }
+            f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                f = d[j];
+                g = e[j];
+                for k in j..i {
+                    v[k][j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+
+    for i in 0..n - 1 {
+        v[n - 1][i] = v[i][i];
+        v[i][i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k][i + 1] / h;
+            }
+            for j in 0..=i {
+                let mut g = 0.0;
+                for row in v.iter().take(i + 1) {
+                    g += row[i + 1] * row[j];
+                }
+                for (row, dk) in v.iter_mut().zip(d.iter()).take(i + 1) {
+                    row[j] -= g * dk;
+                }
+            }
+        }
+        for row in v.iter_mut().take(i + 1) {
+            row[i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[n - 1][j];
+        v[n - 1][j] = 0.0;
+    }
+    v[n - 1][n - 1] = 1.0;
+    e[0] = 0.0;
+}
+
+fn tql2(v: &mut [Vec<f64>], d: &mut [f64], e: &mut [f64]) {
+    let n = d.len();
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    e[n - 1] = 0.0;
+
+    let mut f = 0.0;
+    let mut tst1: f64 = 0.0;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n {
+            if e[m].abs() <= eps * tst1 {
+                break;
+            }
+            m += 1;
+        }
+        if m > l {
+            loop {
+                let mut g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for di in d.iter_mut().skip(l + 2) {
+                    *di -= h;
+                }
+                f += h;
+
+                p = d[m];
+                let mut c = 1.0;
+                let mut c2 = c;
+                let mut c3 = c;
+                let el1 = e[l + 1];
+                let mut s = 0.0;
+                let mut s2 = 0.0;
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for row in v.iter_mut() {
+                        h = row[i + 1];
+                        row[i + 1] = s * row[i] + c * h;
+                        row[i] = c * row[i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+}
+
+/// `z' s z` for a square `s` (by rows) and `z` given by columns.
+pub(crate) fn congruence(s: &[Vec<f64>], z: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let sz: Vec<Vec<f64>> = z.iter().map(|col| mat_vec(s, col)).collect();
+    z.iter()
+        .map(|a| sz.iter().map(|b| dot(a, b)).collect())
+        .collect()
+}
+
+/// Matrix (by rows) times vector.
+pub(crate) fn mat_vec(a: &[Vec<f64>], x: &[f64]) -> Vec<f64> {
+    a.iter().map(|row| dot(row, x)).collect()
+}
+
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Identity matrix of size `n`.
+pub(crate) fn identity(n: usize) -> Vec<Vec<f64>> {
+    (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect()
+}
+
+/// Kronecker product of two square matrices.
+pub(crate) fn kronecker(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let (na, nb) = (a.len(), b.len());
+    let mut out = vec![vec![0.0; na * nb]; na * nb];
+    for i in 0..na {
+        for j in 0..na {
+            if a[i][j] == 0.0 {
+                continue;
+            }
+            for k in 0..nb {
+                for l in 0..nb {
+                    out[i * nb + k][j * nb + l] = a[i][j] * b[k][l];
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symmetric_eigen_reconstructs_matrix() {
+        let a = vec![
+            vec![4.0, 1.0, -2.0, 2.0],
+            vec![1.0, 2.0, 0.0, 1.0],
+            vec![-2.0, 0.0, 3.0, -2.0],
+            vec![2.0, 1.0, -2.0, -1.0],
+        ];
+        let (values, vectors) = symmetric_eigen(&a);
+        assert!(values.windows(2).all(|w| w[0] >= w[1]));
+        for (lambda, v) in values.iter().zip(&vectors) {
+            let av = mat_vec(&a, v);
+            for (x, y) in av.iter().zip(v) {
+                assert!((x - lambda * y).abs() < 1e-10);
+            }
+            assert!((dot(v, v) - 1.0).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_constraint_null_space_is_orthogonal_to_constraint() {
+        let c = vec![vec![0.2, 0.5, 0.1, 0.7]];
+        let z = constraint_null_space(&c);
+        assert_eq!(z.len(), 3);
+        for col in &z {
+            assert!(dot(&c[0], col).abs() < 1e-12);
+        }
+    }
+}
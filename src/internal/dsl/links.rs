@@ -0,0 +1,550 @@
+//! Link functions of the response families.
+//!
+//! A link maps the mean `mu` of a family to the linear predictor `eta`; its
+//! inverse maps predictions back and `mu_eta` (the derivative of the inverse,
+//! dmu/deta) gives the IRLS weights. The links follow R's `make.link()`:
+//! inverses and derivatives of the bounded links are kept at least
+//! `f64::EPSILON` away from the bounds, so that the weights stay finite.
+//!
+//! [`ModelSpec::link_function`] resolves the link of a spec from
+//! `link=`, the family argument or the family default.
+
+use super::ast::{Family, Link, ModelSpec};
+use crate::Error;
+use polars::prelude::*;
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+const EPS: f64 = f64::EPSILON;
+
+/// A link function `eta = g(mu)` with its inverse and derivative.
+///
+/// The scalar methods define the link; the `_series` and (behind the `faer`
+/// feature) `_faer` methods apply them elementwise.
+pub trait LinkFunction: std::fmt::Debug + Send + Sync {
+    /// Name as written in `link=`, e.g. `logit`.
+    fn name(&self) -> &'static str;
+
+    /// The link `eta = g(mu)`.
+    fn link(&self, mu: f64) -> f64;
+
+    /// The inverse link `mu = g^-1(eta)`.
+    fn inverse(&self, eta: f64) -> f64;
+
+    /// The derivative of the inverse link, `dmu/deta` at `eta`.
+    fn mu_eta(&self, eta: f64) -> f64;
+
+    /// The link of each value; nulls stay null.
+    fn link_series(&self, mu: &Series) -> Result<Series, Error> {
+        map_series(mu, |v| self.link(v))
+    }
+
+    /// The inverse link of each value; nulls stay null.
+    fn inverse_series(&self, eta: &Series) -> Result<Series, Error> {
+        map_series(eta, |v| self.inverse(v))
+    }
+
+    /// The derivative of the inverse link at each value; nulls stay null.
+    fn mu_eta_series(&self, eta: &Series) -> Result<Series, Error> {
+        map_series(eta, |v| self.mu_eta(v))
+    }
+
+    /// The link of each value of a `faer` column.
+    #[cfg(feature = "faer")]
+    fn link_faer(&self, mu: faer::ColRef<'_, f64>) -> faer::Col<f64> {
+        faer::Col::from_fn(mu.nrows(), |i| self.link(mu[i]))
+    }
+
+    /// The inverse link of each value of a `faer` column.
+    #[cfg(feature = "faer")]
+    fn inverse_faer(&self, eta: faer::ColRef<'_, f64>) -> faer::Col<f64> {
+        faer::Col::from_fn(eta.nrows(), |i| self.inverse(eta[i]))
+    }
+
+    /// The derivative of the inverse link at each value of a `faer` column.
+    #[cfg(feature = "faer")]
+    fn mu_eta_faer(&self, eta: faer::ColRef<'_, f64>) -> faer::Col<f64> {
+        faer::Col::from_fn(eta.nrows(), |i| self.mu_eta(eta[i]))
+    }
+}
+
+fn map_series(series: &Series, f: impl Fn(f64) -> f64) -> Result<Series, Error> {
+    let values = series
+        .strict_cast(&DataType::Float64)
+        .map_err(|_| Error::Semantic(format!("'{}' is not numeric", series.name())))?;
+    let mapped: Float64Chunked = values
+        .f64()
+        .map_err(|e| Error::Semantic(e.to_string()))?
+        .into_iter()
+        .map(|v| v.map(&f))
+        .collect();
+    Ok(mapped.with_name(series.name().clone()).into_series())
+}
+
+/// `eta = mu`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Identity;
+
+/// `eta = log(mu)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Log;
+
+/// `eta = log(mu / (1 - mu))`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Logit;
+
+/// `eta = Phi^-1(mu)`, the standard normal quantile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Probit;
+
+/// brms' logistic approximation of the probit: the inverse is
+/// `logistic(0.07056 eta^3 + 1.5976 eta)`, the link the exact normal quantile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProbitApprox;
+
+/// `eta = log(-log(1 - mu))`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cloglog;
+
+/// `eta = tan(pi (mu - 1/2))`, the standard Cauchy quantile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cauchit;
+
+/// `eta = 1 / mu`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Inverse;
+
+/// `eta = sqrt(mu)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sqrt;
+
+/// `eta = 1 / mu^2`, the canonical link of `inverse.gaussian`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InverseSquared;
+
+/// `eta = log(exp(mu) - 1)`, whose inverse `log(1 + exp(eta))` is positive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Softplus;
+
+/// `eta = tan(mu / 2)`, the link of `von_mises` angles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TanHalf;
+
+impl LinkFunction for Identity {
+    fn name(&self) -> &'static str {
+        "identity"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        mu
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        eta
+    }
+
+    fn mu_eta(&self, _eta: f64) -> f64 {
+        1.0
+    }
+}
+
+impl LinkFunction for Log {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        mu.ln()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        eta.exp().max(EPS)
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        eta.exp().max(EPS)
+    }
+}
+
+impl LinkFunction for Logit {
+    fn name(&self) -> &'static str {
+        "logit"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        (mu / (1.0 - mu)).ln()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        if eta < -30.0 {
+            EPS
+        } else if eta > 30.0 {
+            1.0 - EPS
+        } else {
+            1.0 / (1.0 + (-eta).exp())
+        }
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        if eta.abs() > 30.0 {
+            EPS
+        } else {
+            let e = eta.exp();
+            e / ((1.0 + e) * (1.0 + e))
+        }
+    }
+}
+
+impl LinkFunction for Probit {
+    fn name(&self) -> &'static str {
+        "probit"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        norm_quantile(mu)
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        let thresh = -norm_quantile(EPS);
+        norm_cdf(eta.clamp(-thresh, thresh))
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        norm_pdf(eta).max(EPS)
+    }
+}
+
+impl LinkFunction for ProbitApprox {
+    fn name(&self) -> &'static str {
+        "probit_approx"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        norm_quantile(mu)
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        Logit.inverse(0.07056 * eta.powi(3) + 1.5976 * eta)
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        let x = 0.07056 * eta.powi(3) + 1.5976 * eta;
+        (Logit.mu_eta(x) * (3.0 * 0.07056 * eta * eta + 1.5976)).max(EPS)
+    }
+}
+
+impl LinkFunction for Cloglog {
+    fn name(&self) -> &'static str {
+        "cloglog"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        (-(-mu).ln_1p()).ln()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        (-(-eta.exp()).exp_m1()).clamp(EPS, 1.0 - EPS)
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        let eta = eta.min(700.0);
+        (eta.exp() * (-eta.exp()).exp()).max(EPS)
+    }
+}
+
+impl LinkFunction for Cauchit {
+    fn name(&self) -> &'static str {
+        "cauchit"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        (PI * (mu - 0.5)).tan()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        // The Cauchy quantile of EPS, -1 / tan(pi EPS).
+        let thresh = 1.0 / (PI * EPS).tan();
+        0.5 + eta.clamp(-thresh, thresh).atan() / PI
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        (1.0 / (PI * (1.0 + eta * eta))).max(EPS)
+    }
+}
+
+impl LinkFunction for Inverse {
+    fn name(&self) -> &'static str {
+        "inverse"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        1.0 / mu
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        1.0 / eta
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        -1.0 / (eta * eta)
+    }
+}
+
+impl LinkFunction for Sqrt {
+    fn name(&self) -> &'static str {
+        "sqrt"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        mu.sqrt()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        eta * eta
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        2.0 * eta
+    }
+}
+
+impl LinkFunction for InverseSquared {
+    fn name(&self) -> &'static str {
+        "1/mu^2"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        1.0 / (mu * mu)
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        1.0 / eta.sqrt()
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        -1.0 / (2.0 * eta.powf(1.5))
+    }
+}
+
+impl LinkFunction for Softplus {
+    fn name(&self) -> &'static str {
+        "softplus"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        // log(exp(mu) - 1), without overflow for large mu.
+        mu + (-(-mu).exp_m1()).ln()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        eta.max(0.0) + (-eta.abs()).exp().ln_1p()
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        1.0 / (1.0 + (-eta).exp())
+    }
+}
+
+impl LinkFunction for TanHalf {
+    fn name(&self) -> &'static str {
+        "tan_half"
+    }
+
+    fn link(&self, mu: f64) -> f64 {
+        (mu / 2.0).tan()
+    }
+
+    fn inverse(&self, eta: f64) -> f64 {
+        2.0 * eta.atan()
+    }
+
+    fn mu_eta(&self, eta: f64) -> f64 {
+        2.0 / (1.0 + eta * eta)
+    }
+}
+
+/// The link function of the given name, e.g. `logit` or `1/mu^2`, or `None`
+/// for a name that is not known.
+pub fn link_function(name: &str) -> Option<Box<dyn LinkFunction>> {
+    let link: Box<dyn LinkFunction> = match name {
+        "identity" => Box::new(Identity),
+        "log" => Box::new(Log),
+        "logit" => Box::new(Logit),
+        "probit" => Box::new(Probit),
+        "probit_approx" => Box::new(ProbitApprox),
+        "cloglog" => Box::new(Cloglog),
+        "cauchit" => Box::new(Cauchit),
+        "inverse" => Box::new(Inverse),
+        "sqrt" => Box::new(Sqrt),
+        "1/mu^2" => Box::new(InverseSquared),
+        "softplus" => Box::new(Softplus),
+        "tan_half" => Box::new(TanHalf),
+        _ => return None,
+    };
+    Some(link)
+}
+
+fn named_link(name: &str) -> Result<Box<dyn LinkFunction>, Error> {
+    link_function(name).ok_or_else(|| Error::Semantic(format!("Unknown link function '{}'", name)))
+}
+
+impl Family {
+    /// The link of the mean: the link argument of a builtin family, e.g.
+    /// `probit` for `binomial(link="probit")`, or else its default link.
+    ///
+    /// Custom families and mixtures have no link of their own; a mixture has
+    /// one per component.
+    pub fn link_function(&self) -> Result<Box<dyn LinkFunction>, Error> {
+        if let Some(name) = self.link_arg() {
+            return named_link(name);
+        }
+        match self.info() {
+            Some(info) => named_link(info.default_link()),
+            None => Err(Error::Semantic(format!(
+                "Family '{}' has no default link; give one with link=",
+                self.name()
+            ))),
+        }
+    }
+}
+
+impl ModelSpec {
+    /// The link of the mean: `link=` after the family, the link argument of
+    /// the family, or the family's default link. Without a family the model
+    /// is gaussian, with the identity link.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use polars_formula::canonicalize;
+    ///
+    /// let spec = canonicalize("y ~ x, family=binomial()").unwrap();
+    /// let link = spec.link_function().unwrap();
+    /// assert_eq!(link.name(), "logit");
+    /// assert!((link.inverse(0.0) - 0.5).abs() < 1e-12);
+    /// ```
+    pub fn link_function(&self) -> Result<Box<dyn LinkFunction>, Error> {
+        if let Some(Link::Named(name, _)) = &self.link {
+            return named_link(name);
+        }
+        match &self.family {
+            Some(family) => family.link_function(),
+            None => Ok(Box::new(Identity)),
+        }
+    }
+}
+
+/// The standard normal density.
+fn norm_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * PI).sqrt()
+}
+
+/// The standard normal distribution function, by Hart's algorithm 5666 as
+/// given by West (2005), accurate to double precision.
+fn norm_cdf(x: f64) -> f64 {
+    let z = x.abs();
+    let tail = if z > 37.0 {
+        0.0
+    } else if z < 10.0 * FRAC_1_SQRT_2 {
+        let e = (-0.5 * z * z).exp();
+        let num = [
+            3.526_249_659_989_11e-2,
+            0.700_383_064_443_688,
+            6.373_962_203_531_65,
+            33.912_866_078_383,
+            112.079_291_497_871,
+            221.213_596_169_931,
+            220.206_867_912_376,
+        ]
+        .iter()
+        .fold(0.0, |acc, c| acc * z + c);
+        let den = [
+            8.838_834_764_831_84e-2,
+            1.755_667_163_182_64,
+            16.064_177_579_207,
+            86.780_732_202_946_1,
+            296.564_248_779_674,
+            637.333_633_378_831,
+            793.826_512_519_948,
+            440.413_735_824_752,
+        ]
+        .iter()
+        .fold(0.0, |acc, c| acc * z + c);
+        e * num / den
+    } else {
+        let e = (-0.5 * z * z).exp();
+        let mut b = z + 0.65;
+        b = z + 4.0 / b;
+        b = z + 3.0 / b;
+        b = z + 2.0 / b;
+        b = z + 1.0 / b;
+        e / b / (2.0 * PI).sqrt()
+    };
+    if x > 0.0 {
+        1.0 - tail
+    } else {
+        tail
+    }
+}
+
+/// The standard normal quantile: Acklam's rational approximation refined by
+/// one Halley step.
+fn norm_quantile(p: f64) -> f64 {
+    if p.is_nan() || !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    if p == 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p == 1.0 {
+        return f64::INFINITY;
+    }
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    let poly = |coefs: &[f64], x: f64| coefs.iter().fold(0.0, |acc, c| acc * x + c);
+    let tail = |q: f64| poly(&C, q) / (poly(&D, q) * q + 1.0);
+    const P_LOW: f64 = 0.02425;
+    let x = if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - P_LOW {
+        -tail((-2.0 * (-p).ln_1p()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        poly(&A, r) * q / (poly(&B, r) * r + 1.0)
+    };
+    // Refine against the distribution function, from the nearer tail.
+    let e = if p < 0.5 {
+        norm_cdf(x) - p
+    } else {
+        (1.0 - p) - norm_cdf(-x)
+    };
+    let u = e * (2.0 * PI).sqrt() * (0.5 * x * x).exp();
+    x - u / (1.0 + x * u / 2.0)
+}
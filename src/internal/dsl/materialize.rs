@@ -1,6 +1,19 @@
 use super::ast::*;
+// The lazy prelude of polars has an `Expr` of its own
+use super::ast::Expr;
+use super::design::{DesignInfo, ResponseInfo, SmoothInfo, TransformState};
+use super::family::check_response as check_family_response;
+use super::family::encode_response;
+use super::pretty::pretty_expr;
+use super::random::{
+    build_multi_membership_block, build_random_block, group_by, RandomBlock, INTERCEPT,
+};
+use super::smooths::{Combine, Covariate, SmoothOptions, SmoothState};
+use super::sparse::SparseZ;
+use super::splines::SplineState;
 use crate::Error;
 use polars::prelude::*;
+use std::collections::{BTreeMap, HashMap};
 
 /// Materialize a DSL ModelSpec against a DataFrame to produce design matrices.
 ///
@@ -48,18 +61,166 @@ pub fn materialize(
     spec: &ModelSpec,
     opts: MaterializeOptions,
 ) -> Result<(DataFrame, DataFrame, DataFrame), Error> {
+    materialize_with_info(df, spec, opts, &mut DesignInfo::default())
+}
+
+/// Materialize a ModelSpec while recording learned transform state in `info`.
+///
+/// Transforms already present in `info` are reused rather than re-learned, so
+/// a `DesignInfo` filled in on training data reproduces the same columns on new
+/// data. Transforms missing from `info` are learned from `df` and added to it.
+pub fn materialize_with_info(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<(DataFrame, DataFrame, DataFrame), Error> {
+    if opts.mixed_model_smooths {
+        info.mixed_model_smooths = true;
+    }
+
     // Materialize the main formula
-    let (y, x, z) = materialize_formula(df, &spec.formula, opts)?;
+    let (check_response, encode) = (opts.check_response, opts.encode_response);
+    let (mut y, x, z) = materialize_formula(df, &spec.formula, opts, info)?;
+    if let Some(family) = &spec.family {
+        if check_response {
+            check_family_response(family, &spec.formula.lhs, &y)?;
+        }
+        if encode {
+            y = encode_response(family, y, &mut info.response_encoding)?;
+        }
+    }
 
-    // TODO: Handle distributional parameters (dpars)
-    // TODO: Handle autocorrelation terms (autocor)
-    // TODO: Handle family/link specifications
+    // Distributional parameters are materialized by `materialize_dpars_with_info`
+    // Autocorrelation terms are evaluated by `autocor::materialize_autocor`
 
     Ok((y, x, z))
 }
 
+/// Materialize the formula of each distributional parameter, e.g. `sigma ~ z`
+/// or `zi ~ x + (1|g)`, into its own X and Z on the rows of `df`, keyed by the
+/// parameter name.
+///
+/// Each X gets an intercept unless its formula removes it, and `offset()`
+/// terms are left out as in the main formula. Learned transform state is kept
+/// per parameter in `info.dpars`, and reused when already present there.
+pub fn materialize_dpars_with_info(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<BTreeMap<String, (DataFrame, DataFrame)>, Error> {
+    let mut designs = BTreeMap::new();
+    for dpar in &spec.dpars {
+        if designs.contains_key(&dpar.name) {
+            return Err(Error::Semantic(format!(
+                "Distributional parameter '{}' has more than one formula",
+                dpar.name
+            )));
+        }
+        let mut opts = opts.clone();
+        if has_intercept_removal(&dpar.rhs) {
+            opts.rhs_intercept = false;
+        }
+        let dpar_info = info.dpars.entry(dpar.name.clone()).or_default();
+        dpar_info.mixed_model_smooths |= opts.mixed_model_smooths;
+        let (x, z) = materialize_rhs_with_random(df, &dpar.rhs, opts, dpar_info)?;
+        designs.insert(dpar.name.clone(), (x, z));
+    }
+    Ok(designs)
+}
+
+/// Materialize a ModelSpec with the random effects design matrix in sparse form.
+///
+/// X and the response are built as in [`materialize_with_info`]. Group terms
+/// such as `(1|g)` go straight into a [`SparseZ`] without a dense intermediate;
+/// its columns, in the same order and with the same names as the dense Z, are
+/// followed by their grouping metadata in [`SparseZ::terms`].
+pub fn materialize_sparse_with_info(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<(DataFrame, DataFrame, SparseZ), Error> {
+    let mut terms = Vec::new();
+    collect_sum_terms(&spec.formula.rhs, &mut terms);
+
+    // Everything but the group terms is materialized densely
+    let mut fixed_spec = spec.clone();
+    fixed_spec.formula.rhs = Expr::Sum(
+        terms
+            .iter()
+            .filter(|term| !matches!(term, Expr::Group { .. }))
+            .map(|term| (*term).clone())
+            .collect(),
+    );
+    let (y, x, smooth_z) = materialize_with_info(df, &fixed_spec, opts.clone(), info)?;
+
+    // Assemble Z term by term, so columns keep the order of the dense Z
+    let mut z = SparseZ::new(df.height());
+    let mut names = Vec::new();
+    let mut mixed_smooths = Vec::new();
+    for term in terms {
+        match term {
+            Expr::Group {
+                inner,
+                spec,
+                kind,
+                id,
+            } => {
+                for block in random_blocks(df, inner, spec, info)? {
+                    let block_names = block.column_names();
+                    names.extend(block_names.iter().cloned());
+                    z.push_block(&block, block_names, kind.clone(), id.clone());
+                }
+            }
+            Expr::Smooth { .. } => {
+                // Penalized parts of smooths written in mixed model form
+                let label = pretty_expr(term);
+                for (i, smooth) in info.smooths.iter_mut().enumerate() {
+                    if smooth.label != label || smooth.random_columns.is_empty() {
+                        continue;
+                    }
+                    let start = z.ncols();
+                    for name in &smooth.random_columns {
+                        let values = series_to_f64(
+                            smooth_z
+                                .column(name)
+                                .map_err(|e| Error::Semantic(e.to_string()))?
+                                .as_materialized_series(),
+                            name,
+                        )?;
+                        let values: Vec<f64> =
+                            values.into_iter().map(|v| v.unwrap_or(0.0)).collect();
+                        names.push(name.clone());
+                        z.push_dense(name.clone(), &values);
+                    }
+                    smooth.random_column_range = start..z.ncols();
+                    mixed_smooths.push(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    z.column_names = final_column_names(names, &opts);
+    for i in mixed_smooths {
+        let smooth = &mut info.smooths[i];
+        smooth.random_columns = z.column_names[smooth.random_column_range.clone()].to_vec();
+    }
+
+    Ok((y, x, z))
+}
+
+/// Collect the terms of a (possibly nested) sum.
+pub(crate) fn collect_sum_terms<'a>(expr: &'a Expr, terms: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Sum(inner) => inner.iter().for_each(|t| collect_sum_terms(t, terms)),
+        expr => terms.push(expr),
+    }
+}
+
 /// Check if an expression contains a -1 term (intercept removal)
-fn has_intercept_removal(expr: &Expr) -> bool {
+pub(crate) fn has_intercept_removal(expr: &Expr) -> bool {
     match expr {
         Expr::Sum(terms) => terms.iter().any(|term| {
             matches!(term, Expr::Func { name, args } if name == "NEG" && args.len() == 1 && matches!(&args[0], Expr::Num(1.0)))
@@ -76,6 +237,7 @@ fn materialize_formula(
     df: &DataFrame,
     formula: &Formula,
     mut opts: MaterializeOptions,
+    info: &mut DesignInfo,
 ) -> Result<(DataFrame, DataFrame, DataFrame), Error> {
     // Check if formula has -1 term (intercept removal)
     if has_intercept_removal(&formula.rhs) {
@@ -83,27 +245,28 @@ fn materialize_formula(
     }
 
     // Materialize LHS (response)
-    let y = materialize_response(df, &formula.lhs)?;
+    let y = materialize_response(df, &formula.lhs, info)?;
 
     // Materialize RHS (predictors) - separate fixed and random effects
-    let (x, z) = materialize_rhs_with_random(df, &formula.rhs, opts)?;
+    let (x, z) = materialize_rhs_with_random(df, &formula.rhs, opts, info)?;
 
-    // TODO: Handle aterms (weights, se, etc.)
+    // Aterms (weights, se, etc.) are evaluated by `materialize_aterms`
 
     Ok((y, x, z))
 }
 
 /// Materialize a response expression.
-fn materialize_response(df: &DataFrame, response: &Response) -> Result<DataFrame, Error> {
+fn materialize_response(
+    df: &DataFrame,
+    response: &Response,
+    info: &mut DesignInfo,
+) -> Result<DataFrame, Error> {
     match response {
         Response::Var(name) => {
             let series = df
                 .column(name)
                 .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))
-                .and_then(|s| {
-                    s.as_series()
-                        .ok_or_else(|| Error::Semantic("Failed to convert column to series".into()))
-                })?;
+                .map(|s| s.as_materialized_series())?;
 
             // Convert Series to DataFrame
             Ok(series.clone().into_frame())
@@ -123,11 +286,7 @@ fn materialize_response(df: &DataFrame, response: &Response) -> Result<DataFrame
                             successes_name
                         ))
                     })
-                    .and_then(|s| {
-                        s.as_series().ok_or_else(|| {
-                            Error::Semantic("Failed to convert column to series".into())
-                        })
-                    })?;
+                    .map(|s| s.as_materialized_series())?;
 
                 // Convert Series to DataFrame
                 Ok(series.clone().into_frame())
@@ -137,43 +296,37 @@ fn materialize_response(df: &DataFrame, response: &Response) -> Result<DataFrame
                 ))
             }
         }
-        Response::Multi(names) => {
-            // For now, just take the first variable
-            // TODO: Implement proper multi-response handling
-            if let Some(name) = names.first() {
-                let series = df
-                    .column(name)
-                    .map_err(|_| {
-                        Error::Semantic(format!("Column '{}' not found in DataFrame", name))
-                    })
-                    .and_then(|s| {
-                        s.as_series().ok_or_else(|| {
-                            Error::Semantic("Failed to convert column to series".into())
-                        })
-                    })?;
-
-                // Convert Series to DataFrame
-                Ok(series.clone().into_frame())
-            } else {
-                Err(Error::Semantic("Empty multi-response specification".into()))
+        Response::Multi { name, responses } => {
+            let mut columns = Vec::with_capacity(responses.len());
+            let mut infos = Vec::with_capacity(responses.len());
+            for response in responses {
+                let label = pretty_expr(response);
+                let values = match response {
+                    Expr::Var(var) => {
+                        let column = df.column(var).map_err(|_| {
+                            Error::Semantic(format!("Column '{}' not found in DataFrame", var))
+                        })?;
+                        series_to_f64(column.as_materialized_series(), var)?
+                    }
+                    response => eval_numeric(df, response)?,
+                };
+                infos.push(ResponseInfo {
+                    name: label.clone(),
+                    bind: name.clone(),
+                    null_count: values.iter().filter(|v| v.is_none()).count(),
+                });
+                columns.push(Float64Chunked::new(label.as_str().into(), &values).into_column());
             }
+            info.responses = infos;
+            DataFrame::new(columns)
+                .map_err(|e| Error::Semantic(format!("Invalid {}() response: {}", name, e)))
         }
-        Response::Surv {
-            time,
-            event: _event,
-            time2: _,
-        } => {
-            // For survival analysis, we'll use the time variable as response for now
-            // TODO: Implement proper survival response handling
-            let series = materialize_expr(df, time)?;
-            // Convert Series to DataFrame
-            Ok(series.clone().into_frame())
-        }
+        Response::Surv { time, event, time2 } => materialize_surv(df, time, event, time2.as_ref()),
         Response::Func { name: _name, args } => {
             // For now, treat function responses as the first argument
             // TODO: Implement proper function response handling
             if let Some(first_arg) = args.first() {
-                let series = materialize_expr(df, first_arg)?;
+                let series = materialize_expr(df, first_arg, info)?;
                 // Convert Series to DataFrame
                 Ok(series.clone().into_frame())
             } else {
@@ -185,11 +338,428 @@ fn materialize_response(df: &DataFrame, response: &Response) -> Result<DataFrame
     }
 }
 
+/// Evaluate the auxiliary terms of a formula, e.g. `y | weights(1/v) + se(s)`,
+/// to one column each: `se`, `weights`, `trials`, `cens`, `trunc_lb`,
+/// `trunc_ub`, `rate`, `thres`, `dec`, `cat`, `index`, and `vreal_1`,
+/// `vreal_2`, ... or `vint_1`, ... for each argument of `vreal()` and `vint()`.
+/// The `trials` of `y | trials(n)` come from the response.
+///
+/// Values are validated as brms does: weights, standard errors and trials are
+/// non-negative, trials are integers no smaller than the successes, censoring
+/// is coded as `left`/`none`/`right`/`interval` (or -1/0/1/2), the response lies
+/// within the truncation bounds, and rates are positive. `subset()` and `mi()`
+/// produce no column; see [`subset_mask`] for the rows `subset()` selects.
+pub(crate) fn materialize_aterms(
+    df: &DataFrame,
+    formula: &Formula,
+    y: &DataFrame,
+) -> Result<DataFrame, Error> {
+    let response = match y.get_columns() {
+        [column] => Some(series_to_f64(column.as_materialized_series(), "response")?),
+        _ => None,
+    };
+    let mut columns: Vec<Column> = Vec::new();
+    let numeric = |name: &str, values: Vec<Option<f64>>| {
+        Float64Chunked::new(name.into(), &values).into_column()
+    };
+
+    let mut aterms: Vec<&Aterm> = formula.aterms.iter().collect();
+    let binomial_trials;
+    if let Response::BinomialTrials { trials, .. } = &formula.lhs {
+        binomial_trials = Aterm::Trials(trials.clone());
+        aterms.insert(0, &binomial_trials);
+    }
+
+    for aterm in aterms {
+        match aterm {
+            Aterm::Se(expr) => {
+                let se = eval_numeric(df, expr)?;
+                check_aterm(&se, "se", "must be non-negative", |v| v >= 0.0)?;
+                columns.push(numeric("se", se));
+            }
+            Aterm::Weights(expr) => {
+                let weights = eval_numeric(df, expr)?;
+                check_aterm(&weights, "weights", "must be non-negative", |v| v >= 0.0)?;
+                columns.push(numeric("weights", weights));
+            }
+            Aterm::Trials(expr) => {
+                let trials = eval_numeric(df, expr)?;
+                check_aterm(&trials, "trials", "must be non-negative integers", |v| {
+                    v >= 0.0 && v.fract() == 0.0
+                })?;
+                if let Some(successes) = &response {
+                    for (i, (s, n)) in successes.iter().zip(&trials).enumerate() {
+                        if let (Some(s), Some(n)) = (s, n) {
+                            if *s < 0.0 || s.fract() != 0.0 || s > n {
+                                return Err(Error::Semantic(format!(
+                                    "Successes must be integers between 0 and trials, found {} of {} in row {}",
+                                    s, n, i
+                                )));
+                            }
+                        }
+                    }
+                }
+                columns.push(numeric("trials", trials));
+            }
+            Aterm::Cens(expr) => columns.push(numeric("cens", censoring_codes(df, expr)?)),
+            Aterm::Trunc { lb, ub } => {
+                for (name, bound, inside) in [
+                    (
+                        "trunc_lb",
+                        lb,
+                        (|y: f64, b: f64| y >= b) as fn(f64, f64) -> bool,
+                    ),
+                    ("trunc_ub", ub, |y, b| y <= b),
+                ] {
+                    let Some(bound) = bound else { continue };
+                    let bound = eval_numeric(df, bound)?;
+                    if let Some(response) = &response {
+                        let outside = response.iter().zip(&bound).position(
+                            |(y, b)| matches!((y, b), (Some(y), Some(b)) if !inside(*y, *b)),
+                        );
+                        if let Some(i) = outside {
+                            return Err(Error::Semantic(format!(
+                                "Response in row {} lies outside the truncation bounds",
+                                i
+                            )));
+                        }
+                    }
+                    columns.push(numeric(name, bound));
+                }
+            }
+            Aterm::Rate(expr) => {
+                let rate = eval_numeric(df, expr)?;
+                check_aterm(&rate, "rate", "must be positive", |v| v > 0.0)?;
+                columns.push(numeric("rate", rate));
+            }
+            Aterm::Dec(expr) => {
+                let dec = eval_numeric(df, expr)?;
+                check_aterm(&dec, "dec", "must be 0 or 1", |v| v == 0.0 || v == 1.0)?;
+                columns.push(numeric("dec", dec));
+            }
+            Aterm::Cat(expr) => {
+                let cat = eval_numeric(df, expr)?;
+                check_aterm(&cat, "cat", "must be positive integers", |v| {
+                    v >= 1.0 && v.fract() == 0.0
+                })?;
+                columns.push(numeric("cat", cat));
+            }
+            Aterm::Thres { gr: Some(expr) } => columns.push(aterm_column(df, expr, "thres")?),
+            Aterm::Index(expr) => columns.push(aterm_column(df, expr, "index")?),
+            Aterm::VReal(exprs) => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    columns.push(numeric(
+                        &format!("vreal_{}", i + 1),
+                        eval_numeric(df, expr)?,
+                    ));
+                }
+            }
+            Aterm::VInt(exprs) => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    let values = eval_numeric(df, expr)?;
+                    check_aterm(&values, "vint", "must be integers", |v| v.fract() == 0.0)?;
+                    columns.push(numeric(&format!("vint_{}", i + 1), values));
+                }
+            }
+            Aterm::Thres { gr: None } | Aterm::Subset(_) | Aterm::Mi => {}
+        }
+    }
+    DataFrame::new(columns).map_err(|e| Error::Semantic(e.to_string()))
+}
+
+/// Fail unless every non-null value of an aterm satisfies `valid`.
+fn check_aterm(
+    values: &[Option<f64>],
+    aterm: &str,
+    requirement: &str,
+    valid: impl Fn(f64) -> bool,
+) -> Result<(), Error> {
+    match values.iter().flatten().find(|&&v| !valid(v)) {
+        Some(v) => Err(Error::Semantic(format!(
+            "Values of {}() {}, found {}",
+            aterm, requirement, v
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// An aterm argument kept in its own type, such as the grouping factor of
+/// `thres(gr = g)`; expressions are evaluated numerically.
+fn aterm_column(df: &DataFrame, expr: &Expr, name: &str) -> Result<Column, Error> {
+    match expr {
+        Expr::Var(var) => Ok(df
+            .column(var)
+            .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", var)))?
+            .clone()
+            .with_name(name.into())),
+        expr => Ok(Float64Chunked::new(name.into(), &eval_numeric(df, expr)?).into_column()),
+    }
+}
+
+/// Censoring of `cens()` as brms codes it: -1 left, 0 none, 1 right and 2
+/// interval censored. Logical values mark right censoring.
+fn censoring_codes(df: &DataFrame, expr: &Expr) -> Result<Vec<Option<f64>>, Error> {
+    if let Expr::Var(name) = expr {
+        let column = df
+            .column(name)
+            .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))?;
+        if let Ok(strings) = column.as_materialized_series().str() {
+            return strings
+                .into_iter()
+                .map(|s| match s {
+                    None => Ok(None),
+                    Some("left") => Ok(Some(-1.0)),
+                    Some("none") => Ok(Some(0.0)),
+                    Some("right") => Ok(Some(1.0)),
+                    Some("interval") => Ok(Some(2.0)),
+                    Some(other) => Err(Error::Semantic(format!(
+                        "Invalid censoring '{}', expected left, none, right or interval",
+                        other
+                    ))),
+                })
+                .collect();
+        }
+    }
+    let codes = eval_numeric(df, expr)?;
+    check_aterm(&codes, "cens", "must be -1, 0, 1 or 2", |v| {
+        [-1.0, 0.0, 1.0, 2.0].contains(&v)
+    })?;
+    Ok(codes)
+}
+
+/// The sum of the `offset()` terms of a right-hand side, e.g. `log(exposure)`
+/// for `y ~ x + offset(log(exposure))`, or `None` without offsets.
+pub(crate) fn materialize_offset(
+    df: &DataFrame,
+    rhs: &Expr,
+) -> Result<Option<Vec<Option<f64>>>, Error> {
+    let mut terms = Vec::new();
+    collect_sum_terms(rhs, &mut terms);
+    let mut offset: Option<Vec<Option<f64>>> = None;
+    for term in terms {
+        let Expr::Func { name, args } = term else {
+            continue;
+        };
+        if name != "offset" {
+            continue;
+        }
+        let [arg] = args.as_slice() else {
+            return Err(Error::Semantic(format!(
+                "offset() takes one argument, found '{}'",
+                pretty_expr(term)
+            )));
+        };
+        let values = eval_numeric(df, arg)?;
+        offset = Some(match offset {
+            None => values,
+            Some(total) => total
+                .into_iter()
+                .zip(values)
+                .map(|(a, b)| Some(a? + b?))
+                .collect(),
+        });
+    }
+    Ok(offset)
+}
+
+/// The rows selected by the condition of `subset()`, e.g. `subset(flag)` or
+/// `subset(age > 18 & sex == "m")`, compiled to a Polars expression.
+///
+/// A bare variable must be logical or coded 0/1. Rows where the condition is
+/// missing are not selected, as in R's `subset()`.
+pub(crate) fn subset_mask(df: &DataFrame, condition: &Expr) -> Result<BooleanChunked, Error> {
+    let mask = df
+        .clone()
+        .lazy()
+        .select([compile_condition(df, condition)?.alias("subset")])
+        .collect()
+        .map_err(|e| {
+            Error::Semantic(format!(
+                "Cannot evaluate subset({}): {}",
+                pretty_expr(condition),
+                e
+            ))
+        })?;
+    let mask = mask
+        .column("subset")
+        .map_err(|e| Error::Semantic(e.to_string()))?
+        .as_materialized_series()
+        .bool()
+        .map_err(|_| {
+            Error::Semantic(format!(
+                "subset({}) is not a logical condition",
+                pretty_expr(condition)
+            ))
+        })?
+        .clone();
+    // A literal condition such as subset(TRUE) has a single value
+    let mask = if mask.len() == df.height() {
+        mask
+    } else {
+        BooleanChunked::full("subset".into(), mask.get(0) == Some(true), df.height())
+    };
+    Ok(mask.fill_null_with_values(false).unwrap_or(mask))
+}
+
+/// Compile a logical condition of `subset()` to a Polars expression.
+fn compile_condition(df: &DataFrame, condition: &Expr) -> Result<polars::prelude::Expr, Error> {
+    match condition {
+        Expr::Func { name, args } => match (name.as_str(), args.as_slice()) {
+            ("!", [operand]) => Ok(compile_condition(df, operand)?.not()),
+            ("&", [lhs, rhs]) => Ok(compile_condition(df, lhs)?.and(compile_condition(df, rhs)?)),
+            ("|", [lhs, rhs]) => Ok(compile_condition(df, lhs)?.or(compile_condition(df, rhs)?)),
+            ("==" | "!=" | "<" | "<=" | ">" | ">=", [lhs, rhs]) => {
+                let (lhs, rhs) = (compile_operand(df, lhs)?, compile_operand(df, rhs)?);
+                Ok(match name.as_str() {
+                    "==" => lhs.eq(rhs),
+                    "!=" => lhs.neq(rhs),
+                    "<" => lhs.lt(rhs),
+                    "<=" => lhs.lt_eq(rhs),
+                    ">" => lhs.gt(rhs),
+                    _ => lhs.gt_eq(rhs),
+                })
+            }
+            _ => Err(Error::Semantic(format!(
+                "'{}' is not a logical condition",
+                pretty_expr(condition)
+            ))),
+        },
+        Expr::Var(var) => {
+            let column = df
+                .column(var)
+                .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", var)))?;
+            if column.dtype() == &DataType::Boolean {
+                return Ok(col(var.as_str()));
+            }
+            let values = series_to_f64(column.as_materialized_series(), var)?;
+            check_aterm(&values, "subset", "must be logical or 0/1", |v| {
+                v == 0.0 || v == 1.0
+            })?;
+            Ok(col(var.as_str()).neq(lit(0)))
+        }
+        Expr::Bool(b) | Expr::Intercept(b) => Ok(lit(*b)),
+        other => Err(Error::Semantic(format!(
+            "'{}' is not a logical condition",
+            pretty_expr(other)
+        ))),
+    }
+}
+
+/// A side of a comparison: a column or a literal as it is, anything else
+/// evaluated as an arithmetic expression.
+fn compile_operand(df: &DataFrame, operand: &Expr) -> Result<polars::prelude::Expr, Error> {
+    match operand {
+        Expr::Var(var) => {
+            if df.column(var).is_err() {
+                return Err(Error::Semantic(format!(
+                    "Column '{}' not found in DataFrame",
+                    var
+                )));
+            }
+            Ok(col(var.as_str()))
+        }
+        Expr::Num(v) => Ok(lit(*v)),
+        Expr::Str(s) => Ok(lit(s.as_str())),
+        Expr::Bool(b) => Ok(lit(*b)),
+        expr => {
+            let values = Float64Chunked::new("operand".into(), &eval_numeric(df, expr)?);
+            Ok(lit(values.into_series()))
+        }
+    }
+}
+
+/// Materialize a survival response as a frame with `time` and `status`, or
+/// `start`, `stop` and `status` for counting-process data.
+///
+/// `status` is 1 for an event and 0 for a censored observation. As in R's
+/// `Surv()`, the event may be logical, coded 0/1 or 1/2, or a factor with two
+/// levels, the first of which marks censoring.
+fn materialize_surv(
+    df: &DataFrame,
+    time: &Expr,
+    event: &Expr,
+    time2: Option<&Expr>,
+) -> Result<DataFrame, Error> {
+    let status = surv_status(df, event)?;
+    let mut columns = Vec::with_capacity(3);
+    match time2 {
+        None => {
+            let time = eval_numeric(df, time)?;
+            if time.iter().flatten().any(|&t| t < 0.0) {
+                return Err(Error::Semantic(
+                    "Survival times must be non-negative".into(),
+                ));
+            }
+            columns.push(Float64Chunked::new("time".into(), &time).into_column());
+        }
+        Some(stop) => {
+            let start = eval_numeric(df, time)?;
+            let stop = eval_numeric(df, stop)?;
+            for (i, (start, stop)) in start.iter().zip(&stop).enumerate() {
+                if let (Some(start), Some(stop)) = (start, stop) {
+                    if start >= stop {
+                        return Err(Error::Semantic(format!(
+                            "Start time must be before stop time, found {} and {} in row {}",
+                            start, stop, i
+                        )));
+                    }
+                }
+            }
+            columns.push(Float64Chunked::new("start".into(), &start).into_column());
+            columns.push(Float64Chunked::new("stop".into(), &stop).into_column());
+        }
+    }
+    columns.push(Float64Chunked::new("status".into(), &status).into_column());
+    DataFrame::new(columns).map_err(|e| Error::Semantic(e.to_string()))
+}
+
+/// Event indicator of `Surv()`: 1 for an event, 0 for censoring.
+fn surv_status(df: &DataFrame, event: &Expr) -> Result<Vec<Option<f64>>, Error> {
+    if let Expr::Var(name) = event {
+        let series = df
+            .column(name)
+            .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))?
+            .as_materialized_series();
+        if let Ok(strings) = series.str() {
+            // A two-level factor: the first level is censoring, the second the event
+            let mut levels: Vec<&str> = strings.into_iter().flatten().collect();
+            levels.sort();
+            levels.dedup();
+            if levels.len() > 2 {
+                return Err(Error::Semantic(format!(
+                    "Survival status '{}' must have at most two levels, found {}",
+                    name,
+                    levels.len()
+                )));
+            }
+            let event_level = levels.get(1).copied();
+            return Ok(strings
+                .into_iter()
+                .map(|s| s.map(|s| if Some(s) == event_level { 1.0 } else { 0.0 }))
+                .collect());
+        }
+    }
+
+    let values = eval_numeric(df, event)?;
+    let observed = || values.iter().flatten();
+    if observed().all(|&v| v == 0.0 || v == 1.0) {
+        Ok(values)
+    } else if observed().all(|&v| v == 1.0 || v == 2.0) {
+        Ok(values.into_iter().map(|v| v.map(|v| v - 1.0)).collect())
+    } else {
+        Err(Error::Semantic(format!(
+            "Survival status '{}' must be logical, 0/1, 1/2 or a two-level factor",
+            pretty_expr(event)
+        )))
+    }
+}
+
 /// Materialize the RHS expression into fixed and random effects design matrices.
 fn materialize_rhs_with_random(
     df: &DataFrame,
     rhs: &Expr,
     opts: MaterializeOptions,
+    info: &mut DesignInfo,
 ) -> Result<(DataFrame, DataFrame), Error> {
     let mut fixed_cols = Vec::new();
     let mut random_cols = Vec::new();
@@ -203,15 +773,19 @@ fn materialize_rhs_with_random(
     }
 
     // Materialize the RHS expression, separating fixed and random effects
-    let (fixed_rhs_cols, random_rhs_cols) = materialize_expr_to_columns_with_random(df, rhs)?;
+    let (fixed_rhs_cols, random_rhs_cols) = materialize_expr_to_columns_with_random(df, rhs, info)?;
     fixed_cols.extend(fixed_rhs_cols);
     random_cols.extend(random_rhs_cols);
 
     // Build fixed effects DataFrame
+    let raw_names: Vec<String> = fixed_cols.iter().map(|(name, _)| name.clone()).collect();
     let fixed_df = build_dataframe_from_cols(fixed_cols, &opts)?;
+    locate_smooth_columns(info, &raw_names, &fixed_df, false);
 
     // Build random effects DataFrame
+    let raw_names: Vec<String> = random_cols.iter().map(|(name, _)| name.clone()).collect();
     let random_df = build_dataframe_from_cols(random_cols, &opts)?;
+    locate_smooth_columns(info, &raw_names, &random_df, true);
 
     Ok((fixed_df, random_df))
 }
@@ -222,11 +796,40 @@ fn materialize_rhs(
     df: &DataFrame,
     rhs: &Expr,
     opts: MaterializeOptions,
+    info: &mut DesignInfo,
 ) -> Result<DataFrame, Error> {
-    let (fixed_df, _random_df) = materialize_rhs_with_random(df, rhs, opts)?;
+    let (fixed_df, _random_df) = materialize_rhs_with_random(df, rhs, opts, info)?;
     Ok(fixed_df)
 }
 
+/// Point the recorded smooth columns at their final (deduplicated, cleaned)
+/// names and record where they sit in the fixed (or random) design matrix.
+fn locate_smooth_columns(
+    info: &mut DesignInfo,
+    raw_names: &[String],
+    df: &DataFrame,
+    random: bool,
+) {
+    let final_names = df.get_column_names();
+    for smooth in info.smooths.iter_mut() {
+        let (columns, range) = if random {
+            (&mut smooth.random_columns, &mut smooth.random_column_range)
+        } else {
+            (&mut smooth.columns, &mut smooth.column_range)
+        };
+        let mut start = None;
+        for column in columns.iter_mut() {
+            if let Some(i) = raw_names.iter().position(|raw| raw == column) {
+                start.get_or_insert(i);
+                *column = final_names[i].to_string();
+            }
+        }
+        if let Some(start) = start {
+            *range = start..start + columns.len();
+        }
+    }
+}
+
 /// Build a DataFrame from a list of columns with proper naming.
 fn build_dataframe_from_cols(
     cols: Vec<(String, Series)>,
@@ -239,24 +842,8 @@ fn build_dataframe_from_cols(
 
     let (names, series): (Vec<_>, Vec<_>) = cols.into_iter().unzip();
     let mut unique_series = Vec::new();
-    let mut name_counts = std::collections::HashMap::new();
-
-    for (name, s) in names.into_iter().zip(series.into_iter()) {
-        let count = name_counts.entry(name.clone()).or_insert(0);
-        *count += 1;
-        let unique_name = if *count > 1 {
-            format!("{}_{}", name, *count - 1)
-        } else {
-            name
-        };
-
-        // Apply name cleaning if requested
-        let final_name = if opts.clean_names {
-            crate::make_clean_names(&unique_name)
-        } else {
-            unique_name
-        };
 
+    for (final_name, s) in final_column_names(names, opts).into_iter().zip(series) {
         let mut new_series = s.clone();
         new_series.rename(final_name.into());
         unique_series.push(new_series.into());
@@ -265,16 +852,39 @@ fn build_dataframe_from_cols(
     DataFrame::new(unique_series).map_err(|e| Error::Semantic(e.to_string()))
 }
 
+/// Deduplicate column names (`x`, `x_1`, ...) and clean them if requested.
+pub(crate) fn final_column_names(names: Vec<String>, opts: &MaterializeOptions) -> Vec<String> {
+    let mut name_counts = std::collections::HashMap::new();
+    names
+        .into_iter()
+        .map(|name| {
+            let count = name_counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            let unique_name = if *count > 1 {
+                format!("{}_{}", name, *count - 1)
+            } else {
+                name
+            };
+
+            // Apply name cleaning if requested
+            if opts.clean_names {
+                crate::make_clean_names(&unique_name)
+            } else {
+                unique_name
+            }
+        })
+        .collect()
+}
+
 /// Materialize an expression to a single Series.
-fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
+fn materialize_expr(df: &DataFrame, expr: &Expr, info: &mut DesignInfo) -> Result<Series, Error> {
     match expr {
         Expr::Var(name) => {
             // Check if this is a categorical variable
             let series = df
                 .column(name)
                 .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))?
-                .as_series()
-                .ok_or_else(|| Error::Semantic("Failed to convert column to series".into()))?
+                .as_materialized_series()
                 .clone();
 
             // Check if this is a categorical variable (string type)
@@ -302,7 +912,7 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
             // For sums, we'll materialize each term and add them
             // TODO: Implement proper sum materialization
             if let Some(first_term) = terms.first() {
-                materialize_expr(df, first_term)
+                materialize_expr(df, first_term, info)
             } else {
                 Err(Error::Semantic("Empty sum expression".into()))
             }
@@ -316,7 +926,7 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
             let mut result: Option<Series> = None;
 
             for term in terms {
-                let term_series = materialize_expr(df, term)?;
+                let term_series = materialize_expr(df, term, info)?;
 
                 if let Some(ref current) = result {
                     // Element-wise multiplication
@@ -336,17 +946,26 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
                 "poly" => {
                     // For poly() in materialize_expr, just return the first polynomial term
                     // The full expansion is handled in materialize_expr_to_columns_with_random
-                    let poly_cols = materialize_poly_to_columns(df, args)?;
+                    let poly_cols = materialize_poly_to_columns(df, expr, args, info)?;
                     if let Some((_, first_series)) = poly_cols.first() {
                         Ok(first_series.clone())
                     } else {
                         Err(Error::Semantic("poly() returned no columns".into()))
                     }
                 }
+                "bs" | "ns" | "cr" | "cc" => {
+                    // Like poly(), a spline used as a single series yields its first basis column
+                    let spline_cols = materialize_spline_to_columns(df, expr, name, args, info)?;
+                    if let Some((_, first_series)) = spline_cols.first() {
+                        Ok(first_series.clone())
+                    } else {
+                        Err(Error::Semantic(format!("{}() returned no columns", name)))
+                    }
+                }
                 "NEG" => {
                     // Negation function - negate the inner expression
                     if let Some(inner) = args.first() {
-                        let inner_series = materialize_expr(df, inner)?;
+                        let inner_series = materialize_expr(df, inner, info)?;
                         // For now, just return the inner series
                         // TODO: Implement proper negation
                         Ok(inner_series)
@@ -355,20 +974,35 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
                     }
                 }
                 "I" => {
-                    // Identity function - materialize the inner expression
+                    // Identity function - evaluate the inner arithmetic, e.g. I(x^2)
                     if let Some(inner) = args.first() {
-                        materialize_expr(df, inner)
+                        match eval_numeric(df, inner) {
+                            Ok(values) => Ok(Float64Chunked::from_iter_options(
+                                name.into(),
+                                values.into_iter(),
+                            )
+                            .into_series()),
+                            Err(_) => materialize_expr(df, inner, info),
+                        }
                     } else {
                         Err(Error::Semantic(
                             "Identity function with no arguments".into(),
                         ))
                     }
                 }
+                name if math_function(name).is_some() => {
+                    // Elementwise math, e.g. log(x) or sqrt(x + 1)
+                    let values = eval_numeric(df, expr)?;
+                    Ok(
+                        Float64Chunked::from_iter_options(name.into(), values.into_iter())
+                            .into_series(),
+                    )
+                }
                 _ => {
                     // For unknown functions, try to materialize the first argument
                     // TODO: Implement proper function handling
                     if let Some(first_arg) = args.first() {
-                        materialize_expr(df, first_arg)
+                        materialize_expr(df, first_arg, info)
                     } else {
                         Err(Error::Semantic(format!(
                             "Unknown function '{}' with no arguments",
@@ -378,27 +1012,13 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
                 }
             }
         }
-        Expr::Smooth {
-            kind: _kind,
-            vars,
-            args: _,
-        } => {
-            // For smooths, we'll use the first variable for now
-            // TODO: Implement proper smooth materialization
-            if let Some(first_var) = vars.first() {
-                df.column(first_var)
-                    .map_err(|_| {
-                        Error::Semantic(format!("Column '{}' not found in DataFrame", first_var))
-                    })
-                    .and_then(|s| {
-                        s.as_series().ok_or_else(|| {
-                            Error::Semantic("Failed to convert column to series".into())
-                        })
-                    })
-                    .map(|s| s.clone())
-            } else {
-                Err(Error::Semantic("Smooth with no variables".into()))
-            }
+        Expr::Smooth { kind, vars, args } => {
+            // A smooth used as a single value contributes its first basis column
+            let (cols, _) = materialize_smooth_to_columns(df, expr, kind, vars, args, info)?;
+            cols.into_iter()
+                .next()
+                .map(|(_, series)| series)
+                .ok_or_else(|| Error::Semantic("Smooth produced no columns".into()))
         }
         Expr::Group {
             inner,
@@ -408,7 +1028,7 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
         } => {
             // For groups, materialize the inner expression
             // TODO: Implement proper group materialization
-            materialize_expr(df, inner)
+            materialize_expr(df, inner, info)
         }
         _ => {
             // For other expressions, return an error for now
@@ -425,13 +1045,15 @@ fn materialize_expr(df: &DataFrame, expr: &Expr) -> Result<Series, Error> {
 fn materialize_expr_to_columns_with_random(
     df: &DataFrame,
     expr: &Expr,
+    info: &mut DesignInfo,
 ) -> Result<(Vec<(String, Series)>, Vec<(String, Series)>), Error> {
     match expr {
         Expr::Sum(terms) => {
             let mut fixed_cols = Vec::new();
             let mut random_cols = Vec::new();
             for term in terms {
-                let (term_fixed, term_random) = materialize_expr_to_columns_with_random(df, term)?;
+                let (term_fixed, term_random) =
+                    materialize_expr_to_columns_with_random(df, term, info)?;
                 fixed_cols.extend(term_fixed);
                 random_cols.extend(term_random);
             }
@@ -444,7 +1066,7 @@ fn materialize_expr_to_columns_with_random(
             id,
         } => {
             // Handle random effects
-            let random_cols = materialize_group_to_columns(df, inner, spec, kind, id)?;
+            let random_cols = materialize_group_to_columns(df, inner, spec, kind, id, info)?;
             Ok((Vec::new(), random_cols))
         }
         Expr::Interaction(terms) => {
@@ -456,52 +1078,26 @@ fn materialize_expr_to_columns_with_random(
             // Materialize each term to get columns (may be multiple for categorical)
             let mut term_columns = Vec::new();
             for term in terms {
-                let (fixed_cols, _) = materialize_expr_to_columns_with_random(df, term)?;
+                let (fixed_cols, _) = materialize_expr_to_columns_with_random(df, term, info)?;
                 term_columns.push(fixed_cols);
             }
 
-            // Create interactions between all combinations
-            let mut interaction_cols = Vec::new();
-
-            if term_columns.len() == 2 {
-                // Binary interaction - most common case
-                let (cols1, cols2) = (&term_columns[0], &term_columns[1]);
-
-                for (name1, series1) in cols1 {
-                    for (name2, series2) in cols2 {
+            // Create interactions between all combinations of the columns of
+            // the terms, the first term varying slowest
+            let mut term_columns = term_columns.into_iter();
+            let mut interaction_cols = term_columns.next().unwrap_or_default();
+            for cols in term_columns {
+                let mut next = Vec::with_capacity(interaction_cols.len() * cols.len());
+                for (name1, series1) in &interaction_cols {
+                    for (name2, series2) in &cols {
                         let interaction_name = format!("{}_x_{}", name1, name2);
                         let interaction_series = (series1 * series2).map_err(|e| {
                             Error::Semantic(format!("Failed to multiply interaction terms: {}", e))
                         })?;
-                        interaction_cols.push((interaction_name, interaction_series));
-                    }
-                }
-            } else {
-                // Multi-way interaction - for now, just multiply the first columns
-                let mut result_series = None;
-                let mut interaction_name = String::new();
-
-                for (_i, cols) in term_columns.iter().enumerate() {
-                    if let Some((name, series)) = cols.first() {
-                        if result_series.is_none() {
-                            result_series = Some(series.clone());
-                            interaction_name = name.clone();
-                        } else {
-                            let current = result_series.as_mut().unwrap();
-                            *current = (&*current * series).map_err(|e| {
-                                Error::Semantic(format!(
-                                    "Failed to multiply interaction terms: {}",
-                                    e
-                                ))
-                            })?;
-                            interaction_name = format!("{}_x_{}", interaction_name, name);
-                        }
+                        next.push((interaction_name, interaction_series));
                     }
                 }
-
-                if let Some(series) = result_series {
-                    interaction_cols.push((interaction_name, series));
-                }
+                interaction_cols = next;
             }
 
             Ok((interaction_cols, Vec::new()))
@@ -512,7 +1108,8 @@ fn materialize_expr_to_columns_with_random(
             let mut fixed_cols = Vec::new();
             let mut random_cols = Vec::new();
             for term in terms {
-                let (term_fixed, term_random) = materialize_expr_to_columns_with_random(df, term)?;
+                let (term_fixed, term_random) =
+                    materialize_expr_to_columns_with_random(df, term, info)?;
                 fixed_cols.extend(term_fixed);
                 random_cols.extend(term_random);
             }
@@ -527,7 +1124,7 @@ fn materialize_expr_to_columns_with_random(
                 } else {
                     // This is -something_else, materialize the inner expression
                     let (fixed_cols, random_cols) =
-                        materialize_expr_to_columns_with_random(df, &args[0])?;
+                        materialize_expr_to_columns_with_random(df, &args[0], info)?;
                     Ok((fixed_cols, random_cols))
                 }
             } else {
@@ -536,24 +1133,38 @@ fn materialize_expr_to_columns_with_random(
                 ))
             }
         }
+        Expr::Func { name, .. } if name == "offset" => {
+            // Offsets have a fixed coefficient of 1, so they are not columns of
+            // X; see `materialize_offset`
+            Ok((Vec::new(), Vec::new()))
+        }
         Expr::Func { name, args } if name == "poly" => {
             // Handle polynomial expansion - return multiple columns
-            let poly_cols = materialize_poly_to_columns(df, args)?;
+            let poly_cols = materialize_poly_to_columns(df, expr, args, info)?;
             Ok((poly_cols, Vec::new()))
         }
+        Expr::Func { name, args } if is_spline_function(name) => {
+            // Handle spline bases - return one column per basis function
+            let spline_cols = materialize_spline_to_columns(df, expr, name, args, info)?;
+            Ok((spline_cols, Vec::new()))
+        }
+        Expr::Smooth { kind, vars, args } => {
+            // Handle smooths - one column per (constrained) basis function, or the
+            // penalized part as random effects in mixed model form
+            materialize_smooth_to_columns(df, expr, kind, vars, args, info)
+        }
         Expr::Var(name) => {
             // Handle categorical variables
             let series = df
                 .column(name)
                 .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))?
-                .as_series()
-                .ok_or_else(|| Error::Semantic("Failed to convert column to series".into()))?
+                .as_materialized_series()
                 .clone();
 
             // Check if this is a categorical variable (string type)
             if let Ok(str_series) = series.str() {
                 // This is a categorical variable - create contrast columns
-                let contrast_cols = create_categorical_contrasts(&str_series, name)?;
+                let contrast_cols = create_categorical_contrasts(str_series, name, info)?;
                 Ok((contrast_cols, Vec::new()))
             } else {
                 // This is a numeric variable - return as single column
@@ -562,7 +1173,7 @@ fn materialize_expr_to_columns_with_random(
         }
         _ => {
             // For single expressions, materialize to one column (fixed effect)
-            let series = materialize_expr(df, expr)?;
+            let series = materialize_expr(df, expr, info)?;
             let name = match expr {
                 Expr::Var(name) => name.clone(),
                 Expr::Num(n) => format!("constant_{}", n),
@@ -588,34 +1199,103 @@ fn materialize_expr_to_columns_with_random(
     }
 }
 
-/// Materialize an expression to multiple columns (for backward compatibility).
-#[allow(dead_code)]
+/// Materialize an expression to its fixed and random effects columns together,
+/// as the inner expression of a group term.
 fn materialize_expr_to_columns(
     df: &DataFrame,
     expr: &Expr,
+    info: &mut DesignInfo,
 ) -> Result<Vec<(String, Series)>, Error> {
-    let (fixed_cols, random_cols) = materialize_expr_to_columns_with_random(df, expr)?;
+    let (fixed_cols, random_cols) = materialize_expr_to_columns_with_random(df, expr, info)?;
     let mut all_cols = fixed_cols;
     all_cols.extend(random_cols);
     Ok(all_cols)
 }
 
 /// Materialize a polynomial function to multiple columns.
+///
+/// Orthogonal polynomials are evaluated with the coefficients recorded in
+/// `info` for the term, learned from the non-null values the first time it is
+/// seen, so new data gets the polynomials of the training data.
 fn materialize_poly_to_columns(
     df: &DataFrame,
+    expr: &Expr,
     args: &[Expr],
+    info: &mut DesignInfo,
 ) -> Result<Vec<(String, Series)>, Error> {
-    if args.len() != 2 {
+    let (var_name, degree, raw) = poly_args(args)?;
+
+    // Get the variable column
+    let var_series = df
+        .column(var_name)
+        .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", var_name)))?
+        .as_materialized_series();
+    let values = series_to_f64(
+        var_series,
+        &format!("Column '{}' for polynomial expansion", var_name),
+    )?;
+
+    if raw {
+        // Raw polynomials: [x, x², x³, ...]
+        return Ok((1..=degree)
+            .map(|d| {
+                let col_name = if d == 1 {
+                    var_name.to_string()
+                } else {
+                    format!("{}_{}", var_name, d)
+                };
+                let col: Float64Chunked =
+                    values.iter().map(|v| v.map(|v| v.powi(d as i32))).collect();
+                (
+                    col_name.clone(),
+                    col.with_name(col_name.into()).into_series(),
+                )
+            })
+            .collect());
+    }
+
+    // Orthogonal polynomials with stored coefficients
+    let key = pretty_expr(expr);
+    let state = match info.transforms.get(&key) {
+        Some(TransformState::Poly(state)) => state.clone(),
+        _ => {
+            let observed: Vec<f64> = values.iter().flatten().copied().collect();
+            let state = PolyState::learn(&observed, degree)?;
+            info.transforms
+                .insert(key, TransformState::Poly(state.clone()));
+            state
+        }
+    };
+    let rows: Vec<Option<Vec<f64>>> = values.iter().map(|v| v.map(|v| state.basis(v))).collect();
+    Ok((0..state.degree())
+        .map(|i| {
+            let col_name = format!("poly_{}_{}", var_name, i + 1);
+            let col: Float64Chunked = rows
+                .iter()
+                .map(|row| row.as_ref().map(|row| row[i]))
+                .collect();
+            (
+                col_name.clone(),
+                col.with_name(col_name.into()).into_series(),
+            )
+        })
+        .collect())
+}
+
+/// The variable, degree and `raw` flag of `poly(x, degree)`.
+///
+/// Arguments are matched as in R's `poly(x, degree, raw=FALSE)`, by name or
+/// by position.
+pub(crate) fn poly_args(args: &[Expr]) -> Result<(&str, usize, bool), Error> {
+    let (positional, named) = split_call_args(args);
+    if positional.len() + named.len() > 3 || positional.is_empty() {
         return Err(Error::Semantic(
-            "poly() requires exactly 2 arguments".into(),
+            "poly() takes a variable, a degree and optionally raw=TRUE".into(),
         ));
     }
 
-    let var_expr = &args[0];
-    let degree_expr = &args[1];
-
     // Get the variable name
-    let var_name = match var_expr {
+    let var_name = match positional[0] {
         Expr::Var(name) => name,
         _ => {
             return Err(Error::Semantic(
@@ -625,8 +1305,8 @@ fn materialize_poly_to_columns(
     };
 
     // Get the degree
-    let degree = match degree_expr {
-        Expr::Num(n) => *n as usize,
+    let degree = match call_arg(&positional, &named, 1, "degree") {
+        Some(Expr::Num(n)) => *n as usize,
         _ => {
             return Err(Error::Semantic(
                 "Second argument to poly() must be a number".into(),
@@ -635,196 +1315,693 @@ fn materialize_poly_to_columns(
     };
 
     // Extract optional arguments
-    let raw = args
-        .get(2)
-        .and_then(|arg| {
-            if let Expr::Bool(b) = arg {
-                Some(*b)
-            } else {
-                None
-            }
-        })
+    let raw = call_arg(&positional, &named, 2, "raw")
+        .map(|arg| const_bool(arg, "poly", "raw"))
+        .transpose()?
         .unwrap_or(false);
+    Ok((var_name, degree, raw))
+}
 
-    let _normalize = args
-        .get(3)
-        .and_then(|arg| {
-            if let Expr::Bool(b) = arg {
-                Some(*b)
-            } else {
-                None
-            }
-        })
-        .unwrap_or(true);
+/// Coefficients of the orthogonal polynomials of `poly(x, degree)`, which R's
+/// `poly()` keeps in its `coefs` attribute for `predict()`.
+///
+/// The polynomials follow the three-term recurrence
+/// `P[k+1](x) = (x - alpha[k]) P[k](x) - norm2[k+1] / norm2[k] P[k-1](x)`
+/// from `P[0] = 1`, and column `k` of the basis is `P[k] / sqrt(norm2[k+1])`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolyState {
+    /// Centre of each step of the recurrence; `alpha[0]` is the mean of x.
+    pub alpha: Vec<f64>,
+    /// Squared norms of `P[-1]` (taken as 1) and of `P[0]`, ..., `P[degree]`
+    /// on the training data.
+    pub norm2: Vec<f64>,
+}
 
-    // Get the variable column
-    let var_series = df
-        .column(var_name)
-        .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", var_name)))?
-        .as_series()
-        .ok_or_else(|| Error::Semantic("Failed to convert column to series".into()))?
-        .clone();
+impl PolyState {
+    /// Learn the coefficients from the observed values of x.
+    pub(crate) fn learn(x: &[f64], degree: usize) -> Result<Self, Error> {
+        let mut unique = x.to_vec();
+        unique.sort_by(f64::total_cmp);
+        unique.dedup();
+        Self::check_degree(degree, unique.len())?;
+
+        let mut alpha = Vec::with_capacity(degree);
+        let mut norm2 = vec![1.0, x.len() as f64];
+        let mut previous = vec![0.0; x.len()];
+        let mut current = vec![1.0; x.len()];
+        for k in 0..degree {
+            let weight: f64 = current.iter().map(|p| p * p).sum();
+            let a = x.iter().zip(&current).map(|(x, p)| x * p * p).sum::<f64>() / weight;
+            let ratio = norm2[k + 1] / norm2[k];
+            let next: Vec<f64> = x
+                .iter()
+                .zip(current.iter().zip(&previous))
+                .map(|(x, (p, q))| (x - a) * p - ratio * q)
+                .collect();
+            alpha.push(a);
+            norm2.push(next.iter().map(|p| p * p).sum());
+            previous = std::mem::replace(&mut current, next);
+        }
+        Ok(Self { alpha, norm2 })
+    }
 
-    // Convert to f64 for polynomial operations
-    let f64_series = var_series.f64().map_err(|_| {
-        Error::Semantic(format!(
-            "Column '{}' cannot be converted to numeric for polynomial expansion",
-            var_name
-        ))
-    })?;
+    /// R's constraint: fewer degrees than unique points.
+    pub(crate) fn check_degree(degree: usize, unique: usize) -> Result<(), Error> {
+        if degree > 0 && degree >= unique {
+            return Err(Error::Semantic(format!(
+                "'degree' must be less than number of unique points. Got degree={}, unique points={}",
+                degree, unique
+            )));
+        }
+        Ok(())
+    }
 
-    if raw {
-        // Raw polynomials: [x, x², x³, ...]
-        let mut poly_cols = Vec::new();
+    /// The degree of the polynomials.
+    pub fn degree(&self) -> usize {
+        self.alpha.len()
+    }
 
-        for d in 1..=degree {
-            let col_name = if d == 1 {
-                var_name.clone()
-            } else {
-                format!("{}_{}", var_name, d)
-            };
+    /// The orthogonal polynomials of degree 1 to `degree` at `x`.
+    pub fn basis(&self, x: f64) -> Vec<f64> {
+        let (mut previous, mut current) = (0.0, 1.0);
+        (0..self.degree())
+            .map(|k| {
+                let next =
+                    (x - self.alpha[k]) * current - self.norm2[k + 1] / self.norm2[k] * previous;
+                previous = current;
+                current = next;
+                next / self.norm2[k + 2].sqrt()
+            })
+            .collect()
+    }
+}
 
-            // Compute the actual polynomial term using Polars power operations
-            let poly_series = if d == 1 {
-                f64_series.clone().into_series()
-            } else {
-                // Use Polars power operation: apply_values with pow
-                let power_series = f64_series.apply_values(|x| x.powi(d as i32));
-                power_series.into_series()
-            };
+/// Check if a function name is a spline basis function
+pub(crate) fn is_spline_function(name: &str) -> bool {
+    matches!(name, "bs" | "ns" | "cr" | "cc")
+}
 
-            poly_cols.push((col_name, poly_series));
+/// Split function call arguments into positional and named (`name=value`) arguments.
+pub(crate) fn split_call_args(args: &[Expr]) -> (Vec<&Expr>, HashMap<&str, &Expr>) {
+    let mut positional = Vec::new();
+    let mut named = HashMap::new();
+    for arg in args {
+        match arg {
+            Expr::Named { name, value } => {
+                named.insert(name.as_str(), value.as_ref());
+            }
+            other => positional.push(other),
         }
+    }
+    (positional, named)
+}
 
-        Ok(poly_cols)
-    } else {
-        // Orthogonal polynomials (numerically stable)
-        // For degree > 1, return multiple columns
-        let orthogonal_polys = compute_orthogonal_polynomials(&f64_series, degree)?;
-        let mut poly_cols = Vec::new();
-        for (i, poly) in orthogonal_polys.into_iter().enumerate() {
-            let col_name = format!("poly_{}_{}", var_name, i + 1);
-            poly_cols.push((col_name, poly.into_series()));
-        }
-        Ok(poly_cols)
+/// Look up a call argument by name, falling back to its position as in R's argument matching.
+fn call_arg<'a>(
+    positional: &[&'a Expr],
+    named: &HashMap<&str, &'a Expr>,
+    position: usize,
+    name: &str,
+) -> Option<&'a Expr> {
+    named
+        .get(name)
+        .copied()
+        .or_else(|| positional.get(position).copied())
+}
+
+/// Evaluate a constant numeric argument such as `5` or `c(1, 2.5, 4)`.
+fn const_numbers(expr: &Expr) -> Option<Vec<f64>> {
+    match expr {
+        Expr::Num(n) => Some(vec![*n]),
+        Expr::Intercept(true) => Some(vec![1.0]),
+        Expr::Intercept(false) => Some(vec![0.0]),
+        Expr::Func { name, args } if name == "c" => args
+            .iter()
+            .map(const_numbers)
+            .collect::<Option<Vec<_>>>()
+            .map(|v| v.concat()),
+        _ => None,
     }
 }
 
-/// Compute orthogonal polynomials using QR decomposition like R's poly() function
-fn compute_orthogonal_polynomials(
-    series: &Float64Chunked,
-    degree: usize,
-) -> Result<Vec<Float64Chunked>, Error> {
-    if degree == 0 {
-        return Ok(Vec::new());
+/// Evaluate a constant scalar argument, reporting which function and argument were invalid.
+fn const_number(expr: &Expr, func: &str, arg: &str) -> Result<f64, Error> {
+    match const_numbers(expr).as_deref() {
+        Some([n]) => Ok(*n),
+        _ => Err(Error::Semantic(format!(
+            "Argument '{}' to {}() must be a number",
+            arg, func
+        ))),
     }
+}
 
-    let n = series.len();
-    if n == 0 {
-        return Ok(Vec::new());
+/// Evaluate a constant logical argument (`TRUE`/`FALSE`, or 1/0).
+fn const_bool(expr: &Expr, func: &str, arg: &str) -> Result<bool, Error> {
+    match expr {
+        Expr::Bool(b) => Ok(*b),
+        other => const_number(other, func, arg)
+            .map(|n| n != 0.0)
+            .map_err(|_| {
+                Error::Semantic(format!(
+                    "Argument '{}' to {}() must be TRUE or FALSE",
+                    arg, func
+                ))
+            }),
     }
+}
 
-    // Check that degree is less than number of unique points (R's constraint)
-    let unique_count = series
-        .unique()
-        .map_err(|e| Error::Semantic(format!("Failed to get unique values: {}", e)))?
-        .len();
+/// Convert a numeric series to `f64` values, keeping nulls.
+pub(crate) fn series_to_f64(series: &Series, what: &str) -> Result<Vec<Option<f64>>, Error> {
+    let cast = series
+        .cast(&DataType::Float64)
+        .map_err(|_| Error::Semantic(format!("{} cannot be converted to numeric", what)))?;
+    let values = cast
+        .f64()
+        .map_err(|e| Error::Semantic(e.to_string()))?
+        .into_iter()
+        .collect();
+    Ok(values)
+}
 
-    if degree >= unique_count {
-        return Err(Error::Semantic(format!(
-            "'degree' must be less than number of unique points. Got degree={}, unique points={}",
-            degree, unique_count
-        )));
-    }
+/// The elementwise math function of a call such as `log(x)`, if it is one.
+pub(crate) fn math_function(name: &str) -> Option<fn(f64) -> f64> {
+    let f: fn(f64) -> f64 = match name {
+        "log" => f64::ln,
+        "log2" => f64::log2,
+        "log10" => f64::log10,
+        "log1p" => f64::ln_1p,
+        "exp" => f64::exp,
+        "sqrt" => f64::sqrt,
+        "abs" => f64::abs,
+        _ => return None,
+    };
+    Some(f)
+}
 
-    // Step 1: Center the data (subtract mean)
-    let mean = series.mean().unwrap_or(0.0);
-    let centered = series - mean;
-
-    if degree == 1 {
-        // For degree 1, return the centered and normalized series
-        // R's poly() normalizes by sqrt(norm2) where norm2 is from QR decomposition
-        // For degree 1, norm2 is the sum of squares of the centered values
-        let norm2 = (&centered * &centered).sum().unwrap_or(0.0);
-        let scale_factor = norm2.sqrt(); // Use sqrt(norm2) to match R's scaling
-        if scale_factor > 0.0 {
-            return Ok(vec![(&centered / scale_factor).into()]);
-        } else {
-            return Ok(vec![centered.into()]);
+/// Evaluate an arithmetic expression row by row, e.g. `size - incidence` or
+/// `log(n + 1)`. Nulls propagate.
+fn eval_numeric(df: &DataFrame, expr: &Expr) -> Result<Vec<Option<f64>>, Error> {
+    let n = df.height();
+    let combine = |a: Vec<Option<f64>>, b: Vec<Option<f64>>, op: fn(f64, f64) -> f64| {
+        a.into_iter()
+            .zip(b)
+            .map(|(a, b)| Some(op(a?, b?)))
+            .collect::<Vec<_>>()
+    };
+    match expr {
+        Expr::Num(v) => Ok(vec![Some(*v); n]),
+        Expr::Intercept(b) => Ok(vec![Some(if *b { 1.0 } else { 0.0 }); n]),
+        Expr::Var(name) => {
+            let column = df.column(name).map_err(|_| {
+                Error::Semantic(format!("Column '{}' not found in DataFrame", name))
+            })?;
+            series_to_f64(column.as_materialized_series(), name)
+        }
+        Expr::Sum(terms) => {
+            let mut total = vec![Some(0.0); n];
+            for term in terms {
+                total = match term {
+                    Expr::Func { name, args } if name == "NEG" && args.len() == 1 => {
+                        combine(total, eval_numeric(df, &args[0])?, |a, b| a - b)
+                    }
+                    term => combine(total, eval_numeric(df, term)?, |a, b| a + b),
+                };
+            }
+            Ok(total)
+        }
+        Expr::Prod(terms) => {
+            let mut total = vec![Some(1.0); n];
+            for term in terms {
+                total = combine(total, eval_numeric(df, term)?, |a, b| a * b);
+            }
+            Ok(total)
         }
+        Expr::Nest {
+            outer,
+            inner,
+            kind: NestKind::Slash,
+        } => Ok(combine(
+            eval_numeric(df, outer)?,
+            eval_numeric(df, inner)?,
+            |a, b| a / b,
+        )),
+        Expr::Pow { base, exp } => Ok(combine(
+            eval_numeric(df, base)?,
+            eval_numeric(df, exp)?,
+            f64::powf,
+        )),
+        Expr::Identity(inner) => eval_numeric(df, inner),
+        Expr::Func { name, args } if args.len() == 1 => {
+            let f = match name.as_str() {
+                "I" => |v| v,
+                "NEG" => |v: f64| -v,
+                name => math_function(name).ok_or_else(|| {
+                    Error::Semantic(format!(
+                        "Unsupported function '{}' in expression '{}'",
+                        name,
+                        pretty_expr(expr)
+                    ))
+                })?,
+            };
+            Ok(eval_numeric(df, &args[0])?
+                .into_iter()
+                .map(|v| v.map(f))
+                .collect())
+        }
+        other => Err(Error::Semantic(format!(
+            "Cannot evaluate '{}' as a numeric expression",
+            pretty_expr(other)
+        ))),
     }
+}
+
+/// Materialize a spline basis function (`bs`, `ns`, `cr`, `cc`) to multiple columns.
+///
+/// Knots are learned from the non-null values of the first argument the first time
+/// the term is seen and recorded in `info`; later calls with the same `info` reuse them.
+fn materialize_spline_to_columns(
+    df: &DataFrame,
+    expr: &Expr,
+    name: &str,
+    args: &[Expr],
+    info: &mut DesignInfo,
+) -> Result<Vec<(String, Series)>, Error> {
+    let (positional, named) = split_call_args(args);
+    let var_expr = *positional.first().ok_or_else(|| {
+        Error::Semantic(format!("{}() requires a variable as first argument", name))
+    })?;
+    let values = spline_values(df, name, var_expr, info)?;
+    let observed: Vec<f64> = values.iter().flatten().copied().collect();
 
-    // Step 2: Create the design matrix X with powers 0 to degree
-    // X = [1, x, x^2, ..., x^degree]
-    let mut x_matrix = vec![vec![1.0; n]; degree + 1];
-    for i in 1..=degree {
-        for j in 0..n {
-            x_matrix[i][j] = centered.get(j).unwrap_or(0.0).powi(i as i32);
+    let key = pretty_expr(expr);
+    let state = match info.transforms.get(&key) {
+        Some(TransformState::Spline(state)) => state.clone(),
+        _ => {
+            let state = learn_spline_state(name, &positional[1..], &named, &observed)?;
+            info.transforms
+                .insert(key, TransformState::Spline(state.clone()));
+            state
         }
+    };
+
+    // Evaluate on observed rows and scatter back, leaving nulls where the input was null
+    let basis = state.basis(&observed);
+    let var_label = pretty_expr(var_expr);
+    let mut spline_cols = Vec::with_capacity(basis.len());
+    for (i, column) in basis.into_iter().enumerate() {
+        let mut observed_values = column.into_iter();
+        let col_name = format!("{}_{}_{}", name, var_label, i + 1);
+        let col: Float64Chunked = values
+            .iter()
+            .map(|v| v.and_then(|_| observed_values.next()))
+            .collect();
+        spline_cols.push((
+            col_name.clone(),
+            col.with_name(col_name.into()).into_series(),
+        ));
     }
+    Ok(spline_cols)
+}
 
-    // Step 3: Perform QR decomposition manually (simplified version)
-    // This is a simplified QR decomposition that approximates R's behavior
-    let mut q_matrix = vec![vec![0.0; n]; degree + 1];
-    let mut r_matrix = vec![vec![0.0; degree + 1]; degree + 1];
-
-    // Gram-Schmidt orthogonalization with QR-like normalization
-    for i in 0..=degree {
-        // Start with the i-th column of X
-        let mut q_col = x_matrix[i].clone();
-
-        // Orthogonalize against previous columns
-        for j in 0..i {
-            let dot_product: f64 = q_col.iter().zip(&q_matrix[j]).map(|(a, b)| a * b).sum();
-            let norm_sq: f64 = q_matrix[j].iter().map(|x| x * x).sum();
-
-            if norm_sq > 0.0 {
-                let proj_coeff = dot_product / norm_sq;
-                for k in 0..n {
-                    q_col[k] -= proj_coeff * q_matrix[j][k];
-                }
-            }
+/// The values of the first argument of a spline basis function on the rows of `df`.
+pub(crate) fn spline_values(
+    df: &DataFrame,
+    name: &str,
+    var_expr: &Expr,
+    info: &mut DesignInfo,
+) -> Result<Vec<Option<f64>>, Error> {
+    let var_series = materialize_expr(df, var_expr, info)?;
+    series_to_f64(&var_series, &format!("Argument to {}()", name))
+}
+
+/// Named design columns of a smooth: the fixed and the random effects part.
+type SmoothColumns = (Vec<(String, Series)>, Vec<(String, Series)>);
+
+/// Materialize an mgcv-style smooth (`s`, `te`, `ti`, `t2`) to multiple columns.
+///
+/// The basis is learned from the rows where all variables (and `by`) are
+/// non-null and recorded in `info` together with its penalties; later calls
+/// with the same `info` rebuild the identical basis on new data. Returns the
+/// fixed and random effects columns; the latter are only non-empty when
+/// `info.mixed_model_smooths` is set.
+fn materialize_smooth_to_columns(
+    df: &DataFrame,
+    expr: &Expr,
+    kind: &SmoothKind,
+    vars: &[String],
+    args: &HashMap<String, Expr>,
+    info: &mut DesignInfo,
+) -> Result<SmoothColumns, Error> {
+    let kind_name = smooth_kind_name(kind);
+    let by_name = smooth_by(args, kind_name)?;
+    let SmoothRows {
+        observed,
+        covariates,
+        by,
+    } = smooth_rows(df, vars, by_name)?;
+
+    let key = pretty_expr(expr);
+    let state = match info.transforms.get(&key) {
+        Some(TransformState::Smooth(state)) => state.clone(),
+        _ => {
+            let opts = smooth_options(kind, kind_name, args)?;
+            let state = SmoothState::learn(&opts, &covariates, by.as_ref())?;
+            info.transforms
+                .insert(key.clone(), TransformState::Smooth(state.clone()));
+            state
         }
+    };
 
-        // Normalize using sqrt(norm2) like R
-        let norm2: f64 = q_col.iter().map(|x| x * x).sum();
-        let scale_factor = norm2.sqrt();
+    let prefix = format!("{}_{}", kind_name, vars.join("_"));
+    // Scatter observed values back into full-height columns
+    let to_series = |name: &str, column: Vec<f64>| {
+        let mut values = column.into_iter();
+        let col: Float64Chunked = observed
+            .iter()
+            .map(|&o| if o { values.next() } else { None })
+            .collect();
+        col.with_name(name.into()).into_series()
+    };
 
-        if scale_factor > 0.0 {
-            for k in 0..n {
-                q_matrix[i][k] = q_col[k] / scale_factor;
+    let mut fixed_cols = Vec::new();
+    let mut random_cols = Vec::new();
+    info.smooths.retain(|smooth| smooth.label != key);
+    for block in state.blocks(&covariates, by.as_ref())? {
+        let block_prefix = match (&block.by_level, by_name) {
+            (Some(level), Some(by)) => format!("{}_{}{}", prefix, by, level),
+            (None, Some(by)) => format!("{}_{}", prefix, by),
+            _ => prefix.clone(),
+        };
+        let null_space_dim = block.null_space_dim();
+        let (fixed, random, penalties) = if info.mixed_model_smooths {
+            let mixed = block.to_mixed()?;
+            let total: usize = mixed.random.iter().map(Vec::len).sum();
+            let mut penalties = Vec::with_capacity(mixed.random.len());
+            let mut offset = 0;
+            for group in &mixed.random {
+                let mut s = vec![vec![0.0; total]; total];
+                for (i, row) in s.iter_mut().enumerate().skip(offset).take(group.len()) {
+                    row[i] = 1.0;
+                }
+                offset += group.len();
+                penalties.push(s);
             }
-            r_matrix[i][i] = scale_factor;
+            (mixed.fixed, mixed.random.concat(), penalties)
         } else {
-            q_matrix[i] = q_col;
-            r_matrix[i][i] = 0.0;
+            (block.columns, Vec::new(), block.penalties)
+        };
+
+        let mut names = Vec::with_capacity(fixed.len());
+        for (i, column) in fixed.into_iter().enumerate() {
+            let col_name = format!("{}_{}", block_prefix, i + 1);
+            fixed_cols.push((col_name.clone(), to_series(&col_name, column)));
+            names.push(col_name);
+        }
+        let mut random_names = Vec::with_capacity(random.len());
+        for (i, column) in random.into_iter().enumerate() {
+            let col_name = format!("{}_rand_{}", block_prefix, i + 1);
+            random_cols.push((col_name.clone(), to_series(&col_name, column)));
+            random_names.push(col_name);
+        }
+        info.smooths.push(SmoothInfo {
+            label: key.clone(),
+            by_level: block.by_level,
+            columns: names,
+            column_range: 0..0,
+            random_columns: random_names,
+            random_column_range: 0..0,
+            penalties,
+            null_space_dim,
+            constraint: block.constraint,
+        });
+    }
+    Ok((fixed_cols, random_cols))
+}
+
+/// Learn the basis of a smooth (`s`, `te`, `ti`, `t2`) from the rows of `df`
+/// where all its variables (and `by`) are non-null, as
+/// [`materialize_smooth_to_columns`] does the first time it sees the term.
+pub(crate) fn learn_smooth_state(
+    df: &DataFrame,
+    kind: &SmoothKind,
+    vars: &[String],
+    args: &HashMap<String, Expr>,
+) -> Result<SmoothState, Error> {
+    let kind_name = smooth_kind_name(kind);
+    let rows = smooth_rows(df, vars, smooth_by(args, kind_name)?)?;
+    let opts = smooth_options(kind, kind_name, args)?;
+    SmoothState::learn(&opts, &rows.covariates, rows.by.as_ref())
+}
+
+fn smooth_kind_name(kind: &SmoothKind) -> &'static str {
+    match kind {
+        SmoothKind::S => "s",
+        SmoothKind::T2 => "t2",
+        SmoothKind::TE => "te",
+        SmoothKind::TI => "ti",
+    }
+}
+
+/// The `by` variable of a smooth, if any.
+fn smooth_by<'a>(
+    args: &'a HashMap<String, Expr>,
+    kind_name: &str,
+) -> Result<Option<&'a str>, Error> {
+    match args.get("by") {
+        None => Ok(None),
+        Some(Expr::Var(name)) => Ok(Some(name.as_str())),
+        Some(_) => Err(Error::Semantic(format!(
+            "Argument 'by' to {}() must be a variable name",
+            kind_name
+        ))),
+    }
+}
+
+/// The covariates of a smooth on the rows where all of them are observed.
+struct SmoothRows {
+    /// Whether each row of the DataFrame is used.
+    observed: Vec<bool>,
+    covariates: Vec<Covariate>,
+    by: Option<Covariate>,
+}
+
+fn smooth_rows(
+    df: &DataFrame,
+    vars: &[String],
+    by_name: Option<&str>,
+) -> Result<SmoothRows, Error> {
+    let mut raw = Vec::with_capacity(vars.len());
+    for var in vars {
+        raw.push(smooth_covariate(df, var)?);
+    }
+    let by_raw = by_name.map(|name| smooth_covariate(df, name)).transpose()?;
+    let observed: Vec<bool> = (0..df.height())
+        .map(|i| raw.iter().chain(by_raw.iter()).all(|c| c.is_observed(i)))
+        .collect();
+    let covariates = raw.iter().map(|c| c.observed(&observed)).collect();
+    let by = by_raw.as_ref().map(|c| c.observed(&observed));
+    Ok(SmoothRows {
+        observed,
+        covariates,
+        by,
+    })
+}
+
+/// A smooth variable as read from the DataFrame: strings are factors, anything
+/// else is converted to numeric.
+enum RawCovariate {
+    Numeric(Vec<Option<f64>>),
+    Factor(Vec<Option<String>>),
+}
+
+impl RawCovariate {
+    fn is_observed(&self, i: usize) -> bool {
+        match self {
+            RawCovariate::Numeric(v) => v[i].is_some(),
+            RawCovariate::Factor(v) => v[i].is_some(),
         }
     }
 
-    // Step 4: Extract the orthogonal polynomials (skip the constant term)
-    let mut result = Vec::new();
-    for i in 1..=degree {
-        let poly_series = Float64Chunked::from_slice("poly".into(), &q_matrix[i]).into_series();
-        result.push(poly_series.f64().unwrap().clone());
+    fn observed(&self, mask: &[bool]) -> Covariate {
+        match self {
+            RawCovariate::Numeric(v) => Covariate::Numeric(
+                v.iter()
+                    .zip(mask)
+                    .filter_map(|(x, &keep)| if keep { *x } else { None })
+                    .collect(),
+            ),
+            RawCovariate::Factor(v) => Covariate::Factor(
+                v.iter()
+                    .zip(mask)
+                    .filter_map(|(x, &keep)| if keep { x.clone() } else { None })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn smooth_covariate(df: &DataFrame, name: &str) -> Result<RawCovariate, Error> {
+    let series = df
+        .column(name)
+        .map_err(|_| Error::Semantic(format!("Column '{}' not found in DataFrame", name)))?
+        .as_materialized_series();
+    if let Ok(strings) = series.str() {
+        Ok(RawCovariate::Factor(
+            strings.into_iter().map(|v| v.map(str::to_string)).collect(),
+        ))
+    } else {
+        series_to_f64(series, &format!("Smooth variable '{}'", name)).map(RawCovariate::Numeric)
+    }
+}
+
+/// Read the basis options of a smooth term from its named arguments.
+fn smooth_options(
+    kind: &SmoothKind,
+    kind_name: &str,
+    args: &HashMap<String, Expr>,
+) -> Result<SmoothOptions, Error> {
+    for name in args.keys() {
+        if !matches!(
+            name.as_str(),
+            "k" | "bs" | "by" | "m" | "xt" | "id" | "sp" | "fx"
+        ) {
+            return Err(Error::Semantic(format!(
+                "Unsupported argument '{}' to {}()",
+                name, kind_name
+            )));
+        }
     }
+    let strings = |expr: &Expr, arg: &str| -> Result<Vec<String>, Error> {
+        match expr {
+            Expr::Str(s) => Ok(vec![s.clone()]),
+            Expr::Func { name, args } if name == "c" => args
+                .iter()
+                .map(|a| match a {
+                    Expr::Str(s) => Ok(s.clone()),
+                    _ => Err(()),
+                })
+                .collect::<Result<_, _>>()
+                .map_err(|_| {
+                    Error::Semantic(format!(
+                        "Argument '{}' to {}() must be a string or c() of strings",
+                        arg, kind_name
+                    ))
+                }),
+            _ => Err(Error::Semantic(format!(
+                "Argument '{}' to {}() must be a string",
+                arg, kind_name
+            ))),
+        }
+    };
+    let bs = args.get("bs").map(|e| strings(e, "bs")).transpose()?;
+    let k = args
+        .get("k")
+        .map(|e| {
+            const_numbers(e).ok_or_else(|| {
+                Error::Semantic(format!("Argument 'k' to {}() must be numeric", kind_name))
+            })
+        })
+        .transpose()?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|k| k as usize)
+        .collect();
+    let m = args
+        .get("m")
+        .map(|e| const_number(e, kind_name, "m").map(|m| m as usize))
+        .transpose()?;
+    let xt = args
+        .get("xt")
+        .map(|e| strings(e, "xt"))
+        .transpose()?
+        .and_then(|v| v.into_iter().next());
+    Ok(SmoothOptions {
+        combine: match kind {
+            SmoothKind::S => Combine::Single,
+            SmoothKind::TE => Combine::Tensor,
+            SmoothKind::TI => Combine::TensorInteraction,
+            SmoothKind::T2 => Combine::T2,
+        },
+        bs: bs.unwrap_or_default(),
+        k,
+        m,
+        xt,
+    })
+}
 
-    Ok(result)
+/// Learn knots for a spline term from its arguments (excluding the variable itself).
+pub(crate) fn learn_spline_state(
+    name: &str,
+    positional: &[&Expr],
+    named: &HashMap<&str, &Expr>,
+    x: &[f64],
+) -> Result<SplineState, Error> {
+    let df_arg = call_arg(positional, named, 0, "df")
+        .map(|e| const_number(e, name, "df").map(|n| n as usize))
+        .transpose()?;
+    let knots = call_arg(positional, named, 1, "knots")
+        .map(|e| {
+            const_numbers(e).ok_or_else(|| {
+                Error::Semantic(format!("Argument 'knots' to {}() must be numeric", name))
+            })
+        })
+        .transpose()?;
+
+    match name {
+        "bs" | "ns" => {
+            let (intercept_pos, boundary_pos) = if name == "bs" { (3, 4) } else { (2, 3) };
+            let intercept = call_arg(positional, named, intercept_pos, "intercept")
+                .map(|e| const_bool(e, name, "intercept"))
+                .transpose()?
+                .unwrap_or(false);
+            let boundary_knots = call_arg(positional, named, boundary_pos, "Boundary.knots")
+                .map(|e| match const_numbers(e).as_deref() {
+                    Some([lo, hi]) => Ok((*lo, *hi)),
+                    _ => Err(Error::Semantic(format!(
+                        "Argument 'Boundary.knots' to {}() must be c(lower, upper)",
+                        name
+                    ))),
+                })
+                .transpose()?;
+            if name == "bs" {
+                let degree = call_arg(positional, named, 2, "degree")
+                    .map(|e| const_number(e, name, "degree").map(|n| n as usize))
+                    .transpose()?
+                    .unwrap_or(3);
+                SplineState::bs(x, df_arg, knots, degree, intercept, boundary_knots)
+            } else {
+                SplineState::ns(x, df_arg, knots, intercept, boundary_knots)
+            }
+        }
+        _ => {
+            let lower = call_arg(positional, named, 2, "lower_bound")
+                .map(|e| const_number(e, name, "lower_bound"))
+                .transpose()?;
+            let upper = call_arg(positional, named, 3, "upper_bound")
+                .map(|e| const_number(e, name, "upper_bound"))
+                .transpose()?;
+            let center = match call_arg(positional, named, 4, "constraints") {
+                None => false,
+                Some(Expr::Str(s)) if s == "center" => true,
+                Some(_) => {
+                    return Err(Error::Semantic(format!(
+                        "Argument 'constraints' to {}() must be \"center\"",
+                        name
+                    )))
+                }
+            };
+            SplineState::cubic_regression(x, df_arg, knots, (lower, upper), center, name == "cc")
+        }
+    }
 }
 
 /// Create contrast columns for categorical variables
 fn create_categorical_contrasts(
     series: &StringChunked,
     var_name: &str,
+    info: &mut DesignInfo,
 ) -> Result<Vec<(String, Series)>, Error> {
-    // Get unique levels and sort them
-    let mut levels: Vec<String> = series
-        .into_iter()
-        .filter_map(|s| s.map(|s| s.to_string()))
-        .collect::<std::collections::HashSet<_>>()
-        .into_iter()
-        .collect();
-    levels.sort();
-
+    let levels = learned_levels(series, var_name, info)?;
     if levels.len() <= 1 {
         // Single level or empty - return empty
         return Ok(Vec::new());
@@ -832,28 +2009,88 @@ fn create_categorical_contrasts(
 
     // For now, implement treatment contrasts (default)
     // TODO: Support different contrast types from MaterializeOptions
-    let mut contrast_cols = Vec::new();
-
     // Create treatment contrasts (skip first level as baseline)
-    for level in &levels[1..] {
+    Ok(level_indicators(series, var_name, &levels[1..]))
+}
+
+/// Create one indicator column per level of a categorical variable.
+fn create_categorical_indicators(
+    series: &StringChunked,
+    var_name: &str,
+    info: &mut DesignInfo,
+) -> Result<Vec<(String, Series)>, Error> {
+    let levels = learned_levels(series, var_name, info)?;
+    Ok(level_indicators(series, var_name, &levels))
+}
+
+/// The levels of a categorical variable recorded in `info`, learned from
+/// `series` the first time the variable is seen; new data is coded with the
+/// same levels and may not have others.
+fn learned_levels(
+    series: &StringChunked,
+    var_name: &str,
+    info: &mut DesignInfo,
+) -> Result<Vec<String>, Error> {
+    match info.levels.get(var_name) {
+        Some(levels) => {
+            if let Some(unseen) = series
+                .into_iter()
+                .flatten()
+                .find(|value| !levels.iter().any(|level| level == value))
+            {
+                return Err(Error::Semantic(format!(
+                    "Level '{}' of '{}' was not seen when the levels were learned",
+                    unseen, var_name
+                )));
+            }
+            Ok(levels.clone())
+        }
+        None => {
+            let levels = sorted_levels(series);
+            info.levels.insert(var_name.to_string(), levels.clone());
+            Ok(levels)
+        }
+    }
+}
+
+/// The distinct non-null values of a categorical variable, sorted.
+fn sorted_levels(series: &StringChunked) -> Vec<String> {
+    let mut levels: Vec<String> = series
+        .into_iter()
+        .filter_map(|s| s.map(|s| s.to_string()))
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect();
+    levels.sort();
+    levels
+}
+
+/// Indicator columns `{var}_{level}` of the given levels; missing values are 0.
+fn level_indicators(
+    series: &StringChunked,
+    var_name: &str,
+    levels: &[String],
+) -> Vec<(String, Series)> {
+    let mut indicator_cols = Vec::new();
+    for level in levels {
         let col_name = format!("{}_{}", var_name, level);
         let mut col_data = vec![0.0; series.len()];
 
         // Set to 1.0 for rows where series == level
         for (i, val) in series.into_iter().enumerate() {
             if let Some(val_str) = val {
-                if val_str.to_string() == *level {
+                if val_str == level {
                     col_data[i] = 1.0;
                 }
             }
         }
 
-        let contrast_series =
+        let indicator_series =
             Float64Chunked::from_slice((&col_name).into(), &col_data).into_series();
-        contrast_cols.push((col_name, contrast_series));
+        indicator_cols.push((col_name, indicator_series));
     }
 
-    Ok(contrast_cols)
+    indicator_cols
 }
 
 /// Materialize a group expression to random effects columns.
@@ -863,210 +2100,226 @@ fn materialize_group_to_columns(
     spec: &GroupSpec,
     _kind: &GroupKind,
     _id: &Option<String>,
+    info: &mut DesignInfo,
 ) -> Result<Vec<(String, Series)>, Error> {
-    let n_rows = df.height();
-
-    match spec {
-        GroupSpec::Expr(GroupExpr(terms)) => {
-            // Get the first grouping variable (for now, handle simple cases)
-            if let Some((group_var, _)) = terms.first() {
-                // Simple grouping variable
-                let group_series = df
-                    .column(group_var)
-                    .map_err(|_| {
-                        Error::Semantic(format!("Group variable '{}' not found", group_var))
-                    })?
-                    .as_series()
-                    .ok_or_else(|| {
-                        Error::Semantic("Failed to convert group column to series".into())
-                    })?
-                    .clone();
-
-                // Get unique group levels
-                let unique_groups = group_series
-                    .unique()
-                    .map_err(|e| Error::Semantic(format!("Failed to get unique groups: {}", e)))?;
-                let sorted_groups = unique_groups
-                    .sort(SortOptions::default())
-                    .map_err(|e| Error::Semantic(format!("Failed to sort groups: {}", e)))?;
-
-                // Convert to strings, handling both string and numeric types
-                let group_levels: Vec<String> = if let Ok(str_series) = sorted_groups.str() {
-                    str_series
-                        .into_iter()
-                        .map(|s| s.unwrap_or("").to_string())
-                        .collect()
-                } else {
-                    // Try as numeric and convert to string
-                    if let Ok(i64_series) = sorted_groups.i64() {
-                        i64_series
-                            .into_iter()
-                            .map(|n| n.map(|v| v.to_string()).unwrap_or("".to_string()))
-                            .collect()
-                    } else if let Ok(f64_series) = sorted_groups.f64() {
-                        f64_series
-                            .into_iter()
-                            .map(|n| n.map(|v| v.to_string()).unwrap_or("".to_string()))
-                            .collect()
-                    } else {
-                        return Err(Error::Semantic(
-                            "Group variable must be string or numeric".into(),
-                        ));
-                    }
-                };
+    Ok(random_blocks(df, inner, spec, info)?
+        .iter()
+        .flat_map(RandomBlock::dense_columns)
+        .collect())
+}
 
-                let mut random_cols = Vec::new();
-
-                match inner {
-                    Expr::Intercept(true) => {
-                        // Random intercept: ri(Subject=<level>) for each group level
-                        for level in &group_levels {
-                            let col_name = format!("ri({}={})", group_var, level);
-                            let mut col_data = vec![0.0; n_rows];
-
-                            // Set to 1.0 for rows where group == level
-                            if let Ok(str_series) = group_series.str() {
-                                for (i, group_val) in str_series.into_iter().enumerate() {
-                                    if let Some(val) = group_val {
-                                        if val == level {
-                                            col_data[i] = 1.0;
-                                        }
-                                    }
-                                }
-                            } else if let Ok(i64_series) = group_series.i64() {
-                                for (i, group_val) in i64_series.into_iter().enumerate() {
-                                    if let Some(val) = group_val {
-                                        if val.to_string() == *level {
-                                            col_data[i] = 1.0;
-                                        }
-                                    }
-                                }
-                            } else if let Ok(f64_series) = group_series.f64() {
-                                for (i, group_val) in f64_series.into_iter().enumerate() {
-                                    if let Some(val) = group_val {
-                                        if val.to_string() == *level {
-                                            col_data[i] = 1.0;
-                                        }
-                                    }
-                                }
-                            }
+/// Build the random-effects blocks of a group term, one per grouping factor
+/// after expanding nesting and splitting (`(1|a/b)` is `(1|a) + (1|a:b)`), or a
+/// single one for the grouping functions `gr()` and `mm()`.
+///
+/// The inner expression is materialized like the fixed effects (contrasts,
+/// transforms, interactions) and later multiplied by the group indicators. As
+/// in lme4, it has an intercept unless it contains `0` or `-1`; without one,
+/// a leading categorical variable is coded with one indicator per level.
+pub(crate) fn random_blocks(
+    df: &DataFrame,
+    inner: &Expr,
+    spec: &GroupSpec,
+    info: &mut DesignInfo,
+) -> Result<Vec<RandomBlock>, Error> {
+    let terms = match inner {
+        Expr::Sum(terms) => terms.iter().collect(),
+        inner => vec![inner],
+    };
 
-                            let series =
-                                Float64Chunked::from_slice(col_name.clone().into(), &col_data)
-                                    .into_series();
-                            random_cols.push((col_name, series));
-                        }
-                    }
-                    Expr::Sum(terms) => {
-                        // Handle (0 + var|group) case
-                        if terms.len() == 2 {
-                            if let (Expr::Intercept(false), Expr::Var(var_name)) =
-                                (&terms[0], &terms[1])
-                            {
-                                // Random slope: rs(var|group=<level>) for each group level
-                                let var_series = df
-                                    .column(var_name)
-                                    .map_err(|_| {
-                                        Error::Semantic(format!(
-                                            "Variable '{}' not found",
-                                            var_name
-                                        ))
-                                    })?
-                                    .as_series()
-                                    .ok_or_else(|| {
-                                        Error::Semantic(
-                                            "Failed to convert variable column to series".into(),
-                                        )
-                                    })?
-                                    .clone();
-
-                                for level in &group_levels {
-                                    let col_name =
-                                        format!("rs({}|{}={})", var_name, group_var, level);
-                                    let mut col_data = vec![0.0; n_rows];
-
-                                    // Set to var value for rows where group == level
-                                    // Handle both f64 and i64 variable types
-                                    let var_values: Vec<Option<f64>> = if let Ok(f64_series) =
-                                        var_series.f64()
-                                    {
-                                        f64_series.into_iter().collect()
-                                    } else if let Ok(i64_series) = var_series.i64() {
-                                        i64_series
-                                            .into_iter()
-                                            .map(|v| v.map(|x| x as f64))
-                                            .collect()
-                                    } else {
-                                        return Err(Error::Semantic(
-                                            "Variable column must be numeric (i64 or f64)".into(),
-                                        ));
-                                    };
-
-                                    if let Ok(str_series) = group_series.str() {
-                                        for (i, (group_val, var_val)) in str_series
-                                            .into_iter()
-                                            .zip(var_values.iter())
-                                            .enumerate()
-                                        {
-                                            if let (Some(g), Some(v)) = (group_val, var_val) {
-                                                if g == level {
-                                                    col_data[i] = *v;
-                                                }
-                                            }
-                                        }
-                                    } else if let Ok(i64_series) = group_series.i64() {
-                                        for (i, (group_val, var_val)) in i64_series
-                                            .into_iter()
-                                            .zip(var_values.iter())
-                                            .enumerate()
-                                        {
-                                            if let (Some(g), Some(v)) = (group_val, var_val) {
-                                                if g.to_string() == *level {
-                                                    col_data[i] = *v;
-                                                }
-                                            }
-                                        }
-                                    } else if let Ok(f64_series) = group_series.f64() {
-                                        for (i, (group_val, var_val)) in f64_series
-                                            .into_iter()
-                                            .zip(var_values.iter())
-                                            .enumerate()
-                                        {
-                                            if let (Some(g), Some(v)) = (group_val, var_val) {
-                                                if g.to_string() == *level {
-                                                    col_data[i] = *v;
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    let series = Float64Chunked::from_slice(
-                                        col_name.clone().into(),
-                                        &col_data,
-                                    )
-                                    .into_series();
-                                    random_cols.push((col_name, series));
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        // For other expressions, treat as fixed effect for now
-                        // TODO: Implement more complex random effects
-                    }
-                }
+    let mut intercept = true;
+    let mut slopes = Vec::new();
+    for term in terms {
+        match term {
+            Expr::Intercept(true) => {}
+            Expr::Intercept(false) => intercept = false,
+            Expr::Func { name, args }
+                if name == "NEG"
+                    && args.len() == 1
+                    && matches!(args[0], Expr::Intercept(true) | Expr::Num(1.0)) =>
+            {
+                intercept = false
+            }
+            term => slopes.push(term),
+        }
+    }
 
-                Ok(random_cols)
-            } else {
-                Ok(Vec::new())
+    let mut columns = Vec::new();
+    if intercept {
+        columns.push((INTERCEPT.to_string(), vec![Some(1.0); df.height()]));
+    }
+    for (i, term) in slopes.into_iter().enumerate() {
+        let term_cols = match term {
+            // Full dummy coding stands in for the missing intercept
+            Expr::Var(name) if !intercept && i == 0 && is_string_column(df, name) => {
+                let series = df
+                    .column(name)
+                    .map_err(|e| Error::Semantic(e.to_string()))?;
+                let str_series = series.str().map_err(|e| Error::Semantic(e.to_string()))?;
+                create_categorical_indicators(str_series, name, info)?
             }
+            term => materialize_expr_to_columns(df, term, info)?,
+        };
+        for (name, series) in term_cols {
+            let values = series_to_f64(&series, &name)?;
+            columns.push((name, values));
+        }
+    }
+
+    let blocks = match spec {
+        GroupSpec::Expr(group) => group
+            .expand()
+            .iter()
+            .map(|factors| build_random_block(df, columns.clone(), factors))
+            .collect::<Result<Vec<_>, _>>()?,
+        GroupSpec::Func { name, args } => {
+            vec![group_function_block(df, columns, name, args, info)?]
         }
+    };
+
+    // Levels learned before give new data the same columns
+    blocks
+        .into_iter()
+        .map(|block| match info.group_levels.get(&block.group) {
+            Some(levels) => {
+                let by = info.group_by.get(&block.group);
+                block.relevel(levels, by)
+            }
+            None => {
+                info.group_levels
+                    .insert(block.group.clone(), block.levels.clone());
+                if let Some(by) = &block.by {
+                    info.group_by.insert(block.group.clone(), by.clone());
+                }
+                Ok(block)
+            }
+        })
+        .collect()
+}
+
+/// Build the block of a brms grouping function: `gr(g, by = x, cor = FALSE,
+/// id = "a")` or the multi-membership `mm(g1, g2, weights = cbind(w1, w2),
+/// scale = TRUE)`.
+fn group_function_block(
+    df: &DataFrame,
+    inner: Vec<(String, Vec<Option<f64>>)>,
+    name: &str,
+    args: &[Expr],
+    info: &DesignInfo,
+) -> Result<RandomBlock, Error> {
+    let (positional, named) = split_call_args(args);
+    let allowed: &[&str] = match name {
+        "gr" => &["by", "cor", "id"],
+        "mm" => &["weights", "scale", "cor", "id"],
         _ => {
-            // For other group specifications, return empty for now
-            // TODO: Implement other group types
-            Ok(Vec::new())
+            return Err(Error::Semantic(format!(
+                "Unknown grouping function '{}()', expected gr() or mm()",
+                name
+            )))
+        }
+    };
+    if let Some(arg) = named.keys().find(|arg| !allowed.contains(arg)) {
+        return Err(Error::Semantic(format!(
+            "Argument '{}' to {}() is not supported",
+            arg, name
+        )));
+    }
+
+    let factors = positional
+        .iter()
+        .map(|arg| match arg {
+            Expr::Var(factor) => Ok(factor.clone()),
+            other => Err(Error::Semantic(format!(
+                "Grouping factors of {}() must be variables, found '{}'",
+                name,
+                pretty_expr(other)
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut block = if name == "gr" {
+        if factors.len() != 1 {
+            return Err(Error::Semantic(
+                "gr() takes exactly one grouping factor".into(),
+            ));
+        }
+        let mut block = build_random_block(df, inner, &factors)?;
+        if let Some(by) = named.get("by") {
+            let Expr::Var(by) = by else {
+                return Err(Error::Semantic(
+                    "Argument 'by' to gr() must be a variable".into(),
+                ));
+            };
+            // A learned mapping is set when the block is releveled
+            if !info.group_by.contains_key(&block.group) {
+                block.by = Some(group_by(df, &block, by)?);
+            }
+        }
+        block
+    } else {
+        if factors.is_empty() {
+            return Err(Error::Semantic(
+                "mm() needs at least one grouping factor".into(),
+            ));
+        }
+        let weights = match named.get("weights") {
+            Some(Expr::Func { name, args }) if name == "cbind" => Some(
+                args.iter()
+                    .map(|arg| match arg {
+                        Expr::Var(column) => Ok(column.clone()),
+                        other => Err(Error::Semantic(format!(
+                            "Weights of mm() must be variables, found '{}'",
+                            pretty_expr(other)
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            Some(Expr::Var(column)) => Some(vec![column.clone()]),
+            Some(other) => {
+                return Err(Error::Semantic(format!(
+                    "Argument 'weights' to mm() must be cbind() of variables, found '{}'",
+                    pretty_expr(other)
+                )))
+            }
+            None => None,
+        };
+        if let Some(weights) = &weights {
+            if weights.len() != factors.len() {
+                return Err(Error::Semantic(format!(
+                    "mm() has {} grouping factors but {} weight columns",
+                    factors.len(),
+                    weights.len()
+                )));
+            }
         }
+        let scale = match named.get("scale") {
+            Some(scale) => const_bool(scale, "mm", "scale")?,
+            None => true,
+        };
+        build_multi_membership_block(df, inner, &factors, weights.as_deref(), scale)?
+    };
+
+    if let Some(cor) = named.get("cor") {
+        block.cor = const_bool(cor, name, "cor")?;
     }
+    block.id = match named.get("id") {
+        Some(Expr::Str(id)) | Some(Expr::Var(id)) => Some(id.clone()),
+        Some(other) => {
+            return Err(Error::Semantic(format!(
+                "Argument 'id' to {}() must be a label, found '{}'",
+                name,
+                pretty_expr(other)
+            )))
+        }
+        None => None,
+    };
+    Ok(block)
+}
+
+fn is_string_column(df: &DataFrame, name: &str) -> bool {
+    df.column(name)
+        .map(|c| c.dtype() == &DataType::String)
+        .unwrap_or(false)
 }
 
 /// Convert a categorical variable to treatment contrasts.
@@ -3,15 +3,40 @@
 //! This module provides a comprehensive formula DSL implementation with:
 //! - Rich AST representation
 //! - Chumsky-based parser
-//! - Canonicalization
+//! - Canonicalization, with distributional parameters checked against the family
+//! - A registry of response families with their links and response support
+//! - Link functions with their inverses and derivatives
 //! - Pretty-printing
-//! - Materialization
+//! - Materialization, with auxiliary terms and missing-value handling
+//! - Compilation of design columns to Polars expressions for lazy frames
+//! - Batch-by-batch materialization with state learned from all the data
+//! - Least-squares cross-products accumulated over batches
+//! - Autocorrelation terms checked and evaluated into time and group metadata
+//! - Spline bases and mgcv-style smooths
+//! - Sparse random-effects design matrices
+//! - Conversion of design matrices to faer, ndarray and nalgebra matrices
 //! - Property-based testing
 
 pub mod ast;
+pub mod autocor;
 pub mod canon;
+pub mod chunked;
+#[cfg(any(feature = "faer", feature = "ndarray", feature = "nalgebra"))]
+pub mod convert;
+pub mod design;
+pub mod family;
+pub mod lazy;
+pub(crate) mod linalg;
+pub mod links;
 pub mod materialize;
+pub mod model_data;
 pub mod parser;
 pub mod pretty;
+pub(crate) mod random;
+pub mod reterms;
+pub mod smooths;
+pub mod sparse;
+pub mod suffstats;
+pub mod splines;
 
 pub use ast::*;
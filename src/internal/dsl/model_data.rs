@@ -0,0 +1,304 @@
+//! Everything a model fitter needs from a formula and a data frame.
+//!
+//! Besides the response and the design matrices, a model uses the auxiliary
+//! terms of the response (`y | weights(w) + trials(n) ~ x`), the offset
+//! (`offset(log(exposure))`) and the formulas of distributional parameters
+//! (`sigma ~ z`) and the autocorrelation terms (`ar(time=t, gr=g)`).
+//! [`ModelData`] evaluates them next to y, X and Z, and keeps
+//! all of them on the same rows by dropping the observations with a missing
+//! value in any of them, as R's `na.omit` does. A `subset()` aterm selects
+//! the rows first.
+
+use super::ast::{Aterm, Expr, GroupSpec, MaterializeOptions, ModelSpec, Response};
+use super::autocor::{autocor_columns, materialize_autocor, AutocorTerm};
+use super::design::DesignInfo;
+use super::materialize::{
+    materialize_aterms, materialize_dpars_with_info, materialize_offset, materialize_with_info,
+    subset_mask,
+};
+use crate::Error;
+use polars::prelude::*;
+use std::collections::BTreeMap;
+
+/// Response, design matrices and auxiliary terms of a model, on the complete
+/// rows of the data.
+#[derive(Debug, Clone)]
+pub struct ModelData {
+    /// Response column(s).
+    pub y: DataFrame,
+    /// Fixed effects design matrix.
+    pub x: DataFrame,
+    /// Random effects design matrix.
+    pub z: DataFrame,
+    /// Evaluated auxiliary terms, one column each, e.g. `weights` or `trials`
+    /// (see the column names listed for each aterm in [`ModelData::aterm`]).
+    pub aterms: DataFrame,
+    /// Sum of the `offset()` terms of the formula, e.g. `log(exposure)` for
+    /// `y ~ x + offset(log(exposure))`, or `None` without offsets.
+    pub offset: Option<Series>,
+    /// Design matrices of the distributional parameter formulas, keyed by
+    /// parameter name, e.g. `sigma` for `y ~ x + sigma ~ z`.
+    pub dpars: BTreeMap<String, DparData>,
+    /// Time and group metadata of the autocorrelation terms, e.g.
+    /// `ar(time=t, gr=g)`, on the rows used.
+    pub autocor: Vec<AutocorTerm>,
+    /// Rows of the input data used, in order. Rows not selected by `subset()`
+    /// or with a missing value in y, X, Z, an aterm, a distributional
+    /// parameter formula or the time or group of an autocorrelation term are
+    /// left out.
+    pub rows: Vec<usize>,
+    /// State learned from the used rows, for [`crate::materialize_new_data`].
+    pub info: DesignInfo,
+}
+
+/// Design matrices of one distributional parameter formula, on the same rows
+/// as the rest of [`ModelData`].
+#[derive(Debug, Clone)]
+pub struct DparData {
+    /// Fixed effects design matrix, with an intercept unless removed.
+    pub x: DataFrame,
+    /// Random effects design matrix of the group terms, e.g. `(1|g)` in
+    /// `sigma ~ z + (1|g)`.
+    pub z: DataFrame,
+    /// Sum of the `offset()` terms of the formula, or `None` without offsets.
+    pub offset: Option<Series>,
+}
+
+impl ModelData {
+    /// The column of an auxiliary term: `se`, `weights`, `trials`, `cens`,
+    /// `trunc_lb`, `trunc_ub`, `rate`, `thres`, `dec`, `cat`, `index`, or
+    /// `vreal_1`, `vint_1`, ... for the arguments of `vreal()` and `vint()`.
+    pub fn aterm(&self, name: &str) -> Option<&Series> {
+        self.aterms
+            .column(name)
+            .ok()
+            .map(|column| column.as_materialized_series())
+    }
+}
+
+/// Materialize a spec together with its aterms, on the complete rows of `df`
+/// selected by `subset()`.
+///
+/// When rows have to be dropped, the model is materialized again from the
+/// remaining rows, so learned state (spline knots, factor levels) only
+/// reflects the data the model is fitted to.
+pub(crate) fn materialize_model_data(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+) -> Result<ModelData, Error> {
+    let subset = spec.formula.aterms.iter().find_map(|aterm| match aterm {
+        Aterm::Subset(condition) => Some(condition),
+        _ => None,
+    });
+    if let Some(condition) = subset {
+        let mask = subset_mask(df, condition)?;
+        let selected: Vec<usize> = mask
+            .into_iter()
+            .enumerate()
+            .filter_map(|(row, keep)| (keep == Some(true)).then_some(row))
+            .collect();
+        if selected.is_empty() {
+            return Err(Error::Semantic("subset() selects no rows".into()));
+        }
+        let df = df
+            .filter(&mask)
+            .map_err(|e| Error::Semantic(e.to_string()))?;
+        let mut data = materialize_complete(&df, spec, opts)?;
+        data.rows = data.rows.iter().map(|&row| selected[row]).collect();
+        return Ok(data);
+    }
+    materialize_complete(df, spec, opts)
+}
+
+/// Materialize a spec together with its aterms, on the complete rows of `df`.
+fn materialize_complete(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+) -> Result<ModelData, Error> {
+    let data = materialize_rows(df, spec, opts.clone())?;
+
+    // Factors are checked in the data, as their indicators are never null
+    let mut used = Vec::new();
+    for column in referenced_columns(spec) {
+        if let Ok(column) = df.column(&column) {
+            used.push(column.clone());
+        }
+    }
+    let offsets = std::iter::once(&data.offset).chain(data.dpars.values().map(|d| &d.offset));
+    used.extend(offsets.flatten().map(|offset| offset.clone().into_column()));
+    let mut frames = vec![&data.y, &data.x, &data.z, &data.aterms];
+    for dpar in data.dpars.values() {
+        frames.extend([&dpar.x, &dpar.z]);
+    }
+    let mut complete = vec![true; df.height()];
+    let columns = frames.into_iter().flat_map(|frame| frame.get_columns());
+    for column in columns.chain(&used) {
+        if column.null_count() == 0 {
+            continue;
+        }
+        for (row, is_null) in column.is_null().into_iter().enumerate() {
+            if is_null == Some(true) {
+                complete[row] = false;
+            }
+        }
+    }
+    if complete.iter().all(|&c| c) {
+        return Ok(ModelData {
+            autocor: materialize_autocor(df, spec)?,
+            ..data
+        });
+    }
+
+    let rows: Vec<usize> = (0..df.height()).filter(|&row| complete[row]).collect();
+    let mask = BooleanChunked::from_slice("complete".into(), &complete);
+    let df = df
+        .filter(&mask)
+        .map_err(|e| Error::Semantic(e.to_string()))?;
+    Ok(ModelData {
+        rows,
+        autocor: materialize_autocor(&df, spec)?,
+        ..materialize_rows(&df, spec, opts)?
+    })
+}
+
+/// Materialize everything in a spec on all rows of `df`, but the
+/// autocorrelation terms, whose time and group may not be missing.
+fn materialize_rows(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+) -> Result<ModelData, Error> {
+    let mut info = DesignInfo::default();
+    let (y, x, z) = materialize_with_info(df, spec, opts.clone(), &mut info)?;
+    let aterms = materialize_aterms(df, &spec.formula, &y)?;
+    let offset = offset_series(df, &spec.formula.rhs)?;
+    let dpars = materialize_dpars(df, spec, opts, &mut info)?;
+    Ok(ModelData {
+        y,
+        x,
+        z,
+        aterms,
+        offset,
+        dpars,
+        autocor: Vec::new(),
+        rows: (0..df.height()).collect(),
+        info,
+    })
+}
+
+/// Design matrices and offsets of the distributional parameter formulas.
+pub(crate) fn materialize_dpars(
+    df: &DataFrame,
+    spec: &ModelSpec,
+    opts: MaterializeOptions,
+    info: &mut DesignInfo,
+) -> Result<BTreeMap<String, DparData>, Error> {
+    let mut dpars = BTreeMap::new();
+    for (name, (x, z)) in materialize_dpars_with_info(df, spec, opts, info)? {
+        let rhs = &spec
+            .dpars
+            .iter()
+            .find(|dpar| dpar.name == name)
+            .unwrap()
+            .rhs;
+        let offset = offset_series(df, rhs)?;
+        dpars.insert(name, DparData { x, z, offset });
+    }
+    Ok(dpars)
+}
+
+/// The offset of a right-hand side as a column named `offset`.
+fn offset_series(df: &DataFrame, rhs: &Expr) -> Result<Option<Series>, Error> {
+    Ok(materialize_offset(df, rhs)?
+        .map(|offset| Float64Chunked::new("offset".into(), &offset).into_series()))
+}
+
+/// Names of the variables a spec refers to, in the response, the right-hand
+/// sides (including grouping factors and smooth covariates), the aterms and
+/// the time and group of the autocorrelation terms.
+/// Variables only used by `subset()` don't count, as it is applied first.
+fn referenced_columns(spec: &ModelSpec) -> Vec<String> {
+    let mut names = Vec::new();
+    response_vars(&spec.formula.lhs, &mut names);
+    collect_vars(&spec.formula.rhs, &mut names);
+    for dpar in &spec.dpars {
+        collect_vars(&dpar.rhs, &mut names);
+    }
+    for aterm in &spec.formula.aterms {
+        match aterm {
+            Aterm::Se(e)
+            | Aterm::Weights(e)
+            | Aterm::Trials(e)
+            | Aterm::Cens(e)
+            | Aterm::Rate(e)
+            | Aterm::Dec(e)
+            | Aterm::Cat(e)
+            | Aterm::Index(e) => collect_vars(e, &mut names),
+            Aterm::Trunc { lb, ub } => lb
+                .iter()
+                .chain(ub.iter())
+                .for_each(|e| collect_vars(e, &mut names)),
+            Aterm::Thres { gr } => gr.iter().for_each(|e| collect_vars(e, &mut names)),
+            Aterm::VReal(es) | Aterm::VInt(es) => {
+                es.iter().for_each(|e| collect_vars(e, &mut names))
+            }
+            Aterm::Subset(_) | Aterm::Mi => {}
+        }
+    }
+    names.extend(autocor_columns(spec));
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Names of the variables of a response.
+pub(crate) fn response_vars(response: &Response, names: &mut Vec<String>) {
+    match response {
+        Response::Var(name) => names.push(name.clone()),
+        Response::Multi { responses, .. } => responses.iter().for_each(|e| collect_vars(e, names)),
+        Response::Surv { time, event, time2 } => {
+            collect_vars(time, names);
+            collect_vars(event, names);
+            time2.iter().for_each(|e| collect_vars(e, names));
+        }
+        Response::Func { args, .. } => args.iter().for_each(|e| collect_vars(e, names)),
+        Response::BinomialTrials { successes, trials } => {
+            collect_vars(successes, names);
+            collect_vars(trials, names);
+        }
+    }
+}
+
+pub(crate) fn collect_vars(expr: &Expr, names: &mut Vec<String>) {
+    match expr {
+        Expr::Var(name) => names.push(name.clone()),
+        Expr::Sum(terms) | Expr::Prod(terms) | Expr::Interaction(terms) => {
+            terms.iter().for_each(|t| collect_vars(t, names))
+        }
+        Expr::Nest { outer, inner, .. } => {
+            collect_vars(outer, names);
+            collect_vars(inner, names);
+        }
+        Expr::Pow { base, exp } => {
+            collect_vars(base, names);
+            collect_vars(exp, names);
+        }
+        Expr::Group { inner, spec, .. } => {
+            collect_vars(inner, names);
+            match spec {
+                GroupSpec::Expr(group) => names.extend(group.0.iter().map(|(g, _)| g.clone())),
+                GroupSpec::Func { args, .. } => args.iter().for_each(|a| collect_vars(a, names)),
+            }
+        }
+        Expr::Smooth { vars, args, .. } => {
+            names.extend(vars.iter().cloned());
+            args.values().for_each(|a| collect_vars(a, names));
+        }
+        Expr::Func { args, .. } => args.iter().for_each(|a| collect_vars(a, names)),
+        Expr::Named { value, .. } => collect_vars(value, names),
+        Expr::Identity(inner) => collect_vars(inner, names),
+        Expr::Num(_) | Expr::Bool(_) | Expr::Str(_) | Expr::Intercept(_) | Expr::Dot => {}
+    }
+}
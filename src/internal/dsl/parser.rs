@@ -93,84 +93,61 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
     // forward decls
     let expr = recursive(|expr| {
         // smooths: s(x, k=10, bs="tp"), t2(x,z), te(...), ti(...)
-        let varlist = ident
-            .clone()
-            .separated_by(just(','))
-            .at_least(1)
-            .collect::<Vec<_>>();
-        let named_arg = choice((
-            just("k").to("k".to_string()),
-            just("bs").to("bs".to_string()),
-            just("by").to("by".to_string()),
-            just("id").to("id".to_string()),
-            just("sp").to("sp".to_string()),
-            just("fx").to("fx".to_string()),
-            just("knots").to("knots".to_string()),
-            just("xt").to("xt".to_string()),
-            just("pc").to("pc".to_string()),
-            text::ident(),
-        ))
-        .then_ignore(just('=').padded())
-        .then(expr.clone())
-        .map(|(k, v)| (k, v));
-
-        let smooth_args = named_arg
-            .separated_by(just(','))
-            .allow_trailing()
-            .map(|kvs: Vec<(String, Expr)>| kvs.into_iter().collect::<HashMap<_, _>>());
+        // Variables come first, followed by named options such as k=, bs= or by=
+        let named_arg = ident
+            .padded()
+            .then_ignore(just('='))
+            .then(expr.clone().padded());
+        let smooth_var = ident.padded().then_ignore(just('=').not().rewind());
 
         let smooth = choice((
-            just("s")
-                .ignore_then(just('('))
-                .ignore_then(varlist.clone())
-                .then(just(',').ignore_then(smooth_args.clone()).or_not())
-                .then_ignore(just(')'))
-                .map(|(vars, args)| Expr::Smooth {
-                    kind: SmoothKind::S,
-                    vars,
-                    args: args.unwrap_or_default(),
-                }),
-            just("t2")
-                .ignore_then(just('('))
-                .ignore_then(varlist.clone())
-                .then(just(',').ignore_then(smooth_args.clone()).or_not())
-                .then_ignore(just(')'))
-                .map(|(vars, args)| Expr::Smooth {
-                    kind: SmoothKind::T2,
-                    vars,
-                    args: args.unwrap_or_default(),
-                }),
-            just("te")
-                .ignore_then(just('('))
-                .ignore_then(varlist.clone())
-                .then(just(',').ignore_then(smooth_args.clone()).or_not())
-                .then_ignore(just(')'))
-                .map(|(vars, args)| Expr::Smooth {
-                    kind: SmoothKind::TE,
-                    vars,
-                    args: args.unwrap_or_default(),
-                }),
-            just("ti")
-                .ignore_then(just('('))
-                .ignore_then(varlist.clone())
-                .then(just(',').ignore_then(smooth_args.clone()).or_not())
-                .then_ignore(just(')'))
-                .map(|(vars, args)| Expr::Smooth {
-                    kind: SmoothKind::TI,
-                    vars,
-                    args: args.unwrap_or_default(),
-                }),
-        ));
+            just("s").to(SmoothKind::S),
+            just("t2").to(SmoothKind::T2),
+            just("te").to(SmoothKind::TE),
+            just("ti").to(SmoothKind::TI),
+        ))
+        .then_ignore(just('('))
+        .then(smooth_var.separated_by(just(',')).at_least(1))
+        .then(
+            just(',')
+                .ignore_then(named_arg.separated_by(just(',')).allow_trailing())
+                .or_not(),
+        )
+        .then_ignore(just(')'))
+        .map(|((kind, vars), args)| Expr::Smooth {
+            kind,
+            vars,
+            args: args
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+        });
 
-        // group terms: (1|g), (x||g), (1|ID|g)
+        // func_call (includes dotted); arguments may be named, e.g. bs(x, df=5)
+        let call_arg = dotted_ident
+            .padded()
+            .then_ignore(just('='))
+            .then(expr.clone())
+            .map(|(name, value)| Expr::Named {
+                name,
+                value: Box::new(value),
+            })
+            .or(expr.clone());
+        let args = call_arg.separated_by(just(',')).allow_trailing();
+        let func_call = dotted_ident
+            .clone()
+            .then(just('(').ignore_then(args.clone()).then_ignore(just(')')))
+            .map(|(name, args)| Expr::Func { name, args });
+
+        // group terms: (1|g), (x||g), (1|p|g) with any id label
         let group_op = choice((
             just(':').to(GroupOp::Cross),
             just('/').to(GroupOp::Nest),
             just('+').to(GroupOp::Split),
         ));
         let group_expr = ident
-            .clone()
-            .then((group_op.then(ident.clone())).repeated())
+            .padded()
+            .then((group_op.padded().then(ident.padded())).repeated())
             .map(|(g1, tail)| {
                 let mut v = vec![(g1, None)];
                 for (op, name) in tail {
@@ -179,50 +156,61 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
                 GroupExpr(v)
             });
 
-        let group_spec = group_expr.clone().map(GroupSpec::Expr).or(dotted_ident
+        // Grouping functions such as gr(g, by=x) and mm(g1, g2) come first, as
+        // their name alone would already parse as a group expression
+        let group_spec = dotted_ident
             .clone()
-            .then(
-                just('(')
-                    .ignore_then(expr.clone().separated_by(just(',')).allow_trailing())
-                    .then_ignore(just(')')),
-            )
-            .map(|(name, args)| GroupSpec::Func { name, args }));
+            .padded()
+            .then(just('(').ignore_then(args.clone()).then_ignore(just(')')))
+            .map(|(name, args)| GroupSpec::Func { name, args })
+            .padded()
+            .or(group_expr.clone().map(GroupSpec::Expr));
 
         // Group inner expressions can include sums, but need to be parsed carefully
-        // to avoid conflicts with the | character
-        let group_inner = choice((
+        // to avoid conflicts with the | character. Terms may be function calls
+        // and interactions, e.g. (poly(x, 2)|g) or (x:z|g)
+        let group_factor = choice((
+            func_call.clone(),
+            ident.map(Expr::Var),
+            just('(').ignore_then(expr.clone()).then_ignore(just(')')),
+        ))
+        .padded()
+        .separated_by(just(':'))
+        .at_least(1)
+        .map(|mut xs: Vec<Expr>| {
+            if xs.len() == 1 {
+                xs.remove(0)
+            } else {
+                Expr::Interaction(xs)
+            }
+        });
+        let group_item = choice((
             just('0').to(Expr::Intercept(false)),
             just('1').to(Expr::Intercept(true)),
+            group_factor,
         ))
-        .or(ident.clone().map(Expr::Var))
-        .or(just('(').ignore_then(expr.clone()).then_ignore(just(')')))
-        .then(
-            (one_of("+-").padded().then(choice((
-                just('0').to(Expr::Intercept(false)),
-                just('1').to(Expr::Intercept(true)),
-                ident.clone().map(Expr::Var),
-                just('(').ignore_then(expr.clone()).then_ignore(just(')')),
-            ))))
-            .repeated(),
-        )
-        .map(|(head, tail)| {
-            if tail.is_empty() {
-                return head;
-            }
-            let mut xs = vec![head];
-            for (op, term) in tail {
-                if op == '-' {
-                    xs.push(Expr::Func {
-                        name: "NEG".into(),
-                        args: vec![term],
-                    });
-                } else {
-                    xs.push(term);
+        .padded();
+        let group_inner = group_item
+            .clone()
+            .then((one_of("+-").padded().then(group_item)).repeated())
+            .map(|(head, tail)| {
+                if tail.is_empty() {
+                    return head;
                 }
-            }
-            Expr::Sum(xs)
-        })
-        .or(expr.clone());
+                let mut xs = vec![head];
+                for (op, term) in tail {
+                    if op == '-' {
+                        xs.push(Expr::Func {
+                            name: "NEG".into(),
+                            args: vec![term],
+                        });
+                    } else {
+                        xs.push(term);
+                    }
+                }
+                Expr::Sum(xs)
+            })
+            .or(expr.clone());
 
         let group_term = choice((
             just('(')
@@ -250,25 +238,18 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
             just('(')
                 .ignore_then(group_inner.clone())
                 .then_ignore(just('|').padded())
-                .then_ignore(just("ID"))
+                .then(ident.padded())
                 .then_ignore(just('|').padded())
                 .then(group_spec.clone())
                 .then_ignore(just(')'))
-                .map(|(inner, spec)| Expr::Group {
+                .map(|((inner, id), spec)| Expr::Group {
                     inner: Box::new(inner),
                     spec,
                     kind: GroupKind::Correlated,
-                    id: Some("ID".into()),
+                    id: Some(id),
                 }),
         ));
 
-        // func_call (includes dotted)
-        let args = expr.clone().separated_by(just(',')).allow_trailing();
-        let func_call = dotted_ident
-            .clone()
-            .then(just('(').ignore_then(args.clone()).then_ignore(just(')')))
-            .map(|(name, args)| Expr::Func { name, args });
-
         // atoms
         let atom = choice((
             number.clone(),
@@ -350,9 +331,17 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
         });
 
         // sum: prod (('+'|'-') prod)*
+        // A term followed by `~` starts the next formula instead, e.g. `sigma`
+        // in y ~ x + sigma ~ z
+        let not_dpar = just('~').not().rewind().ignored().or(end());
         let sum = prod
             .clone()
-            .then((one_of("+-").padded().then(prod.clone())).repeated())
+            .then(
+                (one_of("+-")
+                    .padded()
+                    .then(prod.clone().then_ignore(not_dpar)))
+                .repeated(),
+            )
             .map(|(head, tail)| {
                 if tail.is_empty() {
                     return head;
@@ -374,20 +363,17 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
         sum.padded()
     });
 
-    // LHS (response)
-    let response_basic = ident
-        .clone()
-        .map(|v| Response::Var(v))
-        .or(just("mvbind")
-            .ignore_then(just('('))
-            .ignore_then(ident.clone().separated_by(just(',')).at_least(2))
-            .then_ignore(just(')'))
-            .map(Response::Multi))
-        .or(just("cbind")
-            .ignore_then(just('('))
-            .ignore_then(ident.clone().separated_by(just(',')).at_least(2))
-            .then_ignore(just(')'))
-            .map(Response::Multi))
+    // LHS (response); calls come first, as their name alone would already
+    // parse as a variable
+    let response_basic = just("mvbind")
+        .or(just("cbind"))
+        .then_ignore(just('('))
+        .then(expr.clone().separated_by(just(',')).at_least(2))
+        .then_ignore(just(')'))
+        .map(|(name, responses)| Response::Multi {
+            name: name.to_string(),
+            responses,
+        })
         .or(just("Surv")
             .ignore_then(just('('))
             .ignore_then(expr.clone())
@@ -395,7 +381,19 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
             .then(expr.clone())
             .then(just(',').ignore_then(expr.clone()).or_not())
             .then_ignore(just(')'))
-            .map(|((time, event), time2)| Response::Surv { time, event, time2 }))
+            .map(|((time, second), third)| match third {
+                // Surv(start, stop, event) for counting-process data
+                Some(event) => Response::Surv {
+                    time,
+                    event,
+                    time2: Some(second),
+                },
+                None => Response::Surv {
+                    time,
+                    event: second,
+                    time2: None,
+                },
+            }))
         .or(dotted_ident
             .clone()
             .then(
@@ -403,7 +401,65 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
                     .ignore_then(expr.clone().separated_by(just(',')).allow_trailing())
                     .then_ignore(just(')')),
             )
-            .map(|(name, args)| Response::Func { name, args }));
+            .map(|(name, args)| Response::Func { name, args }))
+        .or(ident.clone().map(Response::Var));
+
+    // Row conditions of subset(), e.g. age > 18 & sex == "m". Like NEG, the
+    // operators are encoded as calls: `>`(age, 18)
+    let condition = recursive(|condition| {
+        let binary = |name: &str, lhs: Expr, rhs: Expr| Expr::Func {
+            name: name.to_string(),
+            args: vec![lhs, rhs],
+        };
+        let compare_op = choice((
+            just("=="),
+            just("!="),
+            just("<="),
+            just(">="),
+            just("<"),
+            just(">"),
+        ))
+        .padded();
+        let comparison = expr
+            .clone()
+            .then(compare_op.then(expr.clone()).or_not())
+            .map(move |(lhs, rhs)| match rhs {
+                Some((op, rhs)) => binary(op, lhs, rhs),
+                None => lhs,
+            });
+        let atom = comparison.or(just('(')
+            .padded()
+            .ignore_then(condition)
+            .then_ignore(just(')').padded()));
+        let negation = just('!')
+            .padded()
+            .repeated()
+            .then(atom)
+            .foldr(|_, operand| Expr::Func {
+                name: "!".into(),
+                args: vec![operand],
+            });
+        let conjunction = negation
+            .clone()
+            .then(
+                just("&&")
+                    .or(just("&"))
+                    .padded()
+                    .ignore_then(negation)
+                    .repeated(),
+            )
+            .foldl(move |lhs, rhs| binary("&", lhs, rhs));
+        conjunction
+            .clone()
+            .then(
+                just("||")
+                    .or(just("|"))
+                    .padded()
+                    .ignore_then(conjunction)
+                    .repeated(),
+            )
+            .foldl(move |lhs, rhs| binary("|", lhs, rhs))
+    });
 
     // Proper aterm parsing
     let aterm = choice((
@@ -431,12 +487,15 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
             .ignore_then(just('('))
             .ignore_then(
                 just("lb")
+                    .padded()
                     .ignore_then(just('=').padded())
                     .ignore_then(expr.clone())
                     .or_not()
                     .then(
                         just(',')
-                            .ignore_then(just("ub"))
+                            .padded()
+                            .or_not()
+                            .ignore_then(just("ub").padded())
                             .ignore_then(just('=').padded())
                             .ignore_then(expr.clone())
                             .or_not(),
@@ -446,7 +505,7 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
             .map(|(lb, ub)| Aterm::Trunc { lb, ub }),
         just("subset")
             .ignore_then(just('('))
-            .ignore_then(expr.clone())
+            .ignore_then(condition)
             .then_ignore(just(')'))
             .map(Aterm::Subset),
         just("rate")
@@ -495,7 +554,12 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
             .to(Aterm::Mi),
     ));
 
-    let aterm_chain = aterm.clone().separated_by(just(',')).collect::<Vec<_>>();
+    // Aterms are joined with `+` as in brms, e.g. y | se(s) + cens(c)
+    let aterm_chain = aterm
+        .clone()
+        .padded()
+        .separated_by(one_of(",+"))
+        .collect::<Vec<_>>();
 
     let response = response_basic
         .clone()
@@ -523,27 +587,42 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
     // RHS
     let rhs = expr.clone();
 
-    // Optional header
+    // Optional header; family arguments may be named, e.g. poisson(link="sqrt")
+    let family_arg = ident
+        .padded()
+        .then_ignore(just('='))
+        .then(expr.clone())
+        .map(|(name, value)| Expr::Named {
+            name,
+            value: Box::new(value),
+        })
+        .or(expr.clone());
     let family_spec = dotted_ident
         .clone()
         .then(
             just('(')
-                .ignore_then(expr.clone().separated_by(just(',')).allow_trailing())
+                .ignore_then(family_arg.separated_by(just(',')).allow_trailing())
                 .then_ignore(just(')')),
         )
         .map(|(name, args)| Family::Builtin(name, args));
 
-    let family = family_spec
-        .clone()
-        .or(just("mixture")
-            .ignore_then(just('('))
-            .ignore_then(family_spec.clone().separated_by(just(',')).at_least(2))
-            .then_ignore(just(')'))
-            .map(Family::Mixture))
+    // mixture() and custom_family() come first, as they would also parse as a
+    // builtin family
+    let family = just("mixture")
+        .ignore_then(just('('))
+        .ignore_then(
+            family_spec
+                .clone()
+                .padded()
+                .separated_by(just(','))
+                .at_least(2),
+        )
+        .then_ignore(just(')'))
+        .map(Family::Mixture)
         .or(just("custom_family")
             .ignore_then(just('('))
-            .ignore_then(string.clone())
-            .then(just(',').ignore_then(string.clone()).repeated())
+            .ignore_then(string.clone().padded())
+            .then(just(',').ignore_then(string.clone().padded()).repeated())
             .then_ignore(just(')'))
             .map(|(name, dpars)| Family::Custom {
                 name: if let Expr::Str(s) = name {
@@ -555,7 +634,8 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
                     .into_iter()
                     .map(|e| if let Expr::Str(s) = e { s } else { "".into() })
                     .collect(),
-            }));
+            }))
+        .or(family_spec.clone());
 
     let link = dotted_ident
         .clone()
@@ -572,31 +652,20 @@ pub fn parser() -> impl Parser<char, ModelSpec, Error = Simple<char>> {
         .ignore_then(family)
         .then(
             just(',')
+                .padded()
                 .ignore_then(just("link"))
                 .ignore_then(just('=').padded())
                 .ignore_then(link)
                 .or_not(),
         );
 
-    // Distributional parameter formulas
-    let dpar_name = choice((
-        just("sigma").to("sigma".to_string()),
-        just("nu").to("nu".to_string()),
-        just("phi").to("phi".to_string()),
-        just("zi").to("zi".to_string()),
-        just("hu").to("hu".to_string()),
-        just("zoi").to("zoi".to_string()),
-        just("coi").to("coi".to_string()),
-        just("kappa").to("kappa".to_string()),
-        just("beta").to("beta".to_string()),
-        just("disc").to("disc".to_string()),
-        just("bs").to("bs".to_string()),
-        just("ndt").to("ndt".to_string()),
-        just("bias").to("bias".to_string()),
-    ));
+    // Distributional parameter formulas; any name parses, and is checked
+    // against the family after canonicalization
+    let dpar_name = ident.clone();
 
     let dpar_formula = dpar_name
-        .then_ignore(just('~'))
+        .padded()
+        .then_ignore(just('~').padded())
         .then(rhs.clone())
         .map(|(name, rhs)| Dpar { name, rhs });
 
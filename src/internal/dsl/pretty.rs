@@ -1,4 +1,5 @@
 use super::ast::*;
+use super::autocor::ordered_args;
 
 /// Pretty-print a ModelSpec as a formula string.
 ///
@@ -88,16 +89,11 @@ pub fn pretty(spec: &ModelSpec) -> String {
 
     // Add autocorrelation terms
     for autocor in &spec.autocor {
-        let args_str = if autocor.args.is_empty() {
-            String::new()
-        } else {
-            autocor
-                .args
-                .iter()
-                .map(|(k, v)| format!("{}={}", k, pretty_expr(v)))
-                .collect::<Vec<_>>()
-                .join(", ")
-        };
+        let args_str = ordered_args(autocor)
+            .into_iter()
+            .map(|(k, v)| format!("{}={}", k, pretty_expr(v)))
+            .collect::<Vec<_>>()
+            .join(", ");
         parts.push(format!("{}({})", autocor.name, args_str));
     }
 
@@ -149,16 +145,21 @@ fn pretty_link(link: &Link) -> String {
 fn pretty_response(response: &Response) -> String {
     match response {
         Response::Var(v) => v.clone(),
-        Response::Multi(vars) => {
-            format!("cbind({})", vars.join(", "))
+        Response::Multi { name, responses } => {
+            let args_str = responses
+                .iter()
+                .map(pretty_expr)
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{}({})", name, args_str)
         }
         Response::Surv { time, event, time2 } => {
             if let Some(time2) = time2 {
                 format!(
                     "Surv({}, {}, {})",
                     pretty_expr(time),
-                    pretty_expr(event),
-                    pretty_expr(time2)
+                    pretty_expr(time2),
+                    pretty_expr(event)
                 )
             } else {
                 format!("Surv({}, {})", pretty_expr(time), pretty_expr(event))
@@ -311,11 +312,24 @@ pub fn pretty_expr(expr: &Expr) -> String {
         Expr::Bool(b) => b.to_string(),
         Expr::Str(s) => format!("\"{}\"", s),
         Expr::Var(v) => v.clone(),
-        Expr::Sum(terms) => terms
-            .iter()
-            .map(pretty_expr)
-            .collect::<Vec<_>>()
-            .join(" + "),
+        Expr::Sum(terms) => {
+            let mut out = String::new();
+            for (i, term) in terms.iter().enumerate() {
+                match term {
+                    Expr::Func { name, args } if name == "NEG" && args.len() == 1 && i > 0 => {
+                        out.push_str(" - ");
+                        out.push_str(&pretty_expr(&args[0]));
+                    }
+                    _ => {
+                        if i > 0 {
+                            out.push_str(" + ");
+                        }
+                        out.push_str(&pretty_expr(term));
+                    }
+                }
+            }
+            out
+        }
         Expr::Prod(terms) => terms
             .iter()
             .map(|t| match t {
@@ -372,15 +386,31 @@ pub fn pretty_expr(expr: &Expr) -> String {
                 SmoothKind::TI => "ti",
             };
             let mut parts = vec![vars.join(", ")];
-            for (k, v) in args {
+            let mut named: Vec<_> = args.iter().collect();
+            named.sort_by(|a, b| a.0.cmp(b.0));
+            for (k, v) in named {
                 parts.push(format!("{}={}", k, pretty_expr(v)));
             }
             format!("{}({})", kind_str, parts.join(", "))
         }
-        Expr::Func { name, args } => {
-            let args_str = args.iter().map(pretty_expr).collect::<Vec<_>>().join(", ");
-            format!("{}({})", name, args_str)
-        }
+        Expr::Func { name, args } => match (name.as_str(), args.as_slice()) {
+            // Conditions of subset()
+            ("==" | "!=" | "<" | "<=" | ">" | ">=", [lhs, rhs]) => {
+                format!("{} {} {}", pretty_expr(lhs), name, pretty_expr(rhs))
+            }
+            ("&" | "|", [lhs, rhs]) => format!(
+                "{} {} {}",
+                pretty_operand(lhs, name),
+                name,
+                pretty_operand(rhs, name)
+            ),
+            ("!", [operand]) => format!("!{}", pretty_operand(operand, name)),
+            _ => {
+                let args_str = args.iter().map(pretty_expr).collect::<Vec<_>>().join(", ");
+                format!("{}({})", name, args_str)
+            }
+        },
+        Expr::Named { name, value } => format!("{}={}", name, pretty_expr(value)),
         Expr::Identity(inner) => {
             format!("I({})", pretty_expr(inner))
         }
@@ -390,6 +420,23 @@ pub fn pretty_expr(expr: &Expr) -> String {
     }
 }
 
+/// An operand of a logical operator, in parentheses when it binds more loosely.
+fn pretty_operand(expr: &Expr, op: &str) -> String {
+    let looser = match expr {
+        Expr::Func { name, args } if args.len() == 2 => match op {
+            "!" => ["==", "!=", "<", "<=", ">", ">=", "&", "|"].contains(&name.as_str()),
+            "&" => name == "|",
+            _ => false,
+        },
+        _ => false,
+    };
+    if looser {
+        format!("({})", pretty_expr(expr))
+    } else {
+        pretty_expr(expr)
+    }
+}
+
 fn pretty_group_spec(spec: &GroupSpec) -> String {
     match spec {
         GroupSpec::Expr(group_expr) => group_expr
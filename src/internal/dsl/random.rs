@@ -0,0 +1,429 @@
+//! Random-effects blocks for group terms such as `(1|g)` and `(0 + x|g)`.
+//!
+//! A group term is the product of a grouping factor and the columns of its
+//! inner model matrix. [`RandomBlock`] keeps both factors separately (the level
+//! of each row and the inner columns), from which the dense Z columns or the
+//! sparse Z matrix are generated without ever allocating `n × levels` zeros.
+//! Multi-membership terms `mm(g1, g2)` put a row in several levels at once,
+//! each with a weight.
+
+use super::sparse::GroupBy;
+use crate::Error;
+use polars::prelude::*;
+use std::collections::HashMap;
+
+/// Label of the intercept in the inner model matrix, as in lme4's `cnms`.
+pub(crate) const INTERCEPT: &str = "(Intercept)";
+
+/// A grouping factor crossed with the inner model matrix of a group term.
+#[derive(Debug, Clone)]
+pub(crate) struct RandomBlock {
+    /// Name of the grouping factor, e.g. `Subject`.
+    pub group: String,
+    /// Sorted levels of the grouping factor.
+    pub levels: Vec<String>,
+    /// Levels of each row with their weights: a single `(level, 1.0)` for a
+    /// plain grouping factor, one entry per member for `mm()`, and none where
+    /// the grouping factor is null.
+    pub members: Vec<Vec<(usize, f64)>>,
+    /// Inner columns: label (`(Intercept)` or the variable) and values per row,
+    /// `None` where the inner expression is missing.
+    pub inner: Vec<(String, Vec<Option<f64>>)>,
+    /// The `by` variable of `gr(g, by = x)`.
+    pub by: Option<GroupBy>,
+    /// `false` for `gr(g, cor = FALSE)`, whose inner columns are uncorrelated.
+    pub cor: bool,
+    /// The `id` label of `gr(g, id = "a")`.
+    pub id: Option<String>,
+}
+
+impl RandomBlock {
+    /// A block of a plain grouping factor, given the level of every row.
+    pub(crate) fn new(
+        group: String,
+        levels: Vec<String>,
+        row_levels: Vec<Option<usize>>,
+        inner: Vec<(String, Vec<Option<f64>>)>,
+    ) -> Self {
+        let members = row_levels
+            .into_iter()
+            .map(|level| level.map(|level| (level, 1.0)).into_iter().collect())
+            .collect();
+        Self {
+            group,
+            levels,
+            members,
+            inner,
+            by: None,
+            cor: true,
+            id: None,
+        }
+    }
+
+    /// Index the block by previously learned `levels` of its grouping factor,
+    /// so new data gets the same columns. Members in other levels are dropped,
+    /// leaving their rows without random effects. A learned `by` mapping of
+    /// `gr(g, by = x)` replaces the one found in the data.
+    pub(crate) fn relevel(
+        mut self,
+        levels: &[String],
+        by: Option<&GroupBy>,
+    ) -> Result<Self, Error> {
+        if let Some(by) = by {
+            self.by = Some(by.clone());
+        }
+        if self.levels == levels {
+            return Ok(self);
+        }
+        let index: HashMap<&str, usize> = levels
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (l.as_str(), i))
+            .collect();
+        let new_level: Vec<Option<usize>> = self
+            .levels
+            .iter()
+            .map(|level| index.get(level.as_str()).copied())
+            .collect();
+        for members in &mut self.members {
+            *members = members
+                .iter()
+                .filter_map(|&(level, weight)| new_level[level].map(|level| (level, weight)))
+                .collect();
+        }
+        if let (None, Some(by)) = (by, &mut self.by) {
+            let mut level_of_group = vec![None; levels.len()];
+            for (old, new) in new_level.iter().enumerate() {
+                if let Some(new) = new {
+                    level_of_group[*new] = Some(by.level_of_group[old]);
+                }
+            }
+            by.level_of_group = level_of_group
+                .into_iter()
+                .enumerate()
+                .map(|(level, by_level)| {
+                    by_level.ok_or_else(|| {
+                        Error::Semantic(format!(
+                            "Level '{}' of '{}' is not in the data, so its level of by variable '{}' is unknown",
+                            levels[level], self.group, by.variable
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        self.levels = levels.to_vec();
+        Ok(self)
+    }
+
+    /// Number of Z columns: one per level and inner column.
+    pub(crate) fn ncols(&self) -> usize {
+        self.levels.len() * self.inner.len()
+    }
+
+    /// Z column names, level-major as in lme4: all inner columns of the first
+    /// level, then those of the second, and so on.
+    pub(crate) fn column_names(&self) -> Vec<String> {
+        let mut names = Vec::with_capacity(self.ncols());
+        for level in &self.levels {
+            for (label, _) in &self.inner {
+                names.push(if label == INTERCEPT {
+                    format!("ri({}={})", self.group, level)
+                } else {
+                    format!("rs({}|{}={})", label, self.group, level)
+                });
+            }
+        }
+        names
+    }
+
+    /// Rows belonging to each level with their weights, in increasing order.
+    pub(crate) fn rows_by_level(&self) -> Vec<Vec<(usize, f64)>> {
+        let mut rows = vec![Vec::new(); self.levels.len()];
+        for (i, members) in self.members.iter().enumerate() {
+            for &(level, weight) in members {
+                rows[level].push((i, weight));
+            }
+        }
+        rows
+    }
+
+    /// Dense Z columns (zeros outside each level). A missing inner value is
+    /// missing in the column of the level of its row, as it is in X.
+    pub(crate) fn dense_columns(&self) -> Vec<(String, Series)> {
+        let n = self.members.len();
+        let names = self.column_names();
+        let mut names = names.into_iter();
+        let mut cols = Vec::with_capacity(self.ncols());
+        for rows in self.rows_by_level() {
+            for (_, values) in &self.inner {
+                let mut col_data = vec![Some(0.0); n];
+                for &(i, weight) in &rows {
+                    col_data[i] = values[i].map(|value| weight * value);
+                }
+                let col_name = names.next().unwrap_or_default();
+                let series =
+                    Float64Chunked::from_slice_options(col_name.as_str().into(), &col_data)
+                        .into_series();
+                cols.push((col_name, series));
+            }
+        }
+        cols
+    }
+}
+
+/// Cross the inner columns of a group term with its grouping factor.
+///
+/// Several `factors` form their interaction `a:b`: one level per combination
+/// present in the data, labelled `a1:b1` and ordered by the levels of `a`, then
+/// of `b`.
+pub(crate) fn build_random_block(
+    df: &DataFrame,
+    inner: Vec<(String, Vec<Option<f64>>)>,
+    factors: &[String],
+) -> Result<RandomBlock, Error> {
+    let group = factors.join(":");
+    if factors.len() == 1 {
+        let (levels, row_levels) = group_levels(df, &factors[0])?;
+        return Ok(RandomBlock::new(group, levels, row_levels, inner));
+    }
+
+    let per_factor = factors
+        .iter()
+        .map(|factor| group_levels(df, factor))
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys: Vec<Option<Vec<usize>>> = (0..df.height())
+        .map(|i| {
+            per_factor
+                .iter()
+                .map(|(_, row_levels)| row_levels[i])
+                .collect()
+        })
+        .collect();
+    let mut combinations: Vec<&Vec<usize>> = keys.iter().flatten().collect();
+    combinations.sort();
+    combinations.dedup();
+
+    let index: HashMap<&Vec<usize>, usize> = combinations
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (*key, i))
+        .collect();
+    let row_levels = keys
+        .iter()
+        .map(|key| key.as_ref().map(|key| index[key]))
+        .collect();
+    let levels = combinations
+        .iter()
+        .map(|key| {
+            key.iter()
+                .zip(&per_factor)
+                .map(|(&level, (labels, _))| labels[level].as_str())
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+        .collect();
+    Ok(RandomBlock::new(group, levels, row_levels, inner))
+}
+
+/// Cross the inner columns with a multi-membership factor `mm(g1, g2, ...)`.
+///
+/// The levels are the union of the levels of all `factors`; every row belongs
+/// to the level of each factor, weighted by the matching `weights` column or
+/// equally when there is none. With `scale`, the weights of a row are
+/// rescaled to sum to one, as in brms. Members naming the same level add up.
+pub(crate) fn build_multi_membership_block(
+    df: &DataFrame,
+    inner: Vec<(String, Vec<Option<f64>>)>,
+    factors: &[String],
+    weights: Option<&[String]>,
+    scale: bool,
+) -> Result<RandomBlock, Error> {
+    let per_factor = factors
+        .iter()
+        .map(|factor| group_levels(df, factor))
+        .collect::<Result<Vec<_>, _>>()?;
+    let weights = match weights {
+        Some(columns) => columns
+            .iter()
+            .map(|column| weight_values(df, column))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![vec![1.0 / factors.len() as f64; df.height()]; factors.len()],
+    };
+
+    // Union of the levels, ordered like the levels of a single factor
+    let mut levels: Vec<String> = per_factor
+        .iter()
+        .flat_map(|(labels, _)| labels.iter().cloned())
+        .collect();
+    let numeric = factors.iter().all(|factor| {
+        df.column(factor)
+            .map(|c| c.dtype().is_primitive_numeric())
+            .unwrap_or(false)
+    });
+    if numeric {
+        levels.sort_by(|a, b| {
+            let (a, b) = (a.parse::<f64>(), b.parse::<f64>());
+            a.unwrap_or(f64::NAN).total_cmp(&b.unwrap_or(f64::NAN))
+        });
+    } else {
+        levels.sort();
+    }
+    levels.dedup();
+    let index: HashMap<&str, usize> = levels
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.as_str(), i))
+        .collect();
+
+    let members = (0..df.height())
+        .map(|i| {
+            let mut row: Vec<(usize, f64)> = Vec::with_capacity(factors.len());
+            for ((labels, row_levels), weights) in per_factor.iter().zip(&weights) {
+                let Some(level) = row_levels[i] else { continue };
+                let level = index[labels[level].as_str()];
+                match row.iter_mut().find(|(l, _)| *l == level) {
+                    Some((_, weight)) => *weight += weights[i],
+                    None => row.push((level, weights[i])),
+                }
+            }
+            let total: f64 = row.iter().map(|(_, w)| w).sum();
+            if scale && total != 0.0 {
+                for (_, weight) in &mut row {
+                    *weight /= total;
+                }
+            }
+            row
+        })
+        .collect();
+
+    Ok(RandomBlock {
+        group: format!("mm({})", factors.join(", ")),
+        levels,
+        members,
+        inner,
+        by: None,
+        cor: true,
+        id: None,
+    })
+}
+
+/// Map every level of a block's grouping factor to the level of `by`.
+///
+/// As in brms, each level of the grouping factor must fall within a single
+/// level of the `by` variable.
+pub(crate) fn group_by(df: &DataFrame, block: &RandomBlock, by: &str) -> Result<GroupBy, Error> {
+    let (by_levels, by_rows) = group_levels(df, by)?;
+    let mut level_of_group: Vec<Option<usize>> = vec![None; block.levels.len()];
+    for (members, by_level) in block.members.iter().zip(by_rows) {
+        let Some(by_level) = by_level else { continue };
+        for &(level, _) in members {
+            match level_of_group[level] {
+                Some(existing) if existing != by_level => {
+                    return Err(Error::Semantic(format!(
+                        "Level '{}' of '{}' belongs to several levels of by variable '{}'",
+                        block.levels[level], block.group, by
+                    )));
+                }
+                _ => level_of_group[level] = Some(by_level),
+            }
+        }
+    }
+    let level_of_group = level_of_group
+        .into_iter()
+        .enumerate()
+        .map(|(level, by_level)| {
+            by_level.ok_or_else(|| {
+                Error::Semantic(format!(
+                    "Level '{}' of '{}' has no value of by variable '{}'",
+                    block.levels[level], block.group, by
+                ))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(GroupBy {
+        variable: by.to_string(),
+        levels: by_levels,
+        level_of_group,
+    })
+}
+
+/// Values of a numeric weight column, with nulls as zero weights.
+fn weight_values(df: &DataFrame, column: &str) -> Result<Vec<f64>, Error> {
+    let series = df
+        .column(column)
+        .map_err(|_| Error::Semantic(format!("Weight variable '{}' not found", column)))?
+        .as_materialized_series()
+        .cast(&DataType::Float64)
+        .map_err(|e| Error::Semantic(e.to_string()))?;
+    Ok(series
+        .f64()
+        .map_err(|e| Error::Semantic(e.to_string()))?
+        .into_iter()
+        .map(|v| v.unwrap_or(0.0))
+        .collect())
+}
+
+/// Sorted levels of a grouping variable and the level index of every row.
+///
+/// String groups sort lexicographically and numeric groups numerically; levels
+/// are labelled by their string form. Null groups get no level.
+pub(crate) fn group_levels(
+    df: &DataFrame,
+    group_var: &str,
+) -> Result<(Vec<String>, Vec<Option<usize>>), Error> {
+    let group_series = df
+        .column(group_var)
+        .map_err(|_| Error::Semantic(format!("Group variable '{}' not found", group_var)))?
+        .as_materialized_series();
+
+    let (levels, keys) = if let Ok(str_series) = group_series.str() {
+        let keys: Vec<Option<String>> = str_series
+            .into_iter()
+            .map(|s| s.map(str::to_string))
+            .collect();
+        let mut levels: Vec<String> = keys.iter().flatten().cloned().collect();
+        levels.sort();
+        levels.dedup();
+        (levels, keys)
+    } else if group_series.dtype().is_primitive_numeric() {
+        let integer = group_series.dtype().is_integer();
+        let cast = group_series
+            .cast(&DataType::Float64)
+            .map_err(|e| Error::Semantic(e.to_string()))?;
+        let values: Vec<Option<f64>> = cast
+            .f64()
+            .map_err(|e| Error::Semantic(e.to_string()))?
+            .into_iter()
+            .collect();
+        let mut sorted: Vec<f64> = values.iter().flatten().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        sorted.dedup();
+        let label = |v: f64| {
+            if integer {
+                (v as i64).to_string()
+            } else {
+                v.to_string()
+            }
+        };
+        let levels: Vec<String> = sorted.into_iter().map(label).collect();
+        let keys: Vec<Option<String>> = values.into_iter().map(|v| v.map(label)).collect();
+        (levels, keys)
+    } else {
+        return Err(Error::Semantic(
+            "Group variable must be string or numeric".into(),
+        ));
+    };
+    let row_levels = index_rows(&levels, &keys);
+    Ok((levels, row_levels))
+}
+
+fn index_rows(levels: &[String], keys: &[Option<String>]) -> Vec<Option<usize>> {
+    let index: HashMap<&str, usize> = levels
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.as_str(), i))
+        .collect();
+    keys.iter()
+        .map(|k| k.as_deref().and_then(|k| index.get(k).copied()))
+        .collect()
+}
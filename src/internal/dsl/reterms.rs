@@ -0,0 +1,229 @@
+//! Random-effects structure for mixed model fitting, after lme4's `mkReTrms`.
+//!
+//! The random effects are `b = Λ u` with `u ~ N(0, σ² I)`. The relative
+//! covariance factor Λ is block diagonal with one block per term and level;
+//! within a term every level shares the same lower-triangular template, whose
+//! entries are the variance parameters θ (`gr(g, by = x)` has one template per
+//! level of `x`). [`ReTerms`] holds Z together with the sparsity pattern of Λᵀ,
+//! the map from its non-zeros to θ, and the initial values and lower bounds of θ.
+
+use super::ast::GroupKind;
+use super::sparse::{CscMatrix, GroupBy, SparseZ};
+use crate::Error;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Random-effects structure of a model: Z plus the template for Λ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReTerms {
+    /// Random-effects design matrix Z (lme4's `Zt` is its transpose).
+    pub z: SparseZ,
+    /// Per-term metadata, in the column order of Z.
+    pub terms: Vec<ReTerm>,
+    /// Column offsets of the terms in Z, of length `terms.len() + 1` (lme4's `Gp`).
+    pub gp: Vec<usize>,
+    /// Initial variance parameters: 1 on the diagonals of the templates, 0 off them.
+    pub theta: Vec<f64>,
+    /// Lower bounds of `theta`: 0 on the diagonals, `-inf` off them.
+    pub lower: Vec<f64>,
+    /// Index into `theta` of every stored entry of `lambdat`.
+    pub lind: Vec<usize>,
+    /// Upper-triangular Λᵀ, filled in with the initial `theta`.
+    pub lambdat: CscMatrix,
+}
+
+/// Metadata of one random-effects term, e.g. `(1 + x | g)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReTerm {
+    /// Name of the grouping factor, e.g. `g` or `a:b`.
+    pub group: String,
+    /// Number of levels of the grouping factor.
+    pub n_levels: usize,
+    /// Names of the columns per level, e.g. `["(Intercept)", "x"]` (lme4's `cnms`).
+    pub cnms: Vec<String>,
+    /// Correlated (`|`, full lower-triangular template) or uncorrelated
+    /// (`||`, diagonal template).
+    pub kind: GroupKind,
+    /// Shared `|<id>|` label, if any.
+    pub id: Option<String>,
+    /// The `by` variable of `gr(g, by = x)`, whose levels have separate templates.
+    pub by: Option<GroupBy>,
+    /// Columns of Z (and rows and columns of Λ) spanned by the term.
+    pub columns: Range<usize>,
+    /// Entries of `theta` belonging to the term.
+    pub theta: Range<usize>,
+}
+
+impl ReTerm {
+    /// Number of columns per level.
+    pub fn n_cols(&self) -> usize {
+        self.cnms.len()
+    }
+
+    /// Number of variance parameters: `n(n + 1) / 2` for a correlated term with
+    /// `n` columns per level, `n` for an uncorrelated one, times the number of
+    /// levels of the `by` variable.
+    pub fn n_theta(&self) -> usize {
+        self.theta.len()
+    }
+}
+
+impl ReTerms {
+    /// Lay out the Λ template for the terms of `z`, whose columns must all
+    /// belong to group terms.
+    ///
+    /// Terms sharing an `|<id>|` label must have the same grouping factor, as
+    /// their effects are correlated level by level.
+    pub(crate) fn new(z: SparseZ) -> Result<Self, Error> {
+        let mut id_groups: BTreeMap<&str, &str> = BTreeMap::new();
+        for term in &z.terms {
+            if let Some(id) = &term.id {
+                let group = id_groups.entry(id).or_insert(&term.group);
+                if *group != term.group {
+                    return Err(Error::Semantic(format!(
+                        "Group terms with id '{}' must share a grouping factor, found '{}' and '{}'",
+                        id, group, term.group
+                    )));
+                }
+            }
+        }
+
+        let mut terms = Vec::with_capacity(z.terms.len());
+        let mut gp = vec![0];
+        let mut theta = Vec::new();
+        let mut lower = Vec::new();
+        let mut lind = Vec::new();
+        let mut col_ptr = vec![0];
+        let mut row_idx = Vec::new();
+
+        for term in &z.terms {
+            let nc = term.inner.len();
+            let correlated = matches!(term.kind, GroupKind::Correlated);
+
+            // θ indices of the template Λ, lower triangle column by column,
+            // once per level of the `by` variable
+            let offset = theta.len();
+            let n_templates = term.by.as_ref().map_or(1, |by| by.levels.len());
+            let mut templates = Vec::with_capacity(n_templates);
+            for _ in 0..n_templates {
+                let mut template = vec![vec![None; nc]; nc];
+                for j in 0..nc {
+                    for (i, row) in template.iter_mut().enumerate().skip(j) {
+                        if i == j || correlated {
+                            row[j] = Some(theta.len());
+                            theta.push(if i == j { 1.0 } else { 0.0 });
+                            lower.push(if i == j { 0.0 } else { f64::NEG_INFINITY });
+                        }
+                    }
+                }
+                templates.push(template);
+            }
+
+            // Λᵀ is block diagonal with one copy of the template per level: its
+            // column `i` within a block holds row `i` of Λ
+            for level in 0..term.levels.len() {
+                let base = term.columns.start + level * nc;
+                let template = match &term.by {
+                    Some(by) => &templates[by.level_of_group[level]],
+                    None => &templates[0],
+                };
+                for (i, row) in template.iter().enumerate() {
+                    for (j, entry) in row.iter().enumerate().take(i + 1) {
+                        if let Some(k) = entry {
+                            row_idx.push(base + j);
+                            lind.push(*k);
+                        }
+                    }
+                    col_ptr.push(row_idx.len());
+                }
+            }
+
+            gp.push(term.columns.end);
+            terms.push(ReTerm {
+                group: term.group.clone(),
+                n_levels: term.levels.len(),
+                cnms: term.inner.clone(),
+                kind: term.kind.clone(),
+                id: term.id.clone(),
+                by: term.by.clone(),
+                columns: term.columns.clone(),
+                theta: offset..theta.len(),
+            });
+        }
+
+        let q = z.ncols();
+        let values = lind.iter().map(|&k| theta[k]).collect();
+        let lambdat = CscMatrix {
+            nrows: q,
+            ncols: q,
+            col_ptr,
+            row_idx,
+            values,
+        };
+        Ok(ReTerms {
+            z,
+            terms,
+            gp,
+            theta,
+            lower,
+            lind,
+            lambdat,
+        })
+    }
+
+    /// Terms linked by a shared `|<id>|` label, whose effects are correlated
+    /// across terms. Only labels used by more than one term are listed.
+    pub fn linked_terms(&self) -> Vec<(String, Vec<usize>)> {
+        let mut ids: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, term) in self.terms.iter().enumerate() {
+            if let Some(id) = &term.id {
+                ids.entry(id.as_str()).or_default().push(i);
+            }
+        }
+        ids.into_iter()
+            .filter(|(_, terms)| terms.len() > 1)
+            .map(|(id, terms)| (id.to_string(), terms))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::dsl::random::RandomBlock;
+
+    fn z_with(kind: GroupKind, nc: usize) -> SparseZ {
+        let block = RandomBlock::new(
+            "g".into(),
+            vec!["a".into(), "b".into()],
+            vec![Some(0), Some(1)],
+            (0..nc)
+                .map(|i| (format!("c{}", i), vec![Some(1.0); 2]))
+                .collect(),
+        );
+        let mut z = SparseZ::new(2);
+        z.push_block(&block, block.column_names(), kind, None);
+        z
+    }
+
+    #[test]
+    fn test_correlated_template_matches_lme4() {
+        let re = ReTerms::new(z_with(GroupKind::Correlated, 3)).unwrap();
+        assert_eq!(re.theta, vec![1.0, 0.0, 0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(re.lower[..3], [0.0, f64::NEG_INFINITY, f64::NEG_INFINITY]);
+        // lme4: Lind = 1 2 4 3 5 6 (1-based) for each level
+        assert_eq!(re.lind, vec![0, 1, 3, 2, 4, 5, 0, 1, 3, 2, 4, 5]);
+        assert_eq!(re.lambdat.col_ptr, vec![0, 1, 3, 6, 7, 9, 12]);
+        assert_eq!(re.lambdat.row_idx[..6], [0, 0, 1, 0, 1, 2]);
+        assert_eq!(re.lambdat.get(4, 4), 1.0);
+        assert_eq!(re.gp, vec![0, 6]);
+    }
+
+    #[test]
+    fn test_uncorrelated_template_is_diagonal() {
+        let re = ReTerms::new(z_with(GroupKind::Uncorrelated, 2)).unwrap();
+        assert_eq!(re.terms[0].n_theta(), 2);
+        assert_eq!(re.lind, vec![0, 1, 0, 1]);
+        assert_eq!(re.lambdat.row_idx, vec![0, 1, 2, 3]);
+    }
+}
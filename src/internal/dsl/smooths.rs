@@ -0,0 +1,1274 @@
+//! mgcv-style smooth terms: `s()`, `te()`, `ti()` and `t2()`.
+//!
+//! Each smooth is built from one or more marginal bases (thin plate, cubic
+//! regression, P-spline, random effect or factor smooth interaction), combined
+//! into a tensor product where needed, varied by a `by=` variable and finally
+//! reparameterized to satisfy the usual sum-to-zero identifiability constraint.
+//! Alongside the columns every smooth yields its penalty matrices, so a GAM
+//! fitter can estimate smoothing parameters without rebuilding the basis.
+//!
+//! Everything learned from the training data lives in a [`SmoothState`], which
+//! evaluates the identical basis on new data.
+
+use super::linalg::{congruence, constraint_null_space, identity, kronecker, symmetric_eigen};
+use super::splines::{
+    bspline_values, cubic_regression_free_basis, cubic_regression_penalty, interior_quantiles,
+};
+use crate::Error;
+
+/// Thin plate bases use at most this many unique covariate values as knots, as mgcv does.
+const TPRS_MAX_KNOTS: usize = 2000;
+
+/// Eigenvalues below this fraction of the largest are treated as zero when
+/// splitting a penalty into its range and null space.
+const RANK_TOLERANCE: f64 = 1e-8;
+
+/// Values of a covariate on the rows used to build a smooth.
+#[derive(Debug, Clone)]
+pub(crate) enum Covariate {
+    Numeric(Vec<f64>),
+    Factor(Vec<String>),
+}
+
+impl Covariate {
+    fn len(&self) -> usize {
+        match self {
+            Covariate::Numeric(v) => v.len(),
+            Covariate::Factor(v) => v.len(),
+        }
+    }
+}
+
+/// Options of a smooth term as written in the formula.
+#[derive(Debug, Clone)]
+pub(crate) struct SmoothOptions {
+    pub combine: Combine,
+    /// `bs=`, either one basis for all margins or one per margin.
+    pub bs: Vec<String>,
+    /// `k=`, either one basis dimension for all margins or one per margin.
+    pub k: Vec<usize>,
+    /// `m=`, the penalty order.
+    pub m: Option<usize>,
+    /// `xt=`, the base basis of a factor smooth interaction.
+    pub xt: Option<String>,
+}
+
+/// How the marginal bases of a smooth are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combine {
+    /// `s()`: a single (possibly multi-dimensional) basis.
+    Single,
+    /// `te()`: tensor product with one penalty per margin.
+    Tensor,
+    /// `ti()`: tensor product interaction, main effects excluded.
+    TensorInteraction,
+    /// `t2()`: tensor product with separately penalized null and range spaces.
+    T2,
+}
+
+/// Learned state of a smooth term.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmoothState {
+    pub combine: Combine,
+    pub margins: Vec<Marginal>,
+    /// Levels of a factor `by=` variable; one smooth is built per level.
+    pub by_levels: Option<Vec<String>>,
+    /// Sum-to-zero constraint absorbed into each smooth (one per `by` level),
+    /// given as the column means of the unconstrained training basis.
+    pub constraints: Vec<Option<Vec<f64>>>,
+}
+
+/// One marginal basis with its penalties.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marginal {
+    pub basis: MarginalBasis,
+    /// Penalty matrices on the marginal coefficients (after `constraint`).
+    pub penalties: Vec<Vec<Vec<f64>>>,
+    /// Centering constraint absorbed before the margin enters a `ti()` product.
+    pub constraint: Option<Vec<f64>>,
+}
+
+/// Learned parameters of a marginal basis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarginalBasis {
+    /// `bs="tp"`: thin plate regression spline of penalty order `order`.
+    /// `transform` maps the radial basis at the knots onto the retained,
+    /// polynomial-free eigenvectors (one entry per knot and column).
+    ThinPlate {
+        knots: Vec<Vec<f64>>,
+        order: usize,
+        transform: Vec<Vec<f64>>,
+    },
+    /// `bs="cr"`, `"cs"` and `"cc"`: cubic regression splines parameterized by
+    /// their values at the knots.
+    CubicRegression { knots: Vec<f64>, cyclic: bool },
+    /// `bs="ps"`: B-splines of the given order on evenly spaced knots.
+    PSpline { knots: Vec<f64>, order: usize },
+    /// `bs="re"`: identity-penalized random effect; factors expand into one
+    /// column per level and several covariates multiply.
+    RandomEffect { levels: Vec<Option<Vec<String>>> },
+    /// `bs="fs"`: a copy of `base`, rotated so its penalty is diagonal, for each
+    /// level of the factor.
+    FactorSmooth {
+        base: Box<Marginal>,
+        levels: Vec<String>,
+        rotation: Vec<Vec<f64>>,
+    },
+}
+
+/// One evaluated smooth (one per level of a factor `by` variable).
+#[derive(Debug, Clone)]
+pub(crate) struct SmoothBlock {
+    pub by_level: Option<String>,
+    pub columns: Vec<Vec<f64>>,
+    pub penalties: Vec<Vec<Vec<f64>>>,
+    pub constraint: Option<Vec<f64>>,
+}
+
+/// Mixed model representation of a smooth block, as produced by mgcv's
+/// `smooth2random()` and used by brms and gamm4.
+#[derive(Debug, Clone)]
+pub(crate) struct MixedSmooth {
+    /// Unpenalized null space columns, which become fixed effects.
+    pub fixed: Vec<Vec<f64>>,
+    /// Penalized columns, one group per penalty, scaled so that each group has
+    /// an identity penalty and can be treated as i.i.d. random effects.
+    pub random: Vec<Vec<Vec<f64>>>,
+}
+
+impl SmoothBlock {
+    /// Dimension of the null space of the total penalty (the unpenalized part of the smooth).
+    pub(crate) fn null_space_dim(&self) -> usize {
+        let width = self.columns.len();
+        if self.penalties.is_empty() {
+            return width;
+        }
+        let (values, _) = symmetric_eigen(&sum_penalties(&self.penalties, width));
+        let tol = values.first().copied().unwrap_or(0.0).max(0.0) * RANK_TOLERANCE;
+        values.iter().filter(|v| **v <= tol).count()
+    }
+
+    /// Split the block into fixed null space columns and identity-penalized
+    /// random columns. Penalties must act on disjoint sets of coefficients, which
+    /// holds for everything except `te()` smooths.
+    pub(crate) fn to_mixed(&self) -> Result<MixedSmooth, Error> {
+        let width = self.columns.len();
+        let supports: Vec<Vec<bool>> = self
+            .penalties
+            .iter()
+            .map(|s| s.iter().map(|row| row.iter().any(|v| *v != 0.0)).collect())
+            .collect();
+        for (a, sa) in supports.iter().enumerate() {
+            for sb in &supports[a + 1..] {
+                if sa.iter().zip(sb).any(|(x, y)| *x && *y) {
+                    return Err(Error::Semantic(
+                        "te() smooths have overlapping penalties and cannot be written as \
+                         random effects; use t2() instead"
+                            .into(),
+                    ));
+                }
+            }
+        }
+
+        let (values, vectors) = symmetric_eigen(&sum_penalties(&self.penalties, width));
+        let tol = values.first().copied().unwrap_or(0.0).max(0.0) * RANK_TOLERANCE;
+        let null: Vec<Vec<f64>> = values
+            .iter()
+            .zip(vectors)
+            .filter(|(v, _)| **v <= tol || self.penalties.is_empty())
+            .map(|(_, u)| u)
+            .collect();
+        let random = self
+            .penalties
+            .iter()
+            .map(|s| {
+                let (values, vectors) = symmetric_eigen(s);
+                let tol = values.first().copied().unwrap_or(0.0).max(0.0) * RANK_TOLERANCE;
+                let range: Vec<Vec<f64>> = values
+                    .iter()
+                    .zip(vectors)
+                    .filter(|(v, _)| **v > tol)
+                    .map(|(v, u)| u.iter().map(|x| x / v.sqrt()).collect())
+                    .collect();
+                multiply_columns(&self.columns, &range)
+            })
+            .collect();
+        Ok(MixedSmooth {
+            fixed: multiply_columns(&self.columns, &null),
+            random,
+        })
+    }
+}
+
+impl SmoothState {
+    /// Learn a smooth from its (null-free) covariates and optional `by` variable.
+    pub(crate) fn learn(
+        opts: &SmoothOptions,
+        covariates: &[Covariate],
+        by: Option<&Covariate>,
+    ) -> Result<Self, Error> {
+        if covariates.is_empty() {
+            return Err(Error::Semantic("Smooth with no variables".into()));
+        }
+        let margins = match opts.combine {
+            Combine::Single => {
+                let bs = opts.bs.first().map_or("tp", String::as_str);
+                let k = opts.k.first().copied();
+                vec![learn_marginal(bs, k, opts, covariates)?]
+            }
+            _ => {
+                let d = covariates.len();
+                if opts.bs.len() > 1 && opts.bs.len() != d || opts.k.len() > 1 && opts.k.len() != d
+                {
+                    return Err(Error::Semantic(format!(
+                        "Tensor smooth needs one 'bs' and 'k' value or one per variable ({})",
+                        d
+                    )));
+                }
+                let mut margins = Vec::with_capacity(d);
+                for (j, covariate) in covariates.iter().enumerate() {
+                    let bs = opts
+                        .bs
+                        .get(j)
+                        .or(opts.bs.first())
+                        .map_or("cr", String::as_str);
+                    let k = opts.k.get(j).or(opts.k.first()).copied().unwrap_or(5);
+                    let mut margin =
+                        learn_marginal(bs, Some(k), opts, std::slice::from_ref(covariate))?;
+                    if !matches!(
+                        margin.basis,
+                        MarginalBasis::ThinPlate { .. }
+                            | MarginalBasis::CubicRegression { .. }
+                            | MarginalBasis::PSpline { .. }
+                    ) {
+                        return Err(Error::Semantic(format!(
+                            "bs=\"{}\" cannot be used as a tensor product margin",
+                            bs
+                        )));
+                    }
+                    if opts.combine == Combine::TensorInteraction {
+                        let x = margin.basis.evaluate(std::slice::from_ref(covariate))?;
+                        let c: Vec<f64> = x.iter().map(|col| mean(col)).collect();
+                        let z = constraint_null_space(std::slice::from_ref(&c));
+                        margin.penalties =
+                            margin.penalties.iter().map(|s| congruence(s, &z)).collect();
+                        margin.constraint = Some(c);
+                    }
+                    if opts.combine != Combine::T2 {
+                        for s in margin.penalties.iter_mut() {
+                            normalize_penalty(s);
+                        }
+                    }
+                    margins.push(margin);
+                }
+                margins
+            }
+        };
+
+        let by_levels = match by {
+            Some(Covariate::Factor(values)) => Some(sorted_levels(values)),
+            _ => None,
+        };
+        let mut state = SmoothState {
+            combine: opts.combine,
+            margins,
+            by_levels,
+            constraints: Vec::new(),
+        };
+
+        let centered = opts.combine != Combine::TensorInteraction
+            && !matches!(by, Some(Covariate::Numeric(_)))
+            && !state.margins.iter().any(|m| {
+                matches!(
+                    m.basis,
+                    MarginalBasis::RandomEffect { .. } | MarginalBasis::FactorSmooth { .. }
+                )
+            });
+        let unconstrained = state.evaluate_unconstrained(covariates)?;
+        state.constraints = state
+            .by_masks(by, unconstrained.first().map_or(0, |c| c.len()))?
+            .into_iter()
+            .map(|(_, mask)| {
+                centered.then(|| {
+                    unconstrained
+                        .iter()
+                        .map(|col| mean(&apply_mask(col, mask.as_deref())))
+                        .collect()
+                })
+            })
+            .collect();
+        Ok(state)
+    }
+
+    /// Evaluate the smooth on (null-free) covariates, one block per `by` level.
+    pub(crate) fn blocks(
+        &self,
+        covariates: &[Covariate],
+        by: Option<&Covariate>,
+    ) -> Result<Vec<SmoothBlock>, Error> {
+        let unconstrained = self.evaluate_unconstrained(covariates)?;
+        let penalties = self.unconstrained_penalties();
+        let n = covariates.first().map_or(0, Covariate::len);
+        let mut blocks = Vec::new();
+        for ((by_level, mask), constraint) in self
+            .by_masks(by, n)?
+            .into_iter()
+            .zip(self.constraints.iter())
+        {
+            let masked: Vec<Vec<f64>> = unconstrained
+                .iter()
+                .map(|col| apply_mask(col, mask.as_deref()))
+                .collect();
+            let (columns, penalties) = match constraint {
+                Some(c) if self.combine == Combine::T2 => t2_centered(
+                    &masked,
+                    &penalties,
+                    c,
+                    mask.as_deref(),
+                    self.t2_null_width(),
+                ),
+                Some(c) => {
+                    let z = constraint_null_space(std::slice::from_ref(c));
+                    (
+                        multiply_columns(&masked, &z),
+                        penalties.iter().map(|s| congruence(s, &z)).collect(),
+                    )
+                }
+                None => (masked, penalties.clone()),
+            };
+            blocks.push(SmoothBlock {
+                by_level,
+                columns,
+                penalties,
+                constraint: constraint.clone(),
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Row multipliers for each smooth: the indicator of every factor level, the
+    /// numeric `by` values, or nothing.
+    #[allow(clippy::type_complexity)]
+    fn by_masks(
+        &self,
+        by: Option<&Covariate>,
+        n: usize,
+    ) -> Result<Vec<(Option<String>, Option<Vec<f64>>)>, Error> {
+        match (by, &self.by_levels) {
+            (None, _) => Ok(vec![(None, None)]),
+            (Some(Covariate::Numeric(values)), None) => Ok(vec![(None, Some(values.clone()))]),
+            (Some(Covariate::Factor(values)), Some(levels)) => Ok(levels
+                .iter()
+                .map(|level| {
+                    let mask = values
+                        .iter()
+                        .map(|v| if v == level { 1.0 } else { 0.0 })
+                        .collect();
+                    (Some(level.clone()), Some(mask))
+                })
+                .collect()),
+            _ => Err(Error::Semantic(format!(
+                "Smooth 'by' variable changed type between fitting and prediction ({} rows)",
+                n
+            ))),
+        }
+    }
+
+    fn evaluate_unconstrained(&self, covariates: &[Covariate]) -> Result<Vec<Vec<f64>>, Error> {
+        if self.combine == Combine::Single {
+            return self.margins[0].evaluate(covariates);
+        }
+        if covariates.len() != self.margins.len() {
+            return Err(Error::Semantic(format!(
+                "Tensor smooth expects {} variables, got {}",
+                self.margins.len(),
+                covariates.len()
+            )));
+        }
+        let marginal: Vec<Vec<Vec<f64>>> = self
+            .margins
+            .iter()
+            .zip(covariates)
+            .map(|(m, c)| m.evaluate(std::slice::from_ref(c)))
+            .collect::<Result<_, _>>()?;
+        if self.combine != Combine::T2 {
+            return Ok(marginal
+                .into_iter()
+                .reduce(|a, b| row_kronecker(&a, &b))
+                .unwrap_or_default());
+        }
+        let mut columns = Vec::new();
+        let pieces: Vec<[Vec<Vec<f64>>; 2]> = marginal
+            .iter()
+            .zip(self.t2_splits())
+            .map(|(x, (range, null))| [multiply_columns(x, &range), multiply_columns(x, &null)])
+            .collect();
+        for mask in t2_block_masks(self.margins.len()) {
+            let block = pieces
+                .iter()
+                .zip(&mask)
+                .map(|(p, &is_null)| p[is_null as usize].clone())
+                .reduce(|a, b| row_kronecker(&a, &b))
+                .unwrap_or_default();
+            columns.extend(block);
+        }
+        Ok(columns)
+    }
+
+    fn unconstrained_penalties(&self) -> Vec<Vec<Vec<f64>>> {
+        match self.combine {
+            Combine::Single => self.margins[0].penalties.clone(),
+            Combine::Tensor | Combine::TensorInteraction => {
+                let widths: Vec<usize> = self.margins.iter().map(Marginal::width).collect();
+                let mut penalties = Vec::new();
+                for (j, margin) in self.margins.iter().enumerate() {
+                    for s in &margin.penalties {
+                        let mut full = vec![vec![1.0]];
+                        for (i, &w) in widths.iter().enumerate() {
+                            full = if i == j {
+                                kronecker(&full, s)
+                            } else {
+                                kronecker(&full, &identity(w))
+                            };
+                        }
+                        penalties.push(full);
+                    }
+                }
+                penalties
+            }
+            Combine::T2 => {
+                let splits = self.t2_splits();
+                let sizes: Vec<usize> = t2_block_masks(self.margins.len())
+                    .iter()
+                    .map(|mask| {
+                        splits
+                            .iter()
+                            .zip(mask)
+                            .map(
+                                |((range, null), &is_null)| {
+                                    if is_null {
+                                        null.len()
+                                    } else {
+                                        range.len()
+                                    }
+                                },
+                            )
+                            .product()
+                    })
+                    .collect();
+                let total: usize = sizes.iter().sum();
+                let mut penalties = Vec::new();
+                let mut offset = 0;
+                for (mask, size) in t2_block_masks(self.margins.len()).iter().zip(&sizes) {
+                    if *size > 0 && mask.iter().any(|is_null| !is_null) {
+                        let mut s = vec![vec![0.0; total]; total];
+                        for (i, row) in s.iter_mut().enumerate().skip(offset).take(*size) {
+                            row[i] = 1.0;
+                        }
+                        penalties.push(s);
+                    }
+                    offset += size;
+                }
+                penalties
+            }
+        }
+    }
+
+    /// Number of columns in the unpenalized (all null space) block of a `t2()`,
+    /// which comes last.
+    fn t2_null_width(&self) -> usize {
+        self.t2_splits()
+            .iter()
+            .map(|(_, null)| null.len())
+            .product()
+    }
+
+    /// For each margin of a `t2()`: the penalty range space scaled so its
+    /// penalty is the identity, and the penalty null space (both by columns).
+    #[allow(clippy::type_complexity)]
+    fn t2_splits(&self) -> Vec<(Vec<Vec<f64>>, Vec<Vec<f64>>)> {
+        self.margins
+            .iter()
+            .map(|m| {
+                let (values, vectors) = symmetric_eigen(&sum_penalties(&m.penalties, m.width()));
+                let tol = values.first().copied().unwrap_or(0.0).max(0.0) * RANK_TOLERANCE;
+                let mut range = Vec::new();
+                let mut null = Vec::new();
+                for (value, vector) in values.into_iter().zip(vectors) {
+                    if value > tol {
+                        range.push(vector.iter().map(|v| v / value.sqrt()).collect());
+                    } else {
+                        null.push(vector);
+                    }
+                }
+                (range, null)
+            })
+            .collect()
+    }
+}
+
+impl Marginal {
+    fn width(&self) -> usize {
+        self.penalties.first().map_or(0, |s| s.len())
+    }
+
+    fn evaluate(&self, covariates: &[Covariate]) -> Result<Vec<Vec<f64>>, Error> {
+        let x = self.basis.evaluate(covariates)?;
+        Ok(match &self.constraint {
+            Some(c) => multiply_columns(&x, &constraint_null_space(std::slice::from_ref(c))),
+            None => x,
+        })
+    }
+}
+
+impl MarginalBasis {
+    /// Unconstrained basis columns for the given covariates.
+    fn evaluate(&self, covariates: &[Covariate]) -> Result<Vec<Vec<f64>>, Error> {
+        match self {
+            MarginalBasis::ThinPlate {
+                knots,
+                order,
+                transform,
+            } => {
+                let x = numeric_columns(covariates, "tp")?;
+                let d = x.len();
+                let n = x.first().map_or(0, |c| c.len());
+                let powers = polynomial_powers(d, *order);
+                let width = transform.first().map_or(0, |t| t.len());
+                let mut columns = vec![Vec::with_capacity(n); width + powers.len()];
+                let mut radial = vec![0.0; knots.len()];
+                for i in 0..n {
+                    let point: Vec<f64> = x.iter().map(|c| c[i]).collect();
+                    for (r, knot) in radial.iter_mut().zip(knots) {
+                        *r = tps_eta(distance(&point, knot), *order, d);
+                    }
+                    for (c, column) in columns.iter_mut().take(width).enumerate() {
+                        column.push(radial.iter().zip(transform).map(|(r, t)| r * t[c]).sum());
+                    }
+                    for (column, p) in columns.iter_mut().skip(width).zip(&powers) {
+                        column.push(monomial(&point, p));
+                    }
+                }
+                Ok(columns)
+            }
+            MarginalBasis::CubicRegression { knots, cyclic } => {
+                let x = numeric_columns(covariates, "cr")?;
+                Ok(cubic_regression_free_basis(&x[0], knots, *cyclic))
+            }
+            MarginalBasis::PSpline { knots, order } => {
+                let x = numeric_columns(covariates, "ps")?;
+                let width = knots.len() - order;
+                let mut columns = vec![Vec::with_capacity(x[0].len()); width];
+                for &xi in &x[0] {
+                    for (column, v) in columns.iter_mut().zip(bspline_values(knots, xi, *order, 0))
+                    {
+                        column.push(v);
+                    }
+                }
+                Ok(columns)
+            }
+            MarginalBasis::RandomEffect { levels } => {
+                let mut columns: Option<Vec<Vec<f64>>> = None;
+                for (covariate, levels) in covariates.iter().zip(levels) {
+                    let next = match (covariate, levels) {
+                        (Covariate::Factor(values), Some(levels)) => dummy_columns(values, levels),
+                        (Covariate::Numeric(values), None) => vec![values.clone()],
+                        _ => {
+                            return Err(Error::Semantic(
+                                "bs=\"re\" covariate changed type between fitting and prediction"
+                                    .into(),
+                            ))
+                        }
+                    };
+                    columns = Some(match columns {
+                        Some(prev) => row_kronecker(&prev, &next),
+                        None => next,
+                    });
+                }
+                Ok(columns.unwrap_or_default())
+            }
+            MarginalBasis::FactorSmooth {
+                base,
+                levels,
+                rotation,
+            } => {
+                let (factor, numeric) = split_factor(covariates)?;
+                let rotated = multiply_columns(&base.evaluate(&numeric)?, rotation);
+                let indicators = dummy_columns(factor, levels);
+                let mut columns = Vec::with_capacity(levels.len() * rotated.len());
+                for indicator in &indicators {
+                    for column in &rotated {
+                        columns.push(column.iter().zip(indicator).map(|(a, b)| a * b).collect());
+                    }
+                }
+                Ok(columns)
+            }
+        }
+    }
+}
+
+/// Learn one marginal basis (for `s()`, all covariates; for tensors, one).
+fn learn_marginal(
+    bs: &str,
+    k: Option<usize>,
+    opts: &SmoothOptions,
+    covariates: &[Covariate],
+) -> Result<Marginal, Error> {
+    let one_dimensional = |name: &str| -> Result<Vec<f64>, Error> {
+        let x = numeric_columns(covariates, name)?;
+        if x.len() != 1 {
+            return Err(Error::Semantic(format!(
+                "bs=\"{}\" smooths take a single variable; use te() for several",
+                name
+            )));
+        }
+        Ok(x.into_iter().next().unwrap_or_default())
+    };
+    match bs {
+        "tp" => {
+            let x = numeric_columns(covariates, bs)?;
+            let d = x.len() as u32;
+            learn_thin_plate(&x, k.unwrap_or(10 * 3usize.pow(d - 1)), opts.m)
+        }
+        "cr" | "cs" | "cc" => {
+            let x = one_dimensional(bs)?;
+            let k = k.unwrap_or(10);
+            let cyclic = bs == "cc";
+            let min_k = if cyclic { 4 } else { 3 };
+            if k < min_k {
+                return Err(Error::Semantic(format!(
+                    "bs=\"{}\" needs k >= {}",
+                    bs, min_k
+                )));
+            }
+            let knots = place_knots(&x, if cyclic { k + 1 } else { k })?;
+            let mut penalty = cubic_regression_penalty(&knots, cyclic);
+            if bs == "cs" {
+                penalty = shrink_null_space(&penalty, 2);
+            }
+            Ok(Marginal {
+                basis: MarginalBasis::CubicRegression { knots, cyclic },
+                penalties: vec![penalty],
+                constraint: None,
+            })
+        }
+        "ps" => {
+            let x = one_dimensional(bs)?;
+            let k = k.unwrap_or(10);
+            let m = opts.m.unwrap_or(2);
+            if k < m + 3 {
+                return Err(Error::Semantic(format!(
+                    "bs=\"ps\" needs k >= {} for m={}",
+                    m + 3,
+                    m
+                )));
+            }
+            // mgcv::smooth.construct.ps.smooth.spec
+            let n_inner = k - m;
+            let lo = x.iter().cloned().fold(f64::INFINITY, f64::min);
+            let hi = x.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let range = hi - lo;
+            let (lo, hi) = (lo - range * 0.001, hi + range * 0.001);
+            let dx = (hi - lo) / (n_inner - 1) as f64;
+            let count = n_inner + 2 * m + 2;
+            let start = lo - dx * (m + 1) as f64;
+            let knots = (0..count).map(|i| start + dx * i as f64).collect();
+            let mut difference = identity(k);
+            for _ in 0..m {
+                difference = difference
+                    .windows(2)
+                    .map(|w| w[1].iter().zip(&w[0]).map(|(a, b)| a - b).collect())
+                    .collect();
+            }
+            let penalty = (0..k)
+                .map(|i| {
+                    (0..k)
+                        .map(|j| difference.iter().map(|row| row[i] * row[j]).sum())
+                        .collect()
+                })
+                .collect();
+            Ok(Marginal {
+                basis: MarginalBasis::PSpline {
+                    knots,
+                    order: m + 2,
+                },
+                penalties: vec![penalty],
+                constraint: None,
+            })
+        }
+        "re" => {
+            let levels: Vec<Option<Vec<String>>> = covariates
+                .iter()
+                .map(|c| match c {
+                    Covariate::Factor(values) => Some(sorted_levels(values)),
+                    Covariate::Numeric(_) => None,
+                })
+                .collect();
+            let width = levels
+                .iter()
+                .map(|l| l.as_ref().map_or(1, Vec::len))
+                .product();
+            Ok(Marginal {
+                basis: MarginalBasis::RandomEffect { levels },
+                penalties: vec![identity(width)],
+                constraint: None,
+            })
+        }
+        "fs" => {
+            let (factor, numeric) = split_factor(covariates)?;
+            let base_bs = opts.xt.as_deref().unwrap_or("tp");
+            if matches!(base_bs, "re" | "fs") {
+                return Err(Error::Semantic(format!(
+                    "bs=\"fs\" cannot use xt=\"{}\" as its base",
+                    base_bs
+                )));
+            }
+            let base = learn_marginal(base_bs, k, opts, &numeric)?;
+            let p = base.width();
+            let (values, rotation) = symmetric_eigen(&sum_penalties(&base.penalties, p));
+            let tol = values.first().copied().unwrap_or(0.0).max(0.0) * RANK_TOLERANCE;
+            let levels = sorted_levels(factor);
+            let blocks = |diag: &dyn Fn(usize) -> f64| {
+                let block: Vec<Vec<f64>> = (0..p)
+                    .map(|i| (0..p).map(|j| if i == j { diag(i) } else { 0.0 }).collect())
+                    .collect();
+                kronecker(&identity(levels.len()), &block)
+            };
+            // Wiggliness penalty plus one penalty per null space direction, so
+            // that every level's curve is shrunk towards zero
+            let mut penalties = vec![blocks(&|i| values[i].max(0.0))];
+            for j in (0..p).filter(|&j| values[j] <= tol) {
+                penalties.push(blocks(&|i| if i == j { 1.0 } else { 0.0 }));
+            }
+            Ok(Marginal {
+                basis: MarginalBasis::FactorSmooth {
+                    base: Box::new(base),
+                    levels,
+                    rotation,
+                },
+                penalties,
+                constraint: None,
+            })
+        }
+        other => Err(Error::Semantic(format!(
+            "Unsupported smooth basis bs=\"{}\"; expected one of tp, cr, cs, cc, ps, re, fs",
+            other
+        ))),
+    }
+}
+
+/// Thin plate regression spline (Wood 2003): the radial basis at the unique
+/// covariate values is truncated to its `k` dominant eigenvectors, which are
+/// constrained to be orthogonal to the polynomial null space.
+fn learn_thin_plate(x: &[Vec<f64>], k: usize, m: Option<usize>) -> Result<Marginal, Error> {
+    let d = x.len();
+    let order = m.unwrap_or((d + 1) / 2 + 1);
+    if 2 * order <= d {
+        return Err(Error::Semantic(format!(
+            "bs=\"tp\" needs 2m > d; m={} is too small for {} variables",
+            order, d
+        )));
+    }
+    let powers = polynomial_powers(d, order);
+    let null_dim = powers.len();
+    if k <= null_dim {
+        return Err(Error::Semantic(format!(
+            "bs=\"tp\" needs k > {} (the penalty null space dimension)",
+            null_dim
+        )));
+    }
+
+    let n = x.first().map_or(0, |c| c.len());
+    let mut points: Vec<Vec<f64>> = (0..n).map(|i| x.iter().map(|c| c[i]).collect()).collect();
+    points.sort_by(|a, b| {
+        a.iter()
+            .zip(b)
+            .map(|(p, q)| p.total_cmp(q))
+            .find(|o| o.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    points.dedup();
+    if points.len() > TPRS_MAX_KNOTS {
+        let step = points.len() as f64 / TPRS_MAX_KNOTS as f64;
+        points = (0..TPRS_MAX_KNOTS)
+            .map(|i| points[(i as f64 * step) as usize].clone())
+            .collect();
+    }
+    if points.len() < k {
+        return Err(Error::Semantic(format!(
+            "bs=\"tp\" with k={} needs at least {} unique covariate values, found {}",
+            k,
+            k,
+            points.len()
+        )));
+    }
+
+    let radial: Vec<Vec<f64>> = points
+        .iter()
+        .map(|p| {
+            points
+                .iter()
+                .map(|q| tps_eta(distance(p, q), order, d))
+                .collect()
+        })
+        .collect();
+    let (values, vectors) = symmetric_eigen(&radial);
+    let mut dominant: Vec<usize> = (0..values.len()).collect();
+    dominant.sort_by(|&i, &j| values[j].abs().total_cmp(&values[i].abs()));
+    dominant.truncate(k);
+
+    // Constrain the truncated coefficients to be orthogonal to the polynomials
+    let constraints: Vec<Vec<f64>> = powers
+        .iter()
+        .map(|p| {
+            dominant
+                .iter()
+                .map(|&j| {
+                    points
+                        .iter()
+                        .zip(&vectors[j])
+                        .map(|(pt, u)| monomial(pt, p) * u)
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+    let z = constraint_null_space(&constraints);
+    let transform: Vec<Vec<f64>> = (0..points.len())
+        .map(|i| {
+            z.iter()
+                .map(|zc| {
+                    dominant
+                        .iter()
+                        .zip(zc)
+                        .map(|(&j, w)| vectors[j][i] * w)
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+    let diagonal: Vec<Vec<f64>> = dominant
+        .iter()
+        .enumerate()
+        .map(|(a, &j)| {
+            (0..k)
+                .map(|b| if a == b { values[j] } else { 0.0 })
+                .collect()
+        })
+        .collect();
+    let wiggly = congruence(&diagonal, &z);
+    let mut penalty = vec![vec![0.0; k]; k];
+    for (row, w) in penalty.iter_mut().zip(wiggly) {
+        row[..w.len()].copy_from_slice(&w);
+    }
+    Ok(Marginal {
+        basis: MarginalBasis::ThinPlate {
+            knots: points,
+            order,
+            transform,
+        },
+        penalties: vec![penalty],
+        constraint: None,
+    })
+}
+
+/// Thin plate radial basis function `eta_{md}(r)`.
+fn tps_eta(r: f64, m: usize, d: usize) -> f64 {
+    if r <= 0.0 {
+        return 0.0;
+    }
+    let pi = std::f64::consts::PI;
+    let factorial = |n: usize| (1..=n).map(|i| i as f64).product::<f64>();
+    let power = (2 * m - d) as i32;
+    if d % 2 == 0 {
+        let sign = if (m + 1 + d / 2) % 2 == 0 { 1.0 } else { -1.0 };
+        let c = sign
+            / (2f64.powi(2 * m as i32 - 1)
+                * pi.powf(d as f64 / 2.0)
+                * factorial(m - 1)
+                * factorial(m - d / 2));
+        c * r.powi(power) * r.ln()
+    } else {
+        // Gamma(d/2 - m) for the (negative) half-integer argument
+        let mut gamma = pi.sqrt();
+        let mut arg = 0.5;
+        let target = d as f64 / 2.0 - m as f64;
+        while arg > target {
+            arg -= 1.0;
+            gamma /= arg;
+        }
+        while arg < target {
+            gamma *= arg;
+            arg += 1.0;
+        }
+        let c = gamma / (2f64.powi(2 * m as i32) * pi.powf(d as f64 / 2.0) * factorial(m - 1));
+        c * r.powi(power)
+    }
+}
+
+/// Exponents of the monomials of total degree below `m` in `d` variables.
+fn polynomial_powers(d: usize, m: usize) -> Vec<Vec<usize>> {
+    let mut powers = vec![Vec::new()];
+    for _ in 0..d {
+        powers = powers
+            .into_iter()
+            .flat_map(|p: Vec<usize>| {
+                let used: usize = p.iter().sum();
+                (0..m - used).map(move |e| {
+                    let mut q = p.clone();
+                    q.push(e);
+                    q
+                })
+            })
+            .collect();
+    }
+    powers.sort_by_key(|p| p.iter().sum::<usize>());
+    powers
+}
+
+fn monomial(point: &[f64], powers: &[usize]) -> f64 {
+    point
+        .iter()
+        .zip(powers)
+        .map(|(x, &e)| x.powi(e as i32))
+        .product()
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Knots at evenly spaced quantiles of the unique values, as `mgcv::place.knots`.
+fn place_knots(x: &[f64], n: usize) -> Result<Vec<f64>, Error> {
+    let mut unique = x.to_vec();
+    unique.sort_by(|a, b| a.total_cmp(b));
+    unique.dedup();
+    if unique.len() < n {
+        return Err(Error::Semantic(format!(
+            "Smooth needs at least {} unique covariate values, found {}",
+            n,
+            unique.len()
+        )));
+    }
+    let (lo, hi) = (unique[0], unique[unique.len() - 1]);
+    let mut knots = vec![lo];
+    knots.extend(interior_quantiles(&unique, (lo, hi), n - 2, true, "s")?);
+    knots.push(hi);
+    Ok(knots)
+}
+
+/// Shrinkage version of a penalty (mgcv's `cs`): the zero eigenvalues of the
+/// `null_dim`-dimensional null space become small positive multiples of the
+/// smallest positive one, so the whole term can be penalized to zero.
+fn shrink_null_space(penalty: &[Vec<f64>], null_dim: usize) -> Vec<Vec<f64>> {
+    let (mut values, vectors) = symmetric_eigen(penalty);
+    let n = values.len();
+    for i in n - null_dim..n {
+        values[i] = values[i - 1] * 0.1;
+    }
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| {
+                    values
+                        .iter()
+                        .zip(&vectors)
+                        .map(|(l, v)| l * v[i] * v[j])
+                        .sum()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Scale a penalty so its largest eigenvalue is one, as mgcv does for tensor margins.
+fn normalize_penalty(penalty: &mut [Vec<f64>]) {
+    let (values, _) = symmetric_eigen(penalty);
+    if let Some(&max) = values.first().filter(|v| **v > 0.0) {
+        for v in penalty.iter_mut().flatten() {
+            *v /= max;
+        }
+    }
+}
+
+fn sum_penalties(penalties: &[Vec<Vec<f64>>], width: usize) -> Vec<Vec<f64>> {
+    let mut total = vec![vec![0.0; width]; width];
+    for s in penalties {
+        for (t, r) in total.iter_mut().zip(s) {
+            for (a, b) in t.iter_mut().zip(r) {
+                *a += b;
+            }
+        }
+    }
+    total
+}
+
+/// Center a `t2()` basis without mixing its separately penalized blocks: the
+/// penalized columns are centered directly (within the rows selected by
+/// `mask`), and the constraint is absorbed into the trailing unpenalized
+/// block only, so every penalty remains an identity on its own columns.
+#[allow(clippy::type_complexity)]
+fn t2_centered(
+    columns: &[Vec<f64>],
+    penalties: &[Vec<Vec<f64>>],
+    means: &[f64],
+    mask: Option<&[f64]>,
+    null_width: usize,
+) -> (Vec<Vec<f64>>, Vec<Vec<Vec<f64>>>) {
+    let penalized = columns.len() - null_width;
+    let share = mask.map_or(1.0, mean);
+    let mut centered: Vec<Vec<f64>> = columns[..penalized]
+        .iter()
+        .zip(means)
+        .map(|(col, m)| {
+            let shift = if share > 0.0 { m / share } else { 0.0 };
+            match mask {
+                Some(mask) => col.iter().zip(mask).map(|(v, w)| v - shift * w).collect(),
+                None => col.iter().map(|v| v - shift).collect(),
+            }
+        })
+        .collect();
+    let z = constraint_null_space(&[means[penalized..].to_vec()]);
+    centered.extend(multiply_columns(&columns[penalized..], &z));
+
+    let width = centered.len();
+    let penalties = penalties
+        .iter()
+        .map(|s| {
+            let mut out = vec![vec![0.0; width]; width];
+            for (o, r) in out.iter_mut().zip(s).take(penalized) {
+                o[..penalized].copy_from_slice(&r[..penalized]);
+            }
+            out
+        })
+        .collect();
+    (centered, penalties)
+}
+
+/// Null/range choice for each margin of every `t2()` block (`true` = null space),
+/// starting with the all-range block and ending with the unpenalized one.
+fn t2_block_masks(d: usize) -> Vec<Vec<bool>> {
+    (0..1usize << d)
+        .map(|bits| (0..d).map(|j| bits >> (d - 1 - j) & 1 == 1).collect())
+        .collect()
+}
+
+/// Row-wise Kronecker product of two bases given by columns.
+fn row_kronecker(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let mut out = Vec::with_capacity(a.len() * b.len());
+    for ca in a {
+        for cb in b {
+            out.push(ca.iter().zip(cb).map(|(x, y)| x * y).collect());
+        }
+    }
+    out
+}
+
+/// Basis (by columns) times a coefficient matrix (by columns).
+fn multiply_columns(x: &[Vec<f64>], z: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let n = x.first().map_or(0, |c| c.len());
+    z.iter()
+        .map(|zc| {
+            let mut column = vec![0.0; n];
+            for (xc, w) in x.iter().zip(zc) {
+                if *w != 0.0 {
+                    for (acc, v) in column.iter_mut().zip(xc) {
+                        *acc += w * v;
+                    }
+                }
+            }
+            column
+        })
+        .collect()
+}
+
+fn apply_mask(column: &[f64], mask: Option<&[f64]>) -> Vec<f64> {
+    match mask {
+        Some(mask) => column.iter().zip(mask).map(|(a, b)| a * b).collect(),
+        None => column.to_vec(),
+    }
+}
+
+fn dummy_columns(values: &[String], levels: &[String]) -> Vec<Vec<f64>> {
+    levels
+        .iter()
+        .map(|level| {
+            values
+                .iter()
+                .map(|v| if v == level { 1.0 } else { 0.0 })
+                .collect()
+        })
+        .collect()
+}
+
+fn sorted_levels(values: &[String]) -> Vec<String> {
+    let mut levels = values.to_vec();
+    levels.sort();
+    levels.dedup();
+    levels
+}
+
+fn numeric_columns(covariates: &[Covariate], bs: &str) -> Result<Vec<Vec<f64>>, Error> {
+    covariates
+        .iter()
+        .map(|c| match c {
+            Covariate::Numeric(values) => Ok(values.clone()),
+            Covariate::Factor(_) => Err(Error::Semantic(format!(
+                "bs=\"{}\" smooths need numeric variables",
+                bs
+            ))),
+        })
+        .collect()
+}
+
+/// The factor and the numeric covariates of a factor smooth interaction.
+fn split_factor(covariates: &[Covariate]) -> Result<(&[String], Vec<Covariate>), Error> {
+    let mut factor = None;
+    let mut numeric = Vec::new();
+    for c in covariates {
+        match c {
+            Covariate::Factor(values) if factor.is_none() => factor = Some(values.as_slice()),
+            Covariate::Factor(_) => {
+                return Err(Error::Semantic(
+                    "bs=\"fs\" takes exactly one factor variable".into(),
+                ))
+            }
+            Covariate::Numeric(_) => numeric.push(c.clone()),
+        }
+    }
+    match factor {
+        Some(factor) if !numeric.is_empty() => Ok((factor, numeric)),
+        _ => Err(Error::Semantic(
+            "bs=\"fs\" needs numeric variables and one factor variable".into(),
+        )),
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(combine: Combine, bs: &str, k: usize) -> SmoothOptions {
+        SmoothOptions {
+            combine,
+            bs: vec![bs.to_string()],
+            k: vec![k],
+            m: None,
+            xt: None,
+        }
+    }
+
+    fn grid(n: usize) -> Vec<f64> {
+        (0..n).map(|i| i as f64 / (n - 1) as f64).collect()
+    }
+
+    #[test]
+    fn test_tps_eta_matches_cubic_for_one_dimension() {
+        // eta_{2,1}(r) = r^3 / 12
+        assert!((tps_eta(2.0, 2, 1) - 8.0 / 12.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_thin_plate_penalty_annihilates_linear_functions() {
+        let x = grid(30);
+        let state = SmoothState::learn(
+            &options(Combine::Single, "tp", 8),
+            &[Covariate::Numeric(x.clone())],
+            None,
+        )
+        .unwrap();
+        let penalty = &state.margins[0].penalties[0];
+        // The last two (polynomial) columns are unpenalized
+        assert!(penalty[6..].iter().flatten().all(|v| *v == 0.0));
+        let (values, _) = symmetric_eigen(penalty);
+        assert!(values.iter().all(|v| *v > -1e-8));
+    }
+
+    #[test]
+    fn test_centered_smooth_columns_sum_to_zero() {
+        let x = grid(40);
+        let covariates = [Covariate::Numeric(x)];
+        for bs in ["tp", "cr", "cs", "cc", "ps"] {
+            let state =
+                SmoothState::learn(&options(Combine::Single, bs, 6), &covariates, None).unwrap();
+            let blocks = state.blocks(&covariates, None).unwrap();
+            assert_eq!(blocks[0].columns.len(), 5, "{}", bs);
+            for column in &blocks[0].columns {
+                assert!(mean(column).abs() < 1e-10, "{}", bs);
+            }
+            assert_eq!(blocks[0].penalties[0].len(), 5);
+        }
+    }
+
+    #[test]
+    fn test_mixed_representation_reproduces_the_smooth() {
+        let x = grid(30);
+        let covariates = [Covariate::Numeric(x)];
+        let state =
+            SmoothState::learn(&options(Combine::Single, "cr", 6), &covariates, None).unwrap();
+        let block = state.blocks(&covariates, None).unwrap().remove(0);
+        assert_eq!(block.null_space_dim(), 1);
+
+        let mixed = block.to_mixed().unwrap();
+        assert_eq!(mixed.fixed.len(), 1);
+        assert_eq!(mixed.random.len(), 1);
+        assert_eq!(mixed.random[0].len(), 4);
+        // The unpenalized part of a centered cubic spline is the centered linear trend
+        let x = grid(30);
+        let ratio = mixed.fixed[0][0] / (x[0] - mean(&x));
+        for (f, xi) in mixed.fixed[0].iter().zip(&x) {
+            assert!((f - ratio * (xi - mean(&x))).abs() < 1e-8);
+        }
+
+        let te = SmoothState::learn(
+            &options(Combine::Tensor, "cr", 4),
+            &[Covariate::Numeric(grid(30)), Covariate::Numeric(grid(30))],
+            None,
+        )
+        .unwrap();
+        let block = te
+            .blocks(
+                &[Covariate::Numeric(grid(30)), Covariate::Numeric(grid(30))],
+                None,
+            )
+            .unwrap()
+            .remove(0);
+        assert!(block.to_mixed().is_err());
+    }
+
+    #[test]
+    fn test_tensor_product_dimensions() {
+        let x = grid(30);
+        let z: Vec<f64> = x.iter().map(|v| (v * 7.0).sin()).collect();
+        let covariates = [Covariate::Numeric(x), Covariate::Numeric(z)];
+        let te = SmoothState::learn(&options(Combine::Tensor, "cr", 4), &covariates, None).unwrap();
+        let blocks = te.blocks(&covariates, None).unwrap();
+        assert_eq!(blocks[0].columns.len(), 15);
+        assert_eq!(blocks[0].penalties.len(), 2);
+
+        let ti = SmoothState::learn(
+            &options(Combine::TensorInteraction, "cr", 4),
+            &covariates,
+            None,
+        )
+        .unwrap();
+        assert_eq!(ti.blocks(&covariates, None).unwrap()[0].columns.len(), 9);
+
+        let t2 = SmoothState::learn(&options(Combine::T2, "cr", 4), &covariates, None).unwrap();
+        let blocks = t2.blocks(&covariates, None).unwrap();
+        assert_eq!(blocks[0].columns.len(), 15);
+        assert_eq!(blocks[0].penalties.len(), 3);
+    }
+}
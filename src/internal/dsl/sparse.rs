@@ -0,0 +1,365 @@
+//! Sparse random-effects design matrices.
+//!
+//! Each row of a random-effects design matrix Z has one non-zero per inner
+//! column of every group term, so storing Z densely wastes memory as soon as a
+//! grouping factor has more than a handful of levels. [`SparseZ`] holds Z in
+//! compressed sparse column (CSC) form together with the grouping metadata
+//! needed to fit a mixed model (levels, inner columns and column spans).
+
+use super::ast::GroupKind;
+use super::random::RandomBlock;
+#[cfg(any(feature = "ndarray", feature = "nalgebra"))]
+use crate::Error;
+use polars::prelude::*;
+use std::ops::Range;
+
+/// Random-effects design matrix Z in compressed sparse column (CSC) form.
+///
+/// The entries are held in a [`CscMatrix`]. Entries implied by the grouping
+/// structure are stored even when their value is zero (e.g. a random slope at
+/// `x = 0`), so the sparsity pattern depends on the grouping factors only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseZ {
+    /// The entries of Z.
+    pub matrix: CscMatrix,
+    /// Column names, the same as in the dense Z returned by `materialize`.
+    pub column_names: Vec<String>,
+    /// Grouping metadata of each random-effects term, in column order.
+    pub terms: Vec<SparseZTerm>,
+}
+
+/// Grouping metadata of one random-effects term, e.g. `(1 + x | g)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseZTerm {
+    /// Name of the grouping factor.
+    pub group: String,
+    /// Levels of the grouping factor, in column order.
+    pub levels: Vec<String>,
+    /// Inner columns per level, e.g. `["(Intercept)", "x"]`.
+    pub inner: Vec<String>,
+    /// Columns of Z spanned by the term. Columns are level-major: the inner
+    /// columns of the first level come first, then those of the second level.
+    pub columns: Range<usize>,
+    /// Whether the inner columns are correlated (`|`) or independent (`||`).
+    pub kind: GroupKind,
+    /// Shared `|<id>|` label linking terms with correlated effects, if any.
+    pub id: Option<String>,
+    /// The `by` variable of `gr(g, by = x)`, if any.
+    pub by: Option<GroupBy>,
+}
+
+/// The `by` variable of a `gr(g, by = x)` term: each of its levels gets its
+/// own variance parameters for the levels of `g` it contains.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupBy {
+    /// Name of the `by` variable.
+    pub variable: String,
+    /// Sorted levels of the `by` variable.
+    pub levels: Vec<String>,
+    /// Index into `levels` of every level of the grouping factor.
+    pub level_of_group: Vec<usize>,
+}
+
+/// A sparse matrix in compressed sparse row (CSR) form.
+///
+/// The non-zeros of row `i` are `values[row_ptr[i]..row_ptr[i + 1]]`, at the
+/// columns `col_idx[row_ptr[i]..row_ptr[i + 1]]` (increasing within a row).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrMatrix {
+    /// Number of rows.
+    pub nrows: usize,
+    /// Number of columns.
+    pub ncols: usize,
+    /// Row pointers, of length `nrows + 1`.
+    pub row_ptr: Vec<usize>,
+    /// Column index of every stored entry.
+    pub col_idx: Vec<usize>,
+    /// Value of every stored entry.
+    pub values: Vec<f64>,
+}
+
+/// A sparse matrix in compressed sparse column (CSC) form.
+///
+/// The non-zeros of column `j` are `values[col_ptr[j]..col_ptr[j + 1]]`, at the
+/// rows `row_idx[col_ptr[j]..col_ptr[j + 1]]` (increasing within a column).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CscMatrix {
+    /// Number of rows.
+    pub nrows: usize,
+    /// Number of columns.
+    pub ncols: usize,
+    /// Column pointers, of length `ncols + 1`.
+    pub col_ptr: Vec<usize>,
+    /// Row index of every stored entry.
+    pub row_idx: Vec<usize>,
+    /// Value of every stored entry.
+    pub values: Vec<f64>,
+}
+
+impl CscMatrix {
+    /// An empty `nrows × 0` matrix.
+    pub(crate) fn new(nrows: usize) -> Self {
+        Self {
+            nrows,
+            ncols: 0,
+            col_ptr: vec![0],
+            row_idx: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Number of stored entries.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The entry at (`row`, `col`); zero where nothing is stored.
+    pub fn get(&self, row: usize, col: usize) -> f64 {
+        let span = self.col_ptr[col]..self.col_ptr[col + 1];
+        match self.row_idx[span.clone()].binary_search(&row) {
+            Ok(k) => self.values[span.start + k],
+            Err(_) => 0.0,
+        }
+    }
+
+    /// The same matrix in compressed sparse row form.
+    pub fn to_csr(&self) -> CsrMatrix {
+        let mut row_ptr = vec![0; self.nrows + 1];
+        for &i in &self.row_idx {
+            row_ptr[i + 1] += 1;
+        }
+        for i in 0..self.nrows {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+        let mut next = row_ptr.clone();
+        let mut col_idx = vec![0; self.nnz()];
+        let mut values = vec![0.0; self.nnz()];
+        for j in 0..self.ncols {
+            for k in self.col_ptr[j]..self.col_ptr[j + 1] {
+                let slot = &mut next[self.row_idx[k]];
+                col_idx[*slot] = j;
+                values[*slot] = self.values[k];
+                *slot += 1;
+            }
+        }
+        CsrMatrix {
+            nrows: self.nrows,
+            ncols: self.ncols,
+            row_ptr,
+            col_idx,
+            values,
+        }
+    }
+
+    /// The matrix as a `faer` sparse column matrix.
+    #[cfg(feature = "faer")]
+    pub fn to_faer(&self) -> faer::sparse::SparseColMat<usize, f64> {
+        let symbolic = faer::sparse::SymbolicSparseColMat::new_checked(
+            self.nrows,
+            self.ncols,
+            self.col_ptr.clone(),
+            None,
+            self.row_idx.clone(),
+        );
+        faer::sparse::SparseColMat::new(symbolic, self.values.clone())
+    }
+
+    /// The matrix as a `sprs` CSC matrix.
+    #[cfg(feature = "ndarray")]
+    pub fn to_sprs(&self) -> Result<sprs::CsMat<f64>, Error> {
+        sprs::CsMat::try_new_csc(
+            (self.nrows, self.ncols),
+            self.col_ptr.clone(),
+            self.row_idx.clone(),
+            self.values.clone(),
+        )
+        .map_err(|(_, _, _, e)| Error::Semantic(e.to_string()))
+    }
+
+    /// The matrix as a `nalgebra-sparse` CSC matrix.
+    #[cfg(feature = "nalgebra")]
+    pub fn to_nalgebra_sparse(&self) -> Result<nalgebra_sparse::CscMatrix<f64>, Error> {
+        nalgebra_sparse::CscMatrix::try_from_csc_data(
+            self.nrows,
+            self.ncols,
+            self.col_ptr.clone(),
+            self.row_idx.clone(),
+            self.values.clone(),
+        )
+        .map_err(|e| Error::Semantic(e.to_string()))
+    }
+
+    /// Append a column with the given `(row, value)` entries, in increasing
+    /// row order.
+    pub(crate) fn push_column(&mut self, entries: impl IntoIterator<Item = (usize, f64)>) {
+        for (i, v) in entries {
+            self.row_idx.push(i);
+            self.values.push(v);
+        }
+        self.ncols += 1;
+        self.col_ptr.push(self.values.len());
+    }
+}
+
+impl SparseZ {
+    /// An empty `nrows × 0` matrix.
+    pub(crate) fn new(nrows: usize) -> Self {
+        Self {
+            matrix: CscMatrix::new(nrows),
+            column_names: Vec::new(),
+            terms: Vec::new(),
+        }
+    }
+
+    /// Number of rows (observations).
+    pub fn nrows(&self) -> usize {
+        self.matrix.nrows
+    }
+
+    /// Number of columns.
+    pub fn ncols(&self) -> usize {
+        self.matrix.ncols
+    }
+
+    /// Number of stored entries.
+    pub fn nnz(&self) -> usize {
+        self.matrix.nnz()
+    }
+
+    /// The entry at (`row`, `col`); zero where nothing is stored.
+    pub fn get(&self, row: usize, col: usize) -> f64 {
+        self.matrix.get(row, col)
+    }
+
+    /// The same matrix in compressed sparse row form.
+    pub fn to_csr(&self) -> CsrMatrix {
+        self.matrix.to_csr()
+    }
+
+    /// The matrix as a dense DataFrame, as returned by `materialize`.
+    pub fn to_dense(&self) -> PolarsResult<DataFrame> {
+        let m = &self.matrix;
+        let columns = (0..m.ncols)
+            .map(|j| {
+                let mut data = vec![0.0; m.nrows];
+                for k in m.col_ptr[j]..m.col_ptr[j + 1] {
+                    data[m.row_idx[k]] = m.values[k];
+                }
+                Float64Chunked::from_vec(self.column_names[j].as_str().into(), data)
+                    .into_series()
+                    .into()
+            })
+            .collect();
+        DataFrame::new(columns)
+    }
+
+    /// The matrix as a `faer` sparse column matrix.
+    #[cfg(feature = "faer")]
+    pub fn to_faer(&self) -> faer::sparse::SparseColMat<usize, f64> {
+        self.matrix.to_faer()
+    }
+
+    /// The matrix as a `sprs` CSC matrix.
+    #[cfg(feature = "ndarray")]
+    pub fn to_sprs(&self) -> Result<sprs::CsMat<f64>, Error> {
+        self.matrix.to_sprs()
+    }
+
+    /// The matrix as a `nalgebra-sparse` CSC matrix.
+    #[cfg(feature = "nalgebra")]
+    pub fn to_nalgebra_sparse(&self) -> Result<nalgebra_sparse::CscMatrix<f64>, Error> {
+        self.matrix.to_nalgebra_sparse()
+    }
+
+    /// Append the columns of a random-effects block. The `cor` and `id`
+    /// arguments of `gr()` override `kind` and `id`. A missing inner value is
+    /// stored as NaN, as a sparse matrix has no missing entries.
+    pub(crate) fn push_block(
+        &mut self,
+        block: &RandomBlock,
+        names: Vec<String>,
+        kind: GroupKind,
+        id: Option<String>,
+    ) {
+        let start = self.matrix.ncols;
+        for rows in block.rows_by_level() {
+            for (_, values) in &block.inner {
+                self.matrix.push_column(
+                    rows.iter()
+                        .map(|&(i, weight)| (i, weight * values[i].unwrap_or(f64::NAN))),
+                );
+            }
+        }
+        self.column_names.extend(names);
+        self.terms.push(SparseZTerm {
+            group: block.group.clone(),
+            levels: block.levels.clone(),
+            inner: block.inner.iter().map(|(label, _)| label.clone()).collect(),
+            columns: start..self.matrix.ncols,
+            kind: if block.cor {
+                kind
+            } else {
+                GroupKind::Uncorrelated
+            },
+            id: block.id.clone().or(id),
+            by: block.by.clone(),
+        });
+    }
+
+    /// Append a dense column, storing its non-zero entries only.
+    pub(crate) fn push_dense(&mut self, name: String, values: &[f64]) {
+        self.matrix.push_column(
+            values
+                .iter()
+                .enumerate()
+                .filter(|&(_, &v)| v != 0.0)
+                .map(|(i, &v)| (i, v)),
+        );
+        self.column_names.push(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> SparseZ {
+        let block = RandomBlock::new(
+            "g".into(),
+            vec!["a".into(), "b".into()],
+            vec![Some(0), Some(1), None, Some(0)],
+            vec![
+                ("(Intercept)".into(), vec![Some(1.0); 4]),
+                ("x".into(), vec![Some(0.5), Some(2.0), Some(3.0), Some(0.0)]),
+            ],
+        );
+        let mut z = SparseZ::new(4);
+        z.push_block(&block, block.column_names(), GroupKind::Correlated, None);
+        z
+    }
+
+    #[test]
+    fn test_block_columns_are_level_major() {
+        let z = example();
+        assert_eq!(z.ncols(), 4);
+        assert_eq!(z.matrix.col_ptr, vec![0, 2, 4, 5, 6]);
+        assert_eq!(z.matrix.row_idx, vec![0, 3, 0, 3, 1, 1]);
+        // The slope at x = 0 is kept as a structural entry.
+        assert_eq!(z.matrix.values, vec![1.0, 1.0, 0.5, 0.0, 1.0, 2.0]);
+        assert_eq!(z.terms[0].columns, 0..4);
+        assert_eq!(z.get(1, 3), 2.0);
+        assert_eq!(z.get(2, 0), 0.0);
+    }
+
+    #[test]
+    fn test_csr_matches_csc() {
+        let z = example();
+        let csr = z.to_csr();
+        assert_eq!(csr.row_ptr, vec![0, 2, 4, 4, 6]);
+        for i in 0..csr.nrows {
+            for k in csr.row_ptr[i]..csr.row_ptr[i + 1] {
+                assert_eq!(csr.values[k], z.get(i, csr.col_idx[k]));
+            }
+        }
+    }
+}
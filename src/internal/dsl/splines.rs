@@ -0,0 +1,676 @@
+//! Spline bases for `bs()`, `ns()`, `cr()` and `cc()`.
+//!
+//! B-splines and natural cubic splines follow R's `splines` package (`bs()`,
+//! `ns()`, including their boundary extrapolation), while the cubic regression
+//! splines `cr()` / `cc()` follow the mgcv parameterization used by Patsy and
+//! Formulaic. Knots are learned once from the training data and kept in a
+//! [`SplineState`] so the identical basis can be evaluated on new data.
+
+use super::linalg::{solve_dense, Householder};
+use crate::Error;
+
+/// Learned parameters of a spline basis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplineState {
+    /// `bs(x, df, knots, degree, intercept, Boundary.knots)`
+    BSpline {
+        degree: usize,
+        knots: Vec<f64>,
+        boundary_knots: (f64, f64),
+        intercept: bool,
+    },
+    /// `ns(x, df, knots, intercept, Boundary.knots)`
+    Natural {
+        knots: Vec<f64>,
+        boundary_knots: (f64, f64),
+        intercept: bool,
+    },
+    /// `cr(x, df, knots, lower_bound, upper_bound, constraints)` and its cyclic
+    /// variant `cc()`. `knots` holds all knots, boundaries included.
+    CubicRegression {
+        knots: Vec<f64>,
+        constraints: Option<Vec<Vec<f64>>>,
+        cyclic: bool,
+    },
+}
+
+impl SplineState {
+    /// Learn a B-spline basis from data, mirroring `splines::bs()`.
+    pub fn bs(
+        x: &[f64],
+        df: Option<usize>,
+        knots: Option<Vec<f64>>,
+        degree: usize,
+        intercept: bool,
+        boundary_knots: Option<(f64, f64)>,
+    ) -> Result<Self, Error> {
+        if degree == 0 {
+            return Err(Error::Semantic("bs(): 'degree' must be at least 1".into()));
+        }
+        let boundary_knots = resolve_boundary(x, boundary_knots, "bs")?;
+        let knots = match (knots, df) {
+            (Some(knots), _) => knots,
+            (None, Some(df)) => {
+                let n_interior = df as i64 - degree as i64 - intercept as i64;
+                if n_interior < 0 {
+                    return Err(Error::Semantic(format!(
+                        "bs(): 'df' was too small; have used {}",
+                        degree + intercept as usize
+                    )));
+                }
+                interior_quantiles(x, boundary_knots, n_interior as usize, false, "bs")?
+            }
+            (None, None) => Vec::new(),
+        };
+        Ok(SplineState::BSpline {
+            degree,
+            knots,
+            boundary_knots,
+            intercept,
+        })
+    }
+
+    /// Learn a natural cubic spline basis from data, mirroring `splines::ns()`.
+    pub fn ns(
+        x: &[f64],
+        df: Option<usize>,
+        knots: Option<Vec<f64>>,
+        intercept: bool,
+        boundary_knots: Option<(f64, f64)>,
+    ) -> Result<Self, Error> {
+        let boundary_knots = resolve_boundary(x, boundary_knots, "ns")?;
+        let knots = match (knots, df) {
+            (Some(knots), _) => knots,
+            (None, Some(df)) => {
+                let n_interior = df as i64 - 1 - intercept as i64;
+                if n_interior < 0 {
+                    return Err(Error::Semantic(format!(
+                        "ns(): 'df' was too small; have used {}",
+                        1 + intercept as usize
+                    )));
+                }
+                interior_quantiles(x, boundary_knots, n_interior as usize, false, "ns")?
+            }
+            (None, None) => Vec::new(),
+        };
+        Ok(SplineState::Natural {
+            knots,
+            boundary_knots,
+            intercept,
+        })
+    }
+
+    /// Learn a cubic regression spline (`cr`) or cyclic cubic spline (`cc`) basis.
+    ///
+    /// `center` applies the sum-to-zero constraint Patsy calls `constraints="center"`.
+    pub fn cubic_regression(
+        x: &[f64],
+        df: Option<usize>,
+        knots: Option<Vec<f64>>,
+        bounds: (Option<f64>, Option<f64>),
+        center: bool,
+        cyclic: bool,
+    ) -> Result<Self, Error> {
+        let name = if cyclic { "cc" } else { "cr" };
+        let n_constraints = center as usize;
+        let n_inner = match (&knots, df) {
+            (Some(_), _) => None,
+            (None, Some(df)) => {
+                let min_df = if !cyclic && n_constraints == 0 { 2 } else { 1 };
+                if df < min_df {
+                    return Err(Error::Semantic(format!(
+                        "{}(): 'df' must be at least {}",
+                        name, min_df
+                    )));
+                }
+                Some(df - 2 + n_constraints + cyclic as usize)
+            }
+            (None, None) => {
+                return Err(Error::Semantic(format!(
+                    "{}(): must specify either 'df' or 'knots'",
+                    name
+                )))
+            }
+        };
+
+        let lower = bounds.0.or_else(|| x.iter().cloned().reduce(f64::min));
+        let upper = bounds.1.or_else(|| x.iter().cloned().reduce(f64::max));
+        let (lower, upper) = match (lower, upper) {
+            (Some(l), Some(u)) if l <= u => (l, u),
+            (Some(_), Some(_)) => {
+                return Err(Error::Semantic(format!(
+                    "{}(): lower bound is greater than upper bound",
+                    name
+                )))
+            }
+            _ => {
+                return Err(Error::Semantic(format!(
+                    "{}(): cannot place knots without data",
+                    name
+                )))
+            }
+        };
+
+        let inner = match (knots, n_inner) {
+            (Some(knots), _) => knots,
+            (None, Some(n_inner)) => interior_quantiles(x, (lower, upper), n_inner, true, name)?,
+            (None, None) => Vec::new(),
+        };
+        let mut all_knots = vec![lower, upper];
+        all_knots.extend(inner);
+        all_knots.sort_by(|a, b| a.total_cmp(b));
+        all_knots.dedup();
+        if let Some(n_inner) = n_inner {
+            if all_knots.len() != n_inner + 2 {
+                return Err(Error::Semantic(format!(
+                    "{}(): unable to compute {} distinct knots from the data",
+                    name,
+                    n_inner + 2
+                )));
+            }
+        }
+
+        let constraints = if center {
+            let free = cubic_regression_free_basis(x, &all_knots, cyclic);
+            Some(vec![free.iter().map(|col| mean(col)).collect()])
+        } else {
+            None
+        };
+
+        Ok(SplineState::CubicRegression {
+            knots: all_knots,
+            constraints,
+            cyclic,
+        })
+    }
+
+    /// Number of basis columns this state produces.
+    pub fn ncols(&self) -> usize {
+        match self {
+            SplineState::BSpline {
+                degree,
+                knots,
+                intercept,
+                ..
+            } => knots.len() + degree + *intercept as usize,
+            SplineState::Natural {
+                knots, intercept, ..
+            } => knots.len() + 1 + *intercept as usize,
+            SplineState::CubicRegression {
+                knots,
+                constraints,
+                cyclic,
+            } => knots.len() - *cyclic as usize - constraints.as_ref().map_or(0, |c| c.len()),
+        }
+    }
+
+    /// Evaluate the basis at `x`, returning one vector per basis column.
+    pub fn basis(&self, x: &[f64]) -> Vec<Vec<f64>> {
+        match self {
+            SplineState::BSpline {
+                degree,
+                knots,
+                boundary_knots,
+                intercept,
+            } => bspline_basis(x, *degree, knots, *boundary_knots, *intercept),
+            SplineState::Natural {
+                knots,
+                boundary_knots,
+                intercept,
+            } => natural_spline_basis(x, knots, *boundary_knots, *intercept),
+            SplineState::CubicRegression {
+                knots,
+                constraints,
+                cyclic,
+            } => {
+                let free = cubic_regression_free_basis(x, knots, *cyclic);
+                match constraints {
+                    Some(c) => absorb_constraints(&free, c),
+                    None => free,
+                }
+            }
+        }
+    }
+}
+
+/// Boundary knots default to the range of the data.
+fn resolve_boundary(
+    x: &[f64],
+    boundary_knots: Option<(f64, f64)>,
+    name: &str,
+) -> Result<(f64, f64), Error> {
+    if let Some(b) = boundary_knots {
+        return Ok(b);
+    }
+    let lo = x.iter().cloned().reduce(f64::min);
+    let hi = x.iter().cloned().reduce(f64::max);
+    match (lo, hi) {
+        (Some(lo), Some(hi)) => Ok((lo, hi)),
+        _ => Err(Error::Semantic(format!(
+            "{}(): cannot place knots without data",
+            name
+        ))),
+    }
+}
+
+/// Interior knots at evenly spaced quantiles of the data inside the boundary.
+///
+/// It is an error when the data cannot give `n` knots strictly inside the
+/// boundary, e.g. for constant `x` or boundary knots that exclude the data, as
+/// the basis would have fewer (or degenerate) columns than requested.
+pub(crate) fn interior_quantiles(
+    x: &[f64],
+    bounds: (f64, f64),
+    n: usize,
+    unique: bool,
+    name: &str,
+) -> Result<Vec<f64>, Error> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut inside: Vec<f64> = x
+        .iter()
+        .cloned()
+        .filter(|v| *v >= bounds.0 && *v <= bounds.1)
+        .collect();
+    inside.sort_by(|a, b| a.total_cmp(b));
+    if unique {
+        inside.dedup();
+    }
+    let knots: Vec<f64> = if inside.is_empty() {
+        Vec::new()
+    } else {
+        (1..=n)
+            .map(|i| quantile_sorted(&inside, i as f64 / (n + 1) as f64))
+            .collect()
+    };
+    if knots.is_empty() || knots.iter().any(|k| *k <= bounds.0 || *k >= bounds.1) {
+        return Err(Error::Semantic(format!(
+            "{}(): cannot place {} interior knots strictly between the boundary knots {} and {} \
+             from {} data values inside them",
+            name,
+            n,
+            bounds.0,
+            bounds.1,
+            inside.len()
+        )));
+    }
+    Ok(knots)
+}
+
+/// Linear-interpolation quantile of sorted data (R type 7, NumPy default).
+fn quantile_sorted(sorted: &[f64], p: f64) -> f64 {
+    let h = (sorted.len() - 1) as f64 * p;
+    let lo = h.floor() as usize;
+    let hi = (lo + 1).min(sorted.len() - 1);
+    sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+/// Values (or `deriv`-th derivatives) of all B-splines of the given order at `x`,
+/// as computed by `splines::splineDesign()`. `x` equal to the last knot is
+/// treated as lying in the last non-empty interval.
+pub(crate) fn bspline_values(t: &[f64], x: f64, order: usize, deriv: usize) -> Vec<f64> {
+    let n = t.len() - order;
+    let mut values = vec![0.0; n];
+    if order == 1 {
+        if deriv > 0 {
+            return values;
+        }
+        if let Some(i) = (0..n).find(|&i| t[i] <= x && x < t[i + 1]) {
+            values[i] = 1.0;
+        } else if x == t[t.len() - 1] {
+            if let Some(i) = (0..n).rev().find(|&i| t[i] < t[i + 1]) {
+                values[i] = 1.0;
+            }
+        }
+        return values;
+    }
+
+    let ratio = |num: f64, den: f64| if den > 0.0 { num / den } else { 0.0 };
+    if deriv > 0 {
+        let lower = bspline_values(t, x, order - 1, deriv - 1);
+        let scale = (order - 1) as f64;
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = scale
+                * (ratio(lower[i], t[i + order - 1] - t[i])
+                    - ratio(lower[i + 1], t[i + order] - t[i + 1]));
+        }
+    } else {
+        let lower = bspline_values(t, x, order - 1, 0);
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = ratio(x - t[i], t[i + order - 1] - t[i]) * lower[i]
+                + ratio(t[i + order] - x, t[i + order] - t[i + 1]) * lower[i + 1];
+        }
+    }
+    values
+}
+
+/// Full knot sequence with the boundary knots repeated `order` times.
+fn augmented_knots(knots: &[f64], boundary_knots: (f64, f64), order: usize) -> Vec<f64> {
+    let mut t = vec![boundary_knots.0; order];
+    t.extend_from_slice(knots);
+    t.extend(std::iter::repeat(boundary_knots.1).take(order));
+    t
+}
+
+/// Taylor expansion of the basis around a boundary knot, used for values of `x`
+/// outside the boundary exactly as `bs()` and `ns()` extrapolate.
+fn extrapolate(t: &[f64], order: usize, pivot: f64, x: f64, max_deriv: usize) -> Vec<f64> {
+    let mut row = vec![0.0; t.len() - order];
+    let mut factorial = 1.0;
+    for d in 0..=max_deriv {
+        if d > 0 {
+            factorial *= d as f64;
+        }
+        let scale = (x - pivot).powi(d as i32) / factorial;
+        for (acc, v) in row.iter_mut().zip(bspline_values(t, pivot, order, d)) {
+            *acc += scale * v;
+        }
+    }
+    row
+}
+
+fn bspline_basis(
+    x: &[f64],
+    degree: usize,
+    knots: &[f64],
+    boundary_knots: (f64, f64),
+    intercept: bool,
+) -> Vec<Vec<f64>> {
+    let order = degree + 1;
+    let t = augmented_knots(knots, boundary_knots, order);
+    let rows = x.iter().map(|&xi| {
+        if xi < boundary_knots.0 {
+            extrapolate(&t, order, boundary_knots.0, xi, degree)
+        } else if xi > boundary_knots.1 {
+            extrapolate(&t, order, boundary_knots.1, xi, degree)
+        } else {
+            bspline_values(&t, xi, order, 0)
+        }
+    });
+    rows_to_columns(rows, t.len() - order, !intercept)
+}
+
+fn natural_spline_basis(
+    x: &[f64],
+    knots: &[f64],
+    boundary_knots: (f64, f64),
+    intercept: bool,
+) -> Vec<Vec<f64>> {
+    let t = augmented_knots(knots, boundary_knots, 4);
+    let skip = !intercept as usize;
+
+    // Second derivatives at both boundaries must vanish
+    let constraint: Vec<Vec<f64>> = [boundary_knots.0, boundary_knots.1]
+        .iter()
+        .map(|&b| bspline_values(&t, b, 4, 2)[skip..].to_vec())
+        .collect();
+    let qr = Householder::new(constraint);
+
+    let rows = x.iter().map(|&xi| {
+        let row = if xi < boundary_knots.0 {
+            extrapolate(&t, 4, boundary_knots.0, xi, 1)
+        } else if xi > boundary_knots.1 {
+            extrapolate(&t, 4, boundary_knots.1, xi, 1)
+        } else {
+            bspline_values(&t, xi, 4, 0)
+        };
+        let mut row = row[skip..].to_vec();
+        qr.qty(&mut row);
+        row.drain(..2);
+        row
+    });
+    let ncols = t.len() - 4 - skip - 2;
+    rows_to_columns(rows, ncols, false)
+}
+
+fn rows_to_columns(
+    rows: impl Iterator<Item = Vec<f64>>,
+    width: usize,
+    drop_first: bool,
+) -> Vec<Vec<f64>> {
+    let skip = drop_first as usize;
+    let mut columns = vec![Vec::new(); width - skip];
+    for row in rows {
+        for (column, value) in columns.iter_mut().zip(row.into_iter().skip(skip)) {
+            column.push(value);
+        }
+    }
+    columns
+}
+
+/// Reparameterize a basis so that `constraints %*% beta = 0`, keeping the
+/// columns of `Q` (from the QR of the constraints' transpose) past the first `m`.
+pub(crate) fn absorb_constraints(basis: &[Vec<f64>], constraints: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let m = constraints.len();
+    let width = basis.len();
+    let n = basis.first().map_or(0, |c| c.len());
+    let qr = Householder::new(constraints.to_vec());
+    let rows = (0..n).map(|i| {
+        let mut row: Vec<f64> = basis.iter().map(|c| c[i]).collect();
+        qr.qty(&mut row);
+        row.drain(..m);
+        row
+    });
+    rows_to_columns(rows, width - m, false)
+}
+
+/// Band matrices `B` and `D` of a natural cubic spline through the knots: the
+/// second derivatives `g''` at the interior knots satisfy `B g'' = D g`.
+fn natural_bd(knots: &[f64]) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+    let n = knots.len();
+    let h: Vec<f64> = knots.windows(2).map(|w| w[1] - w[0]).collect();
+    let m = n.saturating_sub(2);
+    let mut b = vec![vec![0.0; m]; m];
+    let mut d = vec![vec![0.0; n]; m];
+    for i in 0..m {
+        b[i][i] = (h[i] + h[i + 1]) / 3.0;
+        if i + 1 < m {
+            b[i][i + 1] = h[i + 1] / 6.0;
+            b[i + 1][i] = h[i + 1] / 6.0;
+        }
+        d[i][i] = 1.0 / h[i];
+        d[i][i + 2] = 1.0 / h[i + 1];
+        d[i][i + 1] = -d[i][i] - d[i][i + 2];
+    }
+    (b, d)
+}
+
+/// Cyclic counterpart of [`natural_bd`]; the last knot wraps onto the first.
+fn cyclic_bd(knots: &[f64]) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+    let h: Vec<f64> = knots.windows(2).map(|w| w[1] - w[0]).collect();
+    let n = knots.len() - 1;
+    let mut b = vec![vec![0.0; n]; n];
+    let mut d = vec![vec![0.0; n]; n];
+    b[0][0] = (h[n - 1] + h[0]) / 3.0;
+    b[0][n - 1] = h[n - 1] / 6.0;
+    b[n - 1][0] = h[n - 1] / 6.0;
+    d[0][0] = -1.0 / h[0] - 1.0 / h[n - 1];
+    d[0][n - 1] = 1.0 / h[n - 1];
+    d[n - 1][0] = 1.0 / h[n - 1];
+    for i in 1..n {
+        b[i][i] = (h[i - 1] + h[i]) / 3.0;
+        b[i][i - 1] = h[i - 1] / 6.0;
+        b[i - 1][i] = h[i - 1] / 6.0;
+        d[i][i] = -1.0 / h[i - 1] - 1.0 / h[i];
+        d[i][i - 1] = 1.0 / h[i - 1];
+        d[i - 1][i] = 1.0 / h[i - 1];
+    }
+    (b, d)
+}
+
+/// Maps second derivatives at the knots to values at the knots for a natural
+/// cubic spline (zero curvature at both ends).
+fn natural_f(knots: &[f64]) -> Vec<Vec<f64>> {
+    let n = knots.len();
+    let mut f = vec![vec![0.0; n]; n];
+    if n < 3 {
+        return f;
+    }
+    let (b, d) = natural_bd(knots);
+    let fm = solve_dense(b, d);
+    f[1..(n - 1)].clone_from_slice(&fm);
+    f
+}
+
+/// Cyclic counterpart of [`natural_f`].
+fn cyclic_f(knots: &[f64]) -> Vec<Vec<f64>> {
+    let (b, d) = cyclic_bd(knots);
+    solve_dense(b, d)
+}
+
+/// Wiggliness penalty `D' B^-1 D` of the cubic regression spline basis, i.e. the
+/// integrated squared second derivative in terms of the values at the knots.
+pub(crate) fn cubic_regression_penalty(knots: &[f64], cyclic: bool) -> Vec<Vec<f64>> {
+    let (b, d) = if cyclic {
+        cyclic_bd(knots)
+    } else {
+        natural_bd(knots)
+    };
+    let n = d.first().map_or(knots.len(), |row| row.len());
+    if d.is_empty() {
+        return vec![vec![0.0; n]; n];
+    }
+    let fm = solve_dense(b, d.clone());
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| d.iter().zip(&fm).map(|(dr, fr)| dr[i] * fr[j]).sum())
+                .collect()
+        })
+        .collect()
+}
+
+/// Unconstrained cubic regression spline basis, one column per knot (per knot
+/// but the last for the cyclic variant), as in mgcv's `cr`/`cc` smooths.
+pub(crate) fn cubic_regression_free_basis(x: &[f64], knots: &[f64], cyclic: bool) -> Vec<Vec<f64>> {
+    let n_knots = knots.len();
+    let lower = knots[0];
+    let upper = knots[n_knots - 1];
+    let n = if cyclic { n_knots - 1 } else { n_knots };
+    let f = if cyclic {
+        cyclic_f(knots)
+    } else {
+        natural_f(knots)
+    };
+
+    let rows = x.iter().map(|&xi| {
+        let xi = if cyclic && upper > lower {
+            let period = upper - lower;
+            if xi > upper {
+                lower + (xi - upper) % period
+            } else if xi < lower {
+                upper - (lower - xi) % period
+            } else {
+                xi
+            }
+        } else {
+            xi
+        };
+
+        // Lower knot of the interval containing xi, clamped to the outer intervals
+        let j = knots
+            .partition_point(|k| *k < xi)
+            .saturating_sub(1)
+            .min(n_knots - 2);
+        let hj = knots[j + 1] - knots[j];
+        let xj1_x = knots[j + 1] - xi;
+        let x_xj = xi - knots[j];
+        let ajm = xj1_x / hj;
+        let ajp = x_xj / hj;
+        let cjm_3 = if xi > upper {
+            0.0
+        } else {
+            xj1_x.powi(3) / (6.0 * hj)
+        };
+        let cjm = cjm_3 - hj * xj1_x / 6.0;
+        let cjp_3 = if xi < lower {
+            0.0
+        } else {
+            x_xj.powi(3) / (6.0 * hj)
+        };
+        let cjp = cjp_3 - hj * x_xj / 6.0;
+
+        let j1 = if cyclic && j + 1 == n { 0 } else { j + 1 };
+        let mut row = vec![0.0; n];
+        row[j] += ajm;
+        row[j1] += ajp;
+        for (k, value) in row.iter_mut().enumerate() {
+            *value += cjm * f[j][k] + cjp * f[j1][k];
+        }
+        row
+    });
+    rows_to_columns(rows, n, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-10, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_bs_without_interior_knots_is_bernstein() {
+        // splines::bs(c(0, 0.5, 1), degree = 3)[2, ] == c(0.375, 0.375, 0.125)
+        let state = SplineState::bs(&[0.0, 0.5, 1.0], None, None, 3, false, None).unwrap();
+        let basis = state.basis(&[0.5]);
+        assert_eq!(basis.len(), 3);
+        assert_close(basis[0][0], 0.375);
+        assert_close(basis[1][0], 0.375);
+        assert_close(basis[2][0], 0.125);
+    }
+
+    #[test]
+    fn test_bs_with_intercept_is_partition_of_unity() {
+        let x: Vec<f64> = (0..20).map(|i| i as f64 / 2.0).collect();
+        let state = SplineState::bs(&x, Some(6), None, 3, true, None).unwrap();
+        let basis = state.basis(&x);
+        assert_eq!(basis.len(), 6);
+        for i in 0..x.len() {
+            assert_close(basis.iter().map(|c| c[i]).sum::<f64>(), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_ns_is_linear_beyond_boundary() {
+        let x: Vec<f64> = (0..30).map(|i| (i as f64).sqrt()).collect();
+        let state = SplineState::ns(&x, Some(4), None, false, None).unwrap();
+        let hi = x[x.len() - 1];
+        let basis = state.basis(&[hi + 1.0, hi + 2.0, hi + 3.0]);
+        for col in basis {
+            assert_close(col[1] - col[0], col[2] - col[1]);
+        }
+    }
+
+    #[test]
+    fn test_cr_interpolates_at_knots() {
+        let knots = vec![0.0, 1.0, 3.0, 4.0];
+        let basis = cubic_regression_free_basis(&knots, &knots, false);
+        for (j, col) in basis.iter().enumerate() {
+            for (k, value) in col.iter().enumerate() {
+                assert_close(*value, if j == k { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn test_cc_is_periodic() {
+        let x: Vec<f64> = (0..=20).map(|i| i as f64 / 20.0).collect();
+        let state =
+            SplineState::cubic_regression(&x, Some(5), None, (None, None), false, true).unwrap();
+        let basis = state.basis(&[0.0, 1.0]);
+        for col in basis {
+            assert_close(col[0], col[1]);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Sufficient statistics of weighted least squares, accumulated batch by batch.
+//!
+//! Fitting `y = Xβ + ε` by (weighted) least squares needs the data only
+//! through the cross-products `X'WX`, `X'Wy` and `y'Wy`. They are summed over
+//! batches, so memory grows with the square of the number of columns of X and
+//! not with the number of rows.
+
+use super::materialize::series_to_f64;
+use crate::Error;
+use polars::prelude::DataFrame;
+
+/// Cross-products of the design matrix X and the response y with the weights
+/// W, summed over the rows used.
+///
+/// Square matrices are stored by rows; `xtwy` has one row per column of X and
+/// one column per response column. Rows with a missing value in y, X, the
+/// weights or the offset are left out. Without a `weights()` aterm, W is the
+/// identity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SufficientStats {
+    /// Names of the columns of X.
+    pub columns: Vec<String>,
+    /// Names of the response columns.
+    pub responses: Vec<String>,
+    /// Number of rows used.
+    pub n: usize,
+    /// Sum of the weights of the rows used; `n` without weights.
+    pub weight_sum: f64,
+    /// `X'WX`.
+    pub xtwx: Vec<Vec<f64>>,
+    /// `X'Wy`.
+    pub xtwy: Vec<Vec<f64>>,
+    /// `y'Wy`.
+    pub ytwy: Vec<Vec<f64>>,
+    /// Weighted sums of the columns of X, `X'W1`.
+    pub column_sums: Vec<f64>,
+    /// Weighted sums of the response columns, `y'W1`.
+    pub response_sums: Vec<f64>,
+}
+
+impl SufficientStats {
+    /// Statistics of no rows for the given columns of X and y.
+    pub(crate) fn new(columns: Vec<String>, responses: Vec<String>) -> Self {
+        let (p, k) = (columns.len(), responses.len());
+        Self {
+            columns,
+            responses,
+            n: 0,
+            weight_sum: 0.0,
+            xtwx: vec![vec![0.0; p]; p],
+            xtwy: vec![vec![0.0; k]; p],
+            ytwy: vec![vec![0.0; k]; k],
+            column_sums: vec![0.0; p],
+            response_sums: vec![0.0; k],
+        }
+    }
+
+    /// Add the rows of a batch. The offset, if any, is subtracted from every
+    /// response column.
+    pub(crate) fn update(
+        &mut self,
+        y: &DataFrame,
+        x: &DataFrame,
+        weights: Option<&[Option<f64>]>,
+        offset: Option<&[Option<f64>]>,
+    ) -> Result<(), Error> {
+        let numeric = |df: &DataFrame, what: &str| {
+            df.get_columns()
+                .iter()
+                .map(|column| series_to_f64(column.as_materialized_series(), what))
+                .collect::<Result<Vec<_>, Error>>()
+        };
+        let y = numeric(y, "Response")?;
+        let x = numeric(x, "Design column")?;
+
+        let complete: Vec<usize> = (0..x.first().or(y.first()).map_or(0, Vec::len))
+            .filter(|&i| {
+                let missing = |values: &Vec<Option<f64>>| values[i].is_none();
+                !(y.iter().any(missing)
+                    || x.iter().any(missing)
+                    || weights.is_some_and(|w| w[i].is_none())
+                    || offset.is_some_and(|o| o[i].is_none()))
+            })
+            .collect();
+        let dense = |values: &Vec<Option<f64>>| -> Vec<f64> {
+            complete.iter().map(|&i| values[i].unwrap_or(0.0)).collect()
+        };
+        let w: Vec<f64> = match weights {
+            Some(w) => complete.iter().map(|&i| w[i].unwrap_or(0.0)).collect(),
+            None => vec![1.0; complete.len()],
+        };
+        let x: Vec<Vec<f64>> = x.iter().map(dense).collect();
+        let y: Vec<Vec<f64>> = y
+            .iter()
+            .map(|values| {
+                let mut values = dense(values);
+                if let Some(offset) = offset {
+                    for (v, &i) in values.iter_mut().zip(&complete) {
+                        *v -= offset[i].unwrap_or(0.0);
+                    }
+                }
+                values
+            })
+            .collect();
+
+        let weighted =
+            |column: &Vec<f64>| -> Vec<f64> { column.iter().zip(&w).map(|(v, w)| v * w).collect() };
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        let wx: Vec<Vec<f64>> = x.iter().map(weighted).collect();
+        let wy: Vec<Vec<f64>> = y.iter().map(weighted).collect();
+
+        for (j, wx_j) in wx.iter().enumerate() {
+            for (l, x_l) in x.iter().enumerate().skip(j) {
+                let value = dot(wx_j, x_l);
+                self.xtwx[j][l] += value;
+                if l != j {
+                    self.xtwx[l][j] += value;
+                }
+            }
+            for (r, y) in y.iter().enumerate() {
+                self.xtwy[j][r] += dot(wx_j, y);
+            }
+            self.column_sums[j] += wx_j.iter().sum::<f64>();
+        }
+        for (r, wy_r) in wy.iter().enumerate() {
+            for (s, y_s) in y.iter().enumerate().skip(r) {
+                let value = dot(wy_r, y_s);
+                self.ytwy[r][s] += value;
+                if s != r {
+                    self.ytwy[s][r] += value;
+                }
+            }
+            self.response_sums[r] += wy_r.iter().sum::<f64>();
+        }
+        self.n += complete.len();
+        self.weight_sum += w.iter().sum::<f64>();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "faer")]
+impl SufficientStats {
+    /// `X'WX` as a `faer` matrix.
+    pub fn xtwx_faer(&self) -> faer::Mat<f64> {
+        to_faer(&self.xtwx, self.columns.len())
+    }
+
+    /// `X'Wy` as a `faer` matrix, with one column per response column.
+    pub fn xtwy_faer(&self) -> faer::Mat<f64> {
+        to_faer(&self.xtwy, self.responses.len())
+    }
+
+    /// `y'Wy` as a `faer` matrix.
+    pub fn ytwy_faer(&self) -> faer::Mat<f64> {
+        to_faer(&self.ytwy, self.responses.len())
+    }
+
+    /// The weighted column sums of X as a `faer` column.
+    pub fn column_sums_faer(&self) -> faer::Col<f64> {
+        faer::Col::from_fn(self.column_sums.len(), |j| self.column_sums[j])
+    }
+}
+
+#[cfg(feature = "faer")]
+fn to_faer(rows: &[Vec<f64>], ncols: usize) -> faer::Mat<f64> {
+    faer::Mat::from_fn(rows.len(), ncols, |i, j| rows[i][j])
+}
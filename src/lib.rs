@@ -38,6 +38,7 @@
 //! | `y ~ x1 * x2` | Product terms (expands to x1 + x2 + x1:x2) |
 //! | `y ~ x1:x2` | Interaction terms |
 //! | `y ~ poly(x1, 2)` | Polynomial terms (x, x², x³, ...) |
+//! | `y ~ bs(x, df=5)` | B-splines (also `ns()`, `cr()`, `cc()`) |
 //! | `y ~ (1\|group)` | Random intercepts |
 //! | `y ~ (x\|group)` | Random slopes |
 //! | `y ~ (x\|\|group)` | Uncorrelated random effects |
@@ -49,7 +50,7 @@
 //! | `y \| weights(w) ~ x` | Auxiliary terms (weights, se, trials, etc.) |
 //! | `Surv(time, event) ~ x` | Survival analysis |
 //! | `cbind(success, failure) ~ x` | Multivariate responses |
-//! | `s(x, k=10, bs="tp")` | Smooth terms (s, t2, te, ti) |
+//! | `s(x, k=10, bs="tp")` | Smooth terms (s, t2, te, ti) with penalties in `DesignInfo` |
 //! | `family=gaussian() y ~ x` | Distribution families |
 //! | `y ~ x + sigma ~ z` | Distributional parameters |
 //! | `y ~ x + ar(p=1)` | Autocorrelation terms |
@@ -63,6 +64,33 @@ use thiserror::Error;
 // Internal implementation modules - not exposed to users
 mod internal;
 
+pub use internal::dsl::ast::{GroupKind, MaterializeOptions};
+pub use internal::dsl::autocor::AutocorTerm;
+pub use internal::dsl::chunked::DesignBatches;
+#[cfg(any(feature = "faer", feature = "ndarray", feature = "nalgebra"))]
+pub use internal::dsl::convert::column_names;
+#[cfg(feature = "faer")]
+pub use internal::dsl::convert::{coefficients_from_faer, ToFaer};
+#[cfg(feature = "nalgebra")]
+pub use internal::dsl::convert::{coefficients_from_nalgebra, ToNalgebra};
+#[cfg(feature = "ndarray")]
+pub use internal::dsl::convert::{coefficients_from_ndarray, ToNdarray};
+pub use internal::dsl::design::{
+    DesignInfo, ResponseCoding, ResponseEncoding, ResponseInfo, SmoothInfo, TransformState,
+};
+pub use internal::dsl::family::{FamilyInfo, Support};
+pub use internal::dsl::links::{
+    link_function, Cauchit, Cloglog, Identity, Inverse, InverseSquared, LinkFunction, Log, Logit,
+    Probit, ProbitApprox, Softplus, Sqrt, TanHalf,
+};
+pub use internal::dsl::materialize::PolyState;
+pub use internal::dsl::model_data::{DparData, ModelData};
+pub use internal::dsl::reterms::{ReTerm, ReTerms};
+pub use internal::dsl::smooths::{Combine, Marginal, MarginalBasis, SmoothState};
+pub use internal::dsl::sparse::{CscMatrix, CsrMatrix, GroupBy, SparseZ, SparseZTerm};
+pub use internal::dsl::splines::SplineState;
+pub use internal::dsl::suffstats::SufficientStats;
+
 // Re-export the error type for users
 #[derive(Debug, Error)]
 pub enum Error {
@@ -138,7 +166,11 @@ pub enum Error {
 /// # Returns
 ///
 /// Returns a `Result<ModelSpec, Error>` containing the parsed and canonicalized
-/// formula or an error if the formula syntax is invalid.
+/// formula or an error if the formula syntax is invalid, if the family is not
+/// known or its link not valid for it (see [`FamilyInfo`]), or if a
+/// distributional parameter formula such as `zi ~ x` names a parameter the
+/// declared family does not have, or if an autocorrelation term such as
+/// `ar(time=t, gr=g, p=1)` has an unknown, missing or invalid argument.
 ///
 /// # Examples
 ///
@@ -156,7 +188,11 @@ pub fn canonicalize(formula: &str) -> Result<internal::dsl::ModelSpec, Error> {
             msg: format!("Parse error: {:?}", e),
         })?;
 
-    Ok(internal::dsl::canon::canonicalize(&model_spec))
+    let spec = internal::dsl::canon::canonicalize(&model_spec);
+    internal::dsl::family::check_family(&spec)?;
+    internal::dsl::family::check_dpars(&spec)?;
+    internal::dsl::autocor::check_autocor(&spec)?;
+    Ok(spec)
 }
 
 /// Materialize a ModelSpec against a DataFrame to produce design matrices.
@@ -200,6 +236,507 @@ pub fn materialize(
     internal::dsl::materialize::materialize(df, spec, opts)
 }
 
+/// Materialize a ModelSpec and return the learned design information.
+///
+/// Works like [`materialize`], but additionally returns a [`DesignInfo`] holding
+/// the state learned from `df` by stateful transforms (e.g. spline knots). Pass it
+/// to [`materialize_new_data`] to build matching design matrices for new data.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_with_info};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0, 5.0],
+///     "x" => [1.0, 2.0, 3.0, 4.0, 5.0]
+/// )?;
+///
+/// let spec = canonicalize("y ~ ns(x, df=3)")?;
+/// let (y, x, z, info) = materialize_with_info(&spec, &df)?;
+/// assert_eq!(info.transforms.len(), 1);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_with_info(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+) -> Result<(DataFrame, DataFrame, DataFrame, DesignInfo), Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    let (y, x, z) = internal::dsl::materialize::materialize_with_info(df, spec, opts, &mut info)?;
+    Ok((y, x, z, info))
+}
+
+/// Materialize a ModelSpec with explicit options and return the learned design information.
+///
+/// Works like [`materialize_with_info`] but lets the caller choose the
+/// [`MaterializeOptions`], e.g. to write smooth terms in mixed model form: with
+/// `mixed_model_smooths` set, the unpenalized part of each smooth goes into X
+/// and the penalized part, scaled to an identity penalty, into Z, as brms does.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_with_options, MaterializeOptions};
+///
+/// let x: Vec<f64> = (0..20).map(|i| i as f64).collect();
+/// let y: Vec<f64> = x.iter().map(|v| v.sin()).collect();
+/// let df = df!("y" => y, "x" => x)?;
+///
+/// let spec = canonicalize("y ~ s(x, bs=\"cr\", k=5)")?;
+/// let opts = MaterializeOptions { mixed_model_smooths: true, ..Default::default() };
+/// let (_y, x, z, info) = materialize_with_options(&spec, &df, opts)?;
+/// assert_eq!(x.width(), 2); // intercept + linear trend
+/// assert_eq!(z.width(), 3); // penalized part of the smooth
+/// assert_eq!(info.smooths[0].random_column_range, 0..3);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_with_options(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+    opts: MaterializeOptions,
+) -> Result<(DataFrame, DataFrame, DataFrame, DesignInfo), Error> {
+    let mut info = DesignInfo::default();
+    let (y, x, z) = internal::dsl::materialize::materialize_with_info(df, spec, opts, &mut info)?;
+    Ok((y, x, z, info))
+}
+
+/// Materialize a ModelSpec with a sparse random effects design matrix.
+///
+/// Works like [`materialize`], but returns Z as a [`SparseZ`] in compressed
+/// sparse column form, built directly from the grouping factors instead of as
+/// dense indicator columns. Each group term is described in [`SparseZ::terms`]
+/// by its grouping factor, levels, inner columns and span of Z columns.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_sparse};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0],
+///     "x" => [0.5, 1.0, 1.5, 2.0],
+///     "g" => ["a", "b", "a", "c"]
+/// )?;
+///
+/// let spec = canonicalize("y ~ x + (1|g)")?;
+/// let (_y, x, z) = materialize_sparse(&spec, &df)?;
+/// assert_eq!(x.width(), 2);
+/// assert_eq!((z.nrows(), z.ncols(), z.nnz()), (4, 3, 4));
+/// assert_eq!(z.terms[0].levels, ["a", "b", "c"]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_sparse(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+) -> Result<(DataFrame, DataFrame, SparseZ), Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    internal::dsl::materialize::materialize_sparse_with_info(df, spec, opts, &mut info)
+}
+
+/// Materialize a ModelSpec with explicit options and a sparse random effects design matrix.
+///
+/// Combines [`materialize_sparse`] and [`materialize_with_options`]: with
+/// `mixed_model_smooths` set, the penalized parts of smooths follow the group
+/// terms in the sparse Z, at the `random_column_range` recorded in the
+/// returned [`DesignInfo`].
+pub fn materialize_sparse_with_options(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+    opts: MaterializeOptions,
+) -> Result<(DataFrame, DataFrame, SparseZ, DesignInfo), Error> {
+    let mut info = DesignInfo::default();
+    let (y, x, z) =
+        internal::dsl::materialize::materialize_sparse_with_info(df, spec, opts, &mut info)?;
+    Ok((y, x, z, info))
+}
+
+/// Materialize a ModelSpec with the random-effects structure needed to fit it.
+///
+/// Like lme4's `mkReTrms`, the returned [`ReTerms`] holds the sparse Z along
+/// with, per term, the grouping factor, number of levels, columns per level,
+/// correlation structure and `|<id>|` label, plus the template of the relative
+/// covariance factor: `lambdat` (Λᵀ), `lind` mapping its entries to `theta`, and
+/// the initial values and lower bounds of `theta`. Terms sharing an `|<id>|`
+/// label must have the same grouping factor; see [`ReTerms::linked_terms`].
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_re_terms};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0],
+///     "x" => [0.5, 1.0, 1.5, 2.0],
+///     "g" => ["a", "b", "a", "b"]
+/// )?;
+///
+/// let spec = canonicalize("y ~ x + (x|g)")?;
+/// let (_y, _x, re) = materialize_re_terms(&spec, &df)?;
+/// assert_eq!(re.terms[0].cnms, ["(Intercept)", "x"]);
+/// assert_eq!(re.theta, [1.0, 0.0, 1.0]);
+/// assert_eq!(re.lind, [0, 1, 2, 0, 1, 2]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_re_terms(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+) -> Result<(DataFrame, DataFrame, ReTerms), Error> {
+    let (y, x, z) = materialize_sparse(spec, df)?;
+    Ok((y, x, ReTerms::new(z)?))
+}
+
+/// Materialize a ModelSpec with its auxiliary terms, ready for model fitting.
+///
+/// Returns a [`ModelData`] holding y, X and Z together with the evaluated
+/// aterms of the response, e.g. `weights` for `y | weights(1/v) ~ x` or
+/// `trials` for `y | trials(n) ~ x`, validated as brms does (non-negative
+/// weights, integer trials no smaller than the successes, and so on), and the
+/// sum of the `offset()` terms, which are left out of X. The formulas of
+/// distributional parameters, e.g. `sigma ~ z`, get their own X and Z in
+/// [`ModelData::dpars`], and autocorrelation terms such as `ar(time=t, gr=g)`
+/// are evaluated into [`ModelData::autocor`]. Only the rows selected by a `subset()` aterm, e.g.
+/// `y | subset(age > 18) ~ x`, are used, and rows with a missing value in any
+/// variable of the formula are dropped from all of them; [`ModelData::rows`]
+/// lists the rows kept.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_model_data};
+///
+/// let df = df!(
+///     "y" => [Some(1.0), Some(2.0), None, Some(4.0)],
+///     "x" => [0.5, 1.0, 1.5, 2.0],
+///     "v" => [1.0, 2.0, 4.0, 4.0]
+/// )?;
+///
+/// let spec = canonicalize("y | weights(1/v) ~ x")?;
+/// let data = materialize_model_data(&spec, &df)?;
+/// assert_eq!(data.rows, [0, 1, 3]);
+/// assert_eq!(data.x.height(), 3);
+/// let weights: Vec<_> = data.aterm("weights").unwrap().f64()?.into_no_null_iter().collect();
+/// assert_eq!(weights, [1.0, 0.5, 0.25]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_model_data(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+) -> Result<ModelData, Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    internal::dsl::model_data::materialize_model_data(df, spec, opts)
+}
+
+/// Materialize the distributional parameter formulas of a ModelSpec.
+///
+/// Returns one X and Z per parameter, e.g. `sigma` for `y ~ x + sigma ~ z`,
+/// keyed by parameter name and on the rows of `df`, together with the sum of
+/// the formula's `offset()` terms. State learned for a parameter is reused from
+/// `info.dpars` when present there, so passing the [`DesignInfo`] of
+/// [`materialize_model_data`] evaluates new data like the training data; pass
+/// `DesignInfo::default()` to learn it from `df`.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_dpars, DesignInfo};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0],
+///     "x" => [0.5, 1.0, 1.5, 2.0],
+///     "z" => [1.0, 0.0, 1.0, 0.0],
+///     "g" => ["a", "b", "a", "b"]
+/// )?;
+///
+/// let spec = canonicalize("y ~ x + sigma ~ z + (1|g)")?;
+/// let dpars = materialize_dpars(&spec, &df, &DesignInfo::default())?;
+/// assert_eq!(dpars["sigma"].x.get_column_names(), ["intercept", "z"]);
+/// assert_eq!(dpars["sigma"].z.width(), 2);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_dpars(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+    info: &DesignInfo,
+) -> Result<std::collections::BTreeMap<String, DparData>, Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    internal::dsl::model_data::materialize_dpars(df, spec, opts, &mut info.clone())
+}
+
+/// Evaluate the autocorrelation terms of a ModelSpec on the rows of a DataFrame.
+///
+/// Each term, e.g. `ar(time=t, gr=g, p=1)` or `car(W, gr=region)`, gives an
+/// [`AutocorTerm`] with its orders and options, the time and group of every
+/// row, the rows in group and time order and the position of each row within
+/// its group; `car()`, `sar()` and `fcor()` name their matrix `M`, which is
+/// supplied with the data. Time points must be unique within a group, and the
+/// time and grouping variables may not be missing.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_autocor};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0],
+///     "t" => [2, 1, 1, 2],
+///     "g" => ["a", "a", "b", "b"]
+/// )?;
+///
+/// let spec = canonicalize("y ~ 1 + ar(time=t, gr=g, p=2)")?;
+/// let terms = materialize_autocor(&spec, &df)?;
+/// assert_eq!(terms[0].p, 2);
+/// assert_eq!(terms[0].order, [1, 0, 2, 3]);
+/// assert_eq!(terms[0].position, [1, 0, 0, 1]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_autocor(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+) -> Result<Vec<AutocorTerm>, Error> {
+    internal::dsl::autocor::materialize_autocor(df, spec)
+}
+
+/// Materialize a ModelSpec against new data using previously learned design information.
+///
+/// Stateful transforms reuse the state stored in `info` (as returned by
+/// [`materialize_with_info`]) instead of learning it from `df`, so e.g. spline
+/// columns are evaluated with the training knots.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_new_data, materialize_with_info};
+///
+/// let train = df!("y" => [1.0, 2.0, 3.0, 4.0], "x" => [0.0, 1.0, 2.0, 3.0])?;
+/// let spec = canonicalize("y ~ bs(x, df=3)")?;
+/// let (_, _, _, info) = materialize_with_info(&spec, &train)?;
+///
+/// let new = df!("y" => [0.0, 0.0], "x" => [0.5, 4.0])?;
+/// let (_, x, _) = materialize_new_data(&spec, &new, &info)?;
+/// assert_eq!(x.height(), 2);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_new_data(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+    info: &DesignInfo,
+) -> Result<(DataFrame, DataFrame, DataFrame), Error> {
+    let opts = internal::dsl::MaterializeOptions {
+        check_response: false,
+        ..Default::default()
+    };
+    let mut info = info.clone();
+    internal::dsl::materialize::materialize_with_info(df, spec, opts, &mut info)
+}
+
+/// Compile a ModelSpec against a LazyFrame into lazy response and design matrices.
+///
+/// The columns of X are compiled into Polars expressions (see
+/// [`ModelSpec::to_exprs`](internal::dsl::ModelSpec::to_exprs)) and selected
+/// from `lf`, so projections and filters are pushed down to the scan and the
+/// query can run in streaming or out-of-core mode. Factor levels and transform
+/// state are learned first with a small query per stateful term, and returned
+/// in the [`DesignInfo`] for use on new data. Random effects and smooth terms
+/// are not compiled; use [`materialize`] for those.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_lazy};
+///
+/// let lf = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0],
+///     "x" => [0.5, 1.5, 2.5, 3.5],
+///     "g" => ["a", "b", "a", "c"]
+/// )?
+/// .lazy();
+///
+/// let spec = canonicalize("y ~ x + g")?;
+/// let (y, x, info) = materialize_lazy(&spec, &lf)?;
+/// let x = x.filter(col("x").gt(lit(1.0))).collect()?;
+/// assert_eq!(x.get_column_names(), ["intercept", "x", "g_b", "g_c"]);
+/// assert_eq!(x.height(), 3);
+/// assert_eq!(info.levels["g"], ["a", "b", "c"]);
+/// assert_eq!(y.collect()?.width(), 1);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_lazy(
+    spec: &internal::dsl::ModelSpec,
+    lf: &LazyFrame,
+) -> Result<(LazyFrame, LazyFrame, DesignInfo), Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    let (y, x) = internal::dsl::lazy::materialize_lazy(lf, spec, opts, &mut info)?;
+    Ok((y, x, info))
+}
+
+/// Learn the design information of a ModelSpec from batches of data.
+///
+/// This is the first pass of batch-by-batch materialization: factor levels,
+/// knots, polynomial coefficients, smoothing constraints and grouping levels
+/// are learned from the rows selected by `subset()`, one batch at a time. Only
+/// a summary of each term that needs state is kept: the distinct levels of
+/// categorical variables, grouping factors and a coded response, and the power
+/// sums of `poly()`; splines and smooths keep the values of their variables.
+/// Pass the result to [`materialize_batches`] with a second iteration over the
+/// same batches.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, learn_design_info, materialize_batches};
+///
+/// let batches = || {
+///     vec![
+///         df!("y" => [1.0, 2.0], "x" => [0.5, 1.5], "g" => ["a", "a"]).unwrap(),
+///         df!("y" => [3.0, 4.0], "x" => [2.5, 3.5], "g" => ["b", "c"]).unwrap(),
+///     ]
+/// };
+///
+/// let spec = canonicalize("y ~ x + g")?;
+/// let info = learn_design_info(&spec, batches())?;
+/// for batch in materialize_batches(&spec, batches(), &info) {
+///     let (_y, x, _z) = batch?;
+///     assert_eq!(x.get_column_names(), ["intercept", "x", "g_b", "g_c"]);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn learn_design_info<I>(
+    spec: &internal::dsl::ModelSpec,
+    batches: I,
+) -> Result<DesignInfo, Error>
+where
+    I: IntoIterator<Item = DataFrame>,
+{
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    internal::dsl::chunked::learn_from_batches(batches, spec, opts, &mut info)?;
+    Ok(info)
+}
+
+/// Materialize a ModelSpec batch by batch with previously learned design information.
+///
+/// Yields the `(y, X, Z)` of the rows of each batch selected by `subset()`,
+/// built with the state in `info` (see [`learn_design_info`]) so that every
+/// batch has the same columns.
+pub fn materialize_batches<'a, I>(
+    spec: &internal::dsl::ModelSpec,
+    batches: I,
+    info: &DesignInfo,
+) -> DesignBatches<'a>
+where
+    I: IntoIterator<Item = DataFrame>,
+    I::IntoIter: 'a,
+{
+    let opts = internal::dsl::MaterializeOptions::default();
+    DesignBatches::new(batches, spec, opts, info.clone())
+}
+
+/// Materialize a ModelSpec over a LazyFrame in batches of `batch_size` rows.
+///
+/// The design information is learned first as in [`learn_design_info`], with
+/// one query per term that needs state reading only its columns. Each batch is
+/// then a slice of `lf` collected on its own, so no more than one batch of
+/// design matrices is in memory at a time; every slice runs the query of `lf`
+/// again, so costly queries are best collected or written out first.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, materialize_lazy_batches};
+///
+/// let lf = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0, 5.0],
+///     "x" => [0.5, 1.5, 2.5, 3.5, 4.5],
+///     "g" => ["a", "b", "a", "c", "b"]
+/// )?
+/// .lazy();
+///
+/// let spec = canonicalize("y ~ poly(x, 2) + (1|g)")?;
+/// let batches = materialize_lazy_batches(&spec, &lf, 2)?;
+/// assert_eq!(batches.info().group_levels["g"], ["a", "b", "c"]);
+/// let heights: Vec<usize> = batches.map(|b| b.unwrap().2.height()).collect();
+/// assert_eq!(heights, [2, 2, 1]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn materialize_lazy_batches(
+    spec: &internal::dsl::ModelSpec,
+    lf: &LazyFrame,
+    batch_size: usize,
+) -> Result<DesignBatches<'static>, Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    internal::dsl::chunked::learn_from_lazy(lf, spec, opts.clone(), &mut info)?;
+    DesignBatches::from_lazy(lf, batch_size, spec, opts, info)
+}
+
+/// Accumulate the least-squares cross-products of a ModelSpec over a DataFrame
+/// in batches of `batch_size` rows.
+///
+/// Returns `X'WX`, `X'Wy`, `y'Wy`, the column sums and the number of rows used
+/// as a [`SufficientStats`], with the weights of a `weights()` aterm and the
+/// response less its `offset()` terms. The state is learned batch by batch as
+/// in [`learn_design_info`], and only one batch of X is materialized at a time;
+/// group terms are left out, as Z does not enter the statistics.
+///
+/// # Examples
+///
+/// ```rust
+/// use polars::prelude::*;
+/// use polars_formula::{canonicalize, sufficient_stats};
+///
+/// let df = df!(
+///     "y" => [1.0, 2.0, 3.0, 4.0, 5.0],
+///     "x" => [0.0, 1.0, 2.0, 3.0, 4.0]
+/// )?;
+///
+/// let spec = canonicalize("y ~ x")?;
+/// let stats = sufficient_stats(&spec, &df, 2)?;
+/// assert_eq!(stats.n, 5);
+/// assert_eq!(stats.xtwx, [[5.0, 10.0], [10.0, 30.0]]);
+/// assert_eq!(stats.xtwy, [[15.0], [40.0]]);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn sufficient_stats(
+    spec: &internal::dsl::ModelSpec,
+    df: &DataFrame,
+    batch_size: usize,
+) -> Result<SufficientStats, Error> {
+    let opts = internal::dsl::MaterializeOptions::default();
+    let mut info = DesignInfo::default();
+    let batches = internal::dsl::chunked::frame_slices(df, batch_size)?;
+    internal::dsl::chunked::learn_from_batches(batches, spec, opts.clone(), &mut info)?;
+    DesignBatches::from_frame(df, batch_size, spec, opts, info)?.sufficient_stats()
+}
+
+/// Accumulate the least-squares cross-products of a ModelSpec over a LazyFrame
+/// in batches of `batch_size` rows.
+///
+/// As [`sufficient_stats`], with the design information learned as in
+/// [`materialize_lazy_batches`]. Besides the statistics and one batch, memory
+/// holds the learned state, and the values of the variables of any spline or
+/// smooth while it is learned.
+pub fn sufficient_stats_lazy(
+    spec: &internal::dsl::ModelSpec,
+    lf: &LazyFrame,
+    batch_size: usize,
+) -> Result<SufficientStats, Error> {
+    materialize_lazy_batches(spec, lf, batch_size)?.sufficient_stats()
+}
+
 /// Print the canonical formula with syntax highlighting.
 ///
 /// This function takes a ModelSpec and prints its canonical form with
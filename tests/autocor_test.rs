@@ -0,0 +1,119 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, materialize_autocor, materialize_model_data};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5, 3.0],
+        "t" => [3, 1, 2, 2, 1, 3],
+        "g" => ["a", "a", "a", "b", "b", "b"],
+        "region" => [2, 1, 2, 3, 3, 1]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_autocor_arguments_are_named_and_checked() {
+    let spec = canonicalize("y ~ x + ar(t, g, p=2)").unwrap();
+    assert_eq!(spec.autocor.len(), 1);
+    let ar = &spec.autocor[0];
+    assert_eq!(ar.name, "ar");
+    assert_eq!(ar.args.len(), 3);
+    assert_eq!(format!("{:?}", ar.args["time"]), "Var(\"t\")");
+    assert_eq!(format!("{:?}", ar.args["gr"]), "Var(\"g\")");
+    assert_eq!(format!("{:?}", ar.args["p"]), "Num(2.0)");
+
+    for formula in [
+        "y ~ x + ar(time=t, gr=g, p=1, cov=TRUE)",
+        "y ~ x + arma(time=t, gr=g, p=1, q=2)",
+        "y ~ x + cosy(time=t)",
+        "y ~ x + unstr(time=t, gr=g)",
+        "y ~ x + car(W, gr=region, type=\"icar\")",
+        "y ~ x + sar(W, type=\"error\")",
+        "y ~ x + fcor(V)",
+    ] {
+        assert!(canonicalize(formula).is_ok(), "{}", formula);
+    }
+    for formula in [
+        "y ~ x + ar(time=t, lag=2)",
+        "y ~ x + ar(t, g, 1, TRUE, 5)",
+        "y ~ x + ar(time=t, p=1.5)",
+        "y ~ x + ar(time=t, cov=1)",
+        "y ~ x + ar(time=log(t))",
+        "y ~ x + unstr(time=t)",
+        "y ~ x + car(gr=region)",
+        "y ~ x + sar(W, type=\"escar\")",
+        "y ~ x + ar(time=t) + ma(time=t)",
+    ] {
+        assert!(canonicalize(formula).is_err(), "{}", formula);
+    }
+    let err = canonicalize("y ~ x + unstr(time=t)").unwrap_err();
+    assert!(
+        err.to_string().contains("needs the argument 'gr'"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_autocor_metadata() {
+    let df = data();
+    let spec = canonicalize("y ~ x + arma(time=t, gr=g, q=2)").unwrap();
+    let terms = materialize_autocor(&spec, &df).unwrap();
+    let arma = &terms[0];
+    assert_eq!((arma.p, arma.q, arma.cov), (1, 2, false));
+    assert_eq!(arma.time.as_deref(), Some("t"));
+    assert_eq!(arma.times, [3.0, 1.0, 2.0, 2.0, 1.0, 3.0]);
+    assert_eq!(arma.groups, ["a", "b"]);
+    assert_eq!(arma.group, [0, 0, 0, 1, 1, 1]);
+    assert_eq!(arma.order, [1, 2, 0, 4, 3, 5]);
+    assert_eq!(arma.position, [2, 0, 1, 1, 0, 2]);
+
+    // Without a time variable the rows are in time order
+    let spec = canonicalize("y ~ x + ar()").unwrap();
+    let ar = &materialize_autocor(&spec, &df).unwrap()[0];
+    assert_eq!(ar.order, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(ar.groups, [""]);
+
+    // Spatial structures refer to their matrix by name
+    let spec = canonicalize("y ~ x + car(W, gr=region)").unwrap();
+    let car = &materialize_autocor(&spec, &df).unwrap()[0];
+    assert_eq!(car.matrix.as_deref(), Some("W"));
+    assert_eq!(car.kind.as_deref(), Some("escar"));
+    assert_eq!(car.group, [1, 0, 1, 2, 2, 0]);
+
+    // Time points must be unique within a group
+    let spec = canonicalize("y ~ x + ar(time=t)").unwrap();
+    assert!(materialize_autocor(&spec, &df).is_err());
+}
+
+#[test]
+fn test_autocor_rows_follow_model_data() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0],
+        "x" => [0.5, 1.0, 1.5, 2.0],
+        "t" => [Some(2), None, Some(1), Some(3)]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ x + ar(time=t)").unwrap();
+    assert!(materialize_autocor(&spec, &df).is_err());
+
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [0, 2, 3]);
+    assert_eq!(data.autocor[0].order, [1, 0, 2]);
+    assert_eq!(data.autocor[0].position, [1, 0, 2]);
+}
+
+#[test]
+fn test_autocor_inside_group_terms_is_hoisted() {
+    let df = data();
+    let spec = canonicalize("y ~ x + (1 + x + ar(time=t)|g)").unwrap();
+    assert_eq!(spec.autocor.len(), 1);
+    assert_eq!(spec.autocor[0].name, "ar");
+
+    // The explicit intercept of the group term is kept next to the slope
+    let expected = canonicalize("y ~ x + (1 + x|g)").unwrap();
+    assert_eq!(spec.formula.rhs, expected.formula.rhs);
+    let (_, _, z) = polars_formula::materialize(&spec, &df).unwrap();
+    assert_eq!(z.width(), 4);
+}
@@ -0,0 +1,218 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, learn_design_info, materialize_batches, materialize_lazy_batches,
+    materialize_model_data, materialize_new_data, materialize_with_info,
+};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.5, 2.0, 4.5, 5.0, 6.5, 6.0, 8.0, 7.5],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5, 4.0, 4.5],
+        "z" => [1.2, 2.3, 0.7, 3.3, 3.1, 2.2, 4.0, 1.9, 2.8],
+        "g" => ["a", "a", "a", "b", "b", "c", "c", "c", "d"],
+        "s" => [1, 1, 2, 2, 3, 3, 4, 4, 1]
+    )
+    .unwrap()
+}
+
+fn batches(df: &DataFrame, size: usize) -> Vec<DataFrame> {
+    (0..df.height())
+        .step_by(size)
+        .map(|offset| df.slice(offset as i64, size))
+        .collect()
+}
+
+fn stack(frames: &[DataFrame]) -> DataFrame {
+    let mut stacked = frames[0].clone();
+    for frame in &frames[1..] {
+        stacked.vstack_mut(frame).unwrap();
+    }
+    stacked
+}
+
+/// Frames with the same columns and values, up to rounding: `poly()` is
+/// learned from power sums batch by batch.
+fn assert_close(a: &DataFrame, b: &DataFrame) {
+    assert_eq!(a.get_column_names(), b.get_column_names());
+    assert_eq!(a.height(), b.height());
+    for (a, b) in a.get_columns().iter().zip(b.get_columns()) {
+        let a = a.cast(&DataType::Float64).unwrap();
+        let b = b.cast(&DataType::Float64).unwrap();
+        for (a, b) in a.f64().unwrap().iter().zip(b.f64().unwrap().iter()) {
+            match (a, b) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-9, "{} != {}", a, b),
+                (a, b) => assert_eq!(a, b),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_batches_match_the_whole_data() {
+    let df = data();
+    let spec = canonicalize("y ~ x + g + poly(z, 2) + bs(x, df=4) + (1 + x|s)").unwrap();
+    let (y, x, z, eager) = materialize_with_info(&spec, &df).unwrap();
+
+    let info = learn_design_info(&spec, batches(&df, 4)).unwrap();
+    assert_eq!(info.levels["g"], ["a", "b", "c", "d"]);
+    assert_eq!(info.group_levels["s"], ["1", "2", "3", "4"]);
+    assert_eq!(info.levels, eager.levels);
+    assert_eq!(info.group_levels, eager.group_levels);
+    assert_eq!(
+        info.transforms["bs(x, df=4)"],
+        eager.transforms["bs(x, df=4)"]
+    );
+    let chunks: Vec<_> = materialize_batches(&spec, batches(&df, 4), &info)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(chunks.len(), 3);
+    for (_, x_chunk, z_chunk) in &chunks {
+        assert_eq!(x_chunk.get_column_names(), x.get_column_names());
+        assert_eq!(z_chunk.get_column_names(), z.get_column_names());
+    }
+    let ys: Vec<_> = chunks.iter().map(|(y, _, _)| y.clone()).collect();
+    let xs: Vec<_> = chunks.iter().map(|(_, x, _)| x.clone()).collect();
+    let zs: Vec<_> = chunks.iter().map(|(_, _, z)| z.clone()).collect();
+    assert_eq!(stack(&ys), y);
+    assert_close(&stack(&xs), &x);
+    assert_eq!(stack(&zs), z);
+
+    // The same from a lazy frame, in slices of any size
+    for size in [2, 4, 100] {
+        let lazy = materialize_lazy_batches(&spec, &df.clone().lazy(), size).unwrap();
+        assert_eq!(lazy.info().levels, info.levels);
+        assert_eq!(lazy.info().group_levels, info.group_levels);
+        let chunks: Vec<_> = lazy.collect::<Result<_, _>>().unwrap();
+        assert_eq!(chunks.len(), df.height().div_ceil(size));
+        let xs: Vec<_> = chunks.iter().map(|(_, x, _)| x.clone()).collect();
+        assert_close(&stack(&xs), &x);
+    }
+    assert!(materialize_lazy_batches(&spec, &df.lazy(), 0).is_err());
+}
+
+#[test]
+fn test_batches_keep_their_columns() {
+    let df = data();
+    let spec = canonicalize("y ~ x + g").unwrap();
+
+    // Levels learned from part of the data reject the others
+    let info = learn_design_info(&spec, batches(&df.head(Some(3)), 3)).unwrap();
+    let results: Vec<_> = materialize_batches(&spec, batches(&df, 3), &info).collect();
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+    assert!(learn_design_info(&spec, Vec::new()).is_err());
+}
+
+#[test]
+fn test_new_data_gets_the_learned_group_levels() {
+    let df = data();
+    let spec = canonicalize("y ~ x + (1|g)").unwrap();
+    let (_, _, z, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(z.width(), 4);
+
+    // Only some of the groups, and one that was not seen
+    let new = df!(
+        "y" => [0.0, 0.0],
+        "x" => [1.0, 2.0],
+        "g" => ["c", "e"],
+        "s" => [1, 2]
+    )
+    .unwrap();
+    let (_, _, z_new) = materialize_new_data(&spec, &new, &info).unwrap();
+    assert_eq!(z_new.get_column_names(), z.get_column_names());
+    let sums: Vec<f64> = (0..2)
+        .map(|row| {
+            z_new
+                .get_columns()
+                .iter()
+                .map(|c| c.f64().unwrap().get(row).unwrap())
+                .sum()
+        })
+        .collect();
+    assert_eq!(sums, [1.0, 0.0]);
+}
+
+#[test]
+fn test_batches_learn_the_state_of_every_term() {
+    let df = df!(
+        "y" => ["no", "yes", "no", "yes", "yes", "no", "yes", "no", "yes", "no"],
+        "x" => [0.1, 0.4, 0.2, 0.9, 0.5, 0.7, 0.3, 0.8, 0.6, 1.0],
+        "z" => [2.0, 1.0, 4.0, 3.0, 5.0, 7.0, 6.0, 9.0, 8.0, 10.0],
+        "g" => ["a", "b", "a", "b", "c", "c", "a", "b", "c", "a"]
+    )
+    .unwrap();
+    let spec =
+        canonicalize("y ~ s(x, k=5) + cr(z, df=4) + poly(x, 3):g + (0 + g|g), family=bernoulli()")
+            .unwrap();
+    let (_, x, _, eager) = materialize_with_info(&spec, &df).unwrap();
+
+    for size in [1, 3, 10] {
+        let info = learn_design_info(&spec, batches(&df, size)).unwrap();
+        assert_eq!(info.levels, eager.levels);
+        assert_eq!(info.group_levels, eager.group_levels);
+        assert_eq!(info.response_encoding, eager.response_encoding);
+        assert_eq!(info.transforms["s(x, k=5)"], eager.transforms["s(x, k=5)"]);
+        assert_eq!(
+            info.transforms["cr(z, df=4)"],
+            eager.transforms["cr(z, df=4)"]
+        );
+
+        let chunks: Vec<_> = materialize_batches(&spec, batches(&df, size), &info)
+            .map(|batch| batch.unwrap().1)
+            .collect();
+        assert_close(&stack(&chunks), &x);
+    }
+}
+
+#[test]
+fn test_batches_learn_from_the_subset() {
+    let df = data()
+        .lazy()
+        .with_column(
+            col("g")
+                .neq(lit("d"))
+                .and(col("x").neq(lit(1.0)))
+                .alias("keep"),
+        )
+        .collect()
+        .unwrap();
+    let spec = canonicalize("y | subset(keep) ~ x + g + poly(z, 2) + (1|s)").unwrap();
+    let model = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(model.x.width(), 6);
+
+    // Level "d" of g is only in rows the subset leaves out, which are
+    // neither learned from nor materialized
+    let info = learn_design_info(&spec, batches(&df, 4)).unwrap();
+    assert_eq!(info.levels["g"], ["a", "b", "c"]);
+    let lazy = materialize_lazy_batches(&spec, &df.clone().lazy(), 4).unwrap();
+    assert_eq!(lazy.info().levels["g"], ["a", "b", "c"]);
+
+    let xs: Vec<_> = materialize_batches(&spec, batches(&df, 4), &info)
+        .map(|batch| batch.unwrap().1)
+        .collect();
+    assert_close(&stack(&xs), &model.x);
+    let xs: Vec<_> = lazy.map(|batch| batch.unwrap().1).collect();
+    assert_close(&stack(&xs), &model.x);
+}
+
+#[test]
+fn test_lazy_batches_of_a_scanned_file() {
+    let mut df = data();
+    let path = std::env::temp_dir().join(format!("batch_test_{}.csv", std::process::id()));
+    CsvWriter::new(std::fs::File::create(&path).unwrap())
+        .finish(&mut df)
+        .unwrap();
+
+    let spec = canonicalize("y ~ x + g + poly(z, 2)").unwrap();
+    let (_, x, _, _) = materialize_with_info(&spec, &df).unwrap();
+    let lf = LazyCsvReader::new(PlPath::new(path.to_str().unwrap()))
+        .finish()
+        .unwrap();
+    let chunks: Vec<_> = materialize_lazy_batches(&spec, &lf, 4)
+        .unwrap()
+        .map(|batch| batch.unwrap().1)
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert_close(&stack(&chunks), &x);
+}
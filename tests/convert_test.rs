@@ -0,0 +1,192 @@
+#![cfg(any(feature = "faer", feature = "ndarray", feature = "nalgebra"))]
+
+use polars::prelude::*;
+use polars_formula::{canonicalize, column_names, materialize, materialize_sparse, CscMatrix};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 3.0, 5.0, 7.0, 9.0, 11.0],
+        "x" => [0.0, 1.0, 2.0, 3.0, 4.0, 5.0],
+        "g" => ["a", "b", "a", "b", "c", "c"]
+    )
+    .unwrap()
+}
+
+fn with_nulls() -> DataFrame {
+    df!(
+        "x" => [Some(1.0), None, Some(3.0)],
+        "g" => ["a", "b", "c"]
+    )
+    .unwrap()
+}
+
+/// A CSC matrix whose row indices are out of order within a column.
+#[cfg(any(feature = "ndarray", feature = "nalgebra"))]
+fn unsorted() -> CscMatrix {
+    CscMatrix {
+        nrows: 2,
+        ncols: 1,
+        col_ptr: vec![0, 2],
+        row_idx: vec![1, 0],
+        values: vec![1.0, 2.0],
+    }
+}
+
+fn values(column: &Column) -> Vec<f64> {
+    column.f64().unwrap().into_no_null_iter().collect()
+}
+
+fn estimates(coefficients: &DataFrame) -> Vec<f64> {
+    assert_eq!(coefficients.get_column_names(), ["term", "estimate"]);
+    let terms: Vec<&str> = coefficients
+        .column("term")
+        .unwrap()
+        .str()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(terms, ["intercept", "x"]);
+    values(coefficients.column("estimate").unwrap())
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_design_to_faer() {
+    use polars_formula::ToFaer;
+
+    let df = data();
+    let spec = canonicalize("y ~ x + g + (1 + x|g)").unwrap();
+    let (y, x, z) = materialize(&spec, &df).unwrap();
+
+    let x_faer = x.to_faer().unwrap();
+    assert_eq!((x_faer.nrows(), x_faer.ncols()), (6, 4));
+    assert_eq!(x_faer.row_stride(), 1);
+    for (j, column) in x.get_columns().iter().enumerate() {
+        for (i, v) in values(column).into_iter().enumerate() {
+            assert_eq!(x_faer[(i, j)], v);
+        }
+    }
+    assert_eq!(y.to_faer().unwrap().col(0).iter().sum::<f64>(), 36.0);
+
+    // Z keeps its nonzero entries only, like the sparse materialization
+    let z_faer = z.to_faer_sparse().unwrap();
+    let (_, _, z_sparse) = materialize_sparse(&spec, &df).unwrap();
+    assert_eq!(z_faer.compute_nnz(), z_sparse.nnz() - 1);
+    assert_eq!(z_faer.to_dense(), z_sparse.to_faer().to_dense());
+    assert_eq!(z_faer.to_dense(), z.to_faer().unwrap());
+
+    let df = with_nulls();
+    let err = df.select(["x"]).unwrap().to_faer().unwrap_err();
+    assert!(err.to_string().contains("missing values"), "{}", err);
+    assert!(df.select(["x"]).unwrap().to_faer_sparse().is_err());
+    assert!(df.select(["g"]).unwrap().to_faer().is_err());
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_coefficients_from_faer() {
+    use faer::linalg::solvers::Solve;
+    use polars_formula::{coefficients_from_faer, ToFaer};
+
+    let spec = canonicalize("y ~ x").unwrap();
+    let (y, x, _) = materialize(&spec, &data()).unwrap();
+    let names = column_names(&x);
+    let (y, x) = (y.to_faer().unwrap(), x.to_faer().unwrap());
+
+    let beta = (x.transpose() * &x)
+        .partial_piv_lu()
+        .solve(x.transpose() * &y);
+    let coefficients = coefficients_from_faer(&names, beta.col(0)).unwrap();
+    let estimates = estimates(&coefficients);
+    assert!((estimates[0] - 1.0).abs() < 1e-9);
+    assert!((estimates[1] - 2.0).abs() < 1e-9);
+
+    assert!(coefficients_from_faer(&names[..1], beta.col(0)).is_err());
+}
+
+#[cfg(feature = "ndarray")]
+#[test]
+fn test_design_to_ndarray() {
+    use polars_formula::{coefficients_from_ndarray, ToNdarray};
+
+    let df = data();
+    let spec = canonicalize("y ~ x + g + (1 + x|g)").unwrap();
+    let (y, x, z) = materialize(&spec, &df).unwrap();
+
+    let x_array = x.to_ndarray().unwrap();
+    assert_eq!(x_array.dim(), (6, 4));
+    assert!(x_array.t().is_standard_layout());
+    for (j, column) in x.get_columns().iter().enumerate() {
+        assert_eq!(x_array.column(j).to_vec(), values(column));
+    }
+    assert_eq!(y.to_ndarray().unwrap().sum(), 36.0);
+
+    let z_sprs = z.to_sprs().unwrap();
+    let (_, _, z_sparse) = materialize_sparse(&spec, &df).unwrap();
+    assert!(z_sprs.is_csc());
+    assert_eq!(z_sprs.nnz(), z_sparse.nnz() - 1);
+    assert_eq!(z_sprs.to_dense(), z_sparse.to_sprs().unwrap().to_dense());
+    assert_eq!(z_sprs.to_dense(), z.to_ndarray().unwrap());
+
+    let df = with_nulls();
+    assert!(df.select(["x"]).unwrap().to_ndarray().is_err());
+    assert!(df.select(["x"]).unwrap().to_sprs().is_err());
+    assert!(df.select(["g"]).unwrap().to_ndarray().is_err());
+    assert!(unsorted().to_sprs().is_err());
+
+    let names = column_names(&x.select(["intercept", "x"]).unwrap());
+    let beta = ndarray::arr1(&[1.0, 2.0]);
+    let coefficients = coefficients_from_ndarray(&names, beta.view()).unwrap();
+    assert_eq!(estimates(&coefficients), [1.0, 2.0]);
+    assert!(coefficients_from_ndarray(&names[..1], beta.view()).is_err());
+}
+
+#[cfg(feature = "nalgebra")]
+#[test]
+fn test_design_to_nalgebra() {
+    use polars_formula::{coefficients_from_nalgebra, ToNalgebra};
+
+    let df = data();
+    let spec = canonicalize("y ~ x + g + (1 + x|g)").unwrap();
+    let (_, x, z) = materialize(&spec, &df).unwrap();
+
+    let x_matrix = x.to_nalgebra().unwrap();
+    assert_eq!(x_matrix.shape(), (6, 4));
+    for (j, column) in x.get_columns().iter().enumerate() {
+        assert_eq!(
+            x_matrix.column(j).iter().copied().collect::<Vec<_>>(),
+            values(column)
+        );
+    }
+
+    let z_csc = z.to_nalgebra_sparse().unwrap();
+    let (_, _, z_sparse) = materialize_sparse(&spec, &df).unwrap();
+    assert_eq!(z_csc.nnz(), z_sparse.nnz() - 1);
+    let dense = |csc: &nalgebra_sparse::CscMatrix<f64>| nalgebra::DMatrix::from(csc);
+    assert_eq!(
+        dense(&z_csc),
+        dense(&z_sparse.to_nalgebra_sparse().unwrap())
+    );
+    assert_eq!(dense(&z_csc), z.to_nalgebra().unwrap());
+
+    let df = with_nulls();
+    assert!(df.select(["x"]).unwrap().to_nalgebra().is_err());
+    assert!(df.select(["x"]).unwrap().to_nalgebra_sparse().is_err());
+    assert!(df.select(["g"]).unwrap().to_nalgebra().is_err());
+    assert!(unsorted().to_nalgebra_sparse().is_err());
+
+    // Least squares with nalgebra, labelled back with the names of X
+    let spec = canonicalize("y ~ x").unwrap();
+    let (y, x, _) = materialize(&spec, &data()).unwrap();
+    let names = column_names(&x);
+    let (y, x) = (y.to_nalgebra().unwrap(), x.to_nalgebra().unwrap());
+    let beta = (x.transpose() * &x)
+        .lu()
+        .solve(&(x.transpose() * &y))
+        .unwrap();
+    let coefficients = coefficients_from_nalgebra(&names, beta.column(0)).unwrap();
+    let estimates = estimates(&coefficients);
+    assert!((estimates[0] - 1.0).abs() < 1e-9);
+    assert!((estimates[1] - 2.0).abs() < 1e-9);
+    assert!(coefficients_from_nalgebra(&names[..1], beta.column(0)).is_err());
+}
@@ -0,0 +1,89 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, materialize_dpars, materialize_model_data, DesignInfo};
+
+fn values(series: &Series) -> Vec<f64> {
+    series.f64().unwrap().into_no_null_iter().collect()
+}
+
+fn frame() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5, 3.0],
+        "z" => [Some(1.0), Some(0.0), None, Some(0.0), Some(1.0), Some(2.0)],
+        "n" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "g" => ["a", "b", "a", "b", "c", "c"]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_dpar_formulas_parse() {
+    let spec = canonicalize("y ~ x + sigma ~ z + zi ~ x + (1|g)").unwrap();
+    assert_eq!(spec.formula.rhs, canonicalize("y ~ x").unwrap().formula.rhs);
+    let names: Vec<_> = spec.dpars.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, ["sigma", "zi"]);
+}
+
+#[test]
+fn test_dpar_design_matrices() {
+    let spec = canonicalize("y ~ x + sigma ~ z + offset(log(n)) + zi ~ x - 1 + (1|g)").unwrap();
+    let data = materialize_model_data(&spec, &frame()).unwrap();
+
+    // The missing z drops the row everywhere
+    assert_eq!(data.rows, [0, 1, 3, 4, 5]);
+    assert_eq!(data.x.height(), 5);
+    assert!(data.offset.is_none());
+
+    let sigma = &data.dpars["sigma"];
+    assert_eq!(sigma.x.get_column_names(), ["intercept", "z"]);
+    assert_eq!(sigma.z.width(), 0);
+    let offset = values(sigma.offset.as_ref().unwrap());
+    assert_eq!(offset.len(), 5);
+    assert!((offset[2] - 4.0_f64.ln()).abs() < 1e-12);
+
+    let zi = &data.dpars["zi"];
+    assert_eq!(zi.x.get_column_names(), ["x"]);
+    assert_eq!(zi.z.width(), 3);
+    assert_eq!(zi.z.height(), 5);
+}
+
+#[test]
+fn test_dpar_state_is_reused_for_new_data() {
+    let spec = canonicalize("y ~ x + sigma ~ bs(x, df=4)").unwrap();
+    let data = materialize_model_data(&spec, &frame()).unwrap();
+    assert!(data.info.dpars["sigma"]
+        .transforms
+        .contains_key("bs(x, df=4)"));
+
+    let new = df!("y" => [0.0, 0.0], "x" => [0.75, 2.75]).unwrap();
+    let learned = materialize_dpars(&spec, &new, &data.info).unwrap();
+    let relearned = materialize_dpars(&spec, &new, &DesignInfo::default()).unwrap();
+    assert_eq!(learned["sigma"].x.width(), 5);
+    assert_ne!(learned["sigma"].x, relearned["sigma"].x);
+}
+
+#[test]
+fn test_dpar_names_follow_the_family() {
+    for formula in [
+        "y ~ x + alpha ~ z, family=skew_normal()",
+        "y ~ x + mu2 ~ z + theta2 ~ 1, family=mixture(gaussian(), gaussian())",
+        "y ~ x + phi ~ z, family=custom_family(\"kumaraswamy\", \"mu\", \"phi\")",
+        "y ~ x + muB ~ z, family=categorical()",
+        "y ~ x + zi ~ z + shape ~ 1, family=zero_inflated_negbinomial()",
+        "y ~ x + anything ~ z",
+    ] {
+        assert!(canonicalize(formula).is_ok(), "{}", formula);
+    }
+
+    for formula in [
+        "y ~ x + zi ~ z, family=gaussian()",
+        "y ~ x + mu ~ z, family=gaussian()",
+        "y ~ x + sigma ~ z, family=poisson()",
+        "y ~ x + mu3 ~ z, family=mixture(gaussian(), gaussian())",
+        "y ~ x + nu ~ z, family=custom_family(\"kumaraswamy\", \"mu\", \"phi\")",
+    ] {
+        assert!(canonicalize(formula).is_err(), "{}", formula);
+    }
+    let err = canonicalize("y ~ x + zi ~ z, family=student()").unwrap_err();
+    assert!(err.to_string().contains("it has sigma, nu"), "{}", err);
+}
@@ -0,0 +1,186 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize, materialize_model_data, materialize_new_data, materialize_with_info,
+    FamilyInfo, ResponseCoding, Support,
+};
+
+fn frame() -> DataFrame {
+    df!(
+        "count" => [0.0, 3.0, 1.0, 7.0],
+        "rate" => [0.0, 0.5, 1.5, 2.0],
+        "prop" => [0.1, 0.5, 0.9, 0.3],
+        "sex" => ["m", "f", "m", "f"],
+        "grade" => ["a", "b", "c", "a"],
+        "x" => [0.5, 1.0, 1.5, 2.0]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_family_registry() {
+    let poisson = FamilyInfo::lookup("poisson").unwrap();
+    assert_eq!(poisson.default_link(), "log");
+    assert_eq!(poisson.dpars, ["mu"]);
+    assert_eq!(poisson.support, Support::Count);
+
+    let zinb = FamilyInfo::lookup("zero_inflated_negbinomial").unwrap();
+    assert_eq!(zinb.dpars, ["mu", "shape", "zi"]);
+    assert_eq!(
+        FamilyInfo::lookup("cumulative").unwrap().default_link(),
+        "logit"
+    );
+    assert!(FamilyInfo::lookup("gausian").is_none());
+    assert!(FamilyInfo::all()
+        .iter()
+        .all(|family| family.dpars[0] == "mu"));
+}
+
+#[test]
+fn test_family_and_link_are_checked() {
+    for formula in [
+        "count ~ x, family=poisson()",
+        "count ~ x, family=poisson(link=\"sqrt\")",
+        "count ~ x, family=poisson(), link=identity",
+        "prop ~ x, family=Beta(\"probit\")",
+        "count ~ x, family=mixture(poisson(), negbinomial())",
+        "count ~ x, family=custom_family(\"kumaraswamy\", \"mu\", \"phi\")",
+    ] {
+        assert!(canonicalize(formula).is_ok(), "{}", formula);
+    }
+    for formula in [
+        "count ~ x, family=poison()",
+        "count ~ x, family=poisson(link=\"logit\")",
+        "count ~ x, family=poisson(), link=probit",
+        "count ~ x, family=mixture(poisson(), student(link=\"logit\"))",
+    ] {
+        assert!(canonicalize(formula).is_err(), "{}", formula);
+    }
+    let err = canonicalize("y ~ x, family=Gamma(link=\"logit\")").unwrap_err();
+    assert!(
+        err.to_string().contains("log, identity, inverse, softplus"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_response_support_is_checked() {
+    let df = frame();
+    for formula in [
+        "count ~ x, family=poisson()",
+        "rate ~ x, family=hurdle_gamma()",
+        "prop ~ x, family=Beta()",
+        "sex ~ x, family=bernoulli()",
+        "grade ~ x, family=categorical()",
+        "rate ~ x, family=gaussian()",
+    ] {
+        let spec = canonicalize(formula).unwrap();
+        assert!(materialize(&spec, &df).is_ok(), "{}", formula);
+    }
+    for formula in [
+        "rate ~ x, family=poisson()",
+        "rate ~ x, family=Gamma()",
+        "count ~ x, family=Beta()",
+        "grade ~ x, family=bernoulli()",
+        "sex ~ x, family=poisson()",
+        "rate ~ x, family=mixture(gaussian(), lognormal())",
+    ] {
+        let spec = canonicalize(formula).unwrap();
+        assert!(materialize(&spec, &df).is_err(), "{}", formula);
+    }
+
+    // New data may carry a placeholder response
+    let spec = canonicalize("count ~ x, family=poisson()").unwrap();
+    let (_, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let new = df!("count" => [-1.0, -1.0], "x" => [0.0, 3.0]).unwrap();
+    assert!(materialize_new_data(&spec, &new, &info).is_ok());
+}
+
+#[test]
+fn test_response_is_encoded_for_the_family() {
+    let df = frame();
+
+    // Ordinal codes 1..K in level order
+    let spec = canonicalize("grade ~ x, family=cumulative()").unwrap();
+    let (y, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let codes: Vec<i32> = y
+        .column("grade")
+        .unwrap()
+        .i32()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(codes, [1, 2, 3, 1]);
+    let encoding = info.response_encoding.clone().unwrap();
+    assert_eq!(encoding.coding, ResponseCoding::Ordinal);
+    assert_eq!(encoding.levels, ["a", "b", "c"]);
+    assert_eq!(encoding.level(3), Some("c"));
+
+    // New data keeps the training levels and rejects unseen ones
+    let new = df!("grade" => ["c", "b"], "x" => [0.0, 1.0]).unwrap();
+    let (y_new, _, _) = materialize_new_data(&spec, &new, &info).unwrap();
+    let codes: Vec<i32> = y_new
+        .column("grade")
+        .unwrap()
+        .i32()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(codes, [3, 2]);
+    let unseen = df!("grade" => ["d"], "x" => [0.0]).unwrap();
+    assert!(materialize_new_data(&spec, &unseen, &info).is_err());
+
+    // One indicator column per level
+    let spec = canonicalize("grade ~ x, family=categorical()").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    let names: Vec<&str> = data
+        .y
+        .get_column_names()
+        .iter()
+        .map(|n| n.as_str())
+        .collect();
+    assert_eq!(names, ["grade_a", "grade_b", "grade_c"]);
+    let b: Vec<f64> = data
+        .y
+        .column("grade_b")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(b, [0.0, 1.0, 0.0, 0.0]);
+    assert_eq!(
+        data.info.response_encoding.unwrap().coding,
+        ResponseCoding::Indicators
+    );
+
+    // Two-level strings and booleans become 0/1, the second level 1
+    let spec = canonicalize("sex ~ x, family=bernoulli()").unwrap();
+    let (y, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let sex: Vec<f64> = y
+        .column("sex")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(sex, [1.0, 0.0, 1.0, 0.0]);
+    assert_eq!(info.response_encoding.unwrap().levels, ["f", "m"]);
+    let flags = df!("hit" => [true, false, true], "x" => [0.0, 1.0, 2.0]).unwrap();
+    let spec = canonicalize("hit ~ x, family=bernoulli()").unwrap();
+    let (y, _, _) = materialize(&spec, &flags).unwrap();
+    let hit: Vec<f64> = y
+        .column("hit")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(hit, [1.0, 0.0, 1.0]);
+
+    // Numeric codes and other families are left as they are
+    let spec = canonicalize("count ~ x, family=poisson()").unwrap();
+    let (y, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert!(info.response_encoding.is_none());
+    assert_eq!(y.column("count").unwrap().dtype(), &DataType::Float64);
+}
@@ -0,0 +1,137 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, materialize_lazy, materialize_new_data, materialize_with_info};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.5, 2.0, 4.5, 5.0, 6.5, 6.0, 8.0],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5, 4.0],
+        "z" => [Some(1.2), Some(2.3), Some(0.7), None, Some(3.1), Some(2.2), Some(4.0), Some(1.9)],
+        "n" => [3i32, 1, 4, 1, 5, 9, 2, 6],
+        "g" => ["b", "a", "c", "a", "b", "c", "a", "b"],
+        "h" => ["u", "v", "u", "v", "v", "u", "u", "v"]
+    )
+    .unwrap()
+}
+
+fn assert_frames_close(lazy: &DataFrame, eager: &DataFrame) {
+    assert_eq!(lazy.get_column_names(), eager.get_column_names());
+    assert_eq!(lazy.height(), eager.height());
+    for (a, b) in lazy.get_columns().iter().zip(eager.get_columns()) {
+        let a = a.cast(&DataType::Float64).unwrap();
+        let b = b.cast(&DataType::Float64).unwrap();
+        for (a, b) in a.f64().unwrap().into_iter().zip(b.f64().unwrap()) {
+            match (a, b) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-9, "{}: {} != {}", a, b, b),
+                (a, b) => assert_eq!(a, b, "{}", lazy.get_column_names()[0]),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lazy_design_matches_eager() {
+    let df = data();
+    for formula in [
+        "y ~ x + g + x:g",
+        "y ~ x * g * h",
+        "y ~ poly(z, 2) + log(z) + sqrt(x + 1) + I(x^2)",
+        "y ~ poly(x, 3, raw=TRUE) + n",
+        "y ~ bs(z, df=4) + ns(x, df=3)",
+        "y ~ bs(x, df=3):g + bs(x, df=3)",
+        "y ~ 1",
+        "y ~ g + (1|h) - 1",
+        "cbind(n, 10 - n) ~ x + offset(z)",
+    ] {
+        let spec = canonicalize(formula).unwrap();
+        let (y, x, info) = materialize_lazy(&spec, &df.clone().lazy()).unwrap();
+        let (y_eager, x_eager, _, info_eager) = materialize_with_info(&spec, &df).unwrap();
+        assert_frames_close(&x.collect().unwrap(), &x_eager);
+        assert_frames_close(&y.collect().unwrap(), &y_eager);
+        assert_eq!(info.levels, info_eager.levels, "{}", formula);
+        assert_eq!(info.transforms, info_eager.transforms, "{}", formula);
+    }
+}
+
+#[test]
+fn test_exprs_reuse_training_state() {
+    let train = data();
+    let spec = canonicalize("y ~ poly(x, 2) + g + bs(z, df=4)").unwrap();
+    let (_, _, _, info) = materialize_with_info(&spec, &train).unwrap();
+
+    // New rows are coded with the training levels, coefficients and knots
+    let new = df!(
+        "y" => [0.0, 0.0, 0.0],
+        "x" => [0.0, 2.2, 5.0],
+        "z" => [1.0, 2.0, 3.0],
+        "n" => [1i32, 2, 3],
+        "g" => ["c", "c", "a"],
+        "h" => ["u", "u", "u"]
+    )
+    .unwrap();
+    let exprs = spec.to_exprs_with_info(new.schema(), &info).unwrap();
+    let x = new.clone().lazy().select(exprs).collect().unwrap();
+    let (_, x_eager, _) = materialize_new_data(&spec, &new, &info).unwrap();
+    assert_frames_close(&x, &x_eager);
+    assert!(x.column("g_b").is_ok());
+
+    // Stateful terms need learned state; stateless ones do not
+    assert!(spec.to_exprs(new.schema()).is_err());
+    let stateless = canonicalize("y ~ x + log(z) + x:n").unwrap();
+    assert_eq!(stateless.to_exprs(new.schema()).unwrap().len(), 4);
+    assert!(canonicalize("y ~ s(x)")
+        .unwrap()
+        .to_exprs(new.schema())
+        .is_err());
+    assert!(canonicalize("y ~ w")
+        .unwrap()
+        .to_exprs(new.schema())
+        .is_err());
+}
+
+#[test]
+fn test_lazy_filters_push_through_the_design() {
+    let df = data();
+    let spec = canonicalize("y ~ x + g").unwrap();
+    let (_, x, _) = materialize_lazy(&spec, &df.clone().lazy()).unwrap();
+    let x = x.filter(col("x").gt(lit(2.0))).collect().unwrap();
+    assert_eq!(x.height(), 4);
+    assert_eq!(x.get_column_names(), ["intercept", "x", "g_b", "g_c"]);
+
+    // Eager orthogonal polynomials are orthonormal on the training data
+    let spec = canonicalize("y ~ poly(x, 2)").unwrap();
+    let (_, x, _, _) = materialize_with_info(&spec, &df).unwrap();
+    let p1: Vec<f64> = x
+        .column("poly_x_1")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    let p2: Vec<f64> = x
+        .column("poly_x_2")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+    assert!(dot(&p1, &p2).abs() < 1e-12);
+    assert!((dot(&p1, &p1) - 1.0).abs() < 1e-12);
+    assert!(p1.iter().sum::<f64>().abs() < 1e-12);
+}
+
+#[test]
+fn test_lazy_spline_basis_is_computed_once() {
+    let df = data();
+    let spec = canonicalize("y ~ ns(x, df=3) + ns(x, df=3):h").unwrap();
+    let (_, x, _) = materialize_lazy(&spec, &df.clone().lazy()).unwrap();
+
+    // The basis is evaluated by one map into a temporary struct column, and
+    // the six columns of X read its fields
+    let plan = x.clone().explain(false).unwrap();
+    assert_eq!(plan.matches(".map()").count(), 1, "{}", plan);
+    assert_eq!(plan.matches("struct.field_by_name").count(), 6, "{}", plan);
+    let x = x.collect().unwrap();
+    assert_eq!(x.width(), 7);
+    assert!(x.get_column_names().iter().all(|n| !n.starts_with("__")));
+}
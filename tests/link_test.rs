@@ -0,0 +1,110 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, link_function, FamilyInfo, LinkFunction, Probit};
+
+fn assert_close(a: f64, b: f64, tol: f64) {
+    assert!((a - b).abs() <= tol, "{} != {}", a, b);
+}
+
+#[test]
+fn test_links_invert_and_differentiate() {
+    let links = [
+        ("identity", 0.3),
+        ("log", 2.5),
+        ("logit", 0.3),
+        ("probit", 0.3),
+        ("probit_approx", 0.3),
+        ("cloglog", 0.3),
+        ("cauchit", 0.3),
+        ("inverse", 2.5),
+        ("sqrt", 2.5),
+        ("1/mu^2", 2.5),
+        ("softplus", 2.5),
+        ("tan_half", 1.2),
+    ];
+    for (name, mu) in links {
+        let link = link_function(name).unwrap();
+        assert_eq!(link.name(), name);
+        let eta = link.link(mu);
+        let tol = if name == "probit_approx" { 1e-2 } else { 1e-12 };
+        assert_close(link.inverse(eta), mu, tol);
+
+        // mu_eta against a central difference of the inverse.
+        let h = 1e-6;
+        let numeric = (link.inverse(eta + h) - link.inverse(eta - h)) / (2.0 * h);
+        assert_close(link.mu_eta(eta), numeric, 1e-6);
+    }
+    assert!(link_function("loglog").is_none());
+
+    // Every link of the registry resolves.
+    for family in FamilyInfo::all() {
+        for name in family.links {
+            assert!(link_function(name).is_some(), "{}", name);
+        }
+    }
+
+    // Known normal quantiles and probabilities, and bounded inverses.
+    assert_close(Probit.link(0.975), 1.959963984540054, 1e-12);
+    assert_close(Probit.link(1e-10), -6.361340902404056, 1e-9);
+    assert_close(Probit.inverse(-3.0), 0.0013498980316301, 1e-15);
+    assert!(Probit.inverse(-40.0) > 0.0);
+    let logit = link_function("logit").unwrap();
+    assert!(logit.inverse(50.0) < 1.0);
+    assert!(logit.mu_eta(50.0) > 0.0);
+}
+
+#[test]
+fn test_links_apply_to_series() {
+    let link = link_function("log").unwrap();
+    let mu = Series::new("mu".into(), [Some(1.0), None, Some(std::f64::consts::E)]);
+    let eta = link.link_series(&mu).unwrap();
+    assert_eq!(eta.name().as_str(), "mu");
+    let values: Vec<Option<f64>> = eta.f64().unwrap().into_iter().collect();
+    assert_eq!(values[1], None);
+    assert_close(values[2].unwrap(), 1.0, 1e-12);
+
+    // Integer columns are cast; the inverse round-trips.
+    let counts = Series::new("n".into(), [1i32, 2, 4]);
+    let back = link
+        .inverse_series(&link.link_series(&counts).unwrap())
+        .unwrap();
+    let back: Vec<f64> = back.f64().unwrap().into_no_null_iter().collect();
+    assert_close(back[2], 4.0, 1e-12);
+    assert_eq!(link.mu_eta_series(&counts).unwrap().len(), 3);
+
+    let text = Series::new("s".into(), ["a", "b"]);
+    assert!(link.link_series(&text).is_err());
+}
+
+#[test]
+fn test_link_resolution() {
+    let resolve = |formula: &str| {
+        canonicalize(formula)
+            .unwrap()
+            .link_function()
+            .unwrap()
+            .name()
+    };
+    assert_eq!(resolve("y ~ x"), "identity");
+    assert_eq!(resolve("y ~ x, family=poisson()"), "log");
+    assert_eq!(resolve("y ~ x, family=binomial(link=\"probit\")"), "probit");
+    assert_eq!(resolve("y ~ x, family=binomial(\"cloglog\")"), "cloglog");
+    assert_eq!(resolve("y ~ x, family=poisson(), link=sqrt"), "sqrt");
+    assert_eq!(resolve("y ~ x, family=inverse.gaussian()"), "1/mu^2");
+
+    let mixture = canonicalize("y ~ x, family=mixture(gaussian(), poisson())").unwrap();
+    assert!(mixture.link_function().is_err());
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_links_apply_to_faer_columns() {
+    let link = link_function("logit").unwrap();
+    let mu = faer::Col::from_fn(3, |i| 0.2 * (i + 1) as f64);
+    let eta = link.link_faer(mu.as_ref());
+    let back = link.inverse_faer(eta.as_ref());
+    let weights = link.mu_eta_faer(eta.as_ref());
+    for i in 0..3 {
+        assert_close(back[i], mu[i], 1e-12);
+        assert_close(weights[i], mu[i] * (1.0 - mu[i]), 1e-12);
+    }
+}
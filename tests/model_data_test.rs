@@ -0,0 +1,155 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, materialize_model_data};
+
+fn values(series: &Series) -> Vec<f64> {
+    series.f64().unwrap().into_no_null_iter().collect()
+}
+
+fn frame() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0],
+        "n" => [4.0, 2.0, 5.0, 6.0],
+        "x" => [0.5, 1.0, 1.5, 2.0],
+        "s" => [0.1, 0.2, 0.3, 0.4],
+        "c" => ["none", "right", "left", "none"],
+        "g" => ["a", "b", "a", "b"]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_binomial_trials_are_kept() {
+    let spec = canonicalize("y | trials(n) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &frame()).unwrap();
+    assert_eq!(values(data.aterm("trials").unwrap()), [4.0, 2.0, 5.0, 6.0]);
+    assert_eq!(data.y.width(), 1);
+
+    // Successes may not exceed the trials
+    let spec = canonicalize("n | trials(y) ~ x").unwrap();
+    assert!(materialize_model_data(&spec, &frame()).is_err());
+    let spec = canonicalize("y | trials(s) ~ x").unwrap();
+    assert!(materialize_model_data(&spec, &frame()).is_err());
+}
+
+#[test]
+fn test_aterms_are_evaluated() {
+    let spec = canonicalize("y | se(s) + cens(c) + trunc(lb=0, ub=10) + rate(n / 2) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &frame()).unwrap();
+    assert_eq!(
+        data.aterms.get_column_names(),
+        ["se", "cens", "trunc_lb", "trunc_ub", "rate"]
+    );
+    assert_eq!(values(data.aterm("cens").unwrap()), [0.0, 1.0, -1.0, 0.0]);
+    assert_eq!(values(data.aterm("trunc_ub").unwrap()), [10.0; 4]);
+    assert_eq!(values(data.aterm("rate").unwrap()), [2.0, 1.0, 2.5, 3.0]);
+
+    let spec = canonicalize("y | thres(gr=g) + vint(n, y) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &frame()).unwrap();
+    assert_eq!(
+        data.aterm("thres").unwrap().str().unwrap().get(1),
+        Some("b")
+    );
+    assert_eq!(values(data.aterm("vint_2").unwrap()), [1.0, 2.0, 3.0, 4.0]);
+}
+
+#[test]
+fn test_aterm_validation() {
+    let df = frame();
+    for formula in [
+        "y | weights(x - 1) ~ x",
+        "y | se(0 - s) ~ x",
+        "y | trunc(ub=3) ~ x",
+        "y | cens(x) ~ x",
+        "y | rate(x - 0.5) ~ x",
+        "y | vint(x) ~ x",
+    ] {
+        let spec = canonicalize(formula).unwrap();
+        assert!(materialize_model_data(&spec, &df).is_err(), "{}", formula);
+    }
+}
+
+#[test]
+fn test_incomplete_rows_are_dropped() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5],
+        "w" => [Some(1.0), None, Some(1.0), Some(2.0), Some(1.0)],
+        "g" => [Some("a"), Some("b"), Some("b"), Some("c"), None]
+    )
+    .unwrap();
+    let spec = canonicalize("y | weights(w) ~ x + (1|g)").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [0, 2, 3]);
+    assert_eq!(
+        values(data.y.column("y").unwrap().as_materialized_series()),
+        [1.0, 3.0, 4.0]
+    );
+    assert_eq!(data.x.height(), 3);
+    assert_eq!(data.aterms.height(), 3);
+    // Levels are learned from the rows kept
+    assert_eq!(data.z.width(), 3);
+
+    let spec = canonicalize("y ~ x").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [0, 1, 2, 3, 4]);
+    assert_eq!(data.aterms.width(), 0);
+}
+
+#[test]
+fn test_subset_selects_rows() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5],
+        "age" => [Some(12.0), Some(30.0), Some(45.0), None, Some(20.0)],
+        "sex" => ["m", "f", "m", "m", "m"],
+        "flag" => [1, 0, 1, 1, 0]
+    )
+    .unwrap();
+
+    let spec = canonicalize("y | subset(flag) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [0, 2, 3]);
+    assert_eq!(data.x.height(), 3);
+
+    // Rows with a missing condition are not selected
+    let spec = canonicalize("y | subset(age > 18 & sex == \"m\") + weights(x) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [2, 4]);
+    assert_eq!(values(data.aterm("weights").unwrap()), [1.5, 2.5]);
+
+    let spec = canonicalize("y | subset(!(x * 2 > 3) || flag == 0) ~ x").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.rows, [0, 1, 2, 4]);
+
+    for formula in [
+        "y | subset(age) ~ x",
+        "y | subset(sex > 1) ~ x",
+        "y | subset(x > 10) ~ x",
+    ] {
+        let spec = canonicalize(formula).unwrap();
+        assert!(materialize_model_data(&spec, &df).is_err(), "{}", formula);
+    }
+}
+
+#[test]
+fn test_offsets_are_summed_outside_x() {
+    let df = df!(
+        "count" => [0.0, 2.0, 1.0, 4.0],
+        "persons" => [1.0, 2.0, 1.0, 4.0],
+        "exposure" => [Some(1.0), Some(2.0), None, Some(4.0)],
+        "base" => [0.5, 0.5, 0.5, 1.0]
+    )
+    .unwrap();
+    let spec = canonicalize("count ~ persons + offset(log(exposure)) + offset(base * 2)").unwrap();
+    let data = materialize_model_data(&spec, &df).unwrap();
+    assert_eq!(data.x.get_column_names(), ["intercept", "persons"]);
+    assert_eq!(data.rows, [0, 1, 3]);
+    let offset = values(data.offset.as_ref().unwrap());
+    let expected = [1.0, 2.0_f64.ln() + 1.0, 4.0_f64.ln() + 2.0];
+    for (o, e) in offset.iter().zip(expected) {
+        assert!((o - e).abs() < 1e-12);
+    }
+
+    let spec = canonicalize("count ~ persons").unwrap();
+    assert!(materialize_model_data(&spec, &df).unwrap().offset.is_none());
+}
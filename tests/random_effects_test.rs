@@ -0,0 +1,376 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize, materialize_new_data, materialize_re_terms, materialize_sparse,
+    materialize_with_info, GroupKind,
+};
+
+fn column(df: &DataFrame, name: &str) -> Vec<f64> {
+    df.column(name)
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_iter()
+        .map(|v| v.unwrap())
+        .collect()
+}
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "z" => [0.5, 1.0, 0.5, 1.0, 0.5, 1.0],
+        "treat" => ["a", "b", "c", "a", "b", "c"],
+        "g" => ["g1", "g1", "g2", "g2", "g3", "g3"]
+    )
+    .unwrap()
+}
+
+fn z_names(formula: &str) -> Vec<String> {
+    let spec = canonicalize(formula).unwrap();
+    let (_, _, z) = materialize(&spec, &data()).unwrap();
+    z.get_column_names().iter().map(|n| n.to_string()).collect()
+}
+
+#[test]
+fn test_correlated_terms_stay_together() {
+    let spec = canonicalize("y ~ (x|g)").unwrap();
+    let explicit = canonicalize("y ~ (1 + x|g)").unwrap();
+    assert_eq!(spec, explicit);
+
+    // Columns are level-major, as in lme4
+    assert_eq!(
+        z_names("y ~ (x|g)"),
+        [
+            "ri_g_g1",
+            "rs_x_g_g1",
+            "ri_g_g2",
+            "rs_x_g_g2",
+            "ri_g_g3",
+            "rs_x_g_g3"
+        ]
+    );
+}
+
+#[test]
+fn test_categorical_slopes() {
+    // Without an intercept, every level gets an indicator
+    let names = z_names("y ~ (0 + treat|g)");
+    assert_eq!(names.len(), 9);
+    assert_eq!(
+        &names[..3],
+        ["rs_treat_a_g_g1", "rs_treat_b_g_g1", "rs_treat_c_g_g1"]
+    );
+
+    // With an intercept, treatment contrasts are used
+    let names = z_names("y ~ (1 + treat|g)");
+    assert_eq!(
+        &names[..3],
+        ["ri_g_g1", "rs_treat_b_g_g1", "rs_treat_c_g_g1"]
+    );
+
+    let spec = canonicalize("y ~ (1 + treat|g)").unwrap();
+    let (_, _, z) = materialize(&spec, &data()).unwrap();
+    assert_eq!(
+        column(&z, "rs_treat_b_g_g1"),
+        [0.0, 1.0, 0.0, 0.0, 0.0, 0.0]
+    );
+}
+
+#[test]
+fn test_transforms_and_interactions_in_group_terms() {
+    let df = data();
+    let spec = canonicalize("y ~ (poly(x, 2)|g)").unwrap();
+    let (_, x, z) = materialize(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1);
+    assert_eq!(z.width(), 9);
+    assert_eq!(column(&z, "rs_poly_x_1_g_g2")[..2], [0.0, 0.0]);
+
+    let spec = canonicalize("y ~ (0 + x:z|g)").unwrap();
+    let (_, _, z) = materialize(&spec, &df).unwrap();
+    assert_eq!(column(&z, "rs_x_x_z_g_g2"), [0.0, 0.0, 1.5, 4.0, 0.0, 0.0]);
+}
+
+#[test]
+fn test_missing_slopes_stay_missing() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0],
+        "x" => [Some(1.0), None, Some(3.0), Some(4.0)],
+        "g" => ["g1", "g1", "g2", "g2"]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ x + (x|g)").unwrap();
+    let (_, x, z) = materialize(&spec, &df).unwrap();
+    assert_eq!(x.column("x").unwrap().null_count(), 1);
+
+    // The slope of the row is missing in Z as in X, not a zero
+    let slopes = z.column("rs_x_g_g1").unwrap().f64().unwrap().clone();
+    assert_eq!(
+        slopes.into_iter().collect::<Vec<_>>(),
+        [Some(1.0), None, Some(0.0), Some(0.0)]
+    );
+    assert_eq!(column(&z, "ri_g_g1"), [1.0, 1.0, 0.0, 0.0]);
+    assert_eq!(column(&z, "rs_x_g_g2"), [0.0, 0.0, 3.0, 4.0]);
+
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+    assert!(z.get(1, 1).is_nan());
+}
+
+#[test]
+fn test_intercept_removal_in_group_terms() {
+    let minus_one = canonicalize("y ~ (x - 1|g)").unwrap();
+    let zero = canonicalize("y ~ (0 + x|g)").unwrap();
+    assert_eq!(minus_one, zero);
+    assert_eq!(
+        z_names("y ~ (1 + x + z|g)")[..3],
+        ["ri_g_g1", "rs_x_g_g1", "rs_z_g_g1"]
+    );
+}
+
+fn nested_data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "LOCATION" => ["s1", "s1", "s1", "s2", "s2", "s2"],
+        "BROOD" => ["p1", "p2", "p1", "p1", "p2", "p2"]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_nested_groups_expand_to_crossed_terms() {
+    let nested = canonicalize("y ~ (1|LOCATION/BROOD)").unwrap();
+    let expanded = canonicalize("y ~ (1|LOCATION) + (1|LOCATION:BROOD)").unwrap();
+    assert_eq!(nested, expanded);
+
+    let (_, _, z) = materialize_sparse(&nested, &nested_data()).unwrap();
+    assert_eq!(z.terms.len(), 2);
+    assert_eq!(z.terms[0].group, "LOCATION");
+    assert_eq!(z.terms[0].levels, ["s1", "s2"]);
+    assert_eq!(z.terms[1].group, "LOCATION:BROOD");
+    // Only combinations present in the data are levels
+    assert_eq!(z.terms[1].levels, ["s1:p1", "s1:p2", "s2:p1", "s2:p2"]);
+    assert_eq!(z.terms[1].columns, 2..6);
+    // Every row has one entry per term
+    assert_eq!(z.nnz(), 12);
+    assert_eq!(z.get(5, 5), 1.0);
+}
+
+#[test]
+fn test_crossed_group_levels() {
+    let df = nested_data();
+    let spec = canonicalize("y ~ (1|LOCATION:BROOD)").unwrap();
+    let (_, _, z) = materialize(&spec, &df).unwrap();
+    assert_eq!(z.width(), 4);
+    assert_eq!(
+        column(&z, "ri_location_brood_s1_p2"),
+        [0.0, 1.0, 0.0, 0.0, 0.0, 0.0]
+    );
+}
+
+#[test]
+fn test_split_groups_and_nested_slopes() {
+    let split = canonicalize("y ~ (1|LOCATION + BROOD)").unwrap();
+    let separate = canonicalize("y ~ (1|LOCATION) + (1|BROOD)").unwrap();
+    assert_eq!(split, separate);
+
+    let spec = canonicalize("y ~ x + (x|LOCATION/BROOD)").unwrap();
+    let (_, x, z) = materialize_sparse(&spec, &nested_data()).unwrap();
+    assert_eq!(x.width(), 2);
+    assert_eq!(z.terms.len(), 2);
+    assert_eq!(z.terms[1].inner, ["(Intercept)", "x"]);
+    assert_eq!(z.ncols(), 2 * 2 + 4 * 2);
+}
+
+#[test]
+fn test_re_terms_structure() {
+    let spec = canonicalize("y ~ x + (x|LOCATION) + (1|LOCATION:BROOD) + (x||BROOD)").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &nested_data()).unwrap();
+
+    let summary: Vec<_> = re
+        .terms
+        .iter()
+        .map(|t| (t.group.as_str(), t.n_levels, t.n_cols(), t.n_theta()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("LOCATION", 2, 2, 3),
+            ("LOCATION:BROOD", 4, 1, 1),
+            ("BROOD", 2, 1, 1),
+            ("BROOD", 2, 1, 1)
+        ]
+    );
+    assert_eq!(re.terms[0].kind, GroupKind::Correlated);
+    assert_eq!(re.terms[2].kind, GroupKind::Uncorrelated);
+    assert_eq!(re.gp, [0, 4, 8, 10, 12]);
+    assert_eq!(re.theta, [1.0, 0.0, 1.0, 1.0, 1.0, 1.0]);
+    assert_eq!(re.lower[1], f64::NEG_INFINITY);
+    assert_eq!(re.lind.len(), re.lambdat.values.len());
+    assert_eq!((re.lambdat.nrows, re.lambdat.ncols), (12, 12));
+    // Off-diagonal template entries start at zero
+    assert_eq!(re.lambdat.get(0, 1), 0.0);
+    assert_eq!(re.lambdat.get(1, 1), 1.0);
+}
+
+#[test]
+fn test_re_terms_id_linkage() {
+    let spec = canonicalize("y ~ (1|ID|LOCATION) + (0 + x|ID|LOCATION)").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &nested_data()).unwrap();
+    assert_eq!(re.terms[0].id.as_deref(), Some("ID"));
+    assert_eq!(re.linked_terms(), [("ID".to_string(), vec![0, 1])]);
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_lambdat_to_faer() {
+    let spec = canonicalize("y ~ (x|LOCATION)").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &nested_data()).unwrap();
+    let lambdat = re.lambdat.to_faer().to_dense();
+    for i in 0..re.lambdat.nrows {
+        for j in 0..re.lambdat.ncols {
+            assert_eq!(lambdat[(i, j)], re.lambdat.get(i, j));
+        }
+    }
+}
+
+#[test]
+fn test_uncorrelated_terms_split_per_inner_term() {
+    let spec = canonicalize("y ~ (1 + x + treat||g)").unwrap();
+    let expected = canonicalize("y ~ (1||g) + (0 + x||g) + (0 + treat||g)").unwrap();
+    assert_eq!(spec, expected);
+
+    // Transforms are split off like variables
+    let spec = canonicalize("y ~ (poly(x, 2)||g)").unwrap();
+    let expected = canonicalize("y ~ (1||g) + (0 + poly(x, 2)||g)").unwrap();
+    assert_eq!(spec, expected);
+
+    // A factor slope without intercept gets one indicator per level
+    let spec = canonicalize("y ~ (1 + treat||g)").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &data()).unwrap();
+    assert_eq!(re.terms.len(), 2);
+    assert_eq!(re.terms[1].cnms, ["treat_a", "treat_b", "treat_c"]);
+    assert_eq!(re.terms[1].kind, GroupKind::Uncorrelated);
+    assert_eq!(re.terms[1].n_theta(), 3);
+}
+
+#[test]
+fn test_group_id_labels() {
+    let spec = canonicalize("y ~ (1|p|g) + (0 + x|p|g) + (1|q|LOCATION)").unwrap();
+    let df = nested_data()
+        .hstack(&[Column::new("g".into(), ["a", "a", "b", "b", "c", "c"])])
+        .unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &df).unwrap();
+    assert_eq!(re.terms[0].id.as_deref(), Some("p"));
+    assert_eq!(re.terms[2].id.as_deref(), Some("q"));
+    assert_eq!(re.linked_terms(), [("p".to_string(), vec![0, 1])]);
+
+    // Linked terms must share their grouping factor
+    let spec = canonicalize("y ~ (1|p|g) + (1|p|LOCATION)").unwrap();
+    assert!(materialize_re_terms(&spec, &df).is_err());
+}
+
+#[test]
+fn test_gr_by_has_separate_variances() {
+    let df = data()
+        .hstack(&[Column::new("site".into(), ["a", "a", "a", "a", "b", "b"])])
+        .unwrap();
+    let spec = canonicalize("y ~ (1|gr(g, by=site))").unwrap();
+    let plain = canonicalize("y ~ (1|g)").unwrap();
+    let (_, _, z) = materialize(&spec, &df).unwrap();
+    assert_eq!(z, materialize(&plain, &df).unwrap().2);
+
+    let (_, _, re) = materialize_re_terms(&spec, &df).unwrap();
+    let by = re.terms[0].by.as_ref().unwrap();
+    assert_eq!(by.levels, ["a", "b"]);
+    assert_eq!(by.level_of_group, [0, 0, 1]);
+    assert_eq!(re.theta, [1.0, 1.0]);
+    assert_eq!(re.lind, [0, 0, 1]);
+
+    // Every level of g must fall within one level of the by variable
+    let spec = canonicalize("y ~ (1|gr(g, by=treat))").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+}
+
+#[test]
+fn test_gr_by_on_new_data_with_fewer_levels() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0],
+        "g" => ["a", "b", "c", "d"],
+        "x" => ["u", "u", "v", "v"]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ (1|gr(g, by=x))").unwrap();
+    let (_, _, z, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(info.group_by["g"].levels, ["u", "v"]);
+    assert_eq!(info.group_by["g"].level_of_group, [0, 0, 1, 1]);
+
+    // Only one level of g, so only one level of the by variable
+    let new = df!("y" => [0.0, 0.0], "g" => ["a", "a"], "x" => ["u", "u"]).unwrap();
+    let (_, _, z_new) = materialize_new_data(&spec, &new, &info).unwrap();
+    assert_eq!(z_new.get_column_names(), z.get_column_names());
+    assert_eq!(column(&z_new, z.get_column_names()[0]), [1.0, 1.0]);
+    assert_eq!(column(&z_new, z.get_column_names()[3]), [0.0, 0.0]);
+}
+
+#[test]
+fn test_gr_cor_and_id() {
+    let spec = canonicalize("y ~ (1 + x|gr(g, cor=FALSE, id=\"a\"))").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &data()).unwrap();
+    assert_eq!(re.terms[0].kind, GroupKind::Uncorrelated);
+    assert_eq!(re.terms[0].n_theta(), 2);
+    assert_eq!(re.terms[0].id.as_deref(), Some("a"));
+    assert_eq!(re.z.column_names[..2], ["ri_g_g1", "rs_x_g_g1"]);
+
+    let spec = canonicalize("y ~ (1|gr(g, cov=A))").unwrap();
+    assert!(materialize(&spec, &data()).is_err());
+}
+
+fn multi_membership_data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0],
+        "s1" => ["a", "b", "a", "c"],
+        "s2" => ["b", "b", "c", "a"],
+        "w1" => [3.0, 1.0, 1.0, 0.5],
+        "w2" => [1.0, 1.0, 1.0, 0.5]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_multi_membership_weights() {
+    let df = multi_membership_data();
+    let spec = canonicalize("y ~ (1|mm(s1, s2, weights=cbind(w1, w2)))").unwrap();
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+    assert_eq!(z.terms[0].group, "mm(s1, s2)");
+    assert_eq!(z.terms[0].levels, ["a", "b", "c"]);
+    assert_eq!(
+        z.column_names,
+        ["ri_mm_s1_s2_a", "ri_mm_s1_s2_b", "ri_mm_s1_s2_c"]
+    );
+    // Weights are scaled to sum to one per row; repeated levels add up
+    assert_eq!(z.get(0, 0), 0.75);
+    assert_eq!(z.get(0, 1), 0.25);
+    assert_eq!(z.get(1, 1), 1.0);
+    assert_eq!(z.get(3, 0), 0.5);
+    assert_eq!(z.nnz(), 7);
+    assert_eq!(z.to_dense().unwrap(), materialize(&spec, &df).unwrap().2);
+
+    let spec = canonicalize("y ~ (1|mm(s1, s2, weights=cbind(w1, w2), scale=FALSE))").unwrap();
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+    assert_eq!(z.get(0, 0), 3.0);
+    assert_eq!(z.get(1, 1), 2.0);
+}
+
+#[test]
+fn test_multi_membership_slopes_and_equal_weights() {
+    let df = multi_membership_data();
+    let spec = canonicalize("y ~ (0 + w1|mm(s1, s2))").unwrap();
+    let (_, _, re) = materialize_re_terms(&spec, &df).unwrap();
+    assert_eq!(re.terms[0].n_levels, 3);
+    assert_eq!(re.terms[0].cnms, ["w1"]);
+    assert_eq!(re.z.get(0, 0), 1.5);
+    assert_eq!(re.z.get(2, 2), 0.5);
+
+    let spec = canonicalize("y ~ (1|mm(s1, s2, weights=cbind(w1)))").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+}
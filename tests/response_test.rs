@@ -0,0 +1,129 @@
+use polars::prelude::*;
+use polars_formula::{canonicalize, materialize, materialize_with_info};
+
+fn column(df: &DataFrame, name: &str) -> Vec<f64> {
+    df.column(name)
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_iter()
+        .map(|v| v.unwrap())
+        .collect()
+}
+
+fn cbpp() -> DataFrame {
+    df!(
+        "incidence" => [2, 3, 4, 0, 3, 1],
+        "size" => [14, 12, 9, 5, 22, 18],
+        "period" => ["1", "2", "3", "4", "1", "2"],
+        "herd" => ["h1", "h1", "h1", "h1", "h2", "h2"]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_cbind_binomial_response() {
+    let spec = canonicalize("cbind(incidence, size - incidence) ~ period + (1|herd)").unwrap();
+    let (y, x, z, info) = materialize_with_info(&spec, &cbpp()).unwrap();
+    assert_eq!(y.width(), 2);
+    assert_eq!(column(&y, "incidence"), [2.0, 3.0, 4.0, 0.0, 3.0, 1.0]);
+    assert_eq!(
+        column(&y, "size - incidence"),
+        [12.0, 9.0, 5.0, 5.0, 19.0, 17.0]
+    );
+    assert_eq!(x.width(), 4);
+    assert_eq!(z.width(), 2);
+
+    let names: Vec<_> = info.responses.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(names, ["incidence", "size - incidence"]);
+    assert!(info.responses.iter().all(|r| r.bind == "cbind"));
+}
+
+#[test]
+fn test_mvbind_keeps_every_response() {
+    let df = df!(
+        "y1" => [1.0, 2.0, 3.0],
+        "y2" => [Some(0.5), None, Some(1.5)],
+        "y3" => [4.0, 5.0, 6.0],
+        "x" => [0.0, 1.0, 2.0]
+    )
+    .unwrap();
+    let spec = canonicalize("mvbind(y1, y2, log(y3)) ~ x").unwrap();
+    let (y, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(y.get_column_names(), ["y1", "y2", "log(y3)"]);
+    assert_eq!(column(&y, "log(y3)")[0], 4.0_f64.ln());
+    assert_eq!(info.responses[1].bind, "mvbind");
+    assert_eq!(info.responses[1].null_count, 1);
+
+    // A single-column response has no per-response metadata
+    let spec = canonicalize("y1 ~ x").unwrap();
+    let (_, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert!(info.responses.is_empty());
+}
+
+#[test]
+fn test_multi_response_errors() {
+    let df = cbpp();
+    let spec = canonicalize("cbind(incidence, missing) ~ period").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+    let spec = canonicalize("cbind(incidence, incidence) ~ period").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+}
+
+#[test]
+fn test_surv_response_on_kidney() {
+    let df = CsvReadOptions::default()
+        .try_into_reader_with_file_path(Some("examples/data/kidney.csv".into()))
+        .unwrap()
+        .finish()
+        .unwrap();
+    let spec = canonicalize("Surv(time, censored) ~ age + sex").unwrap();
+    let (y, x, _) = materialize(&spec, &df).unwrap();
+    assert_eq!(y.get_column_names(), ["time", "status"]);
+    assert_eq!(y.height(), 76);
+    assert_eq!(x.height(), 76);
+    assert_eq!(column(&y, "time")[..3], [8.0, 23.0, 22.0]);
+    assert_eq!(column(&y, "status").iter().sum::<f64>(), 18.0);
+}
+
+#[test]
+fn test_surv_status_codings() {
+    let df = df!(
+        "time" => [5.0, 3.0, 8.0],
+        "logical" => [true, false, true],
+        "coded" => [2, 1, 2],
+        "outcome" => ["died", "alive", "died"],
+        "x" => [1.0, 2.0, 3.0]
+    )
+    .unwrap();
+    for event in ["logical", "coded", "outcome"] {
+        let spec = canonicalize(&format!("Surv(time, {}) ~ x", event)).unwrap();
+        let (y, _, _) = materialize(&spec, &df).unwrap();
+        assert_eq!(column(&y, "status"), [1.0, 0.0, 1.0], "{}", event);
+    }
+
+    let spec = canonicalize("Surv(time, x) ~ 1").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+}
+
+#[test]
+fn test_surv_counting_process() {
+    let df = df!(
+        "start" => [0.0, 5.0, 0.0],
+        "stop" => [5.0, 9.0, 4.0],
+        "event" => [0, 1, 1],
+        "x" => [1.0, 2.0, 3.0]
+    )
+    .unwrap();
+    let spec = canonicalize("Surv(start, stop, event) ~ x").unwrap();
+    let (y, _, _) = materialize(&spec, &df).unwrap();
+    assert_eq!(y.get_column_names(), ["start", "stop", "status"]);
+    assert_eq!(column(&y, "stop"), [5.0, 9.0, 4.0]);
+    assert_eq!(column(&y, "status"), [0.0, 1.0, 1.0]);
+
+    // Intervals must be non-empty and times non-negative
+    let spec = canonicalize("Surv(stop, start, event) ~ x").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+    let spec = canonicalize("Surv(x - 2, event) ~ 1").unwrap();
+    assert!(materialize(&spec, &df).is_err());
+}
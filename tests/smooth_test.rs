@@ -0,0 +1,177 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize_new_data, materialize_with_info, materialize_with_options,
+    MaterializeOptions,
+};
+
+fn column(df: &DataFrame, name: &str) -> Vec<f64> {
+    df.column(name)
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_iter()
+        .map(|v| v.unwrap())
+        .collect()
+}
+
+fn data(n: usize) -> DataFrame {
+    let x: Vec<f64> = (0..n).map(|i| i as f64 / n as f64).collect();
+    let z: Vec<f64> = x.iter().map(|v| (v * 5.0).cos()).collect();
+    let y: Vec<f64> = x.iter().map(|v| (v * 6.0).sin()).collect();
+    let f: Vec<&str> = (0..n).map(|i| ["a", "b", "c"][i % 3]).collect();
+    df!("y" => y, "x" => x, "z" => z, "f" => f).unwrap()
+}
+
+#[test]
+fn test_s_is_centered_and_penalized() {
+    let df = data(50);
+    let spec = canonicalize("y ~ s(x, bs=\"cr\", k=6)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+
+    // intercept + k - 1 columns after the sum-to-zero constraint
+    assert_eq!(x.width(), 6);
+    assert_eq!(info.smooths.len(), 1);
+    let smooth = &info.smooths[0];
+    assert_eq!(
+        smooth.columns,
+        vec!["s_x_1", "s_x_2", "s_x_3", "s_x_4", "s_x_5"]
+    );
+    assert_eq!(smooth.penalties.len(), 1);
+    assert_eq!(smooth.penalties[0].len(), 5);
+    assert!(smooth.constraint.is_some());
+    for name in &smooth.columns {
+        let values = column(&x, name);
+        assert!(values.iter().sum::<f64>().abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_default_thin_plate_and_new_data() {
+    let df = data(40);
+    let spec = canonicalize("y ~ s(x)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 10); // intercept + (10 - 1)
+
+    let subset = df.slice(5, 4);
+    let (_, x_new, _) = materialize_new_data(&spec, &subset, &info).unwrap();
+    for name in &info.smooths[0].columns {
+        let full = column(&x, name);
+        for (a, b) in full[5..9].iter().zip(column(&x_new, name)) {
+            assert!((a - b).abs() < 1e-10);
+        }
+    }
+}
+
+#[test]
+fn test_factor_by_builds_one_smooth_per_level() {
+    let df = data(60);
+    let spec = canonicalize("y ~ s(x, by=f, bs=\"cr\", k=5)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1 + 3 * 4);
+    let levels: Vec<_> = info.smooths.iter().map(|s| s.by_level.clone()).collect();
+    assert_eq!(
+        levels,
+        vec![Some("a".into()), Some("b".into()), Some("c".into())]
+    );
+
+    // Each level's columns vanish on the rows of the other levels
+    let fa = column(&x, "s_x_fa_1");
+    for (i, v) in fa.iter().enumerate() {
+        if i % 3 != 0 {
+            assert_eq!(*v, 0.0);
+        }
+    }
+}
+
+#[test]
+fn test_tensor_products_and_random_effect_smooths() {
+    let df = data(60);
+
+    let spec = canonicalize("y ~ te(x, z, k=4)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1 + 15);
+    assert_eq!(info.smooths[0].penalties.len(), 2);
+
+    let spec = canonicalize("y ~ ti(x, z, k=4)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1 + 9);
+    assert!(info.smooths[0].constraint.is_none());
+
+    let spec = canonicalize("y ~ s(f, bs=\"re\")").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1 + 3);
+    assert_eq!(info.smooths[0].penalties[0][1], vec![0.0, 1.0, 0.0]);
+
+    let spec = canonicalize("y ~ s(x, f, bs=\"fs\", xt=\"cr\", k=5)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    assert_eq!(x.width(), 1 + 3 * 5);
+    // wiggliness penalty plus one per null space direction of the cr base
+    assert_eq!(info.smooths[0].penalties.len(), 3);
+}
+
+#[test]
+fn test_unknown_basis_is_rejected() {
+    let df = data(20);
+    let spec = canonicalize("y ~ s(x, bs=\"zz\")").unwrap();
+    assert!(materialize_with_info(&spec, &df).is_err());
+}
+
+#[test]
+fn test_smooth_column_ranges_and_null_space() {
+    let df = data(50);
+    let spec = canonicalize("y ~ z + s(x, bs=\"cr\", k=6) + te(x, z, k=3)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let names = x.get_column_names();
+
+    assert_eq!(info.smooths[0].column_range, 2..7);
+    assert_eq!(info.smooths[0].null_space_dim, 1);
+    assert_eq!(info.smooths[1].column_range, 7..15);
+    assert_eq!(info.smooths[1].null_space_dim, 3);
+    for smooth in &info.smooths {
+        for (name, i) in smooth.columns.iter().zip(smooth.column_range.clone()) {
+            assert_eq!(names[i].as_str(), name);
+        }
+    }
+}
+
+#[test]
+fn test_mixed_model_smooths_split_between_x_and_z() {
+    let df = data(50);
+    let spec = canonicalize("y ~ s(x, by=f, bs=\"cr\", k=6) + t2(x, z, k=4)").unwrap();
+    let opts = MaterializeOptions {
+        mixed_model_smooths: true,
+        ..Default::default()
+    };
+    let (_, x, z, info) = materialize_with_options(&spec, &df, opts).unwrap();
+
+    // per level: 1 fixed + 4 random; t2: 3 penalized blocks, 4 - 1 fixed columns
+    assert_eq!(x.width(), 1 + 3 + 3);
+    assert_eq!(z.width(), 3 * 4 + 12);
+    let t2 = &info.smooths[3];
+    assert_eq!(t2.penalties.len(), 3);
+    assert_eq!(t2.random_column_range, 12..24);
+
+    // New data keeps the mixed model form
+    let (_, x_new, z_new) = materialize_new_data(&spec, &df.slice(0, 5), &info).unwrap();
+    assert_eq!(x_new.width(), x.width());
+    assert_eq!(z_new.width(), z.width());
+
+    // te() penalties overlap and have no random effects form
+    let spec = canonicalize("y ~ te(x, z)").unwrap();
+    let opts = MaterializeOptions {
+        mixed_model_smooths: true,
+        ..Default::default()
+    };
+    assert!(materialize_with_options(&spec, &df, opts).is_err());
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_penalties_as_faer_matrices() {
+    let df = data(30);
+    let spec = canonicalize("y ~ s(x, bs=\"ps\", k=6)").unwrap();
+    let (_, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let penalties = info.smooths[0].penalties_faer();
+    assert_eq!(penalties[0].nrows(), 5);
+    assert_eq!(penalties[0][(1, 2)], info.smooths[0].penalties[0][1][2]);
+}
@@ -0,0 +1,110 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize, materialize_sparse, materialize_sparse_with_options,
+    materialize_with_options, MaterializeOptions,
+};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [0.5, 0.0, 1.5, 2.0, 2.5, 3.0],
+        "g" => ["b", "a", "c", "a", "b", "c"],
+        "h" => [2i64, 10, 2, 10, 10, 2]
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_sparse_z_matches_dense_z() {
+    let df = data();
+    let spec = canonicalize("y ~ x + (1|g) + (0 + x|h)").unwrap();
+    let (y, x, z) = materialize(&spec, &df).unwrap();
+    let (y_sparse, x_sparse, z_sparse) = materialize_sparse(&spec, &df).unwrap();
+
+    assert!(y.equals(&y_sparse));
+    assert!(x.equals(&x_sparse));
+    assert_eq!(z_sparse.to_dense().unwrap(), z);
+    assert_eq!(
+        z_sparse.column_names,
+        z.get_column_names()
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+    );
+    // One entry per row and term; the slope at x = 0 is kept.
+    assert_eq!(z_sparse.nnz(), 12);
+}
+
+#[test]
+fn test_sparse_z_term_metadata() {
+    let df = data();
+    let spec = canonicalize("y ~ (1|g) + (0 + x|h)").unwrap();
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+
+    assert_eq!(z.terms.len(), 2);
+    assert_eq!(z.terms[0].group, "g");
+    assert_eq!(z.terms[0].levels, ["a", "b", "c"]);
+    assert_eq!(z.terms[0].inner, ["(Intercept)"]);
+    assert_eq!(z.terms[0].columns, 0..3);
+    // Numeric groups sort numerically
+    assert_eq!(z.terms[1].levels, ["2", "10"]);
+    assert_eq!(z.terms[1].inner, ["x"]);
+    assert_eq!(z.terms[1].columns, 3..5);
+    assert_eq!(z.get(2, 3), 1.5);
+    assert_eq!(z.get(1, 4), 0.0);
+}
+
+#[test]
+fn test_null_groups_have_no_entries() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0],
+        "g" => [Some("a"), None, Some("b")]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ (1|g)").unwrap();
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+
+    assert_eq!(z.terms[0].levels, ["a", "b"]);
+    assert_eq!(z.matrix.col_ptr, vec![0, 1, 2]);
+    assert_eq!(z.matrix.row_idx, vec![0, 2]);
+    assert_eq!(z.to_csr().row_ptr, vec![0, 1, 1, 2]);
+}
+
+#[test]
+fn test_sparse_z_includes_mixed_model_smooths() {
+    let x: Vec<f64> = (0..20).map(|i| i as f64 / 20.0).collect();
+    let y: Vec<f64> = x.iter().map(|v| (v * 6.0).sin()).collect();
+    let g: Vec<&str> = (0..20).map(|i| ["a", "b"][i % 2]).collect();
+    let df = df!("y" => y, "x" => x, "g" => g).unwrap();
+    let spec = canonicalize("y ~ (1|g) + s(x, bs=\"cr\", k=5)").unwrap();
+    let opts = MaterializeOptions {
+        mixed_model_smooths: true,
+        ..Default::default()
+    };
+    let (_, _, z, info) = materialize_with_options(&spec, &df, opts.clone()).unwrap();
+
+    let (_, _, z_sparse, sparse_info) = materialize_sparse_with_options(&spec, &df, opts).unwrap();
+    assert_eq!(z_sparse.to_dense().unwrap(), z);
+    assert_eq!(
+        sparse_info.smooths[0].random_column_range,
+        info.smooths[0].random_column_range
+    );
+    assert_eq!(
+        sparse_info.smooths[0].random_columns,
+        info.smooths[0].random_columns
+    );
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_sparse_z_to_faer() {
+    let df = data();
+    let spec = canonicalize("y ~ (1|g) + (0 + x|h)").unwrap();
+    let (_, _, z) = materialize_sparse(&spec, &df).unwrap();
+    let dense = z.to_faer().to_dense();
+    for i in 0..z.nrows() {
+        for j in 0..z.ncols() {
+            assert_eq!(dense[(i, j)], z.get(i, j));
+        }
+    }
+}
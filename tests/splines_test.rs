@@ -0,0 +1,255 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize, materialize_new_data, materialize_with_info, SplineState,
+};
+
+fn column(df: &DataFrame, name: &str) -> Vec<f64> {
+    df.column(name)
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_iter()
+        .map(|v| v.unwrap())
+        .collect()
+}
+
+#[test]
+fn test_bs_expands_to_df_columns() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+        "x" => [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]
+    )
+    .unwrap();
+
+    let spec = canonicalize("y ~ bs(x, df=5)").unwrap();
+    let (_, x, _) = materialize(&spec, &df).unwrap();
+
+    // intercept + 5 basis columns; the basis without intercept never sums past one
+    assert_eq!(x.width(), 6);
+    assert_eq!(x.get_column_names()[1].as_str(), "bs_x_1");
+    let cols: Vec<Vec<f64>> = (1..=5)
+        .map(|i| column(&x, &format!("bs_x_{}", i)))
+        .collect();
+    for row in 0..df.height() {
+        let total: f64 = cols.iter().map(|c| c[row]).sum();
+        assert!((0.0..=1.0 + 1e-12).contains(&total));
+    }
+}
+
+#[test]
+fn test_spline_knots_are_reused_for_new_data() {
+    let train = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+        "x" => [0.0, 1.0, 2.0, 4.0, 8.0, 16.0]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ ns(x, df=3)").unwrap();
+    let (_, x_train, _, info) = materialize_with_info(&spec, &train).unwrap();
+    assert!(info.transforms.contains_key("ns(x, df=3)"));
+
+    // Re-materializing a subset of the training rows must give the same values
+    let subset = train.slice(2, 3);
+    let (_, x_new, _) = materialize_new_data(&spec, &subset, &info).unwrap();
+    for name in ["ns_x_1", "ns_x_2", "ns_x_3"] {
+        let expected = column(&x_train, name);
+        let actual = column(&x_new, name);
+        for (a, e) in actual.iter().zip(&expected[2..5]) {
+            assert!((a - e).abs() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn test_cr_center_constraint_drops_a_column() {
+    let df = df!(
+        "y" => [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0],
+        "x" => [0.0, 0.5, 1.0, 2.0, 3.0, 5.0, 8.0]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ cr(x, df=4, constraints=\"center\")").unwrap();
+    let (_, x, _) = materialize(&spec, &df).unwrap();
+    assert_eq!(x.width(), 5);
+
+    // Centered columns have zero mean on the training data
+    for i in 1..=4 {
+        let col = column(&x, &format!("cr_x_{}", i));
+        let mean = col.iter().sum::<f64>() / col.len() as f64;
+        assert!(mean.abs() < 1e-12);
+    }
+}
+
+// Reference bases at fixed x, for `splines::bs(x, df = 5)`, `splines::ns(x,
+// df = 4)` (at x, then at 11 and -1 beyond the boundary), and mgcv's cardinal
+// `cr` basis with knots 0.3, 2, 4.5, 7, 9.6 and `cc` basis with knots 0, 2.5,
+// 5, 7.5, 10, without and (for `cr`) with the sum-to-zero constraint absorbed
+// through the QR of the column means. They were computed apart from this
+// crate, from the definitions: exact rational Cox-de Boor recursion for the
+// B-splines, LINPACK's Householder QR of the boundary constraints for `ns`,
+// and the interpolating natural and periodic cubic splines for `cr` and `cc`.
+const X: [f64; 12] = [0.3, 1.1, 1.9, 2.2, 3.0, 3.4, 4.7, 5.5, 6.1, 7.8, 8.2, 9.6];
+
+#[rustfmt::skip]
+const BS_DF5: [[f64; 5]; 12] = [
+    [0.0, 0.0, 0.0, 0.0, 0.0],
+    [0.5746836563290457, 0.1187001874307622, 0.004189784127919346, 0.0, 0.0],
+    [0.5611897735572422, 0.3651266136013488, 0.03351827302335477, 0.0, 0.0],
+    [0.4764535690364267, 0.4568889505180147, 0.05612837760429453, 0.0, 0.0],
+    [0.22752808988764045, 0.6117850846479483, 0.16055126110894033, 0.00013556435547092198, 0.0],
+    [0.14064595181948492, 0.6215465341103197, 0.23568932101596227, 0.0021181930542331557, 0.0],
+    [0.01155962454339483, 0.44481824063426767, 0.4892430884116686, 0.054379046410668916, 0.0],
+    [9.247699634715864e-05, 0.27651062860593856, 0.5720024879101127, 0.1513944064876017, 0.0],
+    [0.0, 0.1721514209769769, 0.5564698716882698, 0.2702997950132375, 0.0010789123215158719],
+    [0.0, 0.02341660844636103, 0.2519864907420583, 0.5684749162872292, 0.1561219845243514],
+    [0.0, 0.01101769094252652, 0.1673541763436878, 0.5582218042186998, 0.2634063284950859],
+    [0.0, 0.0, 0.0, 0.0, 1.0],
+];
+
+#[rustfmt::skip]
+const NS_DF4: [[f64; 4]; 14] = [
+    [0.0, 0.0, 0.0, 0.0],
+    [0.012018118134639013, -0.11055286042262397, 0.31008729142931113, -0.19953443100668716],
+    [0.0961449450771121, -0.17944949108588026, 0.5033339384116153, -0.3238844473257351],
+    [0.16096657637540537, -0.1860513433914601, 0.5218700187608117, -0.33581201207217454],
+    [0.408096865751516, -0.14110239219369028, 0.42545361102565554, -0.2737701497034653],
+    [0.5324577377213217, -0.08677321944262784, 0.3352109713291567, -0.21570097285528347],
+    [0.6510483872382058, 0.21095805324242123, 0.1290549342791202, -0.08072604814566926],
+    [0.49221498758381466, 0.41955406795291517, 0.13074414350384406, -0.05839882602970581],
+    [0.3326645964033459, 0.533405210467271, 0.16852007981866102, -0.03572228589122801],
+    [0.04571599905640203, 0.43459487917872536, 0.29775336008956776, 0.22193576167530482],
+    [0.02150972246412331, 0.3308099322020282, 0.32535213108459227, 0.3223282142492562],
+    [0.0, -0.14885683446178002, 0.41752526739279766, 0.7313315670689824],
+    [0.0, -0.6681385536257484, 0.5081935040722207, 1.159945049553527],
+    [0.0, 0.19093029374659268, -0.5355361897770282, 0.34460589603043557],
+];
+
+#[rustfmt::skip]
+const CR_FREE: [[f64; 5]; 12] = [
+    [1.0, 0.0, 0.0, 0.0, 0.0],
+    [0.44888151259502956, 0.6204628141573445, -0.08750253190196475, 0.02159517614247207, -0.003436970992881346],
+    [0.03520132212591459, 0.985139680388115, -0.025667409357909676, 0.0063345850017918065, -0.0010081781579118626],
+    [-0.0571101764586734, 1.0051184276233482, 0.06547201296333816, -0.01603179807108026, 0.0025515339430672674],
+    [-0.13999928511003348, 0.771069165604261, 0.44877780028357295, -0.0949611878904972, 0.015113507112696913],
+    [-0.12000142977993303, 0.5609656687914786, 0.6594363994328539, -0.11940439344977488, 0.01900375500537543],
+    [0.015423036137687793, -0.06114925367870461, 0.9901193178043343, 0.06603521964444317, -0.010428319907760882],
+    [0.03818703904490699, -0.15140397240524728, 0.739498480858821, 0.4323417210700986, -0.05862326856857949],
+    [0.02794361901130718, -0.11079086065603073, 0.4297294843022079, 0.7271209670151602, -0.07400320967264457],
+    [-0.012098137894942339, 0.047966697125867375, -0.16585095277418546, 0.9206944782364297, 0.20928791530683055],
+    [-0.012831358373423694, 0.05087376967895024, -0.17590252566959064, 0.7806899477798965, 0.35717016658416745],
+    [0.0, 0.0, 0.0, 0.0, 1.0],
+];
+
+#[rustfmt::skip]
+const CR_CENTERED: [[f64; 4]; 12] = [
+    [-0.6458239866645096, -0.4998995374716007, -0.4732041583584094, -0.2528281442210957],
+    [0.1354540419007948, -0.4629231819972486, -0.33377745264056224, -0.19330893355948034],
+    [0.6289078577571209, -0.301408365164817, -0.25468139348361263, -0.1404663470832473],
+    [0.6825223569772874, -0.1842332160712957, -0.2524023963330171, -0.12373886027982535],
+    [0.498656744931572, 0.23791718515046234, -0.2945615324310612, -0.09153091645101094],
+    [0.2974570987538876, 0.4554678027228898, -0.3124807636057838, -0.0841549757395814],
+    [-0.3289437711815885, 0.7828331869058475, -0.13018152343818462, -0.11526491980322054],
+    [-0.42195791028443325, 0.5300764228373305, 0.2341031125509314, -0.16454013049715055],
+    [-0.3784459284287956, 0.22255129435516685, 0.531006400676563, -0.1787852175783266],
+    [-0.17679654277149512, -0.33982875785016486, 0.7560073468171509, 0.12129726459429963],
+    [-0.1563669598969809, -0.33631704572380255, 0.628841801781094, 0.2760392545282062],
+    [-0.13466300109286072, -0.10423578769276783, -0.09866944153510881, 0.9472819260904322],
+];
+
+#[rustfmt::skip]
+const CC_FREE: [[f64; 4]; 12] = [
+    [0.96976, 0.10944000000000004, -0.009504000000000012, -0.06969599999999995],
+    [0.6708799999999998, 0.5139200000000002, -0.08131200000000002, -0.103488],
+    [0.24912000000000006, 0.8876799999999999, -0.10396800000000006, -0.032832],
+    [0.10943999999999993, 0.9697600000000002, -0.06969599999999998, -0.009503999999999985],
+    [-0.096, 0.92, 0.19999999999999996, -0.024000000000000007],
+    [-0.11059200000000004, 0.76672, 0.4060799999999999, -0.06220800000000003],
+    [-0.009504000000000012, 0.10943999999999982, 0.9697600000000001, -0.06969599999999992],
+    [-0.024000000000000007, -0.09600000000000007, 0.9199999999999998, 0.19999999999999996],
+    [-0.081312, -0.10348800000000011, 0.6708800000000001, 0.5139199999999998],
+    [0.10943999999999995, -0.009503999999999978, -0.06969599999999997, 0.9697600000000001],
+    [0.3001599999999996, -0.0423359999999999, -0.10886400000000002, 0.8510400000000002],
+    [0.9475199999999998, -0.08467200000000008, -0.01612800000000003, 0.15328000000000008],
+];
+
+fn assert_basis<const K: usize>(basis: &[Vec<f64>], expected: &[[f64; K]]) {
+    assert_eq!(basis.len(), K);
+    for (j, column) in basis.iter().enumerate() {
+        assert_eq!(column.len(), expected.len());
+        for (i, value) in column.iter().enumerate() {
+            assert!(
+                (value - expected[i][j]).abs() < 1e-10,
+                "row {}, column {}: {} != {}",
+                i,
+                j,
+                value,
+                expected[i][j]
+            );
+        }
+    }
+}
+
+fn columns(df: &DataFrame, prefix: &str) -> Vec<Vec<f64>> {
+    df.get_column_names()
+        .into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| column(df, name))
+        .collect()
+}
+
+#[test]
+fn test_bs_and_ns_match_the_reference() {
+    let n = X.len();
+    let df = df!("y" => vec![0.0; n], "x" => X.to_vec()).unwrap();
+
+    let spec = canonicalize("y ~ bs(x, df=5)").unwrap();
+    let (_, x, _) = materialize(&spec, &df).unwrap();
+    assert_basis(&columns(&x, "bs_x_"), &BS_DF5);
+
+    let spec = canonicalize("y ~ ns(x, df=4)").unwrap();
+    let (_, x, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let new = df!("y" => [0.0, 0.0], "x" => [11.0, -1.0]).unwrap();
+    let (_, x_new, _) = materialize_new_data(&spec, &new, &info).unwrap();
+    let mut basis = columns(&x, "ns_x_");
+    for (column, extra) in basis.iter_mut().zip(columns(&x_new, "ns_x_")) {
+        column.extend(extra);
+    }
+    assert_basis(&basis, &NS_DF4);
+}
+
+#[test]
+fn test_cr_and_cc_match_the_reference() {
+    let inner = Some(vec![2.0, 4.5, 7.0]);
+    let free =
+        SplineState::cubic_regression(&X, None, inner.clone(), (None, None), false, false).unwrap();
+    assert_basis(&free.basis(&X), &CR_FREE);
+    let centered =
+        SplineState::cubic_regression(&X, None, inner, (None, None), true, false).unwrap();
+    assert_basis(&centered.basis(&X), &CR_CENTERED);
+
+    let cyclic = SplineState::cubic_regression(
+        &X,
+        None,
+        Some(vec![2.5, 5.0, 7.5]),
+        (Some(0.0), Some(10.0)),
+        false,
+        true,
+    )
+    .unwrap();
+    assert_basis(&cyclic.basis(&X), &CC_FREE);
+}
+
+#[test]
+fn test_spline_df_that_cannot_be_met_is_an_error() {
+    // Constant or two-valued x leaves no room for interior knots
+    for x in [vec![2.0; 6], vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0]] {
+        let df = df!("y" => vec![0.0; 6], "x" => x).unwrap();
+        for formula in ["y ~ bs(x, df=5)", "y ~ ns(x, df=4)"] {
+            let err = materialize(&canonicalize(formula).unwrap(), &df).unwrap_err();
+            assert!(err.to_string().contains("interior knots"), "{}", err);
+        }
+    }
+
+    // Boundary knots that exclude the data
+    assert!(SplineState::bs(&X, Some(5), None, 3, false, Some((20.0, 30.0))).is_err());
+    assert!(SplineState::ns(&X, Some(4), None, false, Some((20.0, 30.0))).is_err());
+    assert!(SplineState::bs(&X, Some(3), None, 3, false, Some((20.0, 30.0))).is_ok());
+}
@@ -0,0 +1,186 @@
+use polars::prelude::*;
+use polars_formula::{
+    canonicalize, materialize_batches, materialize_with_info, sufficient_stats,
+    sufficient_stats_lazy,
+};
+
+fn data() -> DataFrame {
+    df!(
+        "y" => [Some(1.0), Some(2.5), Some(2.0), None, Some(5.0), Some(6.5), Some(6.0), Some(8.0)],
+        "x" => [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5, 4.0],
+        "w" => [1.0, 2.0, 0.5, 1.0, 1.5, 1.0, 3.0, 0.5],
+        "o" => [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8],
+        "g" => ["a", "b", "a", "c", "b", "c", "a", "b"]
+    )
+    .unwrap()
+}
+
+fn values(column: &Column) -> Vec<f64> {
+    column
+        .cast(&DataType::Float64)
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect()
+}
+
+fn assert_close(a: &[Vec<f64>], b: &[Vec<f64>]) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(b) {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+        }
+    }
+}
+
+#[test]
+fn test_stats_match_the_materialized_design() {
+    let df = data();
+    let spec = canonicalize("y | weights(w) ~ x + g + poly(x, 2) + offset(o)").unwrap();
+
+    // Cross-products of the complete rows, computed from the whole design,
+    // whose state is learned from all the rows
+    let (_, x, _, _) = materialize_with_info(&spec, &df).unwrap();
+    let complete = |values: Vec<f64>| -> Vec<f64> {
+        let mut values = values;
+        values.remove(3);
+        values
+    };
+    let column = |name: &str| df.column(name).unwrap().clone();
+    let x: Vec<Vec<f64>> = x
+        .get_columns()
+        .iter()
+        .map(|c| complete(values(c)))
+        .collect();
+    let w = complete(values(&column("w")));
+    let y: Vec<f64> = values(&column("y").drop_nulls())
+        .iter()
+        .zip(complete(values(&column("o"))))
+        .map(|(y, o)| y - o)
+        .collect();
+    let cross = |a: &[f64], b: &[f64]| -> f64 {
+        a.iter().zip(b).zip(&w).map(|((a, b), w)| a * b * w).sum()
+    };
+    let xtwx: Vec<Vec<f64>> = x
+        .iter()
+        .map(|a| x.iter().map(|b| cross(a, b)).collect())
+        .collect();
+    let xtwy: Vec<Vec<f64>> = x.iter().map(|a| vec![cross(a, &y)]).collect();
+    let ones = vec![1.0; y.len()];
+
+    for size in [1, 3, 100] {
+        let stats = sufficient_stats(&spec, &df, size).unwrap();
+        assert_eq!(stats.n, 7);
+        assert_eq!(
+            stats.columns,
+            ["intercept", "x", "g_b", "g_c", "poly_x_1", "poly_x_2"]
+        );
+        assert_eq!(stats.responses, ["y"]);
+        assert!((stats.weight_sum - w.iter().sum::<f64>()).abs() < 1e-12);
+        assert_close(&stats.xtwx, &xtwx);
+        assert_close(&stats.xtwy, &xtwy);
+        assert_close(&stats.ytwy, &[vec![cross(&y, &y)]]);
+        assert_close(
+            std::slice::from_ref(&stats.column_sums),
+            &[x.iter().map(|a| cross(a, &ones)).collect()],
+        );
+        assert_close(
+            std::slice::from_ref(&stats.response_sums),
+            &[vec![cross(&y, &ones)]],
+        );
+
+        let lazy = sufficient_stats_lazy(&spec, &df.clone().lazy(), size).unwrap();
+        assert_close(&lazy.xtwx, &stats.xtwx);
+        assert_close(&lazy.xtwy, &stats.xtwy);
+    }
+    assert!(sufficient_stats(&spec, &df, 0).is_err());
+}
+
+#[test]
+fn test_stats_from_batches() {
+    let df = data()
+        .drop("w")
+        .unwrap()
+        .drop_nulls::<String>(None)
+        .unwrap();
+    let spec = canonicalize("y ~ x + g").unwrap();
+    let (_, _, _, info) = materialize_with_info(&spec, &df).unwrap();
+    let batches = vec![df.slice(0, 4), df.slice(4, 3)];
+    let stats = materialize_batches(&spec, batches, &info)
+        .sufficient_stats()
+        .unwrap();
+
+    // Without weights the column sums are the sums of X, and the intercept
+    // column counts the rows
+    assert_eq!(stats.n, 7);
+    assert_eq!(stats.weight_sum, 7.0);
+    assert_eq!(stats.xtwx[0][0], 7.0);
+    assert_eq!(stats.column_sums, stats.xtwx[0]);
+    assert_eq!(stats.response_sums[0], stats.xtwy[0][0]);
+
+    let none = materialize_batches(&spec, Vec::new(), &info).sufficient_stats();
+    assert!(none.is_err());
+}
+
+#[test]
+fn test_stats_of_a_subset_and_several_responses() {
+    let df = df!(
+        "a" => [1.0, 2.0, 3.0, 4.0],
+        "b" => [2.0, 0.0, 1.0, 1.0],
+        "x" => [1.0, 2.0, 3.0, 4.0],
+        "keep" => [true, true, false, true]
+    )
+    .unwrap();
+    let spec = canonicalize("mvbind(a, b) | subset(keep) ~ x").unwrap();
+    let stats = sufficient_stats(&spec, &df, 2).unwrap();
+    assert_eq!(stats.n, 3);
+    assert_eq!(stats.responses, ["a", "b"]);
+    assert_eq!(stats.xtwy, [[7.0, 3.0], [21.0, 6.0]]);
+    assert_eq!(stats.ytwy, [[21.0, 6.0], [6.0, 5.0]]);
+}
+
+#[test]
+fn test_stats_do_not_materialize_z() {
+    // A dense Z of one column per level would need 40000² values, 12.8 GB
+    let n = 40_000;
+    let df = df!(
+        "y" => (0..n).map(|i| (i % 7) as f64).collect::<Vec<_>>(),
+        "x" => (0..n).map(|i| (i % 5) as f64).collect::<Vec<_>>(),
+        "g" => (0..n).map(|i| format!("g{}", i)).collect::<Vec<_>>()
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ x + (1|g)").unwrap();
+    for stats in [
+        sufficient_stats(&spec, &df, n).unwrap(),
+        sufficient_stats_lazy(&spec, &df.clone().lazy(), n).unwrap(),
+    ] {
+        assert_eq!(stats.n, n);
+        assert_eq!(stats.columns, ["intercept", "x"]);
+        assert_eq!(stats.xtwx[0], [n as f64, 80_000.0]);
+    }
+}
+
+#[cfg(feature = "faer")]
+#[test]
+fn test_stats_solve_least_squares_with_faer() {
+    use faer::linalg::solvers::Solve;
+
+    let df = df!(
+        "y" => [1.0, 3.0, 5.0, 7.0, 9.0, 11.0],
+        "x" => [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]
+    )
+    .unwrap();
+    let spec = canonicalize("y ~ x").unwrap();
+    let stats = sufficient_stats_lazy(&spec, &df.lazy(), 4).unwrap();
+    let xtx = stats.xtwx_faer();
+    let xty = stats.xtwy_faer();
+    assert_eq!((xtx.nrows(), xtx.ncols()), (2, 2));
+    assert_eq!((xty.nrows(), xty.ncols()), (2, 1));
+    assert_eq!(stats.ytwy_faer()[(0, 0)], 286.0);
+    assert_eq!(stats.column_sums_faer()[1], 15.0);
+
+    let beta = xtx.partial_piv_lu().solve(&xty);
+    assert!((beta[(0, 0)] - 1.0).abs() < 1e-9);
+    assert!((beta[(1, 0)] - 2.0).abs() < 1e-9);
+}